@@ -0,0 +1,178 @@
+use std::collections::{HashMap, HashSet};
+use irc::client::prelude::ServerExt;
+use irc::client::data::{Command, Message};
+
+// Capabilities telling us which services account people are logged in to: on every
+// message, when they join, and when they log in or out
+const CAPABILITIES: &'static str = "account-tag extended-join account-notify";
+
+// Ask the server to tell us people's services accounts. Needs to happen before
+// identifying, like requesting message tags.
+pub fn request_accounts<T: ServerExt>(irc: &T) {
+    let requested = irc.send(Message {
+        tags: None,
+        prefix: None,
+        command: Command::Raw("CAP".to_owned(), vec!["REQ".to_owned()], Some(CAPABILITIES.to_owned())),
+    });
+    if let Err(err) = requested {
+        println!("[WARN] Could not request account capabilities, IRC admins can't be verified: {}", err);
+    }
+}
+
+// The capabilities `msg` acknowledges, if it is a "CAP * ACK :..." reply. Read from the
+// raw line, so it doesn't matter how the IRC library makes sense of CAP.
+fn acknowledged(msg: &Message) -> Vec<String> {
+    let line = msg.to_string();
+    let mut rest = line.trim_right();
+    // Skip the tags and the prefix
+    if rest.starts_with('@') {
+        rest = rest.splitn(2, ' ').nth(1).unwrap_or("");
+    }
+    if rest.starts_with(':') {
+        rest = rest.splitn(2, ' ').nth(1).unwrap_or("");
+    }
+    let (params, trailing) = match rest.find(" :") {
+        Some(start) => (&rest[..start], Some(&rest[start + 2..])),
+        None => (rest, None),
+    };
+    let params: Vec<&str> = params.split(' ').filter(|param| !param.is_empty()).collect();
+    if params.len() < 3 || params[0] != "CAP" || params[2] != "ACK" {
+        return vec![];
+    }
+    let capabilities: Vec<&str> = match trailing {
+        Some(trailing) => trailing.split(' ').collect(),
+        None => params[3..].to_vec(),
+    };
+    // A "-" in front disables a capability
+    capabilities.iter()
+                .filter(|capability| !capability.is_empty() && !capability.starts_with('-'))
+                .map(|capability| capability.to_string())
+                .collect()
+}
+
+// Nicks compare case-insensitively, with the RFC 1459 casemapping most servers use
+fn fold(nick: &str) -> String {
+    nick.chars()
+        .map(|c| {
+            match c {
+                '[' => '{',
+                ']' => '}',
+                '\\' => '|',
+                '~' => '^',
+                c => c.to_lowercase().next().unwrap_or(c),
+            }
+        })
+        .collect()
+}
+
+// Services accounts of the people in our channels, by nick, as the server announces them
+#[derive(Default)]
+pub struct Accounts {
+    // Capabilities the server acknowledged
+    acked: HashSet<String>,
+    by_nick: HashMap<String, String>,
+    // Channels of ours each nick with a known account is seen in
+    channels: HashMap<String, HashSet<String>>,
+}
+
+impl Accounts {
+    // Keep track of acknowledged capabilities, logins, logouts, joins, parts, kicks, nick
+    // changes and quits
+    pub fn track(&mut self, msg: &Message) {
+        for capability in acknowledged(msg) {
+            println!("[INFO] IRC server acknowledged {}", capability);
+            self.acked.insert(capability);
+        }
+        if let Command::KICK(ref channels, ref users, _) = msg.command {
+            for user in users.split(',') {
+                for channel in channels.split(',') {
+                    self.left(user, channel);
+                }
+            }
+            return;
+        }
+        let nick = match msg.source_nickname() {
+            Some(nick) => fold(nick),
+            None => return,
+        };
+        match msg.command {
+            // With extended-join, the account takes the place of the channel keys
+            Command::JOIN(ref channels, Some(ref account), Some(_)) => {
+                self.set(&nick, account);
+                for channel in channels.split(',') {
+                    self.channels.entry(nick.clone()).or_insert(HashSet::new()).insert(channel.to_lowercase());
+                }
+            }
+            Command::PART(ref channels, _) => {
+                for channel in channels.split(',') {
+                    self.left(&nick, channel);
+                }
+            }
+            Command::Raw(ref command, ref args, ref suffix) if command == "ACCOUNT" => {
+                match args.first().or(suffix.as_ref()) {
+                    Some(account) => self.set(&nick, account),
+                    None => {}
+                }
+            }
+            Command::NICK(ref new_nick) => {
+                if let Some(account) = self.by_nick.remove(&nick) {
+                    self.by_nick.insert(fold(new_nick), account);
+                }
+                if let Some(channels) = self.channels.remove(&nick) {
+                    self.channels.insert(fold(new_nick), channels);
+                }
+            }
+            Command::QUIT(_) => self.forget(&nick),
+            _ => {}
+        }
+    }
+
+    // "*" means logged out
+    fn set(&mut self, nick: &str, account: &str) {
+        if account == "*" {
+            self.by_nick.remove(nick);
+        } else {
+            self.by_nick.insert(nick.to_owned(), account.to_owned());
+        }
+    }
+
+    // Once someone shares none of our channels anymore, the server no longer tells us about
+    // them, and their nick may be taken by anyone
+    fn left(&mut self, nick: &str, channel: &str) {
+        let nick = fold(nick);
+        let gone = match self.channels.get_mut(&nick) {
+            Some(channels) => {
+                channels.remove(&channel.to_lowercase());
+                channels.is_empty()
+            }
+            None => true,
+        };
+        if gone {
+            self.forget(&nick);
+        }
+    }
+
+    fn forget(&mut self, nick: &str) {
+        self.by_nick.remove(nick);
+        self.channels.remove(nick);
+    }
+
+    // The account the sender of `msg` is logged in to. With account-tag every message of
+    // someone logged in carries their account, so a message without it is from someone
+    // who isn't. Otherwise the account is remembered from joins and account notifications,
+    // if the server tells us about both.
+    pub fn account<'a>(&'a self, msg: &'a Message) -> Option<&'a str> {
+        if self.acked.contains("account-tag") {
+            return msg.tags
+                      .iter()
+                      .flat_map(|tags| tags)
+                      .find(|tag| tag.0 == "account")
+                      .and_then(|tag| tag.1.as_ref())
+                      .map(|account| &account[..]);
+        }
+        if !self.acked.contains("extended-join") || !self.acked.contains("account-notify") {
+            return None;
+        }
+        msg.source_nickname().and_then(|nick| self.by_nick.get(&fold(nick))).map(|account| &account[..])
+    }
+}
@@ -0,0 +1,162 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use time;
+use outbound::Outbound;
+use super::{TelegramGroup, RelayState};
+
+// Number of days of activity kept around
+const DAYS_KEPT: usize = 7;
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+// What happened on one side of a mapping during one (UTC) day
+#[derive(Clone, Default, Debug)]
+pub struct Day {
+    // Days since the unix epoch
+    pub day: i64,
+    pub messages: usize,
+    // Messages by hour of the day
+    pub hours: [usize; 24],
+    // Messages per participant
+    pub chatters: HashMap<String, usize>,
+    // Messages per participant on the Telegram side, which don't count towards the rest
+    pub tg_chatters: HashMap<String, usize>,
+    // Topic changes, as (nick, topic)
+    pub topics: Vec<(String, String)>,
+    // Nicks we saw for the first time
+    pub new_nicks: Vec<String>,
+}
+
+// Recent activity in the channel of a mapping, and on the Telegram side
+#[derive(Clone, Default, Debug)]
+pub struct ChannelActivity {
+    // Most recent day last
+    pub days: VecDeque<Day>,
+    known_nicks: HashSet<String>,
+    // When the last message was said in the channel, as a unix timestamp
+    pub last_message: Option<i64>,
+}
+
+impl ChannelActivity {
+    // The stats for the current day, starting a new one if needed
+    fn today(&mut self) -> &mut Day {
+        let now = time::get_time().sec / SECONDS_PER_DAY;
+        if self.days.back().map_or(true, |day| day.day != now) {
+            if self.days.len() >= DAYS_KEPT {
+                self.days.pop_front();
+            }
+            self.days.push_back(Day { day: now, ..Day::default() });
+        }
+        self.days.back_mut().unwrap()
+    }
+
+    fn seen(&mut self, nick: &str) {
+        if self.known_nicks.insert(nick.to_owned()) {
+            self.today().new_nicks.push(nick.to_owned());
+        }
+    }
+
+    pub fn message(&mut self, nick: &str) {
+        self.seen(nick);
+        let hour = (time::get_time().sec % SECONDS_PER_DAY / 3600) as usize;
+        self.last_message = Some(time::get_time().sec);
+        let today = self.today();
+        today.messages += 1;
+        today.hours[hour] += 1;
+        *today.chatters.entry(nick.to_owned()).or_insert(0) += 1;
+    }
+
+    pub fn tg_message(&mut self, name: &str) {
+        *self.today().tg_chatters.entry(name.to_owned()).or_insert(0) += 1;
+    }
+
+    // The most active participants on both networks over the last `days` days, as
+    // (name, messages)
+    pub fn top(&self, days: i64, count: usize) -> Vec<(String, usize)> {
+        let since = time::get_time().sec / SECONDS_PER_DAY - days + 1;
+        let mut totals = HashMap::new();
+        for day in self.days.iter().filter(|day| day.day >= since) {
+            for (nick, messages) in &day.chatters {
+                *totals.entry(format!("{} (IRC)", nick)).or_insert(0) += *messages;
+            }
+            for (name, messages) in &day.tg_chatters {
+                *totals.entry(format!("{} (Telegram)", name)).or_insert(0) += *messages;
+            }
+        }
+        let mut totals: Vec<(String, usize)> = totals.into_iter().collect();
+        totals.sort_by(|a, b| (b.1, &a.0).cmp(&(a.1, &b.0)));
+        totals.truncate(count);
+        totals
+    }
+
+    pub fn join(&mut self, nick: &str) {
+        self.seen(nick);
+    }
+
+    pub fn topic(&mut self, nick: &str, topic: &str) {
+        self.today().topics.push((nick.to_owned(), topic.to_owned()));
+    }
+
+    // The stats of the day before today, if anything happened then
+    fn yesterday(&self) -> Option<&Day> {
+        let yesterday = time::get_time().sec / SECONDS_PER_DAY - 1;
+        self.days.iter().find(|day| day.day == yesterday)
+    }
+}
+
+// Activity per mapping, by Telegram group
+pub type Activity = HashMap<TelegramGroup, ChannelActivity>;
+
+fn digest(channel: &str, day: &Day) -> String {
+    let mut lines = vec![format!("Yesterday in {}: {} message{} from {} people",
+                                 channel,
+                                 day.messages,
+                                 if day.messages == 1 { "" } else { "s" },
+                                 day.chatters.len())];
+    if let Some((hour, &count)) = day.hours.iter().enumerate().max_by_key(|&(_, count)| *count) {
+        if count > 0 {
+            lines.push(format!("Busiest hour: {:02}:00–{:02}:00 UTC ({} messages)", hour, (hour + 1) % 24, count));
+        }
+    }
+    for &(ref nick, ref topic) in &day.topics {
+        lines.push(format!("{} changed the topic to: {}", nick, topic));
+    }
+    if !day.new_nicks.is_empty() {
+        lines.push(format!("New faces: {}", day.new_nicks.join(", ")));
+    }
+    lines.join("\n")
+}
+
+// Post a summary of the previous day's IRC activity to every mapped Telegram group, each
+// day at `hour` UTC
+pub fn post_digests(outbound: Arc<Outbound>, state: Arc<Mutex<RelayState>>, hour: i64) {
+    loop {
+        // Sleep until the next time the clock strikes `hour`
+        let now = time::get_time().sec;
+        let next = (now / SECONDS_PER_DAY) * SECONDS_PER_DAY + hour * 3600;
+        let next = if next <= now { next + SECONDS_PER_DAY } else { next };
+        thread::sleep(Duration::from_secs((next - now) as u64));
+
+        let digests: Vec<(TelegramGroup, i64, String)> = {
+            let state = state.lock().unwrap();
+            state.activity
+                 .iter()
+                 .filter_map(|(group, activity)| {
+                     let channel = match state.irc_channel.get(group) {
+                         Some(channel) => channel,
+                         None => return None,
+                     };
+                     match (activity.yesterday(), state.chat_ids.get(group)) {
+                         (Some(day), Some(&id)) => Some((group.clone(), id, digest(channel, day))),
+                         _ => None,
+                     }
+                 })
+                 .collect()
+        };
+        for (group, id, digest) in digests {
+            println!("[INFO] Posting daily digest to \"{}\"", group);
+            outbound.to_tg(&group, id, digest);
+        }
+    }
+}
@@ -0,0 +1,58 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use hyper::method::Method;
+use hyper::client::Request;
+use rustc_serialize::json::Json;
+use telegram_bot::types::Integer;
+use error::{self, ResultExt};
+use urls;
+use super::{ChatID, RelayState};
+
+// How often the admins of the mapped groups are looked up again, unless configured
+pub const DEFAULT_REFRESH_MINUTES: u64 = 60;
+
+// User ids of the administrators of a Telegram chat, its creator included
+fn fetch(token: &str, chat_id: ChatID) -> error::Result<Vec<Integer>> {
+    let mut url = try!(urls::parse(&format!("https://api.telegram.org/bot{}/getChatAdministrators", token),
+                                   "getChatAdministrators url"));
+    url.set_query_from_pairs(vec![("chat_id", chat_id.to_string())].iter().map(|&(key, ref value)| (key, &value[..])));
+    let mut resp = try!(Request::new(Method::Get, url)
+                            .and_then(|req| req.start())
+                            .and_then(|req| req.send())
+                            .context(format!("looking up the admins of chat {}", chat_id)));
+    if !resp.status.is_success() {
+        return Err(format!("looking up the admins of chat {}: server responded with {}", chat_id, resp.status).into());
+    }
+    let mut body = String::new();
+    try!(resp.read_to_string(&mut body).context("reading the admins"));
+    let reply = try!(Json::from_str(&body).map_err(|err| err.to_string()));
+    let members = try!(reply.find("result").and_then(|result| result.as_array()).ok_or("expected a list of admins"));
+    Ok(members.iter()
+              .filter_map(|member| member.find_path(&["user", "id"]).and_then(|id| id.as_i64()))
+              .collect())
+}
+
+// Whether the Telegram user `user_id` administrates the group `group`, as of the last lookup
+pub fn is_group_admin(state: &RelayState, group: &str, user_id: Integer) -> bool {
+    state.group_admins.get(group).map_or(false, |admins| admins.contains(&user_id))
+}
+
+// Look up the admins of every mapped group every `minutes` minutes. A group whose admins
+// can't be looked up keeps the ones from before.
+pub fn refresh(token: String, state: Arc<Mutex<RelayState>>, minutes: u64) {
+    loop {
+        let chat_ids: HashMap<_, _> = state.lock().unwrap().chat_ids.clone();
+        for (group, id) in chat_ids {
+            match fetch(&token, id) {
+                Ok(admins) => {
+                    state.lock().unwrap().group_admins.insert(group, admins);
+                }
+                Err(err) => println!("[WARN] {}", err),
+            }
+        }
+        thread::sleep(Duration::from_secs(minutes * 60));
+    }
+}
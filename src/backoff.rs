@@ -0,0 +1,60 @@
+use std::thread;
+use std::time::Duration;
+use rand;
+use super::ReconnectConfig;
+
+const DEFAULT_INITIAL_DELAY: f64 = 10.0;
+const DEFAULT_MAX_DELAY: f64 = 300.0;
+const DEFAULT_MULTIPLIER: f64 = 2.0;
+const DEFAULT_JITTER: f64 = 0.1;
+const DEFAULT_MAX_ATTEMPTS: u32 = 0;
+
+// Exponential backoff between attempts to recover from a connection error
+pub struct Backoff {
+    initial_delay: f64,
+    max_delay: f64,
+    multiplier: f64,
+    // Fraction of the delay that is randomly added or taken off
+    jitter: f64,
+    // 0 retries forever
+    max_attempts: u32,
+    attempts: u32,
+    delay: f64,
+}
+
+impl Backoff {
+    pub fn new(config: Option<&ReconnectConfig>) -> Backoff {
+        let config = config.cloned().unwrap_or(ReconnectConfig::default());
+        let initial_delay = config.initial_delay.unwrap_or(DEFAULT_INITIAL_DELAY);
+        Backoff {
+            initial_delay: initial_delay,
+            max_delay: config.max_delay.unwrap_or(DEFAULT_MAX_DELAY),
+            multiplier: config.multiplier.unwrap_or(DEFAULT_MULTIPLIER),
+            jitter: config.jitter.unwrap_or(DEFAULT_JITTER),
+            max_attempts: config.max_attempts.unwrap_or(DEFAULT_MAX_ATTEMPTS),
+            attempts: 0,
+            delay: initial_delay,
+        }
+    }
+
+    // Start over after things are working again
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+        self.delay = self.initial_delay;
+    }
+
+    // Wait before the next attempt. Once all attempts are used up the process exits, so that
+    // a supervisor can take over.
+    pub fn wait(&mut self, what: &str) {
+        self.attempts += 1;
+        if self.max_attempts > 0 && self.attempts > self.max_attempts {
+            println!("[ERROR] {} failed {} times in a row, giving up", what, self.max_attempts);
+            ::std::process::exit(1);
+        }
+        let jitter = self.delay * self.jitter * (rand::random::<f64>() * 2.0 - 1.0);
+        let delay = (self.delay + jitter).max(0.0);
+        println!("[WARN] Retrying {} in {:.1}s (attempt {})", what, delay, self.attempts);
+        thread::sleep(Duration::from_millis((delay * 1000.0) as u64));
+        self.delay = (self.delay * self.multiplier).min(self.max_delay);
+    }
+}
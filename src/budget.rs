@@ -0,0 +1,112 @@
+use std::sync::{Arc, Mutex, MutexGuard};
+use time;
+use error;
+use media::format_size;
+use store::{MediaUsage, StateStore};
+use super::Config;
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+// Limits on the bytes moved for mirroring media per UTC day and month, for hosts that pay
+// for their traffic. Downloads count, and so do uploads to a media store other than the
+// download directory. What a web server serves from the download directory doesn't pass
+// through us and can't be counted.
+pub struct Budget {
+    daily: Option<u64>,
+    monthly: Option<u64>,
+    usage: Mutex<MediaUsage>,
+    store: Arc<StateStore>,
+    // Why the budget ran out, until an admin has been told
+    notice: Mutex<Option<String>>,
+}
+
+fn today() -> (i64, i64) {
+    let now = time::now_utc();
+    (time::get_time().sec / SECONDS_PER_DAY, (now.tm_year as i64 + 1900) * 12 + now.tm_mon as i64)
+}
+
+impl Budget {
+    pub fn new(config: &Config, store: Arc<StateStore>) -> Budget {
+        let usage = store.load_media_usage().unwrap_or_else(|err| panic!("error loading media usage: {}", err));
+        Budget {
+            daily: config.media_daily_bytes,
+            monthly: config.media_monthly_bytes,
+            usage: Mutex::new(usage),
+            store: store,
+            notice: Mutex::new(None),
+        }
+    }
+
+    // The usage so far, started over if the day or the month has changed since
+    fn current(&self) -> MutexGuard<MediaUsage> {
+        let mut usage = self.usage.lock().unwrap();
+        let (day, month) = today();
+        if usage.day != day {
+            usage.day = day;
+            usage.day_bytes = 0;
+        }
+        if usage.month != month {
+            usage.month = month;
+            usage.month_bytes = 0;
+        }
+        usage
+    }
+
+    // Whether `size` more bytes, if known, can be spent. Once the budget is used up nothing
+    // is mirrored until the next day or month.
+    pub fn check(&self, size: Option<u64>) -> error::Result<()> {
+        let usage = self.current();
+        let size = size.unwrap_or(0);
+        let limits = [("daily", self.daily, usage.day_bytes as u64), ("monthly", self.monthly, usage.month_bytes as u64)];
+        for &(period, limit, used) in &limits {
+            if let Some(limit) = limit {
+                if used >= limit || used + size > limit {
+                    return Err(format!("the {} media budget of {} is used up", period, format_size(limit as i64)).into());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Count `bytes` moved for mirroring, noting it for the admins if it uses up a budget
+    pub fn spend(&self, bytes: u64) {
+        let mut usage = self.current();
+        usage.day_bytes += bytes as i64;
+        usage.month_bytes += bytes as i64;
+        if let Err(err) = self.store.save_media_usage(&usage) {
+            println!("[ERROR] Could not save media usage: {}", err);
+        }
+        let limits = [("daily", self.daily, usage.day_bytes as u64), ("monthly", self.monthly, usage.month_bytes as u64)];
+        for &(period, limit, used) in &limits {
+            if let Some(limit) = limit {
+                if used >= limit && used - bytes < limit {
+                    let notice = format!("The {} media budget of {} is used up, media is relayed as text until it \
+                                          renews",
+                                         period,
+                                         format_size(limit as i64));
+                    println!("[WARN] {}", notice);
+                    *self.notice.lock().unwrap() = Some(notice);
+                }
+            }
+        }
+    }
+
+    // Why the budget ran out, if that hasn't been passed on yet
+    pub fn take_notice(&self) -> Option<String> {
+        self.notice.lock().unwrap().take()
+    }
+
+    // Bytes spent today and this month, for the status command
+    pub fn describe(&self) -> String {
+        let usage = self.current();
+        let describe = |used: i64, limit: Option<u64>| {
+            match limit {
+                Some(limit) => format!("{} of {}", format_size(used), format_size(limit as i64)),
+                None => format_size(used),
+            }
+        };
+        format!("media today {}, this month {}",
+                describe(usage.day_bytes, self.daily),
+                describe(usage.month_bytes, self.monthly))
+    }
+}
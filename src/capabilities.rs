@@ -0,0 +1,93 @@
+// What a destination can take, so messages can be degraded to fit it in one place rather
+// than with checks for each protocol scattered through the relay
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Capabilities {
+    // Relayed messages can be edited in place; otherwise edits are relayed as notices
+    pub supports_edits: bool,
+    // Files can be posted as such; otherwise they are mirrored and linked to
+    pub supports_media: bool,
+    // Longest text of a single message, in bytes. Longer texts are split up.
+    pub max_message_len: usize,
+    // Replies can be kept together in a thread
+    pub supports_threads: bool,
+}
+
+// An IRC line is at most 512 bytes including the command, channel and the nick we put in
+// front, which leaves about 400 for the text. The exact room depends on the channel and
+// the sender, see outbound::irc_text_len.
+pub const IRC: Capabilities = Capabilities {
+    supports_edits: false,
+    supports_media: false,
+    max_message_len: 400,
+    supports_threads: false,
+};
+
+// Telegram takes 4096 characters per message, stay a little below to be safe in bytes
+pub const TELEGRAM: Capabilities = Capabilities {
+    supports_edits: true,
+    supports_media: true,
+    max_message_len: 4000,
+    supports_threads: true,
+};
+
+// Only new text messages are posted to Slack so far
+pub const SLACK: Capabilities = Capabilities {
+    supports_edits: false,
+    supports_media: false,
+    max_message_len: 4000,
+    supports_threads: false,
+};
+
+// Mattermost and Rocket.Chat through their webhooks, which only post new messages
+pub const TEAMCHAT: Capabilities = Capabilities {
+    supports_edits: false,
+    supports_media: false,
+    max_message_len: 4000,
+    supports_threads: false,
+};
+
+// Split `text` into pieces that fit the destination, preferably at spaces and never inside
+// a character
+pub fn split(capabilities: &Capabilities, text: &str) -> Vec<String> {
+    split_within(text, capabilities.max_message_len)
+}
+
+// Split `text` into pieces of at most `max_len` bytes, like `split`
+pub fn split_within(text: &str, max_len: usize) -> Vec<String> {
+    let mut pieces = vec![];
+    let mut rest = text;
+    while rest.len() > max_len {
+        let mut end = max_len;
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        // Break at the last space, unless that leaves next to nothing in this piece
+        if let Some(space) = rest[..end].rfind(' ') {
+            if space > max_len / 2 {
+                end = space;
+            }
+        }
+        pieces.push(rest[..end].to_owned());
+        rest = rest[end..].trim_left();
+    }
+    if !rest.is_empty() || pieces.is_empty() {
+        pieces.push(rest.to_owned());
+    }
+    pieces
+}
+
+// Short summary for the state dump, e.g. "edits, media, max 4000 bytes"
+pub fn describe(capabilities: &Capabilities) -> String {
+    let mut parts = vec![];
+    if capabilities.supports_edits {
+        parts.push("edits".to_owned());
+    }
+    if capabilities.supports_media {
+        parts.push("media".to_owned());
+    }
+    if capabilities.supports_threads {
+        parts.push("threads".to_owned());
+    }
+    parts.push(format!("max {} bytes", capabilities.max_message_len));
+    parts.join(", ")
+}
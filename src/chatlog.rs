@@ -0,0 +1,205 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use rustc_serialize::json;
+use time;
+use error::{self, ResultExt};
+use media::{ensure_dir, sanitize_filename};
+use super::Config;
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+// How many days back recent() looks for messages
+const DAYS_SEARCHED: i64 = 30;
+
+// A message relayed through a mapping, one per line of the log
+#[derive(Clone, Default, RustcEncodable, RustcDecodable, Debug)]
+pub struct LogEntry {
+    // Time the message was sent, as a unix timestamp
+    pub date: i64,
+    // Where the message was said, "irc" or "telegram"
+    pub network: String,
+    pub nick: String,
+    pub text: String,
+    // A /me action rather than something said
+    pub action: bool,
+    // The Telegram user who sent it, so their messages can be purged. None for messages
+    // from IRC, and in entries logged before it was recorded.
+    pub user_id: Option<i64>,
+}
+
+impl LogEntry {
+    // The entry as a line of text, like "[12:34] <nick> text"
+    pub fn describe(&self) -> String {
+        let sent = time::at_utc(time::Timespec::new(self.date, 0));
+        let time = time::strftime("%H:%M", &sent).unwrap_or(String::new());
+        if self.action {
+            format!("[{}] * {} {}", time, self.nick, self.text)
+        } else {
+            format!("[{}] <{}> {}", time, self.nick, self.text)
+        }
+    }
+}
+
+fn log_dir(config: &Config) -> Option<&Path> {
+    config.chat_log_dir.as_ref().map(|dir| Path::new(&dir[..]))
+}
+
+// Whether the conversation is logged at all
+pub fn enabled(config: &Config) -> bool {
+    log_dir(config).is_some()
+}
+
+// The log of the Telegram group `group` for a day, counted since the unix epoch. Days are
+// UTC days.
+fn day_path(log_dir: &Path, group: &str, day: i64) -> PathBuf {
+    let date = time::at_utc(time::Timespec::new(day * SECONDS_PER_DAY, 0));
+    let name = time::strftime("%Y-%m-%d.jsonl", &date).unwrap_or(format!("{}.jsonl", day));
+    log_dir.join(sanitize_filename(group)).join(name)
+}
+
+// Log a message relayed through the mapping of `group`, if logging is enabled. Failures are
+// logged, relaying goes on regardless.
+pub fn append(config: &Config, group: &str, entry: &LogEntry) {
+    let log_dir = match log_dir(config) {
+        Some(log_dir) => log_dir,
+        None => return,
+    };
+    ensure_dir(log_dir);
+    ensure_dir(&log_dir.join(sanitize_filename(group)));
+    let path = day_path(log_dir, group, entry.date / SECONDS_PER_DAY);
+    let line = match json::encode(entry) {
+        Ok(line) => line,
+        Err(err) => {
+            println!("[ERROR] Could not encode log entry: {}", err);
+            return;
+        }
+    };
+    let written = OpenOptions::new()
+                      .create(true)
+                      .append(true)
+                      .open(&path)
+                      .and_then(|mut file| writeln!(file, "{}", line));
+    if let Err(err) = written {
+        println!("[ERROR] Could not write to {}: {}", path.display(), err);
+    }
+}
+
+// Everything logged for `group` on a day, in order. Lines that can't be read are skipped.
+fn read_day(log_dir: &Path, group: &str, day: i64) -> error::Result<Vec<LogEntry>> {
+    let path = day_path(log_dir, group, day);
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let file = try!(File::open(&path).context(format!("reading {}", path.display())));
+    let mut entries = vec![];
+    for line in BufReader::new(file).lines() {
+        let line = try!(line.context(format!("reading {}", path.display())));
+        if let Ok(entry) = json::decode(&line) {
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+// Days since the unix epoch of a date given as "YYYY-MM-DD"
+pub fn parse_day(text: &str) -> Option<i64> {
+    time::strptime(text, "%Y-%m-%d").ok().map(|date| date.to_timespec().sec / SECONDS_PER_DAY)
+}
+
+// Everything logged for `group` from day `from` through day `to`, in order
+pub fn range(config: &Config, group: &str, from: i64, to: i64) -> error::Result<Vec<LogEntry>> {
+    let log_dir = try!(log_dir(config).ok_or("chat_log_dir is not configured"));
+    let mut entries = vec![];
+    for day in from..to + 1 {
+        entries.extend(try!(read_day(log_dir, group, day)));
+    }
+    Ok(entries)
+}
+
+// Entries as plain text, one line each with the date and the network it was said on
+pub fn to_text(entries: &[LogEntry]) -> String {
+    let mut text = String::new();
+    for entry in entries {
+        let sent = time::at_utc(time::Timespec::new(entry.date, 0));
+        let said = if entry.action {
+            format!("* {} {}", entry.nick, entry.text)
+        } else {
+            format!("<{}> {}", entry.nick, entry.text)
+        };
+        text.push_str(&format!("{} [{}] {}\n",
+                               time::strftime("%Y-%m-%d %H:%M:%S", &sent).unwrap_or(String::new()),
+                               entry.network,
+                               said));
+    }
+    text
+}
+
+pub fn to_json(entries: &[LogEntry]) -> String {
+    json::as_pretty_json(&entries).to_string()
+}
+
+// The last `count` messages said on `network` in the mapping of `group`, oldest first
+pub fn recent(config: &Config, group: &str, network: &str, count: usize) -> error::Result<Vec<LogEntry>> {
+    let log_dir = try!(log_dir(config).ok_or("chat_log_dir is not configured"));
+    let today = time::get_time().sec / SECONDS_PER_DAY;
+    let mut recent = vec![];
+    for day in (0..DAYS_SEARCHED).map(|ago| today - ago) {
+        let entries = try!(read_day(log_dir, group, day));
+        recent.extend(entries.into_iter().rev().filter(|entry| entry.network == network));
+        if recent.len() >= count {
+            break;
+        }
+    }
+    recent.truncate(count);
+    recent.reverse();
+    Ok(recent)
+}
+
+// Remove what the Telegram users `user_ids` said from the logs of every mapping, returning
+// the number of entries removed
+pub fn scrub_user(config: &Config, user_ids: &[i64]) -> error::Result<usize> {
+    let log_dir = match log_dir(config) {
+        Some(log_dir) if log_dir.is_dir() => log_dir,
+        _ => return Ok(0),
+    };
+    let mut removed = 0;
+    for group_dir in try!(fs::read_dir(log_dir).context(format!("reading {}", log_dir.display()))) {
+        let group_dir = try!(group_dir).path();
+        if !group_dir.is_dir() {
+            continue;
+        }
+        for day in try!(fs::read_dir(&group_dir).context(format!("reading {}", group_dir.display()))) {
+            let path = try!(day).path();
+            if path.extension().map_or(false, |extension| extension == "jsonl") {
+                removed += try!(scrub_file(&path, user_ids));
+            }
+        }
+    }
+    Ok(removed)
+}
+
+// Rewrite the log at `path` without the entries of `user_ids`. Lines that can't be read
+// are kept as they are.
+fn scrub_file(path: &Path, user_ids: &[i64]) -> error::Result<usize> {
+    let file = try!(File::open(path).context(format!("reading {}", path.display())));
+    let mut kept = vec![];
+    let mut removed = 0;
+    for line in BufReader::new(file).lines() {
+        let line = try!(line.context(format!("reading {}", path.display())));
+        let entry: Option<LogEntry> = json::decode(&line).ok();
+        if entry.and_then(|entry| entry.user_id).map_or(false, |user_id| user_ids.contains(&user_id)) {
+            removed += 1;
+        } else {
+            kept.push(line);
+        }
+    }
+    if removed > 0 {
+        // Replaced in one go, so the log is never left half written
+        let scrubbed = path.with_extension("jsonl.tmp");
+        try!(File::create(&scrubbed)
+                 .and_then(|mut file| file.write_all(kept.iter().map(|line| format!("{}\n", line)).collect::<String>().as_bytes()))
+                 .and_then(|_| fs::rename(&scrubbed, path))
+                 .context(format!("rewriting {}", path.display())));
+    }
+    Ok(removed)
+}
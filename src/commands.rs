@@ -0,0 +1,1199 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use telegram_bot::types::Integer;
+use time;
+use capabilities;
+use chatlog;
+use locale;
+use media;
+use members;
+use mediastore;
+use permissions::{self, Role};
+use quiet;
+use store::Reminder;
+use templates;
+use outbound::{Delivery, DeliveryStatus, IrcLine, Latency, Outbound};
+use super::{ChatID, Config, RelayState, delete_message};
+
+// Split a line into a command and its arguments if it starts with `prefix`. Telegram
+// commands may be addressed to a specific bot as `/command@bot`: addressed to `username`,
+// ours, the suffix is dropped, and addressed to any other bot it isn't a command of ours.
+// Where there are no bot usernames, `username` is empty.
+pub fn parse<'a>(line: &'a str, prefix: &str, username: &str) -> Option<(&'a str, Vec<&'a str>)> {
+    if !line.starts_with(prefix) {
+        return None;
+    }
+    let mut words = line[prefix.len()..].split_whitespace();
+    let command = match words.next() {
+        Some(word) => {
+            let mut parts = word.splitn(2, '@');
+            let command = parts.next().unwrap();
+            match parts.next() {
+                None => command,
+                Some(bot) if !username.is_empty() && bot.to_lowercase() == username.to_lowercase() => command,
+                Some(_) => return None,
+            }
+        }
+        None => return None,
+    };
+    Some((command, words.collect()))
+}
+
+// A command of the bot, with what the help says about it
+pub struct Command {
+    pub name: &'static str,
+    // Other names it answers to
+    aliases: &'static [&'static str],
+    args: &'static str,
+    // Least role that may run it, unless role_commands says otherwise
+    pub role: Role,
+    // Whether it only works in a bridged group or channel
+    mapped: bool,
+    // Whether it can be run from IRC
+    on_irc: bool,
+    description: &'static str,
+}
+
+// Every command, in the order the help lists them
+pub const COMMANDS: &'static [Command] = &[
+    Command {
+        name: "help",
+        aliases: &["start"],
+        args: "",
+        role: Role::User,
+        mapped: false,
+        on_irc: true,
+        description: "what the bot does and the commands you may run",
+    },
+    Command {
+        name: "top",
+        aliases: &[],
+        args: "[day|week]",
+        role: Role::User,
+        mapped: true,
+        on_irc: true,
+        description: "the most active participants",
+    },
+    Command {
+        name: "catchup",
+        aliases: &[],
+        args: "[count]",
+        role: Role::User,
+        mapped: true,
+        on_irc: false,
+        description: "the last lines said on IRC",
+    },
+    Command {
+        name: "members",
+        aliases: &[],
+        args: "",
+        role: Role::User,
+        mapped: true,
+        on_irc: true,
+        description: "how many people each side has",
+    },
+    Command {
+        name: "remind",
+        aliases: &[],
+        args: "[both] <duration> <text>",
+        role: Role::User,
+        mapped: true,
+        on_irc: true,
+        description: "get reminded of something after a duration like 2h, here or on both sides",
+    },
+    Command {
+        name: "learn",
+        aliases: &[],
+        args: "<name> = <text>",
+        role: Role::User,
+        mapped: true,
+        on_irc: true,
+        description: "teach a new factoid, answered to <name> from then on",
+    },
+    Command {
+        name: "forget",
+        aliases: &[],
+        args: "<name>",
+        role: Role::Moderator,
+        mapped: true,
+        on_irc: true,
+        description: "remove a factoid",
+    },
+    Command {
+        name: "mute",
+        aliases: &[],
+        args: "[group|channel] <irc→tg|tg→irc|both> [duration]",
+        role: Role::Moderator,
+        mapped: false,
+        on_irc: true,
+        description: "pause relaying for a mapping, for an hour or a duration like 30m",
+    },
+    Command {
+        name: "unmute",
+        aliases: &[],
+        args: "[group|channel] <irc→tg|tg→irc|both>",
+        role: Role::Moderator,
+        mapped: false,
+        on_irc: true,
+        description: "resume relaying for a mapping",
+    },
+    Command {
+        name: "tgdel",
+        aliases: &["delete"],
+        args: "<message id|^|^N|name>",
+        role: Role::Moderator,
+        mapped: true,
+        on_irc: true,
+        description: "delete a relayed message in the Telegram group of this mapping",
+    },
+    Command {
+        name: "export",
+        aliases: &[],
+        args: "<from> [to] [text|json]",
+        role: Role::Moderator,
+        mapped: true,
+        on_irc: true,
+        description: "upload the log of this mapping for a range of days, as YYYY-MM-DD",
+    },
+    Command {
+        name: "status",
+        aliases: &[],
+        args: "",
+        role: Role::Admin,
+        mapped: false,
+        on_irc: true,
+        description: "delivery status of every mapping",
+    },
+    Command {
+        name: "dump",
+        aliases: &[],
+        args: "",
+        role: Role::Admin,
+        mapped: false,
+        on_irc: true,
+        description: "everything the relay knows, for debugging",
+    },
+    Command {
+        name: "alias",
+        aliases: &[],
+        args: "<user id> [name]",
+        role: Role::Admin,
+        mapped: false,
+        on_irc: true,
+        description: "relay a Telegram user under another name, or their own again",
+    },
+    Command {
+        name: "pin",
+        aliases: &[],
+        args: "<chat id> [group]",
+        role: Role::Admin,
+        mapped: false,
+        on_irc: true,
+        description: "relay a Telegram group title only for the chat with this id",
+    },
+    Command {
+        name: "unpin",
+        aliases: &[],
+        args: "[group]",
+        role: Role::Admin,
+        mapped: false,
+        on_irc: true,
+        description: "relay a Telegram group title for whichever chat has it again",
+    },
+    Command {
+        name: "bridge",
+        aliases: &[],
+        args: "[group|channel] [on|off]",
+        role: Role::Admin,
+        mapped: false,
+        on_irc: true,
+        description: "list the mappings, or turn relaying for one off until it is turned on again",
+    },
+    Command {
+        name: "reload",
+        aliases: &[],
+        args: "",
+        role: Role::Admin,
+        mapped: false,
+        on_irc: true,
+        description: "read the aliases, factoids and reminders from the state store again",
+    },
+    Command {
+        name: "maintenance",
+        aliases: &[],
+        args: "[on|off]",
+        role: Role::Owner,
+        mapped: false,
+        on_irc: true,
+        description: "hold all deliveries until maintenance is over",
+    },
+    Command {
+        name: "purge",
+        aliases: &[],
+        args: "user <id|username>",
+        role: Role::Owner,
+        mapped: false,
+        on_irc: true,
+        description: "delete what a Telegram user sent: mirrored media, logged and remembered messages",
+    },
+];
+
+// How long a mapping stays muted if no duration is given
+const DEFAULT_MUTE: u64 = 60 * 60;
+// Longest duration a mute or reminder may be given, a year
+const MAX_DURATION_SECONDS: u64 = 365 * 24 * 60 * 60;
+
+// The command going by `name`
+pub fn find(name: &str) -> Option<&'static Command> {
+    COMMANDS.iter().find(|command| command.name == name || command.aliases.iter().any(|&alias| alias == name))
+}
+
+// The reply to a command given the wrong arguments
+fn usage(name: &str) -> String {
+    match find(name) {
+        Some(command) if !command.args.is_empty() => format!("Usage: {} {}", command.name, command.args),
+        _ => format!("Usage: {}", name),
+    }
+}
+
+// Who gave a command, and how
+pub struct Caller<'a> {
+    pub nick: &'a str,
+    // "irc", "telegram" or "control"
+    pub network: &'a str,
+    pub role: Role,
+    // What commands start with where it was given
+    pub prefix: &'a str,
+}
+
+// What a command answers with
+pub enum Reply {
+    Now(String),
+    // Calls to Telegram and the like are made once the relay state is unlocked again, so a
+    // slow API doesn't hold up everything else. The reply comes from `finish`.
+    Later(Pending),
+}
+
+// What is left to do of a command after the relay state has been looked at
+pub enum Pending {
+    // Delete a message relayed to Telegram, and tell the IRC channel of its mapping
+    Delete {
+        group: String,
+        chat_id: ChatID,
+        message_id: Integer,
+        nick: String,
+        irc_channel: Option<String>,
+    },
+    // Export the chat log of a mapping to the media store
+    Export { group: String, args: Vec<String> },
+    // Delete the media and logged messages of a Telegram user, whose relayed messages have
+    // already been forgotten
+    Purge {
+        user: String,
+        user_ids: Vec<Integer>,
+        forgotten: usize,
+    },
+}
+
+// The text of `reply`, doing what is left to do first. Not to be called with the relay
+// state locked.
+pub fn finish(config: &Config, outbound: &Outbound, reply: Reply) -> String {
+    match reply {
+        Reply::Now(text) => text,
+        Reply::Later(Pending::Delete { group, chat_id, message_id, nick, irc_channel }) => {
+            delete_relayed(config, outbound, &group, chat_id, message_id, &nick, irc_channel)
+        }
+        Reply::Later(Pending::Export { group, args }) => {
+            let args: Vec<&str> = args.iter().map(|arg| &arg[..]).collect();
+            export(config, &group, &args)
+        }
+        Reply::Later(Pending::Purge { user, user_ids, forgotten }) => purge_files(config, &user, &user_ids, forgotten),
+    }
+}
+
+// Run a command without checking who is asking, for the control socket and once the
+// caller's role has been checked. `here` is the Telegram group of the mapping the command
+// was given in, if any. Returns the reply to send back, or None if the command isn't one
+// we know about.
+pub fn run(config: &Config,
+           state: &mut RelayState,
+           outbound: &Outbound,
+           here: Option<&str>,
+           caller: &Caller,
+           name: &str,
+           args: &[&str])
+           -> Option<Reply> {
+    let command = match find(name) {
+        Some(command) => command,
+        None => return None,
+    };
+    let group = match here {
+        Some(group) => group,
+        None if command.mapped => {
+            return Some(Reply::Now(format!("The {} command only works in a bridged group or channel", command.name)))
+        }
+        None => "",
+    };
+    Some(Reply::Now(match command.name {
+        "help" => help(config, state, caller, here),
+        "top" => top(state, group, args),
+        "catchup" => catchup(config, group, args),
+        "members" => member_summary(state, group),
+        "learn" => learn(config, state, caller.role, group, args),
+        "forget" => forget(state, group, args),
+        "remind" => remind(state, caller, group, args),
+        "mute" => mute(state, outbound, here, args, true),
+        "unmute" => mute(state, outbound, here, args, false),
+        "tgdel" => return Some(delete(state, group, args)),
+        "export" => {
+            return Some(Reply::Later(Pending::Export {
+                group: group.to_owned(),
+                args: args.iter().map(|arg| arg.to_string()).collect(),
+            }))
+        }
+        "status" => status(config, state, outbound),
+        "dump" => dump(state, outbound),
+        "alias" => alias(state, args),
+        "pin" => pin(state, here, args),
+        "unpin" => unpin(state, here, args),
+        "bridge" => bridge(state, outbound, here, args),
+        "reload" => reload(state),
+        "maintenance" => maintenance(outbound, args),
+        "purge" => return Some(purge(config, state, args)),
+        _ => unreachable!(),
+    }))
+}
+
+// Whether `command` can be run from `network`. Catchup repeats what was said on IRC, so it
+// only makes sense outside of it.
+fn runs_on(command: &Command, network: &str) -> bool {
+    command.on_irc || network != "irc"
+}
+
+// Run a command for the caller, if their role allows it. Roles below admin can't name
+// another mapping than the one the command was given in. Returns None for commands the
+// role may not run or that we don't know about, which are relayed like anything else.
+pub fn dispatch(config: &Config,
+                state: &mut RelayState,
+                outbound: &Outbound,
+                here: Option<&str>,
+                caller: &Caller,
+                name: &str,
+                args: &[&str])
+                -> Option<Reply> {
+    let role = caller.role;
+    let command = match find(name) {
+        Some(command) if permissions::allowed(config, role, command) && runs_on(command, caller.network) => command,
+        _ => return None,
+    };
+    // Commands that only work in a mapping always act on that one
+    if role.scoped() && !command.mapped {
+        let other = args.iter().any(|&name| {
+            let group = if state.irc_channel.contains_key(name) {
+                Some(name)
+            } else {
+                state.tg_group.get(name).map(|group| &group[..])
+            };
+            group.map_or(false, |group| Some(group) != here)
+        });
+        if other {
+            return Some(Reply::Now(format!("As a {} you can only run commands for the mapping you are in", role.name())));
+        }
+    }
+    run(config, state, outbound, here, caller, name, args)
+}
+
+// Most reminders pending in a mapping at once
+const MAX_REMINDERS: usize = 100;
+// Number of participants listed by the top command
+const TOP_COUNT: usize = 10;
+// Directory of the media store exported logs are put in
+const EXPORT_DIR: &'static str = "exports";
+// Most days the export command exports at once
+const MAX_EXPORT_DAYS: i64 = 31;
+// Number of IRC lines the catchup command replies with, unless asked for more or fewer,
+// and the most it replies with
+const DEFAULT_CATCHUP: usize = 20;
+const MAX_CATCHUP: usize = 100;
+// Most factoids a mapping may learn, and the longest text one may have, in bytes. Every
+// factoid is kept in memory and written out on each change.
+const MAX_FACTOIDS: usize = 200;
+const MAX_FACTOID_BYTES: usize = 1000;
+
+// How many people each side of the mapping the command was given in has:
+// members
+fn member_summary(state: &RelayState, group: &str) -> String {
+    members::summary(state, group).unwrap_or("The members of neither side have been counted yet".into())
+}
+
+// The last IRC lines relayed to the mapping the command was given in, to skim what was
+// missed with notifications off:
+// catchup [count]
+fn catchup(config: &Config, group: &str, args: &[&str]) -> String {
+    let count = match args.first() {
+        None => DEFAULT_CATCHUP,
+        Some(count) => {
+            match count.parse::<usize>() {
+                Ok(count) if count > 0 => cmp::min(count, MAX_CATCHUP),
+                _ => return usage("catchup"),
+            }
+        }
+    };
+    if !chatlog::enabled(config) {
+        return "The conversation isn't logged, so there is nothing to catch up on".into();
+    }
+    let entries = match chatlog::recent(config, group, "irc", count) {
+        Ok(entries) => entries,
+        Err(err) => {
+            println!("[ERROR] {}", err.context("reading the chat log"));
+            return "Could not read the chat log".into();
+        }
+    };
+    if entries.is_empty() {
+        return "Nothing was said on IRC lately".into();
+    }
+    // Drop the oldest lines until the reply fits in one message
+    let mut lines: Vec<String> = entries.iter().map(|entry| entry.describe()).collect();
+    let mut len = lines.iter().map(|line| line.len() + 1).sum::<usize>();
+    while lines.len() > 1 && len > capabilities::TELEGRAM.max_message_len {
+        len -= lines.remove(0).len() + 1;
+    }
+    format!("Last {} line{} from IRC:\n{}",
+            lines.len(),
+            if lines.len() == 1 { "" } else { "s" },
+            lines.join("\n"))
+}
+
+// The most active participants of the mapping the command was given in:
+// top [day|week]
+fn top(state: &RelayState, group: &str, args: &[&str]) -> String {
+    let (days, period) = match args.first().cloned() {
+        None | Some("day") => (1, "today"),
+        Some("week") => (7, "this week"),
+        _ => return usage("top"),
+    };
+    let top = state.activity.get(group).map_or(vec![], |activity| activity.top(days, TOP_COUNT));
+    if top.is_empty() {
+        return format!("Nobody has said anything {}", period);
+    }
+    let mut lines = vec![format!("Most active {}:", period)];
+    for (rank, &(ref name, messages)) in top.iter().enumerate() {
+        lines.push(format!("{}. {} - {} message{}", rank + 1, name, messages, if messages == 1 { "" } else { "s" }));
+    }
+    lines.join("\n")
+}
+
+// The commands the caller may run in the mapping of `here`, written with their prefix.
+// Outside of a mapping, such as in a private chat with the bot, led by what the bot does.
+fn help(config: &Config, state: &RelayState, caller: &Caller, here: Option<&str>) -> String {
+    let prefix = caller.prefix;
+    let mut lines = vec![];
+    if here.is_none() {
+        let server = config.irc.server.clone().unwrap_or(String::new());
+        lines.push("I relay messages between Telegram groups and IRC channels:".to_owned());
+        let mut maps: Vec<_> = config.maps.iter().collect();
+        maps.sort();
+        for (group, channel) in maps {
+            lines.push(format!("• {} ⇄ {} on {}", group, channel, server));
+        }
+        if config.maps.is_empty() {
+            lines.push("(no groups are connected yet)".to_owned());
+        }
+        lines.push("Anything said in a connected group shows up in its channel, and the other way around.".to_owned());
+        lines.push(String::new());
+    }
+    lines.push("Commands:".to_owned());
+    let allowed = COMMANDS.iter()
+                          .filter(|command| permissions::allowed(config, caller.role, command) && runs_on(command, caller.network));
+    for command in allowed {
+        let usage = if command.args.is_empty() {
+            format!("{}{}", prefix, command.name)
+        } else {
+            format!("{}{} {}", prefix, command.name, command.args)
+        };
+        let mapped = if command.mapped { " (in a bridged group or channel)" } else { "" };
+        lines.push(format!("{} - {}{}", usage, command.description, mapped));
+    }
+    let mut custom: Vec<_> = here.and_then(|group| custom_commands(config, group))
+                                 .map_or(vec![], |commands| commands.keys().collect());
+    if let Some(factoids) = here.and_then(|group| state.factoids.get(group)) {
+        custom.extend(factoids.keys());
+    }
+    if !custom.is_empty() {
+        custom.sort();
+        custom.dedup();
+        let custom: Vec<_> = custom.iter().map(|name| format!("{}{}", prefix, name)).collect();
+        lines.push(format!("Also here: {}", custom.join(", ")));
+    }
+    lines.join("\n")
+}
+
+fn custom_commands<'a>(config: &'a Config, group: &str) -> Option<&'a HashMap<String, String>> {
+    config.custom_commands.as_ref().and_then(|commands| commands.get(group))
+}
+
+// The text of the custom command or factoid `name` of the mapping of `group`, with {nick}
+// and {network} filled in for whoever asked for it. Neither can take the place of built-in
+// commands, and custom commands come before factoids.
+pub fn custom(config: &Config, state: &RelayState, group: &str, nick: &str, network: &str, name: &str) -> Option<String> {
+    if find(name).is_some() {
+        return None;
+    }
+    custom_commands(config, group).and_then(|commands| commands.get(name))
+                                  .or_else(|| state.factoids.get(group).and_then(|factoids| factoids.get(name)))
+                                  .map(|text| templates::fill(text, &[("nick", nick), ("network", network)]))
+}
+
+// Teach the mapping the command was given in a factoid, for anyone to ask for with the
+// name as a command:
+// learn <name> = <text>
+// Only moderators and up may change what a factoid says.
+fn learn(config: &Config, state: &mut RelayState, role: Role, group: &str, args: &[&str]) -> String {
+    let line = args.join(" ");
+    let (name, text) = match line.find('=') {
+        Some(equals) => (line[..equals].trim(), line[equals + 1..].trim()),
+        None => return usage("learn"),
+    };
+    if name.is_empty() || name.contains(char::is_whitespace) || text.is_empty() {
+        return usage("learn");
+    }
+    // Telegram addresses commands to a bot as "/name@bot", which would never reach it
+    if name.contains('@') {
+        return "Factoid names can't contain \"@\"".to_owned();
+    }
+    if text.len() > MAX_FACTOID_BYTES {
+        return format!("That is too long for a factoid, the most is {} bytes", MAX_FACTOID_BYTES);
+    }
+    if find(name).is_some() || custom_commands(config, group).map_or(false, |commands| commands.contains_key(name)) {
+        return format!("\"{}\" is already a command", name);
+    }
+    let known = state.factoids.get(group).map_or(false, |factoids| factoids.contains_key(name));
+    if known && role < Role::Moderator {
+        return format!("\"{}\" is already known, ask a moderator to change it", name);
+    }
+    if !known && state.factoids.get(group).map_or(0, |factoids| factoids.len()) >= MAX_FACTOIDS {
+        return format!("{} already knows {} factoids, forget some first", group, MAX_FACTOIDS);
+    }
+    state.factoids.entry(group.to_owned()).or_insert(HashMap::new()).insert(name.to_owned(), text.to_owned());
+    save_factoids(state, format!("{} \"{}\" in {}", if known { "Changed" } else { "Learned" }, name, group))
+}
+
+// Remove a factoid of the mapping the command was given in: forget <name>
+fn forget(state: &mut RelayState, group: &str, args: &[&str]) -> String {
+    if args.len() != 1 {
+        return usage("forget");
+    }
+    let removed = state.factoids.get_mut(group).and_then(|factoids| factoids.remove(args[0]));
+    if removed.is_none() {
+        return format!("There is no factoid \"{}\"", args[0]);
+    }
+    save_factoids(state, format!("Forgot \"{}\" in {}", args[0], group))
+}
+
+// Remind the caller of something once a duration has passed, in the mapping the command
+// was given in:
+// remind [both] <duration> <text>
+// The reminder goes to the side it was asked for on, or to both sides.
+fn remind(state: &mut RelayState, caller: &Caller, group: &str, args: &[&str]) -> String {
+    let both = args.first() == Some(&"both");
+    let args = if both { &args[1..] } else { args };
+    let duration = match args.first().and_then(|text| parse_duration(text)) {
+        Some(duration) if args.len() > 1 => duration,
+        _ => return usage("remind"),
+    };
+    if state.reminders.iter().filter(|reminder| reminder.group == group).count() >= MAX_REMINDERS {
+        return format!("There are already {} reminders pending here", MAX_REMINDERS);
+    }
+    state.reminders.push(Reminder {
+        due: time::get_time().sec + duration.as_secs() as i64,
+        network: if both { "both".to_owned() } else { caller.network.to_owned() },
+        group: group.to_owned(),
+        nick: caller.nick.to_owned(),
+        text: args[1..].join(" "),
+    });
+    let reply = format!("Reminding {} in {}", caller.nick, describe_duration(duration));
+    if let Err(err) = state.store.save_reminders(&state.reminders) {
+        println!("[ERROR] Could not save reminders: {}", err);
+        return format!("{}, unless we restart before: {}", reply, err);
+    }
+    println!("[INFO] {} in \"{}\"", reply, group);
+    reply
+}
+
+// Record the factoids, replying `reply` if that works out
+fn save_factoids(state: &RelayState, reply: String) -> String {
+    if let Err(err) = state.store.save_factoids(&state.factoids) {
+        println!("[ERROR] Could not save factoids: {}", err);
+        return format!("{}, but it could not be saved: {}", reply, err);
+    }
+    println!("[INFO] {}", reply);
+    reply
+}
+
+// Parse a duration like "90s", "30m", "1h" or "2d", of at most MAX_DURATION_SECONDS
+fn parse_duration(text: &str) -> Option<Duration> {
+    let (number, unit) = match text.char_indices().last() {
+        Some((last, _)) => text.split_at(last),
+        None => return None,
+    };
+    let unit = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+    number.parse::<u64>()
+          .ok()
+          .and_then(|number| number.checked_mul(unit))
+          .and_then(|seconds| if seconds <= MAX_DURATION_SECONDS { Some(Duration::from_secs(seconds)) } else { None })
+}
+
+// Mute or unmute a mapping in one or both directions:
+// mute [group|channel] <irc→tg|tg→irc|both> [duration]
+fn mute(state: &RelayState, outbound: &Outbound, here: Option<&str>, args: &[&str], muting: bool) -> String {
+    let usage = usage(if muting { "mute" } else { "unmute" });
+    let mut args = args.iter().cloned().peekable();
+
+    // The mapping defaults to the one the command was given in
+    let group = match args.peek().cloned() {
+        Some(name) if state.irc_channel.contains_key(name) => {
+            args.next();
+            name.to_owned()
+        }
+        Some(name) if state.tg_group.contains_key(name) => {
+            args.next();
+            state.tg_group[name].clone()
+        }
+        _ => {
+            match here {
+                Some(group) if state.irc_channel.contains_key(group) => group.to_owned(),
+                _ => return usage,
+            }
+        }
+    };
+    let channel = state.irc_channel[&group].clone();
+
+    let (to_tg, to_irc) = match args.next() {
+        Some("irc→tg") | Some("irc->tg") => (true, false),
+        Some("tg→irc") | Some("tg->irc") => (false, true),
+        Some("both") => (true, true),
+        _ => return usage,
+    };
+    let duration = match args.next() {
+        Some(text) if muting => {
+            match parse_duration(text) {
+                Some(duration) => duration,
+                None => return usage,
+            }
+        }
+        Some(_) => return usage,
+        None => Duration::from_secs(DEFAULT_MUTE),
+    };
+
+    let set = |status: &mut DeliveryStatus| {
+        status.muted_until = if muting { Some(Instant::now() + duration) } else { None };
+    };
+    if to_tg {
+        if let Some(delivery) = outbound.tg.get(&group) {
+            set(&mut *delivery.status.lock().unwrap());
+        }
+    }
+    if to_irc {
+        if let Some(delivery) = outbound.irc.get(&channel) {
+            set(&mut *delivery.status.lock().unwrap());
+        }
+    }
+
+    let direction = match (to_tg, to_irc) {
+        (true, true) => "both directions",
+        (true, false) => "IRC → Telegram",
+        _ => "Telegram → IRC",
+    };
+    if muting {
+        println!("[INFO] Muted {} ⇄ {} ({}) for {}s", group, channel, direction, duration.as_secs());
+        format!("Muted {} ⇄ {} ({}) for {}", group, channel, direction, describe_duration(duration))
+    } else {
+        println!("[INFO] Unmuted {} ⇄ {} ({})", group, channel, direction);
+        format!("Unmuted {} ⇄ {} ({})", group, channel, direction)
+    }
+}
+
+fn describe_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    if seconds >= 60 * 60 && seconds % (60 * 60) == 0 {
+        format!("{}h", seconds / (60 * 60))
+    } else if seconds >= 60 && seconds % 60 == 0 {
+        format!("{}m", seconds / 60)
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
+// Note about a muted destination for the status output
+fn describe_mute(status: &mut DeliveryStatus) -> String {
+    if status.off {
+        return " (turned off)".to_owned();
+    }
+    if !status.muted() {
+        return String::new();
+    }
+    let left = status.muted_until.unwrap().duration_since(Instant::now());
+    format!(" (muted for another {})", describe_duration(Duration::from_secs(left.as_secs())))
+}
+
+// List the mappings, or turn relaying for one off or on again in both directions:
+// bridge [group|channel] [on|off]
+fn bridge(state: &RelayState, outbound: &Outbound, here: Option<&str>, args: &[&str]) -> String {
+    let mut args = args.iter().cloned().peekable();
+
+    // The mapping defaults to the one the command was given in
+    let group = match args.peek().cloned() {
+        Some(name) if state.irc_channel.contains_key(name) => {
+            args.next();
+            Some(name.to_owned())
+        }
+        Some(name) if state.tg_group.contains_key(name) => {
+            args.next();
+            Some(state.tg_group[name].clone())
+        }
+        _ => {
+            match here {
+                Some(group) if state.irc_channel.contains_key(group) => Some(group.to_owned()),
+                _ => None,
+            }
+        }
+    };
+    let on = match (args.next(), args.next()) {
+        (None, _) => None,
+        (Some("on"), None) => Some(true),
+        (Some("off"), None) => Some(false),
+        _ => return usage("bridge"),
+    };
+    let deliveries = |group: &str| {
+        let channel = &state.irc_channel[group];
+        let statuses: Vec<_> = outbound.tg
+                                       .get(group)
+                                       .map(|delivery| delivery.status.clone())
+                                       .into_iter()
+                                       .chain(outbound.irc.get(channel).map(|delivery| delivery.status.clone()))
+                                       .collect();
+        (channel.clone(), statuses)
+    };
+
+    match (group, on) {
+        (Some(group), Some(on)) => {
+            let (channel, statuses) = deliveries(&group);
+            for status in statuses {
+                status.lock().unwrap().off = !on;
+            }
+            let switched = if on { "on" } else { "off" };
+            println!("[INFO] Turned {} ⇄ {} {}", group, channel, switched);
+            format!("Turned {} ⇄ {} {}", group, channel, switched)
+        }
+        (None, Some(_)) => usage("bridge"),
+        (group, None) => {
+            let mut groups: Vec<_> = match group {
+                Some(group) => vec![group],
+                None => state.irc_channel.keys().cloned().collect(),
+            };
+            if groups.is_empty() {
+                return "No mappings configured".into();
+            }
+            groups.sort();
+            let lines: Vec<_> = groups.iter()
+                                      .map(|group| {
+                                          let (channel, statuses) = deliveries(group);
+                                          let off = statuses.iter().any(|status| status.lock().unwrap().off);
+                                          format!("{} ⇄ {}: {}", group, channel, if off { "off" } else { "on" })
+                                      })
+                                      .collect();
+            lines.join("\n")
+        }
+    }
+}
+
+// Read the aliases, factoids and reminders from the state store again, to pick up changes
+// made to it while we were running
+fn reload(state: &mut RelayState) -> String {
+    let loaded = state.store.load_aliases().and_then(|aliases| {
+        let factoids = try!(state.store.load_factoids());
+        let reminders = try!(state.store.load_reminders());
+        Ok((aliases, factoids, reminders))
+    });
+    match loaded {
+        Ok((aliases, factoids, reminders)) => {
+            let reply = format!("Reloaded {} aliases, {} factoids and {} reminders",
+                                aliases.len(),
+                                factoids.values().map(|group| group.len()).sum::<usize>(),
+                                reminders.len());
+            state.aliases = aliases;
+            state.factoids = factoids;
+            state.reminders = reminders;
+            println!("[INFO] {}", reply);
+            reply
+        }
+        Err(err) => {
+            println!("[ERROR] Could not reload the state store: {}", err);
+            format!("Could not reload the state store: {}", err)
+        }
+    }
+}
+
+fn describe_latency(latency: &Latency) -> String {
+    match (latency.percentile(50), latency.percentile(99)) {
+        (Some(p50), Some(p99)) => format!("latency p50 {:.1}s p99 {:.1}s", p50 as f64 / 1000.0, p99 as f64 / 1000.0),
+        _ => "no deliveries yet".into(),
+    }
+}
+
+// One line per mapping describing the state of delivery in each direction
+fn status(config: &Config, state: &RelayState, outbound: &Outbound) -> String {
+    let mut lines = vec![];
+    if outbound.maintenance.is_down() {
+        lines.push("Maintenance mode on, nothing is being delivered".to_owned());
+    }
+    for (group, channel) in &config.maps {
+        let mut line = format!("{} ⇄ {}:", group, channel);
+        if let Some(delivery) = outbound.tg.get(group) {
+            let mut status = delivery.status.lock().unwrap();
+            line.push_str(&format!(" to Telegram {} queued, {} dropped, {}{}",
+                                   delivery.queue.len(),
+                                   delivery.queue.dropped(),
+                                   describe_latency(&status.latency),
+                                   describe_mute(&mut status)));
+            if status.deactivated {
+                line.push_str(" (deactivated, bot can't post)");
+            }
+            if let Some(interval) = status.slow_mode {
+                line.push_str(&format!(" (slow mode, sending every {}s)", interval));
+            }
+            if let Some(quiet_hours) = quiet::schedule(config, group) {
+                let now = if quiet_hours.remaining().is_some() { ", now" } else { "" };
+                line.push_str(&format!(" (quiet hours {}{})", quiet_hours.describe(), now));
+            }
+            line.push(';');
+        }
+        if let Some(delivery) = outbound.irc.get(channel) {
+            let mut status = delivery.status.lock().unwrap();
+            line.push_str(&format!(" to IRC {} queued, {} dropped, {}{}",
+                                   delivery.queue.len(),
+                                   delivery.queue.dropped(),
+                                   describe_latency(&status.latency),
+                                   describe_mute(&mut status)));
+        }
+        lines.push(line);
+    }
+    if config.maps.is_empty() {
+        lines.push("No mappings configured".into());
+    }
+    if config.relay_media.unwrap_or(false) {
+        lines.push(format!("Mirroring: {}", state.media_budget.describe()));
+    }
+    lines.join("\n")
+}
+
+fn describe_delivery<T>(delivery: &Delivery<T>) -> String {
+    let status = delivery.status.lock().unwrap();
+    format!("{} queued, {} dropped, {}, deactivated: {}, slow mode: {}, last error: {}",
+            delivery.queue.len(),
+            delivery.queue.dropped(),
+            describe_latency(&status.latency),
+            status.deactivated,
+            status.slow_mode.map_or("no".into(), |interval| format!("every {}s", interval)),
+            status.last_error.as_ref().map_or("none", |err| &err[..]))
+}
+
+// Everything we know about the relay, for working out why a group isn't relaying
+pub fn dump(state: &RelayState, outbound: &Outbound) -> String {
+    let mut lines = vec!["Mappings:".to_owned()];
+    for (group, channel) in &state.irc_channel {
+        lines.push(format!("  \"{}\" ⇄ \"{}\" (chat id: {})",
+                           group,
+                           channel,
+                           state.chat_ids.get(group).map_or("unknown".into(), |id| id.to_string())));
+        if let Some(delivery) = outbound.tg.get(group) {
+            lines.push(format!("    to Telegram: {}", describe_delivery(delivery)));
+        }
+        if let Some(delivery) = outbound.irc.get(channel) {
+            lines.push(format!("    to IRC: {}", describe_delivery(delivery)));
+        }
+        for (destination, capabilities) in outbound.capabilities(group) {
+            lines.push(format!("    {} takes: {}", destination, capabilities::describe(&capabilities)));
+        }
+    }
+    lines.push("Known chat ids:".to_owned());
+    for (group, id) in &state.chat_ids {
+        lines.push(format!("  \"{}\": {}", group, id));
+    }
+    lines.push("Pinned chat ids:".to_owned());
+    for (group, id) in &state.pinned {
+        lines.push(format!("  \"{}\": {}", group, id));
+    }
+    lines.push("Titles shared by several groups (not relayed):".to_owned());
+    for (group, ids) in &state.ambiguous {
+        lines.push(format!("  \"{}\": {:?}", group, ids));
+    }
+    lines.join("\n")
+}
+
+// Stop or resume all deliveries, messages keep being queued in the meantime
+fn maintenance(outbound: &Outbound, args: &[&str]) -> String {
+    match (args.len(), args.first().cloned()) {
+        (1, Some("on")) => {
+            if outbound.maintenance.set_down() {
+                println!("[INFO] Entering maintenance mode, holding all messages");
+            }
+            "Maintenance mode on, messages are queued until it is turned off".into()
+        }
+        (1, Some("off")) => {
+            if outbound.maintenance.set_up() {
+                println!("[INFO] Leaving maintenance mode, delivering queued messages");
+            }
+            "Maintenance mode off".into()
+        }
+        (0, _) => format!("Maintenance mode is {}", if outbound.maintenance.is_down() { "on" } else { "off" }),
+        _ => usage("maintenance"),
+    }
+}
+
+// Delete a message relayed from the Telegram group of the mapping the command was given
+// in, so that moderators on IRC can act on the Telegram side:
+// tgdel <message id|^|^N|name>
+// "^" is the latest relayed message, "^2" the one before it, and a name the latest message
+// of that Telegram user.
+fn delete(state: &RelayState, group: &str, args: &[&str]) -> Reply {
+    if args.is_empty() {
+        return Reply::Now(usage("tgdel"));
+    }
+    let reference = args.join(" ");
+    let chat_id = match state.chat_ids.get(group) {
+        Some(chat_id) => *chat_id,
+        None => return Reply::Now(format!("The chat id of \"{}\" isn't known yet", group)),
+    };
+    let (message_id, relayed) = match state.relayed
+                                           .find(chat_id, &reference)
+                                           .and_then(|message_id| state.relayed.get(chat_id, message_id).map(|relayed| (message_id, relayed))) {
+        Some(found) => found,
+        None => return Reply::Now(format!("No recently relayed message matches \"{}\"", reference)),
+    };
+    Reply::Later(Pending::Delete {
+        group: group.to_owned(),
+        chat_id: chat_id,
+        message_id: message_id,
+        nick: relayed.nick.clone(),
+        irc_channel: state.irc_channel.get(group).cloned(),
+    })
+}
+
+// The deleting part of tgdel, done without the relay state locked
+fn delete_relayed(config: &Config,
+                  outbound: &Outbound,
+                  group: &str,
+                  chat_id: ChatID,
+                  message_id: Integer,
+                  nick: &str,
+                  irc_channel: Option<String>)
+                  -> String {
+    if let Err(err) = delete_message(&config.token, chat_id, message_id) {
+        println!("[ERROR] {}", err);
+        return format!("Could not delete message {}: {}", message_id, err);
+    }
+    println!("[INFO] Deleted message {} of {} in \"{}\"", message_id, nick, group);
+
+    if config.relay_deletes.unwrap_or(false) {
+        if let Some(channel) = irc_channel {
+            outbound.to_irc(&channel, IrcLine {
+                hostmask: None,
+                nick: nick.to_owned(),
+                text: locale::text(config, group, locale::DELETED, &[]),
+                date: time::get_time().sec,
+                received: Instant::now(),
+            });
+        }
+    }
+    format!("Deleted the message of {}", nick)
+}
+
+// Give a Telegram user a name to be relayed under instead of their own:
+// alias <user id> [name]
+// Without a name, the user's alias is removed.
+fn alias(state: &mut RelayState, args: &[&str]) -> String {
+    let user_id: Integer = match args.first().and_then(|id| id.parse().ok()) {
+        Some(user_id) => user_id,
+        None => return usage("alias"),
+    };
+    let name = args[1..].join(" ");
+    let reply = if name.is_empty() {
+        match state.aliases.remove(&user_id) {
+            Some(old) => format!("User {} is no longer relayed as \"{}\"", user_id, old),
+            None => return format!("User {} has no alias", user_id),
+        }
+    } else {
+        state.aliases.insert(user_id, name.clone());
+        format!("User {} is now relayed as \"{}\"", user_id, name)
+    };
+    if let Err(err) = state.store.save_aliases(&state.aliases) {
+        println!("[ERROR] Could not save aliases: {}", err);
+        return format!("{}, but it could not be saved: {}", reply, err);
+    }
+    println!("[INFO] {}", reply);
+    reply
+}
+
+// Upload the conversation of the mapping the command was given in to the media store,
+// replying with its URL:
+// export <from> [to] [text|json]
+// Days are given as YYYY-MM-DD in UTC, `to` defaults to `from`.
+// Done without the relay state locked, as reading the log and uploading it take a while.
+fn export(config: &Config, group: &str, args: &[&str]) -> String {
+    let mut args = args.to_vec();
+    let format = match args.last().cloned() {
+        Some("text") | Some("json") => args.pop().unwrap(),
+        _ => "text",
+    };
+    let (from, to) = match (args.len(), args.first().and_then(|from| chatlog::parse_day(from))) {
+        (1, Some(from)) => (from, from),
+        (2, Some(from)) => {
+            match chatlog::parse_day(args[1]) {
+                Some(to) => (from, to),
+                None => return usage("export"),
+            }
+        }
+        _ => return usage("export"),
+    };
+    if to < from || to - from >= MAX_EXPORT_DAYS {
+        return format!("Give a range of 1 to {} days, the first one first", MAX_EXPORT_DAYS);
+    }
+    if !chatlog::enabled(config) {
+        return "The conversation isn't logged, so there is nothing to export".into();
+    }
+    let uploaded = chatlog::range(config, group, from, to).and_then(|entries| {
+        let contents = if format == "json" { chatlog::to_json(&entries) } else { chatlog::to_text(&entries) };
+        // Logs are hosted publicly, so their URL has to be unguessable
+        let filename = format!("{}-{}-{}.{}",
+                               try!(media::random_token()),
+                               media::sanitize_filename(group),
+                               args.join("-"),
+                               if format == "json" { "json" } else { "txt" });
+        let store = try!(mediastore::open(config));
+        store.put(contents.as_bytes(), &mediastore::Meta {
+            dir: EXPORT_DIR,
+            filename: &filename,
+        })
+    });
+    match uploaded {
+        Ok(url) => {
+            println!("[INFO] Exported the log of \"{}\" to {}", group, url);
+            format!("Log of {}: {}", group, url)
+        }
+        Err(err) => {
+            println!("[ERROR] {}", err.context(format!("exporting the log of \"{}\"", group)));
+            format!("Could not export the log: {}", err)
+        }
+    }
+}
+
+// Record pins and the chat ids they imply, replying `reply` if that works out
+fn save_pins(state: &RelayState, reply: String) -> String {
+    let saved = state.store
+                     .save_pins(&state.pinned)
+                     .and_then(|_| state.store.save_ambiguous(&state.ambiguous))
+                     .and_then(|_| state.store.save_chat_ids(&state.chat_ids));
+    if let Err(err) = saved {
+        println!("[ERROR] Could not save pins: {}", err);
+        return format!("{}, but it could not be saved: {}", reply, err);
+    }
+    println!("[INFO] {}", reply);
+    reply
+}
+
+// Relay a Telegram group title only for one chat, for when several groups share it:
+// pin <chat id> [group]
+// The group defaults to the one of the mapping the command is given in.
+fn pin(state: &mut RelayState, here: Option<&str>, args: &[&str]) -> String {
+    let chat_id: Integer = match args.first().and_then(|id| id.parse().ok()) {
+        Some(chat_id) => chat_id,
+        None => return usage("pin"),
+    };
+    let group = match (args.len(), here) {
+        (1, Some(here)) => here.to_owned(),
+        (1, None) => return "Name the group to pin outside of a bridged group or channel".into(),
+        _ => args[1..].join(" "),
+    };
+    state.ambiguous.remove(&group);
+    state.pinned.insert(group.clone(), chat_id);
+    state.chat_ids.insert(group.clone(), chat_id);
+    save_pins(state, format!("Telegram group \"{}\" is now pinned to chat id {}", group, chat_id))
+}
+
+// Forget the pin of a Telegram group: unpin [group]
+// Its chat id is learned again from the next message in a group by that title.
+fn unpin(state: &mut RelayState, here: Option<&str>, args: &[&str]) -> String {
+    let group = match (args.is_empty(), here) {
+        (true, Some(here)) => here.to_owned(),
+        (true, None) => return usage("unpin"),
+        (false, _) => args.join(" "),
+    };
+    if state.pinned.remove(&group).is_none() {
+        return format!("Telegram group \"{}\" isn't pinned", group);
+    }
+    state.chat_ids.remove(&group);
+    save_pins(state, format!("Telegram group \"{}\" is no longer pinned", group))
+}
+
+// Delete everything a Telegram user sent that we keep: their mirrored media, their
+// messages in the chat logs and the relayed messages remembered for tgdel and edits:
+// purge user <id|username>
+fn purge(config: &Config, state: &mut RelayState, args: &[&str]) -> Reply {
+    if args.len() != 2 || args[0] != "user" {
+        return Reply::Now(usage("purge"));
+    }
+    let user = args[1];
+    let user_ids = media::user_ids(config, user);
+    if user_ids.is_empty() {
+        return Reply::Now(format!("No Telegram user \"{}\" is known, give their user id instead", user));
+    }
+    let forgotten = state.relayed.forget_users(&user_ids);
+    // Saved right away, so what was purged doesn't come back after a restart
+    if let Err(err) = state.store.save_relayed(&state.relayed.entries()) {
+        println!("[ERROR] Could not save relayed messages: {}", err);
+    }
+    Reply::Later(Pending::Purge {
+        user: user.to_owned(),
+        user_ids: user_ids,
+        forgotten: forgotten,
+    })
+}
+
+fn counted(count: usize, what: &str) -> String {
+    format!("{} {}{}", count, what, if count == 1 { "" } else { "s" })
+}
+
+// The part of purge going through files, done without the relay state locked
+fn purge_files(config: &Config, user: &str, user_ids: &[Integer], forgotten: usize) -> String {
+    let mut deleted = vec![];
+    let mut failed = vec![];
+    match media::purge_user(config, user_ids) {
+        Ok(files) => deleted.push(counted(files, "mirrored file")),
+        Err(err) => {
+            println!("[ERROR] {}", err.context(format!("purging the media of \"{}\"", user)));
+            failed.push(format!("the mirrored media ({})", err));
+        }
+    }
+    match chatlog::scrub_user(config, user_ids) {
+        Ok(entries) => deleted.push(counted(entries, "logged message")),
+        Err(err) => {
+            println!("[ERROR] {}", err.context(format!("purging the logged messages of \"{}\"", user)));
+            failed.push(format!("the logged messages ({})", err));
+        }
+    }
+    deleted.push(counted(forgotten, "remembered message"));
+    println!("[INFO] Purged \"{}\": {}", user, deleted.join(", "));
+    let mut reply = format!("Deleted {} of \"{}\"", deleted.join(", "), user);
+    if !failed.is_empty() {
+        reply.push_str(&format!(", but could not delete {}", failed.join(" or ")));
+    }
+    reply
+}
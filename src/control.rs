@@ -0,0 +1,84 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use commands;
+use permissions::Role;
+use outbound::Outbound;
+use super::{Config, RelayState};
+
+// Answer the commands of one client. Every line is a command with its arguments, answered
+// with the reply followed by an empty line.
+fn serve(stream: UnixStream, config: Arc<Config>, state: Arc<Mutex<RelayState>>, outbound: Arc<Outbound>) {
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(err) => {
+            println!("[ERROR] Control socket: {}", err);
+            return;
+        }
+    };
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        let (command, args) = match commands::parse(line.trim(), "", "") {
+            Some(parsed) => parsed,
+            None => continue,
+        };
+        let reply = {
+            let mut state = state.lock().unwrap();
+            let caller = commands::Caller {
+                nick: "control",
+                network: "control",
+                role: Role::Owner,
+                prefix: "",
+            };
+            commands::run(&config, &mut state, &outbound, None, &caller, command, &args)
+        };
+        let reply = match reply {
+            Some(reply) => {
+                println!("[INFO] Control socket ran \"{}\"", line.trim());
+                commands::finish(&config, &outbound, reply)
+            }
+            None => format!("Unknown command \"{}\"", command),
+        };
+        if writer.write_all(format!("{}\n\n", reply).as_bytes()).is_err() {
+            break;
+        }
+    }
+}
+
+// Accept admin commands from a local unix socket, so they can be scripted without going
+// through IRC or Telegram. Anyone who can connect to the socket is treated as an admin,
+// so it should be placed where only the operators can reach it.
+pub fn listen(path: String, config: Arc<Config>, state: Arc<Mutex<RelayState>>, outbound: Arc<Outbound>) {
+    // Clean up after a previous run
+    if Path::new(&path).exists() {
+        if let Err(err) = fs::remove_file(&path) {
+            println!("[ERROR] Could not remove stale control socket \"{}\": {}", path, err);
+            return;
+        }
+    }
+    let listener = match UnixListener::bind(&path) {
+        Ok(listener) => listener,
+        Err(err) => {
+            println!("[ERROR] Could not create control socket \"{}\": {}", path, err);
+            return;
+        }
+    };
+    println!("[INFO] Listening for commands on \"{}\"", path);
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let config = config.clone();
+                let state = state.clone();
+                let outbound = outbound.clone();
+                thread::spawn(move || serve(stream, config, state, outbound));
+            }
+            Err(err) => println!("[ERROR] Control socket: {}", err),
+        }
+    }
+}
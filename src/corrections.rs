@@ -0,0 +1,157 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::Read;
+use hyper::method::Method;
+use hyper::client::Request;
+use telegram_bot::types::Integer;
+use error::{self, ResultExt};
+use urls;
+use super::{ChatID, IrcChannel};
+
+// Number of messages posted to Telegram that are remembered for editing
+const CAPACITY: usize = 200;
+
+// A correction like "s/teh/the/", or "s/teh/the/g" to replace every occurrence
+#[derive(Clone, Debug, PartialEq)]
+pub struct Substitution {
+    pub old: String,
+    pub new: String,
+    pub global: bool,
+}
+
+// The substitution `text` asks for, if it is nothing but one. The trailing slash may be
+// left out, as it often is. Slashes within the texts can be escaped as "\/".
+pub fn parse(text: &str) -> Option<Substitution> {
+    let text = text.trim();
+    if !text.starts_with("s/") {
+        return None;
+    }
+    let mut parts = vec![String::new()];
+    let mut chars = text[2..].chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                match chars.next() {
+                    Some('/') => parts.last_mut().unwrap().push('/'),
+                    Some(c) => {
+                        let part = parts.last_mut().unwrap();
+                        part.push('\\');
+                        part.push(c);
+                    }
+                    None => parts.last_mut().unwrap().push('\\'),
+                }
+            }
+            '/' => parts.push(String::new()),
+            c => parts.last_mut().unwrap().push(c),
+        }
+    }
+    let global = match parts.get(2).map(|flags| &flags[..]) {
+        None | Some("") => false,
+        Some("g") => true,
+        Some(_) => return None,
+    };
+    if parts.len() < 2 || parts.len() > 3 || parts[0].is_empty() {
+        return None;
+    }
+    Some(Substitution {
+        old: parts[0].clone(),
+        new: parts[1].clone(),
+        global: global,
+    })
+}
+
+impl Substitution {
+    // `text` with the substitution made, if there was anything to replace
+    pub fn apply(&self, text: &str) -> Option<String> {
+        let start = match text.find(&self.old[..]) {
+            Some(start) => start,
+            None => return None,
+        };
+        if self.global {
+            Some(text.replace(&self.old[..], &self.new))
+        } else {
+            Some(format!("{}{}{}", &text[..start], self.new, &text[start + self.old.len()..]))
+        }
+    }
+}
+
+// The last message of an IRC user relayed to Telegram
+#[derive(Clone, Debug)]
+pub struct Said {
+    // The text as said, without the template around it
+    pub text: String,
+    pub action: bool,
+    // The line as posted to Telegram
+    pub line: String,
+}
+
+// What was recently relayed from IRC to Telegram, so IRC users' corrections can be made to
+// the Telegram messages instead of relayed as they are
+#[derive(Clone, Default)]
+pub struct Corrections {
+    // By IRC channel and nick
+    said: HashMap<(IrcChannel, String), Said>,
+    // Messages posted to Telegram, oldest first. Several lines from IRC may have been
+    // batched into one message.
+    posted: VecDeque<(ChatID, Integer, String)>,
+}
+
+impl Corrections {
+    pub fn said(&mut self, channel: &str, nick: &str, said: Said) {
+        self.said.insert((channel.to_owned(), nick.to_owned()), said);
+    }
+
+    pub fn last_said(&self, channel: &str, nick: &str) -> Option<&Said> {
+        self.said.get(&(channel.to_owned(), nick.to_owned()))
+    }
+
+    pub fn posted(&mut self, chat_id: ChatID, message_id: Integer, text: String) {
+        self.posted.push_back((chat_id, message_id, text));
+        if self.posted.len() > CAPACITY {
+            self.posted.pop_front();
+        }
+    }
+
+    // The latest message posted to `chat_id` with `line` in it, as its id and its text with
+    // `line` replaced by `corrected`, for it to be edited
+    pub fn correct(&self, chat_id: ChatID, line: &str, corrected: &str) -> Option<(Integer, String)> {
+        self.posted
+            .iter()
+            .rev()
+            .find(|&&(chat, _, ref text)| chat == chat_id && text.lines().any(|posted| posted == line))
+            .map(|&(_, message_id, ref text)| {
+                let edited = text.lines()
+                                 .map(|posted| if posted == line { corrected } else { posted })
+                                 .collect::<Vec<_>>()
+                                 .join("\n");
+                (message_id, edited)
+            })
+    }
+
+    // Remember the new text of a message once it has been edited
+    pub fn edited(&mut self, chat_id: ChatID, message_id: Integer, text: String) {
+        if let Some(posted) = self.posted.iter_mut().find(|posted| posted.0 == chat_id && posted.1 == message_id) {
+            posted.2 = text;
+        }
+    }
+}
+
+// Replace the text of a message we posted to a Telegram chat
+pub fn edit_message_text(token: &str, chat_id: ChatID, message_id: Integer, text: &str) -> error::Result<()> {
+    let mut url = try!(urls::parse(&format!("https://api.telegram.org/bot{}/editMessageText", token),
+                                   "editMessageText url"));
+    url.set_query_from_pairs(vec![("chat_id", chat_id.to_string()),
+                                  ("message_id", message_id.to_string()),
+                                  ("text", text.to_owned())]
+                                 .iter()
+                                 .map(|&(key, ref value)| (key, &value[..])));
+    let mut resp = try!(Request::new(Method::Get, url)
+                            .and_then(|req| req.start())
+                            .and_then(|req| req.send())
+                            .context(format!("editing message {}", message_id)));
+    if !resp.status.is_success() {
+        let mut body = String::new();
+        let _ = resp.read_to_string(&mut body);
+        return Err(format!("editing message {}: server responded with {}: {}", message_id, resp.status, body).into());
+    }
+    Ok(())
+}
@@ -0,0 +1,45 @@
+use time;
+use super::Config;
+
+const SOURCE_URL: &'static str = "https://github.com/flowbish/tiercel";
+
+// If `text` is a CTCP query, split it into the query and its argument. ACTIONs are left
+// alone, those are relayed like any other message.
+pub fn parse(text: &str) -> Option<(&str, &str)> {
+    if !text.starts_with('\u{1}') {
+        return None;
+    }
+    let inner = text[1..].trim_right_matches('\u{1}');
+    let mut parts = inner.splitn(2, ' ');
+    let query = parts.next().unwrap_or("");
+    if query == "ACTION" {
+        return None;
+    }
+    Some((query, parts.next().unwrap_or("")))
+}
+
+// The text of a CTCP ACTION ("/me waves"), if `text` is one
+pub fn action(text: &str) -> Option<&str> {
+    if text.starts_with("\u{1}ACTION ") {
+        Some(text["\u{1}ACTION ".len()..].trim_right_matches('\u{1}'))
+    } else {
+        None
+    }
+}
+
+// The reply to a CTCP query, ready to be sent as a NOTICE, or None for queries we don't
+// answer.
+pub fn reply(config: &Config, query: &str, arg: &str) -> Option<String> {
+    let answer = match query {
+        "VERSION" => {
+            config.ctcp_version
+                  .clone()
+                  .unwrap_or(format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")))
+        }
+        "SOURCE" => SOURCE_URL.into(),
+        "PING" => arg.into(),
+        "TIME" => time::now().rfc822().to_string(),
+        _ => return None,
+    };
+    Some(format!("\u{1}{} {}\u{1}", query, answer))
+}
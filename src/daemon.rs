@@ -0,0 +1,95 @@
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
+use libc;
+
+const USAGE: &'static str = "Usage: tgirc [--daemon] [--pidfile <path>]\n       \
+                             tgirc --encrypt-secrets <path> [--key-file <path>] < secrets.toml";
+
+// Options given on the command line
+#[derive(Default, Debug)]
+pub struct Options {
+    // Detach from the terminal and keep running in the background
+    pub daemon: bool,
+    pub pidfile: Option<String>,
+    // Encrypt the secrets given on stdin into this file, and exit
+    pub encrypt_secrets: Option<String>,
+    // Derive the key for --encrypt-secrets from this file instead of a passphrase
+    pub key_file: Option<String>,
+}
+
+pub fn parse_args() -> Options {
+    let mut options = Options::default();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match &arg[..] {
+            "--daemon" => options.daemon = true,
+            "--pidfile" => {
+                match args.next() {
+                    Some(path) => options.pidfile = Some(path),
+                    None => usage(),
+                }
+            }
+            "--encrypt-secrets" => {
+                match args.next() {
+                    Some(path) => options.encrypt_secrets = Some(path),
+                    None => usage(),
+                }
+            }
+            "--key-file" => {
+                match args.next() {
+                    Some(path) => options.key_file = Some(path),
+                    None => usage(),
+                }
+            }
+            _ => usage(),
+        }
+    }
+    options
+}
+
+fn usage() -> ! {
+    println!("{}", USAGE);
+    ::std::process::exit(2);
+}
+
+fn redirect(file: &File, fd: libc::c_int) -> io::Result<()> {
+    if unsafe { libc::dup2(file.as_raw_fd(), fd) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Fork into the background and detach from the terminal, sending stdout and stderr to
+// `log_file` (or discarding them). This must happen before any threads are started, as
+// only the forking thread survives. The working directory is kept, since the config and
+// state files are looked up relative to it.
+pub fn daemonize(log_file: Option<&str>) -> io::Result<()> {
+    // Open everything up front, so that errors can still be reported on the terminal
+    let null = try!(File::open("/dev/null"));
+    let log = match log_file {
+        Some(path) => try!(OpenOptions::new().append(true).create(true).open(path)),
+        None => try!(OpenOptions::new().write(true).open("/dev/null")),
+    };
+
+    match unsafe { libc::fork() } {
+        -1 => return Err(io::Error::last_os_error()),
+        0 => {}
+        // The parent is done
+        _ => ::std::process::exit(0),
+    }
+    if unsafe { libc::setsid() } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    try!(redirect(&null, libc::STDIN_FILENO));
+    try!(redirect(&log, libc::STDOUT_FILENO));
+    try!(redirect(&log, libc::STDERR_FILENO));
+    Ok(())
+}
+
+pub fn write_pidfile(path: &str) -> io::Result<()> {
+    let pid = unsafe { libc::getpid() };
+    File::create(path).and_then(|mut file| writeln!(file, "{}", pid))
+}
@@ -0,0 +1,70 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use telegram_bot::types::Integer;
+use store::{StateStore, Updates};
+use super::ChatID;
+
+// Number of recently handled messages remembered across restarts
+const DEDUP_CAPACITY: usize = 1000;
+
+// Keeps track of the Telegram updates we've handled, persisted so that updates redelivered
+// by the long poll after a restart aren't relayed a second time.
+pub struct Seen {
+    store: Arc<StateStore>,
+    // Id of the next update to ask for
+    offset: Integer,
+    recent: VecDeque<String>,
+}
+
+impl Seen {
+    pub fn load(store: Arc<StateStore>) -> Seen {
+        let updates = store.load_updates().unwrap_or_else(|err| {
+            println!("[ERROR] Could not load handled updates: {}", err);
+            Updates::default()
+        });
+        Seen {
+            store: store,
+            offset: updates.offset,
+            recent: updates.recent.into_iter().collect(),
+        }
+    }
+
+    pub fn offset(&self) -> Integer {
+        self.offset
+    }
+
+    // Record that an update is about to be handled, before relaying anything so that we
+    // relay at most once. Returns false if its message was already handled.
+    pub fn record(&mut self, update_id: Integer, message: Option<(ChatID, Integer)>) -> bool {
+        if update_id >= self.offset {
+            self.offset = update_id + 1;
+        }
+        let fresh = match message {
+            Some((chat_id, message_id)) => {
+                let key = format!("{}:{}", chat_id, message_id);
+                if self.recent.contains(&key) {
+                    false
+                } else {
+                    if self.recent.len() >= DEDUP_CAPACITY {
+                        self.recent.pop_front();
+                    }
+                    self.recent.push_back(key);
+                    true
+                }
+            }
+            None => true,
+        };
+        self.save();
+        fresh
+    }
+
+    fn save(&self) {
+        let updates = Updates {
+            offset: self.offset,
+            recent: self.recent.iter().cloned().collect(),
+        };
+        if let Err(err) = self.store.save_updates(&updates) {
+            println!("[ERROR] Could not save handled updates: {}", err);
+        }
+    }
+}
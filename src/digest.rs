@@ -0,0 +1,73 @@
+use std::mem;
+use std::sync::Mutex;
+use super::{ChatID, Config, IrcChannel, TelegramGroup};
+
+// A message from IRC waiting for the next digest
+pub struct Entry {
+    pub nick: String,
+    pub text: String,
+    // A /me action rather than something said
+    pub action: bool,
+}
+
+// Messages of a busy IRC channel collected for a Telegram group, posted together every
+// few minutes instead of one by one
+pub struct Digest {
+    pub channel: IrcChannel,
+    pub minutes: u64,
+    // Chat id of the group as of the latest message, and the messages so far
+    pending: Mutex<(ChatID, Vec<Entry>)>,
+}
+
+impl Digest {
+    pub fn push(&self, id: ChatID, entry: Entry) {
+        let mut pending = self.pending.lock().unwrap();
+        pending.0 = id;
+        pending.1.push(entry);
+    }
+
+    // The chat to post to and the messages collected since the last time
+    pub fn take(&self) -> (ChatID, Vec<Entry>) {
+        let mut pending = self.pending.lock().unwrap();
+        (pending.0, mem::replace(&mut pending.1, vec![]))
+    }
+}
+
+// The digest of the Telegram group `group`, if it is relayed to in digests
+pub fn digest(config: &Config, group: &TelegramGroup, channel: &IrcChannel) -> Option<Digest> {
+    config.digest_minutes
+          .as_ref()
+          .and_then(|digests| digests.get(group))
+          .map(|&minutes| {
+              Digest {
+                  channel: channel.clone(),
+                  minutes: if minutes == 0 { 1 } else { minutes },
+                  pending: Mutex::new((0, vec![])),
+              }
+          })
+}
+
+// One post for the messages collected from `channel`, with consecutive messages of the same
+// sender under a single line naming them
+pub fn format(channel: &str, minutes: u64, entries: &[Entry]) -> String {
+    let mut text = format!("{} message{} in {} over the last {} minute{}:",
+                           entries.len(),
+                           if entries.len() == 1 { "" } else { "s" },
+                           channel,
+                           minutes,
+                           if minutes == 1 { "" } else { "s" });
+    let mut last_nick: Option<&str> = None;
+    for entry in entries {
+        if entry.action {
+            text.push_str(&format!("\n* {} {}", entry.nick, entry.text));
+            last_nick = None;
+            continue;
+        }
+        if last_nick != Some(&entry.nick[..]) {
+            text.push_str(&format!("\n{}:", entry.nick));
+            last_nick = Some(&entry.nick);
+        }
+        text.push_str(&format!("\n  {}", entry.text));
+    }
+    text
+}
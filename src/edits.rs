@@ -0,0 +1,78 @@
+use std::str::FromStr;
+use super::Config;
+
+// How edits of relayed Telegram messages are relayed to IRC
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    Off,
+    // Relay the whole edited message again
+    Full,
+    // Relay only what changed, as "s/old/new/"
+    Diff,
+    // Relay edits made shortly after sending as "nick meant: edited message", the way people
+    // correct themselves on IRC, and later ones in full
+    Meant,
+}
+
+// How long after sending an edit is relayed as what the sender meant, unless configured
+pub const DEFAULT_MEANT_SECONDS: i64 = 120;
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Mode, String> {
+        match s {
+            "off" => Ok(Mode::Off),
+            "full" => Ok(Mode::Full),
+            "diff" => Ok(Mode::Diff),
+            "meant" => Ok(Mode::Meant),
+            _ => Err(format!("unknown edit mode \"{}\"", s)),
+        }
+    }
+}
+
+// The edit mode of the Telegram group `group`: its own, else the configured one, else off
+pub fn mode(config: &Config, group: &str) -> Mode {
+    let mode = config.edit_modes
+                     .as_ref()
+                     .and_then(|modes| modes.get(group))
+                     .or(config.relay_edits.as_ref());
+    match mode {
+        Some(mode) => mode.parse().unwrap_or_else(|err| panic!("error in edit mode config: {}", err)),
+        None => Mode::Off,
+    }
+}
+
+// Describe the change from `old` to `new` as a substitution of whole words, like
+// "s/teh/the/". Returns None when that wouldn't be shorter than the new message, or
+// when nothing is replaced, in which case the whole message is better relayed again.
+pub fn diff(old: &str, new: &str) -> Option<String> {
+    let old: Vec<char> = old.chars().collect();
+    let new: Vec<char> = new.chars().collect();
+
+    let mut start = 0;
+    while start < old.len() && start < new.len() && old[start] == new[start] {
+        start += 1;
+    }
+    let mut end = 0;
+    while end < old.len() - start && end < new.len() - start &&
+          old[old.len() - 1 - end] == new[new.len() - 1 - end] {
+        end += 1;
+    }
+    // Widen the change to whole words
+    while start > 0 && !old[start - 1].is_whitespace() {
+        start -= 1;
+    }
+    while end > 0 && !old[old.len() - end].is_whitespace() {
+        end -= 1;
+    }
+
+    let removed: String = old[start..old.len() - end].iter().cloned().collect();
+    let added: String = new[start..new.len() - end].iter().cloned().collect();
+    let substitution = format!("s/{}/{}/", removed, added);
+    if removed.trim().is_empty() || substitution.chars().count() >= new.len() {
+        None
+    } else {
+        Some(substitution)
+    }
+}
@@ -0,0 +1,238 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+use outbound::Outbound;
+use templates;
+use super::{Config, EmailConfig, RelayState, TelegramGroup, post_to_mapping};
+
+// Lines of the body included in the summary of a mail, unless configured
+const DEFAULT_SUMMARY_LINES: usize = 3;
+// Larger mails are refused
+const MAX_MAIL_SIZE: usize = 1024 * 1024;
+// Longest line taken, in bytes with the line break, as RFC 5321 limits text lines to
+const MAX_LINE_BYTES: u64 = 1000;
+// Seconds a client may keep us waiting for its next line, or for taking our reply
+const SOCKET_TIMEOUT_SECONDS: u64 = 5 * 60;
+// Connections served at once, further ones are turned away until one closes
+const MAX_CONNECTIONS: usize = 10;
+
+// What the summary of a mail is made from
+struct Mail {
+    from: String,
+    subject: String,
+    summary: Vec<String>,
+}
+
+// The address in "MAIL FROM:<alice@example.org>" and the like
+fn address(arg: &str) -> String {
+    let start = arg.find('<').map_or(0, |start| start + 1);
+    let end = arg.rfind('>').unwrap_or(arg.len());
+    arg[start..end].trim().to_lowercase()
+}
+
+fn header<'a>(headers: &'a [String], name: &str) -> Option<&'a str> {
+    let prefix = format!("{}:", name.to_lowercase());
+    headers.iter()
+           .find(|line| line.to_lowercase().starts_with(&prefix))
+           .map(|line| line[prefix.len()..].trim())
+}
+
+// Pick the sender, subject and first lines of the text out of a mail. In multipart mails
+// the first text/plain part is used. Encoded bodies are not decoded.
+fn summarize(data: &[String], sender: &str, lines: usize) -> Mail {
+    let split = data.iter().position(|line| line.is_empty()).unwrap_or(data.len());
+    let headers = &data[..split];
+    let mut body = data[split..].iter().map(|line| &line[..]).skip_while(|line| line.is_empty());
+
+    let multipart = header(headers, "Content-Type").map_or(false, |kind| kind.to_lowercase().starts_with("multipart/"));
+    let summary = if multipart {
+        let mut body = body.skip_while(|line| !line.to_lowercase().starts_with("content-type: text/plain"))
+                           .skip_while(|line| !line.is_empty());
+        body.by_ref()
+            .take_while(|line| !line.starts_with("--"))
+            .filter(|line| !line.trim().is_empty() && !line.starts_with('>'))
+            .take(lines)
+            .map(|line| line.trim().to_owned())
+            .collect()
+    } else {
+        body.by_ref()
+            .filter(|line| !line.trim().is_empty() && !line.starts_with('>'))
+            .take(lines)
+            .map(|line| line.trim().to_owned())
+            .collect()
+    };
+    Mail {
+        from: header(headers, "From").unwrap_or(sender).to_owned(),
+        subject: header(headers, "Subject").unwrap_or("(no subject)").to_owned(),
+        summary: summary,
+    }
+}
+
+// Read a line into `line`, which is left empty at the end of the stream. A line longer than
+// MAX_LINE_BYTES is an error, rather than read into memory however long it gets.
+fn read_line<R: BufRead>(reader: &mut R, line: &mut String) -> io::Result<()> {
+    line.clear();
+    try!(reader.take(MAX_LINE_BYTES).read_line(line));
+    if line.len() as u64 == MAX_LINE_BYTES && !line.ends_with('\n') {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "line too long"));
+    }
+    Ok(())
+}
+
+// A connection being served, counted until it is over
+struct Connection(Arc<AtomicUsize>);
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Speak just enough SMTP to take mail for the configured addresses
+fn serve(stream: TcpStream,
+         email: Arc<EmailConfig>,
+         config: Arc<Config>,
+         outbound: Arc<Outbound>,
+         state: Arc<Mutex<RelayState>>)
+         -> ::std::io::Result<()> {
+    try!(stream.set_read_timeout(Some(Duration::from_secs(SOCKET_TIMEOUT_SECONDS))));
+    try!(stream.set_write_timeout(Some(Duration::from_secs(SOCKET_TIMEOUT_SECONDS))));
+    let mut writer = try!(stream.try_clone());
+    let mut reader = BufReader::new(stream);
+    try!(writer.write_all(b"220 tiercel ESMTP\r\n"));
+
+    let mut sender = String::new();
+    let mut groups: Vec<TelegramGroup> = vec![];
+    let mut line = String::new();
+    loop {
+        try!(read_line(&mut reader, &mut line));
+        if line.is_empty() {
+            return Ok(());
+        }
+        let command = line.trim_right().to_owned();
+        let verb = command.split(|c: char| c == ' ' || c == ':').next().unwrap_or("").to_uppercase();
+        let arg = command.splitn(2, ':').nth(1).unwrap_or("");
+        let reply = match &verb[..] {
+            "HELO" | "EHLO" => "250 tiercel".to_owned(),
+            "MAIL" => {
+                sender = address(arg);
+                groups.clear();
+                let allowed = email.allowed_senders
+                                   .as_ref()
+                                   .map_or(true, |allowed| allowed.iter().any(|allowed| allowed.to_lowercase() == sender));
+                if allowed {
+                    "250 OK".to_owned()
+                } else {
+                    println!("[WARN] Refused mail from {}", sender);
+                    sender.clear();
+                    "550 Sender not allowed".to_owned()
+                }
+            }
+            "RCPT" => {
+                let recipient = address(arg);
+                match email.addresses.iter().find(|&(mailbox, _)| mailbox.to_lowercase() == recipient) {
+                    Some((_, group)) if !sender.is_empty() => {
+                        groups.push(group.clone());
+                        "250 OK".to_owned()
+                    }
+                    Some(_) => "503 MAIL first".to_owned(),
+                    None => "550 No such mailbox".to_owned(),
+                }
+            }
+            "DATA" if groups.is_empty() => "503 RCPT first".to_owned(),
+            "DATA" => {
+                try!(writer.write_all(b"354 End data with <CR><LF>.<CR><LF>\r\n"));
+                let mut data = vec![];
+                let mut size = 0;
+                loop {
+                    try!(read_line(&mut reader, &mut line));
+                    if line.is_empty() {
+                        return Ok(());
+                    }
+                    let text = line.trim_right_matches(|c: char| c == '\r' || c == '\n');
+                    if text == "." {
+                        break;
+                    }
+                    size += line.len();
+                    if size > MAX_MAIL_SIZE {
+                        break;
+                    }
+                    // Lines starting with a dot have an extra one added by the client
+                    data.push(if text.starts_with("..") { text[1..].to_owned() } else { text.to_owned() });
+                }
+                if size > MAX_MAIL_SIZE {
+                    // The rest of the mail would be taken for commands, so hang up
+                    try!(writer.write_all(b"552 Message too large\r\n"));
+                    return Ok(());
+                } else {
+                    let mail = summarize(&data, &sender, email.summary_lines.unwrap_or(DEFAULT_SUMMARY_LINES));
+                    let summary = mail.summary.join("\n");
+                    let state = state.lock().unwrap();
+                    for group in groups.drain(..) {
+                        println!("[INFO] Posting mail from {} to \"{}\": {}", mail.from, group, mail.subject);
+                        let text = templates::render(&config, &group, templates::EMAIL, &[("from", &mail.from[..]),
+                                                                                         ("subject", &mail.subject[..]),
+                                                                                         ("summary", &summary[..])]);
+                        post_to_mapping(&outbound, &state, &group, text);
+                    }
+                    "250 OK".to_owned()
+                }
+            }
+            "RSET" => {
+                sender.clear();
+                groups.clear();
+                "250 OK".to_owned()
+            }
+            "NOOP" => "250 OK".to_owned(),
+            "QUIT" => {
+                try!(writer.write_all(b"221 Bye\r\n"));
+                return Ok(());
+            }
+            _ => "502 Command not implemented".to_owned(),
+        };
+        try!(writer.write_all(format!("{}\r\n", reply).as_bytes()));
+    }
+}
+
+// Accept mail for the configured addresses and post a summary of each to the mapping of
+// its address. Anyone who can connect may send mail, so unless allowed_senders is set
+// this should only listen where the local mail server can reach it.
+pub fn listen(email: EmailConfig, config: Arc<Config>, outbound: Arc<Outbound>, state: Arc<Mutex<RelayState>>) {
+    let listener = match TcpListener::bind(&email.listen[..]) {
+        Ok(listener) => listener,
+        Err(err) => {
+            println!("[ERROR] Could not listen for mail on {}: {}", email.listen, err);
+            return;
+        }
+    };
+    println!("[INFO] Accepting mail on {}", email.listen);
+    let email = Arc::new(email);
+    let connections = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        match stream {
+            Ok(mut stream) => {
+                if connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                    connections.fetch_sub(1, Ordering::SeqCst);
+                    println!("[WARN] Turning away mail connection, {} are open already", MAX_CONNECTIONS);
+                    let _ = stream.write_all(b"421 Too many connections, try again later\r\n");
+                    continue;
+                }
+                let connection = Connection(connections.clone());
+                let email = email.clone();
+                let config = config.clone();
+                let outbound = outbound.clone();
+                let state = state.clone();
+                thread::spawn(move || {
+                    let _connection = connection;
+                    if let Err(err) = serve(stream, email, config, outbound, state) {
+                        println!("[ERROR] Mail gateway: {}", err);
+                    }
+                });
+            }
+            Err(err) => println!("[ERROR] Mail gateway: {}", err),
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+use std::result;
+use hyper;
+use rusqlite;
+use telegram_bot;
+
+pub type Result<T> = result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    // A plain error message without any underlying cause
+    Msg(String),
+    Io(io::Error),
+    Http(hyper::Error),
+    Telegram(telegram_bot::Error),
+    Sqlite(rusqlite::Error),
+    // An error wrapped with a description of what was being attempted when it happened
+    Context(String, Box<Error>),
+}
+
+impl Error {
+    pub fn context<S: Into<String>>(self, msg: S) -> Error {
+        Error::Context(msg.into(), Box::new(self))
+    }
+}
+
+// Extension trait allowing any result whose error converts into our error type to be
+// annotated with context, e.g. `try!(download(url).context("downloading photo"))`.
+pub trait ResultExt<T> {
+    fn context<S: Into<String>>(self, msg: S) -> Result<T>;
+}
+
+impl<T, E: Into<Error>> ResultExt<T> for result::Result<T, E> {
+    fn context<S: Into<String>>(self, msg: S) -> Result<T> {
+        self.map_err(|err| err.into().context(msg))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Msg(ref msg) => write!(f, "{}", msg),
+            Error::Io(ref err) => write!(f, "{}", err),
+            Error::Http(ref err) => write!(f, "{}", err),
+            Error::Telegram(ref err) => write!(f, "{}", err),
+            Error::Sqlite(ref err) => write!(f, "{}", err),
+            // Print the whole chain, outermost context first
+            Error::Context(ref msg, ref cause) => write!(f, "{} → {}", msg, cause),
+        }
+    }
+}
+
+impl StdError for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Msg(ref msg) => msg,
+            Error::Io(ref err) => err.description(),
+            Error::Http(ref err) => err.description(),
+            Error::Telegram(ref err) => err.description(),
+            Error::Sqlite(ref err) => err.description(),
+            Error::Context(ref msg, _) => msg,
+        }
+    }
+
+    fn cause(&self) -> Option<&StdError> {
+        match *self {
+            Error::Msg(_) => None,
+            Error::Io(ref err) => Some(err),
+            Error::Http(ref err) => Some(err),
+            Error::Telegram(ref err) => Some(err),
+            Error::Sqlite(ref err) => Some(err),
+            Error::Context(_, ref cause) => Some(&**cause),
+        }
+    }
+}
+
+impl<'a> From<&'a str> for Error {
+    fn from(msg: &'a str) -> Error {
+        Error::Msg(msg.into())
+    }
+}
+
+impl From<String> for Error {
+    fn from(msg: String) -> Error {
+        Error::Msg(msg)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<hyper::Error> for Error {
+    fn from(err: hyper::Error) -> Error {
+        Error::Http(err)
+    }
+}
+
+impl From<telegram_bot::Error> for Error {
+    fn from(err: telegram_bot::Error) -> Error {
+        Error::Telegram(err)
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(err: rusqlite::Error) -> Error {
+        Error::Sqlite(err)
+    }
+}
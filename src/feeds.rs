@@ -0,0 +1,118 @@
+use std::collections::HashSet;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use hyper::method::Method;
+use hyper::client::Request;
+use xml::reader::{EventReader, XmlEvent};
+use error::{self, ResultExt};
+use urls;
+use outbound::Outbound;
+use templates;
+use super::{Config, FeedConfig, RelayState, post_to_mapping};
+
+// Seconds between polls of a feed, unless configured
+const DEFAULT_FEED_INTERVAL: u64 = 600;
+
+#[derive(Clone, Default, Debug)]
+struct Item {
+    id: String,
+    title: String,
+    link: String,
+}
+
+// The items of an RSS feed or the entries of an Atom feed, in the order they appear
+fn parse(body: &str) -> error::Result<Vec<Item>> {
+    let mut items = vec![];
+    let mut item: Option<Item> = None;
+    let mut element = String::new();
+    for event in EventReader::new(body.as_bytes()) {
+        match try!(event.map_err(|err| err.to_string())) {
+            XmlEvent::StartElement { name, attributes, .. } => {
+                match &name.local_name[..] {
+                    "item" | "entry" => item = Some(Item::default()),
+                    // Atom links are in an attribute
+                    "link" => {
+                        if let Some(ref mut item) = item {
+                            if let Some(href) = attributes.iter().find(|attr| attr.name.local_name == "href") {
+                                if item.link.is_empty() {
+                                    item.link = href.value.clone();
+                                }
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+                element = name.local_name;
+            }
+            XmlEvent::Characters(text) | XmlEvent::CData(text) => {
+                if let Some(ref mut item) = item {
+                    match &element[..] {
+                        "title" => item.title.push_str(&text),
+                        "link" => item.link.push_str(&text),
+                        "guid" | "id" => item.id.push_str(&text),
+                        _ => {}
+                    }
+                }
+            }
+            XmlEvent::EndElement { name } => {
+                if name.local_name == "item" || name.local_name == "entry" {
+                    if let Some(mut item) = item.take() {
+                        if item.id.is_empty() {
+                            item.id = item.link.clone();
+                        }
+                        items.push(item);
+                    }
+                }
+                element.clear();
+            }
+            _ => {}
+        }
+    }
+    Ok(items)
+}
+
+fn fetch(url: &str) -> error::Result<Vec<Item>> {
+    let url = try!(urls::parse(url, "feed url"));
+    let mut resp = try!(Request::new(Method::Get, url)
+                            .and_then(|req| req.start())
+                            .and_then(|req| req.send())
+                            .context("fetching the feed"));
+    if !resp.status.is_success() {
+        return Err(format!("server responded with {}", resp.status).into());
+    }
+    let mut body = String::new();
+    try!(resp.read_to_string(&mut body).context("reading the feed"));
+    parse(&body)
+}
+
+// Poll a feed forever, posting new items to both sides of the mappings it is configured
+// for. Whatever is in the feed when we start has been seen already.
+pub fn poll(feed: FeedConfig, config: Config, outbound: Arc<Outbound>, state: Arc<Mutex<RelayState>>) {
+    let interval = Duration::from_secs(feed.interval.unwrap_or(DEFAULT_FEED_INTERVAL));
+    let mut seen: Option<HashSet<String>> = None;
+    loop {
+        match fetch(&feed.url) {
+            Ok(items) => {
+                if let Some(ref seen) = seen {
+                    let state = state.lock().unwrap();
+                    // Feeds list their newest items first
+                    for item in items.iter().rev().filter(|item| !seen.contains(&item.id)) {
+                        println!("[INFO] New item in feed \"{}\": {}", feed.name, item.title);
+                        for group in &feed.groups {
+                            let text = templates::render(&config, group, templates::FEED, &[("feed", &feed.name[..]),
+                                                                                            ("title", item.title.trim()),
+                                                                                            ("link", item.link.trim())]);
+                            post_to_mapping(&outbound, &state, group, text);
+                        }
+                    }
+                }
+                // Only what is still in the feed needs to be remembered
+                seen = Some(items.into_iter().map(|item| item.id).collect());
+            }
+            Err(err) => println!("[WARN] {}", err.context(format!("polling feed \"{}\"", feed.name))),
+        }
+        thread::sleep(interval);
+    }
+}
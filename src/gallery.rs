@@ -0,0 +1,184 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use rustc_serialize::json;
+use time;
+use error::{self, ResultExt};
+use media;
+
+// Record of every mirrored file, kept in the download directory
+pub const MANIFEST_FILE: &'static str = "manifest.json";
+// Name of the generated HTML index in each user and group directory
+pub const INDEX_FILE: &'static str = "index.html";
+// Directory below the download directory holding the per-group indexes
+const GROUPS_DIR: &'static str = "groups";
+
+#[derive(Clone, RustcEncodable, RustcDecodable, Debug)]
+pub struct MediaEntry {
+    // URL the mirrored file is reachable from
+    pub url: String,
+    // Location of the file, relative to the download directory, with '/' separating its
+    // components whatever the platform, so the manifest can be moved between servers
+    pub path: String,
+    pub user_id: i64,
+    pub user: String,
+    pub chat_id: i64,
+    pub group: String,
+    // Time the file was sent, as a unix timestamp
+    pub date: i64,
+    pub caption: String,
+}
+
+fn load_manifest(download_dir: &Path) -> error::Result<Vec<MediaEntry>> {
+    let path = download_dir.join(MANIFEST_FILE);
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let mut manifest = String::new();
+    try!(File::open(&path)
+             .and_then(|mut file| file.read_to_string(&mut manifest))
+             .context(format!("reading {}", path.display())));
+    json::decode(&manifest).map_err(|err| format!("decoding {}: {}", path.display(), err).into())
+}
+
+// Where the file of `entry` is on this platform, unless its path would lead out of the
+// download directory
+fn local_path(download_dir: &Path, entry: &MediaEntry) -> Option<PathBuf> {
+    let components: Vec<&str> = entry.path.split('/').collect();
+    if !components.iter().all(|component| media::is_safe_component(component)) {
+        return None;
+    }
+    Some(components.iter().fold(download_dir.to_path_buf(), |path, component| path.join(component)))
+}
+
+fn write_file(path: &Path, contents: &str) -> error::Result<()> {
+    File::create(path)
+        .and_then(|mut file| file.write_all(contents.as_bytes()))
+        .context(format!("writing {}", path.display()))
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn render(title: &str, entries: &[&MediaEntry]) -> String {
+    let mut html = format!("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+                            <title>{0}</title>\n</head>\n<body>\n<h1>{0}</h1>\n<ul>\n",
+                           escape(title));
+    // Newest first
+    for entry in entries.iter().rev() {
+        let sent = time::at_utc(time::Timespec::new(entry.date, 0));
+        html.push_str(&format!("<li><a href=\"{url}\">{date}</a> {user} in {group}{caption}</li>\n",
+                               url = escape(&entry.url),
+                               date = sent.rfc3339(),
+                               user = escape(&entry.user),
+                               group = escape(&entry.group),
+                               caption = if entry.caption.is_empty() {
+                                   String::new()
+                               } else {
+                                   format!(": {}", escape(&entry.caption))
+                               }));
+    }
+    html.push_str("</ul>\n</body>\n</html>\n");
+    html
+}
+
+// Drop files that no longer exist (expired or purged) from the manifest and regenerate
+// the index of every user and group that has shared media.
+pub fn rebuild(download_dir: &Path) -> error::Result<()> {
+    let entries = try!(load_manifest(download_dir));
+    if entries.is_empty() {
+        return Ok(());
+    }
+    let (present, missing): (Vec<MediaEntry>, Vec<MediaEntry>) =
+        entries.into_iter().partition(|entry| local_path(download_dir, entry).map_or(false, |path| path.exists()));
+
+    let mut users = BTreeMap::new();
+    let mut groups = BTreeMap::new();
+    for entry in missing.iter().chain(present.iter()) {
+        users.insert(entry.user_id, entry.user.clone());
+        groups.insert(entry.chat_id, entry.group.clone());
+    }
+
+    for (id, name) in &users {
+        let dir = download_dir.join(id.to_string());
+        if !dir.is_dir() {
+            continue;
+        }
+        let shared: Vec<&MediaEntry> = present.iter().filter(|entry| entry.user_id == *id).collect();
+        try!(write_file(&dir.join(INDEX_FILE), &render(&format!("Media shared by {}", name), &shared)));
+    }
+    for (id, title) in &groups {
+        let dir = download_dir.join(GROUPS_DIR).join(id.to_string());
+        try!(fs::create_dir_all(&dir).context(format!("creating {}", dir.display())));
+        let shared: Vec<&MediaEntry> = present.iter().filter(|entry| entry.chat_id == *id).collect();
+        try!(write_file(&dir.join(INDEX_FILE), &render(&format!("Media shared in {}", title), &shared)));
+    }
+
+    if !missing.is_empty() {
+        try!(write_file(&download_dir.join(MANIFEST_FILE), &json::as_pretty_json(&present).to_string()));
+    }
+    Ok(())
+}
+
+// Add a newly mirrored file to the manifest and the indexes
+pub fn record(download_dir: &Path, entry: MediaEntry) -> error::Result<()> {
+    let mut entries = try!(load_manifest(download_dir));
+    entries.push(entry);
+    try!(write_file(&download_dir.join(MANIFEST_FILE), &json::as_pretty_json(&entries).to_string()));
+    rebuild(download_dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use super::{MediaEntry, local_path};
+
+    fn entry(path: &str) -> MediaEntry {
+        MediaEntry {
+            url: String::new(),
+            path: path.to_owned(),
+            user_id: 1,
+            user: "alice".to_owned(),
+            chat_id: -1,
+            group: "group".to_owned(),
+            date: 0,
+            caption: String::new(),
+        }
+    }
+
+    #[test]
+    fn local_path_joins_components() {
+        let dir = Path::new("media");
+        assert_eq!(local_path(dir, &entry("1/photo.jpg")), Some(PathBuf::from("media").join("1").join("photo.jpg")));
+    }
+
+    #[test]
+    fn local_path_rejects_parent_directories() {
+        let dir = Path::new("media");
+        assert_eq!(local_path(dir, &entry("../secret")), None);
+        assert_eq!(local_path(dir, &entry("1/../../secret")), None);
+        assert_eq!(local_path(dir, &entry("1/./photo.jpg")), None);
+    }
+
+    #[test]
+    fn local_path_rejects_absolute_paths() {
+        let dir = Path::new("media");
+        assert_eq!(local_path(dir, &entry("/etc/passwd")), None);
+        assert_eq!(local_path(dir, &entry("C:\\Windows\\win.ini")), None);
+        assert_eq!(local_path(dir, &entry("C:win.ini")), None);
+    }
+
+    #[test]
+    fn local_path_rejects_separators_and_nul() {
+        let dir = Path::new("media");
+        assert_eq!(local_path(dir, &entry("1\\..\\..\\secret")), None);
+        assert_eq!(local_path(dir, &entry("1/photo.jpg\0.txt")), None);
+        assert_eq!(local_path(dir, &entry("1//photo.jpg")), None);
+        assert_eq!(local_path(dir, &entry("")), None);
+    }
+}
@@ -0,0 +1,56 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::thread;
+use rustc_serialize::json::Json;
+use super::Config;
+
+// Events hooks can be run on
+pub const MESSAGE_RELAYED: &'static str = "message_relayed";
+pub const BRIDGE_DOWN: &'static str = "bridge_down";
+pub const BRIDGE_UP: &'static str = "bridge_up";
+pub const USER_JOINED: &'static str = "user_joined";
+pub const MEDIA_BUDGET_EXCEEDED: &'static str = "media_budget_exceeded";
+
+// Run the commands hooked to `event`, passing the event as a JSON object on stdin. The
+// commands run in the background, they can't hold up relaying.
+pub fn fire(config: &Config, event: &str, fields: &[(&str, &str)]) {
+    let hooks = match config.hooks {
+        Some(ref hooks) => hooks,
+        None => return,
+    };
+    let mut payload = BTreeMap::new();
+    payload.insert("event".to_owned(), Json::String(event.to_owned()));
+    for &(key, value) in fields {
+        payload.insert(key.to_owned(), Json::String(value.to_owned()));
+    }
+    let payload = Json::Object(payload).to_string();
+
+    for hook in hooks.iter().filter(|hook| hook.event == event) {
+        let child = Command::new("sh")
+                        .arg("-c")
+                        .arg(&hook.command)
+                        .stdin(Stdio::piped())
+                        .stdout(Stdio::null())
+                        .spawn();
+        let mut child = match child {
+            Ok(child) => child,
+            Err(err) => {
+                println!("[ERROR] Could not run {} hook \"{}\": {}", event, hook.command, err);
+                continue;
+            }
+        };
+        let payload = payload.clone();
+        let command = hook.command.clone();
+        thread::spawn(move || {
+            if let Some(mut stdin) = child.stdin.take() {
+                let _ = stdin.write_all(payload.as_bytes());
+            }
+            match child.wait() {
+                Ok(status) if !status.success() => println!("[WARN] Hook \"{}\" exited with {}", command, status),
+                Err(err) => println!("[ERROR] Hook \"{}\": {}", command, err),
+                _ => {}
+            }
+        });
+    }
+}
@@ -0,0 +1,224 @@
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use hyper::server::{self, Server, Response};
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
+use rustc_serialize::hex::ToHex;
+use rustc_serialize::json::Json;
+use outbound::Outbound;
+use webhook::{reject, secret_matches};
+use super::{InboundConfig, InboundRoute, RelayState, post_to_mapping};
+
+const GITHUB_EVENT_HEADER: &'static str = "X-GitHub-Event";
+const GITHUB_SIGNATURE_HEADER: &'static str = "X-Hub-Signature-256";
+const GITLAB_TOKEN_HEADER: &'static str = "X-Gitlab-Token";
+const AUTHORIZATION_HEADER: &'static str = "Authorization";
+// The kinds of service notifications are accepted from
+const KINDS: &'static [&'static str] = &["github", "gitlab", "alertmanager"];
+// Larger requests are refused
+const MAX_BODY_BYTES: u64 = 1024 * 1024;
+
+// Check the routes before listening: each has a known kind, and a secret unless it is
+// explicitly open to anyone who can reach the port
+pub fn check(inbound: &InboundConfig) -> Result<(), String> {
+    for route in &inbound.routes {
+        if !KINDS.contains(&&route.kind[..]) {
+            return Err(format!("unknown kind \"{}\" of route {}, expected one of {}",
+                               route.kind,
+                               route.path,
+                               KINDS.join(", ")));
+        }
+        if route.secret.is_none() && !route.insecure.unwrap_or(false) {
+            return Err(format!("route {} has no secret, set insecure = true to accept requests from anyone",
+                               route.path));
+        }
+    }
+    Ok(())
+}
+
+fn header<'a>(req: &'a server::Request, name: &str) -> Option<&'a [u8]> {
+    match req.headers.get_raw(name) {
+        Some(values) if values.len() == 1 => Some(&values[0]),
+        _ => None,
+    }
+}
+
+fn string_at<'a>(json: &'a Json, path: &[&str]) -> &'a str {
+    json.find_path(path).and_then(|value| value.as_string()).unwrap_or("")
+}
+
+fn number_at(json: &Json, path: &[&str]) -> i64 {
+    json.find_path(path).and_then(|value| value.as_i64()).unwrap_or(0)
+}
+
+// Check that a request really comes from the service the route is for. Routes without a
+// secret are insecure ones, see `check`.
+fn authentic(route: &InboundRoute, req: &server::Request, body: &[u8]) -> bool {
+    let secret = match route.secret {
+        Some(ref secret) => secret,
+        None => return route.insecure.unwrap_or(false),
+    };
+    match &route.kind[..] {
+        // GitHub signs the body with the secret
+        "github" => {
+            let mut hmac = Hmac::new(Sha256::new(), secret.as_bytes());
+            hmac.input(body);
+            let expected = format!("sha256={}", hmac.result().code().to_hex());
+            header(req, GITHUB_SIGNATURE_HEADER).map_or(false, |given| secret_matches(given, expected.as_bytes()))
+        }
+        "gitlab" => header(req, GITLAB_TOKEN_HEADER).map_or(false, |given| secret_matches(given, secret.as_bytes())),
+        _ => {
+            let expected = format!("Bearer {}", secret);
+            header(req, AUTHORIZATION_HEADER).map_or(false, |given| secret_matches(given, expected.as_bytes()))
+        }
+    }
+}
+
+fn github(event: &str, payload: &Json) -> Option<String> {
+    let repo = string_at(payload, &["repository", "full_name"]);
+    match event {
+        "push" => {
+            let commits = payload.find("commits").and_then(|commits| commits.as_array()).map_or(0, |commits| commits.len());
+            if commits == 0 {
+                return None;
+            }
+            Some(format!("[{}] {} pushed {} commit{} to {}: {} {}",
+                         repo,
+                         string_at(payload, &["pusher", "name"]),
+                         commits,
+                         if commits == 1 { "" } else { "s" },
+                         string_at(payload, &["ref"]).trim_left_matches("refs/heads/"),
+                         string_at(payload, &["head_commit", "message"]).lines().next().unwrap_or(""),
+                         string_at(payload, &["compare"])))
+        }
+        "pull_request" => {
+            Some(format!("[{}] {} {} pull request #{}: {} {}",
+                         repo,
+                         string_at(payload, &["sender", "login"]),
+                         string_at(payload, &["action"]),
+                         number_at(payload, &["pull_request", "number"]),
+                         string_at(payload, &["pull_request", "title"]),
+                         string_at(payload, &["pull_request", "html_url"])))
+        }
+        // Sent when the webhook is set up
+        "ping" => None,
+        _ => Some(format!("[{}] {} event from {}", repo, event, string_at(payload, &["sender", "login"]))),
+    }
+}
+
+fn gitlab(payload: &Json) -> Option<String> {
+    let project = string_at(payload, &["project", "path_with_namespace"]);
+    match string_at(payload, &["object_kind"]) {
+        "push" => {
+            let commits = number_at(payload, &["total_commits_count"]);
+            if commits == 0 {
+                return None;
+            }
+            Some(format!("[{}] {} pushed {} commit{} to {}",
+                         project,
+                         string_at(payload, &["user_name"]),
+                         commits,
+                         if commits == 1 { "" } else { "s" },
+                         string_at(payload, &["ref"]).trim_left_matches("refs/heads/")))
+        }
+        "merge_request" => {
+            Some(format!("[{}] {} {} merge request !{}: {} {}",
+                         project,
+                         string_at(payload, &["user", "name"]),
+                         string_at(payload, &["object_attributes", "action"]),
+                         number_at(payload, &["object_attributes", "iid"]),
+                         string_at(payload, &["object_attributes", "title"]),
+                         string_at(payload, &["object_attributes", "url"])))
+        }
+        _ => None,
+    }
+}
+
+fn alertmanager(payload: &Json) -> Option<String> {
+    let alerts = match payload.find("alerts").and_then(|alerts| alerts.as_array()) {
+        Some(alerts) if !alerts.is_empty() => alerts,
+        _ => return None,
+    };
+    let lines: Vec<String> = alerts.iter()
+                                   .map(|alert| {
+                                       format!("[{}] {}: {}",
+                                               string_at(alert, &["status"]).to_uppercase(),
+                                               string_at(alert, &["labels", "alertname"]),
+                                               string_at(alert, &["annotations", "summary"]))
+                                   })
+                                   .collect();
+    Some(lines.join("\n"))
+}
+
+// Turn the payload of a request into the announcement to post, if it's worth one
+fn announcement(route: &InboundRoute, req: &server::Request, body: &str) -> Result<Option<String>, String> {
+    let payload = try!(Json::from_str(body).map_err(|err| err.to_string()));
+    match &route.kind[..] {
+        "github" => {
+            let event = header(req, GITHUB_EVENT_HEADER).map_or(String::new(), |event| String::from_utf8_lossy(event).into_owned());
+            Ok(github(&event, &payload))
+        }
+        "gitlab" => Ok(gitlab(&payload)),
+        "alertmanager" => Ok(alertmanager(&payload)),
+        kind => Err(format!("unknown kind of inbound webhook \"{}\"", kind)),
+    }
+}
+
+// Accept notifications from other services, such as GitHub, and post them to the
+// mapping of the route they arrive on
+pub fn serve(inbound: InboundConfig, outbound: Arc<Outbound>, state: Arc<Mutex<RelayState>>) {
+    let listen = inbound.listen.clone();
+    let handler = move |mut req: server::Request, res: Response| {
+        let path = match req.uri {
+            RequestUri::AbsolutePath(ref path) => path.split('?').next().unwrap_or("").to_owned(),
+            _ => String::new(),
+        };
+        let route = match inbound.routes.iter().find(|route| route.path == path) {
+            Some(route) => route,
+            None => return reject(res, StatusCode::NotFound),
+        };
+
+        let mut body = vec![];
+        if let Err(err) = req.by_ref().take(MAX_BODY_BYTES + 1).read_to_end(&mut body) {
+            println!("[ERROR] Could not read inbound webhook request: {}", err);
+            return reject(res, StatusCode::BadRequest);
+        }
+        if body.len() as u64 > MAX_BODY_BYTES {
+            println!("[WARN] Refused {} webhook request of over {} bytes from {}", route.kind, MAX_BODY_BYTES, req.remote_addr);
+            return reject(res, StatusCode::BadRequest);
+        }
+        if !authentic(route, &req, &body) {
+            println!("[WARN] Rejected {} webhook request without a valid secret from {}", route.kind, req.remote_addr);
+            return reject(res, StatusCode::Forbidden);
+        }
+        match announcement(route, &req, &String::from_utf8_lossy(&body)) {
+            Ok(Some(text)) => {
+                println!("[INFO] Posting {} notification to \"{}\"", route.kind, route.group);
+                post_to_mapping(&outbound, &state.lock().unwrap(), &route.group, text);
+            }
+            Ok(None) => {}
+            Err(err) => {
+                println!("[ERROR] Could not handle {} webhook request: {}", route.kind, err);
+                return reject(res, StatusCode::BadRequest);
+            }
+        }
+        let _ = res.send(b"");
+    };
+
+    let _listening = match Server::http(&listen[..]).and_then(|server| server.handle(handler)) {
+        Ok(listening) => listening,
+        Err(err) => {
+            println!("[ERROR] Could not listen for inbound webhooks on {}: {}", listen, err);
+            return;
+        }
+    };
+    println!("[INFO] Receiving inbound webhooks on {}", listen);
+    // The server runs on its own threads
+    loop {
+        thread::park();
+    }
+}
@@ -0,0 +1,49 @@
+use super::Config;
+use templates;
+
+// Strings the bridge adds to relayed messages itself
+pub const STICKER: &'static str = "sticker";
+pub const STICKER_WITH_EMOJI: &'static str = "sticker_with_emoji";
+pub const SENT_COMMAND: &'static str = "sent_command";
+pub const BOT_COMMAND: &'static str = "bot_command";
+pub const SENT_DOCUMENT: &'static str = "sent_document";
+pub const UPLOADING_MIRROR: &'static str = "uploading_mirror";
+pub const MIRRORED: &'static str = "mirrored";
+pub const MIRROR_FAILED: &'static str = "mirror_failed";
+pub const LONG_VIDEO: &'static str = "long_video";
+pub const CUSTOM_EMOJI: &'static str = "custom_emoji";
+pub const EDITED: &'static str = "edited";
+pub const EDITED_DIFF: &'static str = "edited_diff";
+pub const EDITED_MEANT: &'static str = "edited_meant";
+pub const DELETED: &'static str = "deleted";
+
+fn default(key: &str) -> &'static str {
+    match key {
+        STICKER => "(Sticker)",
+        STICKER_WITH_EMOJI => "(Sticker) {emoji}",
+        SENT_COMMAND => "(sent {command} to @{bot})",
+        BOT_COMMAND => "(bot command) {message}",
+        SENT_DOCUMENT => "sent document {name}: {url}",
+        UPLOADING_MIRROR => "(uploading mirror…)",
+        MIRRORED => "mirror: {url}",
+        MIRROR_FAILED => "(mirror failed)",
+        LONG_VIDEO => "(long video)",
+        CUSTOM_EMOJI => "[:emoji:]",
+        EDITED => "(edited) {message}",
+        EDITED_DIFF => "edited: {diff}",
+        EDITED_MEANT => "{nick} meant: {message}",
+        DELETED => "[message deleted]",
+        _ => "",
+    }
+}
+
+// The string `key` in the language of the Telegram group `group`, with its {placeholders}
+// filled in from `fields`. Strings missing from the configured locale are in English.
+pub fn text(config: &Config, group: &str, key: &str, fields: &[(&str, &str)]) -> String {
+    let text = config.locale
+                     .as_ref()
+                     .and_then(|locale| locale.get(templates::language(config, group)))
+                     .and_then(|strings| strings.get(key))
+                     .map_or(default(key), |text| &text[..]);
+    templates::fill(text, fields)
+}
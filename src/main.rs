@@ -3,32 +3,142 @@ extern crate telegram_bot;
 extern crate toml;
 extern crate hyper;
 extern crate rustc_serialize;
+extern crate rand;
+extern crate time;
+extern crate chan_signal;
+extern crate libc;
+extern crate net2;
+extern crate rusqlite;
+extern crate crypto;
+extern crate secp256k1;
+extern crate xml;
+
+mod error;
+mod queue;
+mod media;
+mod gallery;
+mod commands;
+mod outbound;
+mod dedup;
+mod watchdog;
+mod control;
+mod daemon;
+mod backoff;
+mod ctcp;
+mod webhook;
+mod prefixes;
+mod activity;
+mod hooks;
+mod store;
+mod secrets;
+mod templates;
+mod locale;
+mod relayed;
+mod edits;
+mod feeds;
+mod inbound;
+mod email;
+mod mastodon;
+mod slack;
+mod teamchat;
+mod capabilities;
+mod message;
+mod mediastore;
+mod urls;
+mod nostr;
+mod quiet;
+mod digest;
+mod chatlog;
+mod budget;
+mod typing;
+mod repeats;
+mod stale;
+mod members;
+mod admins;
+mod accounts;
+mod reminders;
+mod presence;
+mod permissions;
+mod corrections;
+mod sanitize;
+mod servers;
 
 use std::default::Default;
 use std::thread;
-use std::time::Duration;
-use std::fs::File;
-use std::io;
-use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+use std::fs::{self, File};
+use std::io::Read;
 use std::sync::{Arc, Mutex};
-use std::collections::hash_map::{HashMap, Entry};
-use std::path::{Path,PathBuf};
-use irc::client::prelude::{IrcServer, ServerExt};
+use std::collections::hash_map::HashMap;
+use std::path::{Path, PathBuf};
+use irc::client::prelude::ServerExt;
 use rustc_serialize::Decodable;
 use hyper::Url;
 use hyper::method::Method;
-use hyper::client::{Request};
-use telegram_bot::{Api, ListeningMethod, ListeningAction};
-use telegram_bot::types::{User, MessageType};
+use hyper::client::Request;
+use telegram_bot::Api;
+use chan_signal::Signal;
+use telegram_bot::types::{User, Message, MessageType, Update};
+use error::ResultExt;
+use media::{download_file_user, ensure_dir, expire_media};
+use outbound::{Outbound, IrcLine, spawn_outbound};
+use message::RelayMessage;
+use dedup::Seen;
+use store::StateStore;
+use watchdog::Watchdog;
+use backoff::Backoff;
+use servers::{CurrentServer, Servers};
 
 const CONFIG_FILE: &'static str = "config.toml";
+// Extra mappings, one or more bridge pairs per file, next to the config file
+const CONFIG_DIR: &'static str = "conf.d";
 const CHAT_IDS_FILE: &'static str = "chat_ids";
+// Names given to Telegram users with the alias command
+const ALIASES_FILE: &'static str = "aliases";
+// Last handled update and recently relayed messages
+const UPDATES_FILE: &'static str = "updates";
+// Telegram groups pinned to a chat id with the pin command
+const PINS_FILE: &'static str = "pins";
+// Titles shared by several Telegram groups, which aren't relayed until they are pinned
+const AMBIGUOUS_FILE: &'static str = "ambiguous";
+// Texts learned with the learn command
+const FACTOIDS_FILE: &'static str = "factoids";
+// Reminders still to be delivered
+const REMINDERS_FILE: &'static str = "reminders";
+// Bytes moved for mirroring media today and this month
+const MEDIA_USAGE_FILE: &'static str = "media_usage";
+// Messages still waiting to be delivered when we last stopped
+const QUEUED_FILE: &'static str = "queued";
+// Telegram messages recently relayed to IRC, which edits and deletions refer back to
+const RELAYED_FILE: &'static str = "relayed";
+// Seconds between saves of the relayed messages
+const RELAYED_SAVE_SECONDS: u64 = 60;
+// Seconds between saves of the outbound queues, unless configured
+const DEFAULT_QUEUE_SAVE_SECONDS: u64 = 60;
+// Seconds the outbound workers get on shutdown to deliver the messages they have taken
+const SHUTDOWN_GRACE_SECONDS: u64 = 10;
+// Seconds to wait for new updates in each long poll request
+const LONG_POLL_TIMEOUT: i64 = 30;
+// Names the handler threads report to the watchdog under
+const IRC_READER: &'static str = "irc reader";
+const TG_POLL: &'static str = "telegram poll";
+// Appended to Telegram users' nicks with nick_style = "irc"
+const DEFAULT_NICK_SUFFIX: &'static str = "|t";
+// Host part of the hostmasks made up for Telegram users
+const DEFAULT_HOSTMASK_HOST: &'static str = "telegram.bridge";
+// Seconds before another invite of the same person is answered, so invites can't be used
+// to flood the owners and get the bot disconnected for excess flood
+const INVITE_INTERVAL_SECONDS: u64 = 60;
+// The kinds of update we handle
+const DEFAULT_ALLOWED_UPDATES: &'static [&'static str] = &["message", "edited_message", "channel_post"];
+// How posts of Telegram channels are announced on IRC, unless configured
+const DEFAULT_ANNOUNCEMENT_TEMPLATE: &'static str = "[{channel}] {message}";
 
 type ChatID = telegram_bot::types::Integer;
 type IrcChannel = String;
 type TelegramGroup = String;
 
-#[derive(Clone, Default, Debug)]
+#[derive(Clone)]
 struct RelayState {
     // Map from IRC channel to Telegram group
     tg_group: HashMap<IrcChannel, TelegramGroup>,
@@ -36,17 +146,488 @@ struct RelayState {
     irc_channel: HashMap<TelegramGroup, IrcChannel>,
     // Map from Telegram group name to chat_id
     chat_ids: HashMap<TelegramGroup, ChatID>,
+    // Our own Telegram username
+    username: String,
+    // Recent activity in the mapped channels
+    activity: activity::Activity,
+    // Telegram messages recently relayed to IRC
+    relayed: relayed::Relayed,
+    // Names Telegram users are relayed under instead of their own, by user id
+    aliases: HashMap<telegram_bot::types::Integer, String>,
+    // Texts learned in each mapping with the learn command
+    factoids: store::Factoids,
+    // Reminders asked for with the remind command, in no particular order
+    reminders: Vec<store::Reminder>,
+    // Telegram groups only relayed from and to a particular chat, from the config and the
+    // pin command
+    pinned: HashMap<TelegramGroup, ChatID>,
+    // Titles shared by several unpinned groups, with their chat ids. Nothing is relayed
+    // for them until they are pinned.
+    ambiguous: HashMap<TelegramGroup, Vec<ChatID>>,
+    // Where chat_ids and the like are persisted
+    store: Arc<StateStore>,
+    // Traffic allowed for mirroring media
+    media_budget: Arc<Budget>,
+    // Recently relayed texts, to hold back repeats
+    repeats: repeats::Repeats,
+    // Number of users in each mapped IRC channel
+    irc_users: HashMap<IrcChannel, usize>,
+    // Administrators of each Telegram group, as last looked up
+    group_admins: HashMap<TelegramGroup, Vec<telegram_bot::types::Integer>>,
+    // Number of members of each Telegram group, as last counted
+    tg_members: HashMap<TelegramGroup, u64>,
+    // What was recently relayed from IRC to Telegram, for corrections to be made to it
+    corrections: corrections::Corrections,
 }
 
 #[derive(Clone, Default, RustcDecodable, Debug)]
 struct Config {
     pub irc: irc::client::data::Config,
+    // May reference the secrets file as "secret:<name>", as may the IRC password
     pub token: String,
     pub maps: HashMap<TelegramGroup, IrcChannel>,
+    // Chat ids of Telegram groups whose title is shared by other groups the bot is in.
+    // Pins made with the pin command take precedence.
+    pub pins: Option<HashMap<TelegramGroup, ChatID>>,
+    // What to do in Telegram groups that aren't in maps: "record" (the default) remembers
+    // their chat id like any other, "ignore" never stores or logs it and "leave" makes the
+    // bot leave them. Groups in allowed_groups are treated as mapped.
+    pub unmapped_groups: Option<String>,
+    pub allowed_groups: Option<Vec<TelegramGroup>>,
+    // Join the configured IRC channels we are invited to, after being kicked or when they
+    // are invite only. Invites to other channels are declined and the IRC owners told
+    // about them either way.
+    pub irc_join_invites: Option<bool>,
+    // Telegram user ids allowed to run admin commands. On IRC, the owners in the irc
+    // section are used instead.
+    pub admins: Option<Vec<i64>>,
+    // Also let the administrators of a mapped Telegram group run the admin commands that
+    // only concern its own mapping, looked up every group_admin_refresh_minutes (60 by
+    // default)
+    pub group_admins: Option<bool>,
+    pub group_admin_refresh_minutes: Option<u64>,
     pub debug: Option<bool>,
     pub relay_media: Option<bool>,
-    pub base_url: Option<Url>,
+    // URL the download directory is served at, checked at startup
+    pub base_url: Option<String>,
     pub download_dir: Option<String>,
+    // Timeouts for mirroring media, in seconds
+    pub download_connect_timeout: Option<u64>,
+    pub download_read_timeout: Option<u64>,
+    pub download_timeout: Option<u64>,
+    // Number of times an interrupted download is resumed before giving up
+    pub download_retries: Option<u32>,
+    // Prefix mirrored filenames with an unguessable token
+    pub media_url_tokens: Option<bool>,
+    // Delete mirrored media after this many hours
+    pub media_expiry_hours: Option<u64>,
+    // Maintain HTML indexes of mirrored media per user and per group. Note that these
+    // list the tokenized filenames, so only enable this where the indexes are protected.
+    pub media_index: Option<bool>,
+    // Include the original (sanitized) filename of documents in their mirrored filename
+    pub media_keep_filenames: Option<bool>,
+    // Attachments larger than this many bytes aren't mirrored, whichever side they come from
+    pub max_attachment_size: Option<u64>,
+    // Bytes that may be downloaded (and uploaded to a remote media_store) for mirroring per
+    // UTC day and month. Past that, media is relayed as text and the admins are told.
+    pub media_daily_bytes: Option<u64>,
+    pub media_monthly_bytes: Option<u64>,
+    // Where mirrored media is hosted: "local" (the default) keeps it in download_dir, to be
+    // served at base_url, "s3" uploads it to the bucket of the s3 section and "imgur" to
+    // imgur with the client id of the imgur section
+    pub media_store: Option<String>,
+    pub s3: Option<S3Config>,
+    pub imgur: Option<ImgurConfig>,
+    // Videos longer than this many seconds are flagged as "(long video)", or its
+    // translation in the locale
+    pub long_video_seconds: Option<i64>,
+    // Documents, videos and audio larger than this many bytes are relayed right away saying
+    // a mirror is on its way, and its URL follows once downloaded
+    pub defer_media_bytes: Option<i64>,
+    pub queue: Option<QueueConfig>,
+    // Keep the messages waiting in the outbound queues across restarts (the default), saving
+    // them on shutdown and every queue_save_seconds
+    pub persist_queues: Option<bool>,
+    pub queue_save_seconds: Option<u64>,
+    // Coalesce consecutive Telegram messages from the same sender sent within this many
+    // seconds of each other into a single IRC line
+    pub irc_batch_seconds: Option<i64>,
+    // Restart when the IRC reader, the Telegram long poll or an outbound worker shows no
+    // activity for this many seconds. 0 disables the watchdog.
+    pub watchdog_seconds: Option<u64>,
+    // Path of a unix socket accepting admin commands, one per line
+    pub control_socket: Option<String>,
+    // Directory the relayed conversation is logged to, one file per group and day, for
+    // the catchup command
+    pub chat_log_dir: Option<String>,
+    // File that stdout and stderr are sent to when running with --daemon
+    pub log_file: Option<String>,
+    pub reconnect: Option<ReconnectConfig>,
+    // Servers of the IRC network to fall back to, in order, if the one in the irc section
+    // can't be reached or the connection to it is lost. Given as "host" or "host:port".
+    pub irc_fallback_servers: Option<Vec<String>>,
+    // Local address (IPv4 or IPv6) to connect to IRC from, to pick the address, and with it
+    // the reverse DNS or vhost, the network sees on multi-homed hosts
+    pub irc_bind_address: Option<String>,
+    // Answer to CTCP VERSION queries
+    pub ctcp_version: Option<String>,
+    // Whether to relay messages sent only to the ops (or voiced users, ...) of a channel,
+    // such as "@#channel". They are marked with their audience, e.g. "(ops)".
+    pub relay_statusmsg: Option<bool>,
+    // Kinds of Telegram updates to receive, as named by the Bot API
+    pub allowed_updates: Option<Vec<String>>,
+    // Receive updates through a webhook instead of polling for them
+    pub webhook: Option<WebhookConfig>,
+    // Log a warning when relaying a message takes longer than this many seconds
+    pub latency_warning_seconds: Option<u64>,
+    // Prefix of the admin commands on IRC, "!" by default
+    pub irc_command_prefix: Option<String>,
+    // Commands each role ("user", "moderator", "admin" or "owner") may run, "*" for all,
+    // replacing the default set of the role. Admins and IRC owners are owners, everyone
+    // else is a user unless given another role in telegram_roles (by user id) or
+    // irc_roles (by services account with irc_owner_accounts, by nick otherwise).
+    pub role_commands: Option<HashMap<String, Vec<String>>>,
+    // Commands answering with a fixed text on both sides of a mapping, like "rules", by
+    // Telegram group and command name. {nick} and {network} in the text are replaced with
+    // whoever asked and where.
+    pub custom_commands: Option<HashMap<TelegramGroup, HashMap<String, String>>>,
+    pub telegram_roles: Option<HashMap<String, String>>,
+    pub irc_roles: Option<HashMap<String, String>>,
+    // Services accounts allowed to run admin commands on IRC. When given, the nicks in
+    // owners no longer count, so no one can run them by taking an owner's nick.
+    pub irc_owner_accounts: Option<Vec<String>>,
+    // What to do with messages starting with certain prefixes, usually commands for other
+    // bots. The first matching rule applies.
+    pub prefix_rules: Option<Vec<PrefixRule>>,
+    // Relay Telegram commands addressed to other bots (/stats@otherbot) as a short summary
+    // instead of dropping them
+    pub summarize_bot_commands: Option<bool>,
+    // Post a summary of the previous day's IRC activity to every group each day at this
+    // hour (UTC)
+    pub daily_digest_hour: Option<i64>,
+    // Local commands to run on events
+    pub hooks: Option<Vec<HookConfig>>,
+    // Keep chat ids and handled updates in this SQLite database instead of plain files
+    pub state_db: Option<String>,
+    // Encrypted file holding secrets referenced from the config, see --encrypt-secrets
+    pub secrets_file: Option<String>,
+    // File the key for the secrets file is derived from. Without it, a passphrase is asked for.
+    pub secrets_key_file: Option<String>,
+    // Relay IRC joins and parts to Telegram
+    pub relay_joins: Option<bool>,
+    // Language of every mapping, unless set for the mapping in `languages`
+    pub language: Option<String>,
+    pub languages: Option<HashMap<TelegramGroup, String>>,
+    // How messages and events relayed to Telegram are phrased, per language
+    pub templates: Option<HashMap<String, TemplateConfig>>,
+    // The bridge's own strings, such as "(Sticker)", per language. See locale.rs for the
+    // names of the strings.
+    pub locale: Option<HashMap<String, HashMap<String, String>>>,
+    // How edits of relayed Telegram messages are relayed: "off" (the default), "full" to
+    // relay the edited message again, "diff" to relay only what changed or "meant" to relay
+    // edits made within edit_meant_seconds (120 by default) as "nick meant: message"
+    pub relay_edits: Option<String>,
+    pub edit_meant_seconds: Option<i64>,
+    // The same, per Telegram group
+    pub edit_modes: Option<HashMap<TelegramGroup, String>>,
+    // Relay IRC to these Telegram groups in one post every so many minutes, with messages
+    // grouped by sender, instead of message by message. For busy channels.
+    pub digest_minutes: Option<HashMap<TelegramGroup, u64>>,
+    // Hours during which messages aren't relayed to a Telegram group, by group
+    pub quiet_hours: Option<HashMap<TelegramGroup, QuietHoursConfig>>,
+    // Show IRC users typing (IRCv3 +typing tags) as the bot typing in the Telegram group.
+    // Off by default, for maximal quietness. Telegram doesn't tell bots when people type,
+    // so nothing is ever sent the other way.
+    pub relay_typing: Option<bool>,
+    // Relay a message a sender repeats right away only once, followed by "message ×N"
+    // once something else is said
+    pub collapse_repeats: Option<bool>,
+    // Relay the same text from a network at most duplicate_limit (3 by default) times
+    // within this many seconds, whoever sends it
+    pub duplicate_window_seconds: Option<u64>,
+    pub duplicate_limit: Option<usize>,
+    // Messages sent more than this many minutes before they would be relayed, such as
+    // Telegram updates replayed after downtime or a bouncer's playback, are "drop"ped (the
+    // default) or relayed with a "mark" saying when they were sent, per stale_messages
+    pub max_message_age_minutes: Option<u64>,
+    pub stale_messages: Option<String>,
+    // Make corrections like "s/teh/the/" from IRC users to their last message by editing it
+    // on Telegram, instead of relaying them as they are
+    pub irc_corrections: Option<bool>,
+    // Post how many people each side of a mapping has, like "IRC: 24 users; Telegram: 87
+    // members", to both sides every so many hours. The members command does it on demand.
+    pub member_summary_hours: Option<u64>,
+    // Keep a line like "IRC link: connected ✓, last message 2m ago" in the description of
+    // each mapped Telegram group, updated when the IRC link goes down or comes back and
+    // every status_refresh_minutes (10 by default). The bot has to be allowed to change
+    // the group info.
+    pub status_in_description: Option<bool>,
+    pub status_refresh_minutes: Option<u64>,
+    // Tell IRC when a relayed Telegram message is deleted through the bridge
+    pub relay_deletes: Option<bool>,
+    // How Telegram users are named on IRC: "name" (the default) for their full name, or
+    // "irc" for a nick made from their username, ending in nick_suffix ("|t" by default)
+    pub nick_style: Option<String>,
+    pub nick_suffix: Option<String>,
+    // Replace angle brackets in the text of messages relayed to IRC, not only in the names
+    // of their senders, so nobody can make a line look like it comes from someone else
+    pub escape_brackets: Option<bool>,
+    // Characters overriding the direction of relayed text, a way to disguise what it says,
+    // are left out with "strip" (the default), shown as "[RLO]" and the like with "flag", or
+    // relayed as they are with "keep". Invisible characters are always left out of the
+    // names of senders.
+    pub direction_controls: Option<String>,
+    // Tag messages relayed from Telegram with a hostmask for the sender, such as
+    // "alice!12345@telegram.bridge", for bans to match. Needs a server supporting client
+    // message tags.
+    pub relay_hostmasks: Option<bool>,
+    pub hostmask_host: Option<String>,
+    // Telegram channels mirrored one way into IRC channels
+    pub announcements: Option<Vec<AnnouncementConfig>>,
+    // RSS and Atom feeds whose new items are posted to mappings
+    pub feeds: Option<Vec<FeedConfig>>,
+    // Notifications from GitHub and the like, posted to mappings
+    pub inbound: Option<InboundConfig>,
+    // Mail gateway posting summaries of mails to mappings
+    pub email: Option<EmailConfig>,
+    // Mastodon account whose posts are mirrored to mappings
+    pub mastodon: Option<MastodonConfig>,
+    // Slack channels taking part in mappings
+    pub slack: Option<SlackConfig>,
+    // Mattermost or Rocket.Chat channels taking part in mappings through webhooks
+    pub teamchat: Option<TeamChatConfig>,
+    // Nostr public channel whose messages are relayed to mappings (experimental)
+    pub nostr: Option<NostrConfig>,
+}
+
+#[derive(Clone, Default, RustcDecodable, Debug)]
+struct QueueConfig {
+    pub capacity: Option<usize>,
+    // One of "drop-oldest", "drop-newest" or "summarize"
+    pub overflow: Option<String>,
+}
+
+#[derive(Clone, Default, RustcDecodable, Debug)]
+struct WebhookConfig {
+    // Address to accept updates on, e.g. "0.0.0.0:8443"
+    pub listen: String,
+    // Public URL Telegram delivers updates to, which must end up at `listen`
+    pub url: String,
+    // Secret Telegram sends along with every update, to tell its requests from forgeries.
+    // Required unless insecure is set, to accept updates from anyone who finds the webhook.
+    pub secret_token: Option<String>,
+    pub insecure: Option<bool>,
+    // Only accept updates from Telegram's published address ranges. Telegram only delivers
+    // to HTTPS URLs while `listen` speaks plain HTTP, so requests always come through a TLS
+    // terminating proxy, which needs to be named in `trusted_proxy`.
+    pub telegram_ips_only: Option<bool>,
+    // Address of the reverse proxy in front of `listen`, e.g. "127.0.0.1". Requests then
+    // have to come from it, and the sender is the last address of the X-Forwarded-For
+    // header it adds.
+    pub trusted_proxy: Option<String>,
+    // Public key certificate (PEM) to upload when the webhook is served with a self-signed
+    // certificate
+    pub certificate: Option<String>,
+}
+
+// Daily hours, in the local time of the host, during which a Telegram group isn't disturbed
+#[derive(Clone, Default, RustcDecodable, Debug)]
+struct QuietHoursConfig {
+    // As "HH:MM". The end may be before the start to span midnight, e.g. "23:00" to "07:00".
+    pub start: String,
+    pub end: String,
+    // What happens to messages in the meantime: "hold" (the default) delivers them one by
+    // one when quiet hours end, "merge" combines them into as few posts as possible
+    pub mode: Option<String>,
+}
+
+// A file in the conf.d directory, only holding more mappings
+#[derive(Clone, Default, RustcDecodable, Debug)]
+struct ConfigFragment {
+    pub maps: HashMap<TelegramGroup, IrcChannel>,
+}
+
+#[derive(Clone, Default, RustcDecodable, Debug)]
+struct PrefixRule {
+    pub prefix: String,
+    // One of "drop", "annotate" or "relay"
+    pub action: String,
+    // One of "irc→tg", "tg→irc" or "both" (the default)
+    pub direction: Option<String>,
+}
+
+// A Telegram channel whose posts are announced in IRC channels
+#[derive(Clone, Default, RustcDecodable, Debug)]
+struct AnnouncementConfig {
+    // Title of the Telegram channel
+    pub channel: String,
+    pub irc_channels: Vec<IrcChannel>,
+    // May use {channel} and {message}
+    pub template: Option<String>,
+    // Least number of seconds between two announcements
+    pub min_interval: Option<u64>,
+}
+
+#[derive(Clone, Default, RustcDecodable, Debug)]
+struct TeamChatConfig {
+    // Address to accept outgoing webhook requests on, e.g. "0.0.0.0:3001"
+    pub listen: String,
+    // Name the incoming webhooks post under, so our own posts aren't relayed back
+    pub username: Option<String>,
+    pub links: Vec<TeamChatLink>,
+}
+
+// A Mattermost or Rocket.Chat channel joining a mapping
+#[derive(Clone, Default, RustcDecodable, Debug)]
+struct TeamChatLink {
+    // Telegram group of the mapping
+    pub group: TelegramGroup,
+    // Token of the channel's outgoing webhook, which sends us its messages
+    pub token: String,
+    // The channel's incoming webhook, which we post the mapping's messages to
+    pub incoming_url: String,
+}
+
+#[derive(Clone, Default, RustcDecodable, Debug)]
+struct SlackConfig {
+    // Address to accept Events API requests on, e.g. "0.0.0.0:3000"
+    pub listen: String,
+    // From the app's settings, to tell Slack's requests from forgeries
+    pub signing_secret: String,
+    // Bot token, "xoxb-...", needing the chat:write and users:read scopes
+    pub bot_token: String,
+    // Map from Telegram group to the id of the Slack channel joining its mapping
+    pub channels: HashMap<TelegramGroup, String>,
+}
+
+#[derive(Clone, Default, RustcDecodable, Debug)]
+struct S3Config {
+    pub bucket: String,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+    // Host of an S3 compatible service to use instead of AWS, e.g. "s3.example.com". The
+    // bucket is addressed as a subdomain of it.
+    pub endpoint: Option<String>,
+    // URL the bucket is publicly reachable at, such as a CDN, if not its S3 URL
+    pub public_url: Option<String>,
+}
+
+#[derive(Clone, Default, RustcDecodable, Debug)]
+struct ImgurConfig {
+    // Client id of a registered imgur application
+    pub client_id: String,
+}
+
+#[derive(Clone, Default, RustcDecodable, Debug)]
+struct NostrConfig {
+    // Websocket URL of the relay, e.g. "ws://127.0.0.1:7777"
+    pub relay: String,
+    // Event id (hex) of the NIP-28 channel to follow
+    pub channel_id: String,
+    // Telegram groups of the mappings the channel's messages are posted to
+    pub groups: Vec<TelegramGroup>,
+    // Seconds to wait before reconnecting after the relay drops us
+    pub reconnect: Option<u64>,
+}
+
+#[derive(Clone, Default, RustcDecodable, Debug)]
+struct MastodonConfig {
+    // Base URL of the instance, e.g. "https://mastodon.social"
+    pub instance: String,
+    // Numeric id of the account
+    pub account_id: String,
+    // Shown with each post, like the name of a feed
+    pub name: String,
+    // Telegram groups of the mappings posts are mirrored to
+    pub groups: Vec<TelegramGroup>,
+    // Seconds between polls
+    pub interval: Option<u64>,
+    // Mirror attachments to the download directory instead of linking to the instance
+    pub mirror_media: Option<bool>,
+    // Post the announcements of Telegram channels to the account as well, which needs an
+    // access token allowed to post
+    pub post_announcements: Option<bool>,
+    pub access_token: Option<String>,
+}
+
+#[derive(Clone, Default, RustcDecodable, Debug)]
+struct EmailConfig {
+    // Address to accept mail on over SMTP, e.g. "127.0.0.1:2525"
+    pub listen: String,
+    // Map from mail address to the Telegram group of the mapping its mail is posted to
+    pub addresses: HashMap<String, TelegramGroup>,
+    // Only accept mail from these senders
+    pub allowed_senders: Option<Vec<String>>,
+    // Lines of the body included in the summary
+    pub summary_lines: Option<usize>,
+}
+
+#[derive(Clone, Default, RustcDecodable, Debug)]
+struct InboundConfig {
+    // Address to accept notifications on, e.g. "0.0.0.0:8080"
+    pub listen: String,
+    pub routes: Vec<InboundRoute>,
+}
+
+#[derive(Clone, Default, RustcDecodable, Debug)]
+struct InboundRoute {
+    // Path the service posts to, e.g. "/github"
+    pub path: String,
+    // One of "github", "gitlab" or "alertmanager"
+    pub kind: String,
+    // Telegram group of the mapping notifications are posted to
+    pub group: TelegramGroup,
+    // The webhook secret for GitHub, the secret token for GitLab, and a bearer token for
+    // Alertmanager. Required unless insecure is set, to accept requests from anyone.
+    pub secret: Option<String>,
+    pub insecure: Option<bool>,
+}
+
+#[derive(Clone, Default, RustcDecodable, Debug)]
+struct FeedConfig {
+    // Shown with each item
+    pub name: String,
+    pub url: String,
+    // Telegram groups of the mappings new items are posted to
+    pub groups: Vec<TelegramGroup>,
+    // Seconds between polls
+    pub interval: Option<u64>,
+}
+
+// Templates may use {nick}, {host}, {channel} and {message}, parts also {reason}. Feed
+// items use {feed}, {title} and {link} instead, and mails {from}, {subject} and {summary}.
+#[derive(Clone, Default, RustcDecodable, Debug)]
+struct TemplateConfig {
+    pub message: Option<String>,
+    pub action: Option<String>,
+    pub join: Option<String>,
+    pub part: Option<String>,
+    pub feed: Option<String>,
+    pub email: Option<String>,
+}
+
+#[derive(Clone, Default, RustcDecodable, Debug)]
+struct HookConfig {
+    // One of "message_relayed", "bridge_down", "bridge_up", "user_joined" or
+    // "media_budget_exceeded"
+    pub event: String,
+    // Run with `sh -c`, gets the event as a JSON object on stdin
+    pub command: String,
+}
+
+// How the handlers back off after IRC or Telegram errors
+#[derive(Clone, Default, RustcDecodable, Debug)]
+struct ReconnectConfig {
+    // Delays in seconds
+    pub initial_delay: Option<f64>,
+    pub max_delay: Option<f64>,
+    pub multiplier: Option<f64>,
+    // Fraction of the delay to randomly vary it by
+    pub jitter: Option<f64>,
+    // Consecutive failures after which the process exits nonzero so a supervisor can restart
+    // it. Unset or 0 retries forever.
+    pub max_attempts: Option<u32>,
 }
 
 fn format_tg_nick(user: &User) -> String {
@@ -58,6 +639,138 @@ fn format_tg_nick(user: &User) -> String {
     }
 }
 
+fn is_nick_char(c: char) -> bool {
+    (c as u32) < 128 && (c.is_alphanumeric() || "[]\\`_^{|}-".contains(c))
+}
+
+// The name a Telegram user is relayed to IRC under: their alias if they have one, else
+// their full name. With nick_style = "irc" it's an IRC-like nick made from their alias,
+// username or first name, plus nick_suffix, e.g. "alice|t", so each user has one nick
+// that's easy to address on IRC.
+fn tg_nick(config: &Config, state: &RelayState, user: &User) -> String {
+    let alias = state.aliases.get(&user.id);
+    if config.nick_style.as_ref().map_or(true, |style| style != "irc") {
+        return alias.cloned().unwrap_or_else(|| format_tg_nick(user));
+    }
+    let name = alias.or(user.username.as_ref()).unwrap_or(&user.first_name);
+    let mut nick: String = name.chars().filter(|&c| is_nick_char(c)).collect();
+    // Nicks can't be empty or start with a digit or a dash
+    if nick.chars().next().map_or(true, |c| c.is_digit(10) || c == '-') {
+        nick = format!("tg{}{}", if nick.is_empty() { user.id.to_string() } else { String::new() }, nick);
+    }
+    nick.push_str(config.nick_suffix.as_ref().map_or(DEFAULT_NICK_SUFFIX, |suffix| &suffix[..]));
+    nick
+}
+
+// A made up hostmask for a Telegram user, like "alice!12345@telegram.bridge", if relayed
+// messages are tagged with one. Channel bans can then match individual Telegram users.
+fn tg_hostmask(config: &Config, nick: &str, user: &User) -> Option<String> {
+    if !config.relay_hostmasks.unwrap_or(false) {
+        return None;
+    }
+    let nick: String = nick.chars().filter(|&c| is_nick_char(c)).collect();
+    let host = config.hostmask_host.as_ref().map_or(DEFAULT_HOSTMASK_HOST, |host| &host[..]);
+    Some(format!("{}!{}@{}", nick, user.id, host))
+}
+
+fn is_private_use(c: char) -> bool {
+    match c as u32 {
+        0xE000...0xF8FF | 0xF0000...0x10FFFF => true,
+        _ => false,
+    }
+}
+
+// Telegram puts the fallback emoji of a custom emoji in the text, but the fallback of some
+// custom emoji is a private use character, which IRC clients show as an empty box.
+// Replace each run of them with a placeholder.
+fn replace_custom_emoji(config: &Config, group: &str, text: &str) -> String {
+    if !text.chars().any(is_private_use) {
+        return text.to_owned();
+    }
+    let placeholder = locale::text(config, group, locale::CUSTOM_EMOJI, &[]);
+    let mut replaced = String::with_capacity(text.len());
+    let mut in_emoji = false;
+    for c in text.chars() {
+        if is_private_use(c) {
+            if !in_emoji {
+                replaced.push_str(&placeholder);
+            }
+            in_emoji = true;
+        } else {
+            replaced.push(c);
+            in_emoji = false;
+        }
+    }
+    replaced
+}
+
+fn is_tg_admin(config: &Config, user: &User) -> bool {
+    config.admins.as_ref().map_or(false, |admins| admins.contains(&user.id))
+}
+
+fn is_irc_admin(config: &Config, nick: &str, account: Option<&str>) -> bool {
+    match config.irc_owner_accounts {
+        Some(ref accounts) => account.map_or(false, |account| accounts.iter().any(|owner| owner == account)),
+        None => config.irc.owners.as_ref().map_or(false, |owners| owners.iter().any(|owner| owner == nick)),
+    }
+}
+
+// Answer an invite of `inviter` to `channel`: join it if it is one of ours and invites are
+// followed, otherwise decline, letting the IRC owners know either way. Invites are ignored
+// while the inviter's last one is more recent than `INVITE_INTERVAL_SECONDS`, as kept in
+// `invited`.
+fn handle_invite<T: ServerExt>(irc: &T,
+                               config: &Config,
+                               invited: &mut HashMap<String, Instant>,
+                               inviter: &str,
+                               channel: &str) {
+    let interval = Duration::from_secs(INVITE_INTERVAL_SECONDS);
+    let expired: Vec<String> = invited.iter()
+                                      .filter(|&(_, at)| at.elapsed() >= interval)
+                                      .map(|(nick, _)| nick.clone())
+                                      .collect();
+    for nick in expired {
+        invited.remove(&nick);
+    }
+    if invited.contains_key(inviter) {
+        println!("[INFO] Ignoring another invite of {} to \"{}\"", inviter, channel);
+        return;
+    }
+    invited.insert(inviter.to_owned(), Instant::now());
+    let configured = config.irc.channels.as_ref().map_or(false, |channels| channels.iter().any(|c| c == channel));
+    let notice = if configured && config.irc_join_invites.unwrap_or(false) {
+        println!("[INFO] Joining \"{}\" on the invite of {}", channel, inviter);
+        let key = config.irc.channel_keys.as_ref().and_then(|keys| keys.get(channel)).cloned();
+        let joined = irc.send(irc::client::data::Message {
+            tags: None,
+            prefix: None,
+            command: irc::client::data::Command::JOIN(channel.to_owned(), key, None),
+        });
+        if let Err(err) = joined {
+            println!("[ERROR] Could not join \"{}\": {}", channel, err);
+        }
+        format!("{} invited me to {}, joining", inviter, channel)
+    } else {
+        let reason = if configured {
+            "joining on invite is disabled"
+        } else {
+            "it isn't a configured channel"
+        };
+        println!("[WARN] Declining the invite of {} to \"{}\": {}", inviter, channel, reason);
+        if let Err(err) = irc.send_notice(inviter, &format!("Not joining {}: {}", channel, reason)) {
+            println!("[ERROR] Could not answer the invite of \"{}\": {}", inviter, err);
+        }
+        format!("{} invited me to {}, declined: {}", inviter, channel, reason)
+    };
+    for owner in config.irc.owners.iter().flat_map(|owners| owners) {
+        if owner != inviter {
+            if let Err(err) = irc.send_notice(owner, &notice) {
+                println!("[ERROR] Could not tell \"{}\" about an invite: {}", owner, err);
+            }
+        }
+    }
+}
+
 fn load_toml<T: Default + Decodable>(path: &str) -> T {
     let mut config_toml = String::new();
     let mut file = match File::open(&path) {
@@ -94,12 +807,106 @@ fn load_toml<T: Default + Decodable>(path: &str) -> T {
 
 fn load_config(path: &str) -> Config {
     let mut config: Config = load_toml(path);
-    config.irc.channels = Some(config.maps.values().map(|v| v.clone()).collect());
+    merge_config_dir(&mut config, path);
+    // Mapped channels may be given with their key, as "#channel key"
+    let mut keys = config.irc.channel_keys.clone().unwrap_or(HashMap::new());
+    for channel in config.maps.values_mut() {
+        let parsed = {
+            let mut parts = channel.splitn(2, ' ');
+            (parts.next().unwrap_or("").to_owned(), parts.next().map(|key| key.trim().to_owned()))
+        };
+        if let (name, Some(key)) = parsed {
+            keys.insert(name.clone(), key);
+            *channel = name;
+        }
+    }
+    if !keys.is_empty() {
+        config.irc.channel_keys = Some(keys);
+    }
+    let mut channels: Vec<IrcChannel> = config.maps.values().map(|v| v.clone()).collect();
+    // Channels only receiving announcements have to be joined as well
+    for announcement in config.announcements.iter().flat_map(|announcements| announcements) {
+        for channel in &announcement.irc_channels {
+            if !channels.contains(channel) {
+                channels.push(channel.clone());
+            }
+        }
+    }
+    config.irc.channels = Some(channels);
     config
 }
 
-fn load_chat_ids(path: &str) -> HashMap<TelegramGroup, ChatID> {
-    let mapping = load_toml(path);
+// Merge the mappings of each conf.d/*.toml, in name order, into the config
+fn merge_config_dir(config: &mut Config, path: &str) {
+    let dir = Path::new(path).parent().unwrap_or(Path::new("")).join(CONFIG_DIR);
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    let mut files: Vec<PathBuf> = entries.filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|file| file.extension().map_or(false, |ext| ext == "toml"))
+        .collect();
+    files.sort();
+    for file in files {
+        let file = file.to_string_lossy().into_owned();
+        let fragment: ConfigFragment = load_toml(&file);
+        for (group, channel) in fragment.maps {
+            match config.maps.get(&group) {
+                Some(existing) if *existing != channel => {
+                    println!("[WARN] {}: \"{}\" is already mapped to {}, ignoring {}",
+                             file,
+                             group,
+                             existing,
+                             channel);
+                    continue;
+                }
+                _ => {}
+            }
+            config.maps.insert(group, channel);
+        }
+    }
+}
+
+// The host part of the "nick!user@host" a message came from
+fn source_host(msg: &irc::client::data::Message) -> &str {
+    msg.prefix
+       .as_ref()
+       .and_then(|prefix| prefix.splitn(2, '!').nth(1))
+       .unwrap_or("")
+}
+
+// Split the STATUSMSG prefix off a message target like "@#channel", used for messages
+// only sent to the ops (or voiced users, ...) of the channel
+fn split_statusmsg(target: &str) -> (&str, Option<&'static str>) {
+    let audience = match target.chars().next() {
+        Some('~') => "owners",
+        Some('@') => "ops",
+        Some('%') => "halfops",
+        Some('+') => "voiced",
+        _ => return (target, None),
+    };
+    let channel = &target[1..];
+    if channel.starts_with('#') || channel.starts_with('&') {
+        (channel, Some(audience))
+    } else {
+        (target, None)
+    }
+}
+
+fn load_chat_ids(config: &Config, store: &StateStore) -> HashMap<TelegramGroup, ChatID> {
+    let mut mapping = store.load_chat_ids().unwrap_or_else(|err| panic!("error loading chat ids: {}", err));
+    // Forget the groups recorded before unmapped groups were ignored
+    if unmapped_groups(config) != UnmappedGroups::Record {
+        let before = mapping.len();
+        mapping = mapping.into_iter().filter(|&(ref group, _)| is_allowed_group(config, group)).collect();
+        if mapping.len() != before {
+            println!("[INFO] Forgetting the chat ids of {} unmapped Telegram groups", before - mapping.len());
+            if let Err(err) = store.save_chat_ids(&mapping) {
+                println!("[ERROR] Could not save chat ids: {}", err);
+            }
+        }
+    }
     for (group, chat_id) in &mapping {
         println!("[INFO] Loaded Telegram group \"{}\" with id {}",
                  group,
@@ -108,57 +915,389 @@ fn load_chat_ids(path: &str) -> HashMap<TelegramGroup, ChatID> {
     mapping
 }
 
-fn download_file(url: &Url, destination: &Path, baseurl: &Url) -> io::Result<Url> {
-    // Create a request to download the file
-    let req = Request::new(Method::Get, url.clone()).unwrap();
-    let mut resp = req.start().unwrap().send().unwrap();
+fn save_relayed(state: &Mutex<RelayState>) {
+    let (store, messages) = {
+        let state = state.lock().unwrap();
+        (state.store.clone(), state.relayed.entries())
+    };
+    if let Err(err) = store.save_relayed(&messages) {
+        println!("[ERROR] Could not save relayed messages: {}", err);
+    }
+}
+
+fn save_queued(store: &StateStore, outbound: &Outbound) {
+    if let Err(err) = store.save_queued(&outbound.queued()) {
+        println!("[ERROR] Could not save queued messages: {}", err);
+    }
+}
+
+// The pins of the config, overridden by those made with the pin command. Where a pin
+// disagrees with the chat id recorded for the group, the pin wins.
+fn load_pins(config: &Config,
+             store: &StateStore,
+             chat_ids: &mut HashMap<TelegramGroup, ChatID>)
+             -> HashMap<TelegramGroup, ChatID> {
+    let mut pinned = config.pins.clone().unwrap_or(HashMap::new());
+    pinned.extend(store.load_pins().unwrap_or_else(|err| panic!("error loading pins: {}", err)));
+    let mut changed = false;
+    for (group, id) in &pinned {
+        if chat_ids.get(group) != Some(id) {
+            println!("[INFO] Telegram group \"{}\" is pinned to chat id {}", group, id);
+            chat_ids.insert(group.clone(), *id);
+            changed = true;
+        }
+    }
+    if changed {
+        if let Err(err) = store.save_chat_ids(chat_ids) {
+            println!("[ERROR] Could not save chat ids: {}", err);
+        }
+    }
+    pinned
+}
+
+// The titles found to be shared by several groups before the last restart, so that none of
+// those groups is taken for the group of the mapping once we are back. A pin settles them.
+fn load_ambiguous(store: &StateStore,
+                  pinned: &HashMap<TelegramGroup, ChatID>,
+                  chat_ids: &mut HashMap<TelegramGroup, ChatID>)
+                  -> HashMap<TelegramGroup, Vec<ChatID>> {
+    let loaded = store.load_ambiguous().unwrap_or_else(|err| panic!("error loading ambiguous titles: {}", err));
+    let before = loaded.len();
+    let ambiguous: HashMap<_, _> = loaded.into_iter().filter(|&(ref group, _)| !pinned.contains_key(group)).collect();
+    let mut forgotten = false;
+    for (group, ids) in &ambiguous {
+        println!("[WARN] Telegram groups {:?} are all titled \"{}\", not relaying it until one is pinned with \
+                  \"pin <chat id> {}\"",
+                 ids,
+                 group,
+                 group);
+        forgotten |= chat_ids.remove(group).is_some();
+    }
+    if ambiguous.len() != before {
+        if let Err(err) = store.save_ambiguous(&ambiguous) {
+            println!("[ERROR] Could not save ambiguous titles: {}", err);
+        }
+    }
+    if forgotten {
+        if let Err(err) = store.save_chat_ids(chat_ids) {
+            println!("[ERROR] Could not save chat ids: {}", err);
+        }
+    }
+    ambiguous
+}
+
+fn save_ambiguous(state: &RelayState) {
+    if let Err(err) = state.store.save_ambiguous(&state.ambiguous) {
+        println!("[ERROR] Could not save ambiguous titles: {}", err);
+    }
+}
+
+// Whether messages of the group titled `title` with chat id `id` may be relayed, recording
+// its id the first time it is seen. Groups are known by title, so a second group by the
+// same title makes the title ambiguous, and nothing is relayed for it until it is pinned.
+fn accept_group(state: &mut RelayState, title: &str, id: ChatID) -> bool {
+    if let Some(&pinned) = state.pinned.get(title) {
+        return pinned == id;
+    }
+    if state.ambiguous.contains_key(title) {
+        if !state.ambiguous[title].contains(&id) {
+            state.ambiguous.get_mut(title).unwrap().push(id);
+            println!("[WARN] Another Telegram group is titled \"{}\" (chat ids {:?})", title, state.ambiguous[title]);
+            save_ambiguous(state);
+        }
+        return false;
+    }
+    match state.chat_ids.get(title).cloned() {
+        None => {
+            println!("[INFO] Found telegram group \"{}\" with id {}", title, id);
+            state.chat_ids.insert(title.to_owned(), id);
+            save_chat_ids(state);
+            true
+        }
+        Some(known) if known == id => true,
+        Some(known) => {
+            println!("[WARN] Telegram groups {} and {} are both titled \"{}\", not relaying it until one is pinned \
+                      with \"pin <chat id> {}\"",
+                     known,
+                     id,
+                     title,
+                     title);
+            state.ambiguous.insert(title.to_owned(), vec![known, id]);
+            state.chat_ids.remove(title);
+            save_ambiguous(state);
+            save_chat_ids(state);
+            false
+        }
+    }
+}
+
+// What is done in Telegram groups the bot isn't meant to be in
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum UnmappedGroups {
+    Record,
+    Ignore,
+    Leave,
+}
 
-    // Grab the last portion of the url
-    let filename = url.path().unwrap().last().unwrap();
+fn unmapped_groups(config: &Config) -> UnmappedGroups {
+    match config.unmapped_groups.as_ref().map(|mode| &mode[..]) {
+        Some("ignore") => UnmappedGroups::Ignore,
+        Some("leave") => UnmappedGroups::Leave,
+        _ => UnmappedGroups::Record,
+    }
+}
 
-    // Create path by combining filename from url with download dir
-    let mut path = destination.to_path_buf();
-    path.push(filename);
+// Whether the bot is meant to be in the Telegram group titled `title`
+fn is_allowed_group(config: &Config, title: &str) -> bool {
+    config.maps.contains_key(title) ||
+    config.allowed_groups.as_ref().map_or(false, |allowed| allowed.iter().any(|group| group == title))
+}
 
-    // Open file and copy downloaded data
-    let mut file = try!(File::create(path));
-    std::io::copy(&mut resp, &mut file).unwrap();
+// Whether a message in the Telegram group titled `title` should be looked at at all.
+// Unless unmapped groups are recorded, messages of groups that aren't mapped or allowed
+// are dropped before their chat id is seen by anything else, leaving the group if so
+// configured.
+fn check_group(config: &Config, title: &str, id: ChatID) -> bool {
+    if is_allowed_group(config, title) {
+        return true;
+    }
+    match unmapped_groups(config) {
+        UnmappedGroups::Record => true,
+        UnmappedGroups::Ignore => false,
+        UnmappedGroups::Leave => {
+            println!("[INFO] Leaving unmapped Telegram group \"{}\"", title);
+            if let Err(err) = leave_chat(&config.token, id) {
+                println!("[ERROR] {}", err);
+            }
+            false
+        }
+    }
+}
 
-    // Create the return url that maps to this filename
-    let mut returl = baseurl.clone();
-    returl.path_mut().unwrap().push(filename.clone());
-    Ok(returl)
+fn save_chat_ids(state: &RelayState) {
+    if let Err(err) = state.store.save_chat_ids(&state.chat_ids) {
+        println!("[ERROR] Could not save chat ids: {}", err);
+    }
 }
 
-fn ensure_dir(path: &Path) {
-    let _ = std::fs::create_dir(&path);
+// Mirror a file sent to a group if media relaying is enabled, returning the URL it can be
+// found at. Failures are logged, leaving the caller to relay something else instead.
+fn mirror(tg: &Api,
+          config: &Config,
+          budget: &Budget,
+          origin: &media::Origin,
+          kind: &str,
+          file_id: &str,
+          name: Option<&str>)
+          -> Option<Url> {
+    if !config.relay_media.unwrap_or(false) {
+        return None;
+    }
+    let mirrored = download_file_user(tg, config, budget, origin, file_id, name)
+                       .context(format!("downloading {} for group '{}'", kind, origin.group));
+    notify_budget(tg, config, budget);
+    match mirrored {
+        Ok(local_url) => Some(local_url),
+        Err(err) => {
+            println!("[ERROR] {}", err);
+            None
+        }
+    }
+}
+
+// Whether a file of `size` bytes is relayed before it is mirrored
+fn defer_mirror(config: &Config, size: Option<i64>) -> bool {
+    config.relay_media.unwrap_or(false) &&
+    match (config.defer_media_bytes, size) {
+        (Some(limit), Some(size)) => size > limit,
+        _ => false,
+    }
 }
 
-fn user_path(user: &User) -> String {
-    match user.username {
-        Some(ref name) => name.clone(),
-        None => "anonymous".into()
+fn mirror_sticker(tg: &Api, config: &Config, budget: &Budget, group: &str, file_id: &str) -> Option<Url> {
+    if !config.relay_media.unwrap_or(false) {
+        return None;
+    }
+    let mirrored = media::download_sticker(tg, config, budget, file_id)
+                       .context(format!("downloading sticker for group '{}'", group));
+    notify_budget(tg, config, budget);
+    match mirrored {
+        Ok(local_url) => Some(local_url),
+        Err(err) => {
+            println!("[ERROR] {}", err);
+            None
+        }
     }
 }
 
-fn save_chat_ids(path: &str, chat_ids: &HashMap<TelegramGroup, ChatID>) {
-    let mut file = File::create(path).unwrap();
-    file.write_all(toml::encode_str(&chat_ids).as_bytes()).unwrap();
+// Let the admins know in a private message once the media budget is used up. Only admins
+// that have started a chat with the bot can be reached.
+fn notify_budget(tg: &Api, config: &Config, budget: &Budget) {
+    if let Some(notice) = budget.take_notice() {
+        hooks::fire(config, hooks::MEDIA_BUDGET_EXCEEDED, &[("notice", &notice[..])]);
+        for admin in config.admins.iter().flat_map(|admins| admins) {
+            if let Err(err) = tg.send_message(*admin, notice.clone(), None, None, None, None) {
+                println!("[WARN] Could not tell admin {} about the media budget: {}", admin, err);
+            }
+        }
+    }
 }
 
-fn handle_irc<T: ServerExt>(irc: T, tg: Arc<Api>, config: Config, state: Arc<Mutex<RelayState>>) {
-    let tg = tg.clone();
+// Answer a command given on IRC, line by line
+fn reply_irc<T: ServerExt>(irc: &T, target: &str, reply: &str) {
+    for line in reply.lines() {
+        if let Err(err) = irc.send_privmsg(target, line) {
+            println!("[ERROR] Could not reply to \"{}\": {}", target, err);
+        }
+    }
+}
+
+// Relay from IRC, and after losing the connection reconnect, to the next server in the list
+// so that one server going down doesn't keep the bridge down
+fn handle_irc(current: CurrentServer,
+              mut servers: Servers,
+              outbound: Arc<Outbound>,
+              config: Config,
+              state: Arc<Mutex<RelayState>>,
+              watchdog: Arc<Watchdog>) {
+    let mut backoff = Backoff::new(config.reconnect.as_ref());
+    loop {
+        handle_irc_connection(current.get(),
+                              outbound.clone(),
+                              config.clone(),
+                              state.clone(),
+                              watchdog.clone(),
+                              &mut backoff);
+        loop {
+            backoff.wait("connecting to IRC");
+            match servers.connect() {
+                Some(client) => {
+                    match servers::start(&config, &client) {
+                        Ok(()) => {
+                            current.set(client);
+                            break;
+                        }
+                        Err(err) => println!("[ERROR] {}", err),
+                    }
+                }
+                None => println!("[ERROR] Could not connect to any IRC server"),
+            }
+        }
+    }
+}
+
+// Relay from one IRC connection until it is lost
+fn handle_irc_connection<T: ServerExt + Clone + Send + 'static>(irc: T,
+                                                                outbound: Arc<Outbound>,
+                                                                config: Config,
+                                                                state: Arc<Mutex<RelayState>>,
+                                                                watchdog: Arc<Watchdog>,
+                                                                backoff: &mut Backoff) {
+    let mut typing = typing::Typing::default();
+    let mut accounts = accounts::Accounts::default();
+    // When people last invited us somewhere, by nick
+    let mut invited = HashMap::new();
+    watchdog.beat(IRC_READER);
     for message in irc.iter() {
+        // Servers ping us regularly, so a quiet channel still counts as activity
+        watchdog.beat(IRC_READER);
         match message {
             Ok(msg) => {
-                // Acquire lock of shared state
-                let state = state.lock().unwrap();
+                backoff.reset();
+                if outbound.irc_link.set_up() {
+                    println!("[INFO] Connection to IRC restored");
+                    hooks::fire(&config, hooks::BRIDGE_UP, &[("network", "irc")]);
+                }
+
+                // Acquire lock of shared state, which a few things need to take again later
+                let shared = state.clone();
+                let mut state = state.lock().unwrap();
 
                 // Debug print any messages from server
                 if config.debug.unwrap_or(false) {
                     println!("[DEBUG] {}", msg.to_string());
                 }
 
+                if config.irc_owner_accounts.is_some() {
+                    accounts.track(&msg);
+                }
+
+                if config.relay_typing.unwrap_or(false) {
+                    if let (Some(channel), Some(nick)) = (typing::typing_channel(&msg), msg.source_nickname()) {
+                        if nick != irc.current_nickname() {
+                            if let Some(group) = state.tg_group.get(channel) {
+                                if let Some(id) = state.chat_ids.get(group) {
+                                    typing.typing(&config.token, group, *id);
+                                }
+                            }
+                        }
+                    }
+                }
+
+                // Keep track of what is going on in the mapped channels
+                match msg.command {
+                    irc::client::data::Command::JOIN(ref channel, _, _) |
+                    irc::client::data::Command::TOPIC(ref channel, _) => {
+                        if let (Some(group), Some(nick)) = (state.tg_group.get(channel).cloned(),
+                                                            msg.source_nickname()) {
+                            let activity = state.activity.entry(group).or_insert(Default::default());
+                            match msg.command {
+                                irc::client::data::Command::TOPIC(_, Some(ref topic)) => activity.topic(nick, topic),
+                                irc::client::data::Command::JOIN(..) => {
+                                    activity.join(nick);
+                                    hooks::fire(&config, hooks::USER_JOINED, &[("network", "irc"),
+                                                                               ("channel", &channel[..]),
+                                                                               ("nick", nick)]);
+                                }
+                                _ => {}
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+
+                // Keep count of the users in the mapped channels
+                match msg.command {
+                    irc::client::data::Command::JOIN(..) |
+                    irc::client::data::Command::PART(..) |
+                    irc::client::data::Command::KICK(..) |
+                    irc::client::data::Command::QUIT(..) |
+                    irc::client::data::Command::Response(irc::client::data::Response::RPL_ENDOFNAMES, _, _) => {
+                        members::refresh(&irc, &mut state)
+                    }
+                    _ => {}
+                }
+
+                if let irc::client::data::Command::INVITE(_, ref channel) = msg.command {
+                    if let Some(inviter) = msg.source_nickname() {
+                        handle_invite(&irc, &config, &mut invited, inviter, channel);
+                    }
+                    continue;
+                }
+
+                // Joins and parts are relayed too when asked for
+                if config.relay_joins.unwrap_or(false) {
+                    let event = match msg.command {
+                        irc::client::data::Command::JOIN(ref channel, _, _) => Some((channel, templates::JOIN, "")),
+                        irc::client::data::Command::PART(ref channel, ref reason) => {
+                            Some((channel, templates::PART, reason.as_ref().map_or("", |reason| &reason[..])))
+                        }
+                        _ => None,
+                    };
+                    if let (Some((channel, event, reason)), Some(nick)) = (event, msg.source_nickname()) {
+                        let group = state.tg_group.get(channel);
+                        if let (Some(group), false) = (group, nick == irc.current_nickname()) {
+                            if let Some(id) = state.chat_ids.get(group) {
+                                let text = templates::render(&config, group, event, &[("nick", nick),
+                                                                                     ("host", source_host(&msg)),
+                                                                                     ("channel", &channel[..]),
+                                                                                     ("reason", reason)]);
+                                outbound.to_tg(group, *id, text);
+                            }
+                        }
+                    }
+                }
+
                 // The following conditions must be met in order for a message to be relayed.
                 // 1. We must be receiving a PRIVMSG
                 // 2. The message must have been sent by some user
@@ -169,19 +1308,180 @@ fn handle_irc<T: ServerExt>(irc: T, tg: Arc<Api>, config: Config, state: Arc<Mut
                     // 1. PRIVMSG received
                     if let Some(ref nick) = msg.source_nickname() {
                         // 2. Sender's nick exists
+
+                        // CTCP queries are answered, never relayed
+                        if let Some((query, arg)) = ctcp::parse(t) {
+                            if let Some(reply) = ctcp::reply(&config, query, arg) {
+                                if let Err(err) = irc.send_notice(nick, &reply) {
+                                    println!("[ERROR] Could not answer CTCP {} from \"{}\": {}", query, nick, err);
+                                }
+                            }
+                            continue;
+                        }
+
+                        // Commands are answered directly instead of being relayed
+                        let prefix = config.irc_command_prefix
+                                           .as_ref()
+                                           .map_or(prefixes::DEFAULT_IRC_COMMAND_PREFIX, |prefix| &prefix[..]);
+                        if let Some((command, args)) = commands::parse(t, prefix, "") {
+                            let reply = {
+                                let here = state.tg_group.get(&channel[..]).cloned();
+                                let here = here.as_ref().map(|group| &group[..]);
+                                let caller = commands::Caller {
+                                    nick: nick,
+                                    network: "irc",
+                                    role: permissions::irc_role(&config, nick, accounts.account(&msg)),
+                                    prefix: prefix,
+                                };
+                                commands::dispatch(&config, &mut state, &outbound, here, &caller, command, &args)
+                            };
+                            if let Some(reply) = reply {
+                                println!("[INFO] IRC user {} ran \"{}\"", nick, t);
+                                let target = if channel == irc.current_nickname() { *nick } else { &channel[..] };
+                                match reply {
+                                    commands::Reply::Now(reply) => reply_irc(&irc, target, &reply),
+                                    reply => {
+                                        // Finished on a thread of its own, as the state is
+                                        // locked here
+                                        let irc = irc.clone();
+                                        let target = target.to_owned();
+                                        let config = config.clone();
+                                        let outbound = outbound.clone();
+                                        thread::spawn(move || {
+                                            let reply = commands::finish(&config, &outbound, reply);
+                                            reply_irc(&irc, &target, &reply);
+                                        });
+                                    }
+                                }
+                                continue;
+                            }
+
+                            // Custom commands and factoids are answered on both sides of the mapping
+                            if let Some(group) = state.tg_group.get(&channel[..]) {
+                                if let Some(text) = commands::custom(&config, &state, group, nick, "irc", command) {
+                                    println!("[INFO] IRC user {} ran \"{}\"", nick, t);
+                                    // Queued like anything else said in the channel, so a
+                                    // long answer is split and rate limited
+                                    outbound.to_irc(channel, IrcLine {
+                                        nick: String::new(),
+                                        hostmask: None,
+                                        text: text.clone(),
+                                        date: time::get_time().sec,
+                                        received: Instant::now(),
+                                    });
+                                    if let Some(id) = state.chat_ids.get(group) {
+                                        outbound.to_tg(group, *id, text);
+                                    }
+                                    continue;
+                                }
+                            }
+                        }
+
+                        let (channel, audience) = split_statusmsg(channel);
+                        if let Some(group) = state.tg_group.get(channel).cloned() {
+                            state.activity.entry(group).or_insert(Default::default()).message(nick);
+                        }
+                        if audience.is_some() && !config.relay_statusmsg.unwrap_or(true) {
+                            continue;
+                        }
+                        let group = state.tg_group.get(channel).cloned().unwrap_or(String::new());
+                        let t = match prefixes::apply(&config, &group, prefixes::Direction::IrcToTg, t) {
+                            Some(t) => t,
+                            None => continue,
+                        };
+                        let staleness = stale::server_time(&msg).map_or(stale::Verdict::Fresh, |sent| stale::check(&config, sent));
+                        if staleness == stale::Verdict::Drop {
+                            println!("[INFO] Not relaying old message from {} in {}: {}", nick, channel, t);
+                            continue;
+                        }
+                        if config.irc_corrections.unwrap_or(false) && audience.is_none() {
+                            if let Some(substitution) = corrections::parse(&t) {
+                                if correct_on_tg(&config, &mut state, &shared, &outbound, channel, nick, source_host(&msg), &t, &substitution) {
+                                    continue;
+                                }
+                            }
+                        }
+                        match state.repeats.check(&config, "irc", &group, nick, &t) {
+                            repeats::Verdict::Drop => continue,
+                            repeats::Verdict::Relay(Some((repeated_nick, note))) => {
+                                if let Some(id) = state.chat_ids.get(&group) {
+                                    let note = templates::render(&config, &group, templates::MESSAGE, &[("nick", &repeated_nick[..]),
+                                                                                                     ("host", ""),
+                                                                                                     ("channel", channel),
+                                                                                                     ("message", &note[..])]);
+                                    outbound.to_tg(&group, *id, note);
+                                }
+                            }
+                            repeats::Verdict::Relay(None) => {}
+                        }
+                        let outgoing = {
+                            let (action, body) = match ctcp::action(&t) {
+                                Some(action) => (true, action),
+                                None => (false, &t[..]),
+                            };
+                            let body = match staleness {
+                                stale::Verdict::Mark(ref mark) => format!("{} {}", mark, body),
+                                _ => body.to_owned(),
+                            };
+                            RelayMessage {
+                                action: action,
+                                segments: message::parse_irc(&body),
+                                ..RelayMessage::new(Some(message::Sender {
+                                                             nick: nick.to_string(),
+                                                             hostmask: None,
+                                                         }),
+                                                    "")
+                            }
+                        };
+                        outbound.to_slack(&group, message::to_slack(&outgoing));
+                        outbound.to_teamchat(&group, nick, message::to_markdown(&outgoing));
+
+                        let mut said = None;
                         match state.tg_group.get(channel) {
                             Some(group) => {
                                 // 3. IRC channel exists in the mapping
                                 if let Some(id) = state.chat_ids.get(group) {
                                     // 4. Telegram group_id is known, relay the message
-                                    let relay_msg = format!("<{nick}> {message}",
-                                                            nick = nick,
-                                                            message = t);
+                                    // Telegram gets the text without IRC's formatting codes
+                                    let event = if outgoing.action { templates::ACTION } else { templates::MESSAGE };
+                                    let text = match audience {
+                                        Some(audience) => format!("({}) {}", audience, outgoing.plain()),
+                                        None => outgoing.plain(),
+                                    };
+                                    let relay_msg = templates::render(&config, group, event, &[("nick", *nick),
+                                                                                              ("host", source_host(&msg)),
+                                                                                              ("channel", channel),
+                                                                                              ("message", &text[..])]);
                                     println!("[INFO] Relaying \"{}\" → \"{}\": {}",
                                              channel,
                                              group,
                                              relay_msg);
-                                    let _ = tg.send_message(*id, relay_msg, None, None, None, None);
+                                    hooks::fire(&config, hooks::MESSAGE_RELAYED, &[("from", "irc"),
+                                                                                   ("channel", channel),
+                                                                                   ("group", &group[..]),
+                                                                                   ("nick", *nick),
+                                                                                   ("text", &t[..])]);
+                                    chatlog::append(&config, group, &chatlog::LogEntry {
+                                        date: time::get_time().sec,
+                                        network: "irc".to_owned(),
+                                        nick: nick.to_string(),
+                                        text: text.clone(),
+                                        action: outgoing.action,
+                                        user_id: None,
+                                    });
+                                    let entry = digest::Entry {
+                                        nick: nick.to_string(),
+                                        text: text.clone(),
+                                        action: outgoing.action,
+                                    };
+                                    if !outbound.to_digest(group, *id, entry) {
+                                        outbound.to_tg(group, *id, relay_msg.clone());
+                                        said = Some(corrections::Said {
+                                            text: text,
+                                            action: outgoing.action,
+                                            line: relay_msg,
+                                        });
+                                    }
                                 } else {
                                     // Telegram group_id has not yet been seen
                                     println!("[WARN] Cannot find telegram group \"{}\"", group);
@@ -191,183 +1491,761 @@ fn handle_irc<T: ServerExt>(irc: T, tg: Arc<Api>, config: Config, state: Arc<Mut
                                 // IRC channel not specified in config
                             }
                         }
+                        if let Some(said) = said {
+                            if config.irc_corrections.unwrap_or(false) && audience.is_none() {
+                                state.corrections.said(channel, nick, said);
+                            }
+                        }
                     }
                 }
             }
             Err(err) => {
                 println!("[ERROR] IRC error: {}", err);
+                if outbound.irc_link.set_down() {
+                    println!("[WARN] Lost connection to IRC, holding messages for it");
+                    hooks::fire(&config, hooks::BRIDGE_DOWN, &[("network", "irc"), ("error", &err.to_string()[..])]);
+                }
+                return;
             }
         }
     }
+    // The server closed the connection
+    println!("[ERROR] IRC connection closed");
+    if outbound.irc_link.set_down() {
+        println!("[WARN] Lost connection to IRC, holding messages for it");
+        hooks::fire(&config, hooks::BRIDGE_DOWN, &[("network", "irc"), ("error", "connection closed")]);
+    }
 }
 
-fn handle_tg<T: ServerExt>(irc: T, tg: Arc<Api>, config: Config, state: Arc<Mutex<RelayState>>) {
-    let tg = tg.clone();
-    let mut listener = tg.listener(ListeningMethod::LongPoll(None));
+fn handle_message(tg: &Arc<Api>,
+                  outbound: &Arc<Outbound>,
+                  config: &Config,
+                  state: &Mutex<RelayState>,
+                  m: Message) {
+    // Delivery latency is measured from here, so it includes mirroring media
+    let received = Instant::now();
 
-    loop {
-        // Fetch new updates via long poll method
-        let res = listener.listen(|u| {
+    // Groups we aren't meant to be in get no further, not even to the debug log
+    if let telegram_bot::types::Chat::Group { id, ref title, .. } = m.chat {
+        if !check_group(config, title, id) {
+            return;
+        }
+    }
+
+    // Debug print any messages from server
+    if config.debug.unwrap_or(false) {
+        println!("[DEBUG] {:?}", m);
+    }
 
-            // Check for message in received update
-            if let Some(m) = u.message {
+    // Commands are answered directly instead of being relayed
+    if let MessageType::Text(ref t) = m.msg {
+        let username = state.lock().unwrap().username.clone();
+        if let Some((command, args)) = commands::parse(t, "/", &username) {
+            let reply = {
                 let mut state = state.lock().unwrap();
+                let here = match m.chat {
+                    telegram_bot::types::Chat::Group { ref title, .. } => Some(&title[..]),
+                    _ => None,
+                };
+                let nick = tg_nick(config, &state, &m.from);
+                let caller = commands::Caller {
+                    nick: &nick,
+                    network: "telegram",
+                    role: permissions::telegram_role(config, &state, &m.from, here),
+                    prefix: "/",
+                };
+                commands::dispatch(config, &mut state, outbound, here, &caller, command, &args)
+            };
+            if let Some(reply) = reply {
+                println!("[INFO] Telegram user {} ran \"{}\"", m.from.id, t);
+                let reply = commands::finish(config, outbound, reply);
+                if let Err(err) = tg.send_message(m.chat.id(), reply, None, None, None, None) {
+                    println!("[ERROR] Could not reply to command: {}", err);
+                }
+                return;
+            }
 
-                // Debug print any messages from server
-                if config.debug.unwrap_or(false) {
-                    println!("[DEBUG] {:?}", m);
+            // Custom commands and factoids are answered on both sides of the mapping
+            if let telegram_bot::types::Chat::Group { ref title, .. } = m.chat {
+                let custom = {
+                    let state = state.lock().unwrap();
+                    let nick = tg_nick(config, &state, &m.from);
+                    commands::custom(config, &state, title, &nick, "telegram", command)
+                        .map(|text| (text, state.irc_channel.get(title).cloned()))
+                };
+                if let Some((text, channel)) = custom {
+                    println!("[INFO] Telegram user {} ran \"{}\"", m.from.id, t);
+                    if let Err(err) = tg.send_message(m.chat.id(), text.clone(), None, None, None, None) {
+                        println!("[ERROR] Could not reply to command: {}", err);
+                    }
+                    if let Some(channel) = channel {
+                        outbound.to_irc(&channel, IrcLine {
+                            nick: String::new(),
+                            hostmask: None,
+                            text: text,
+                            date: m.date,
+                            received: Instant::now(),
+                        });
+                    }
+                    return;
                 }
+            }
+        }
+    }
 
-                // The following conditions must be met in order for a message to be relayed.
-                // 1. We must be receiving a message from a group (handle channels in the future?)
-                // 2. The Telegram group in question must be present in the mapping
+    // The following conditions must be met in order for a message to be relayed.
+    // 1. We must be receiving a message from a group (handle channels in the future?)
+    // 2. The Telegram group in question must be present in the mapping
 
 
-                match m.chat {
-                    telegram_bot::types::Chat::Group { id, title, .. } => {
+    match m.chat {
+        telegram_bot::types::Chat::Group { id, title, .. } => {
 
-                        // Check if channel's id should be recorded
-                        if state.chat_ids.get(&title).is_none() {
-                            println!("[INFO] Found telegram group \"{}\" with id {}", title, id);
-                            println!("[INFO] Saving to \"{}\"", CHAT_IDS_FILE);
-                            state.chat_ids.insert(title.clone(), id);
-                            save_chat_ids(CHAT_IDS_FILE, &state.chat_ids);
-                        }
+            // Only hold the lock while looking at the shared state, so that slow
+            // work like media downloads doesn't hold up the other handlers
+            let channel = {
+                let mut state = state.lock().unwrap();
 
+                // Record the group's id, and make sure it is the group of the mapping
+                if !accept_group(&mut state, &title, id) {
+                    return;
+                }
 
-                        if let Entry::Occupied(e) = state.irc_channel.entry(title.clone()){
-                            let channel = e.get();
-                            let nick = format_tg_nick(&m.from);
+                (state.irc_channel.get(&title).cloned(),
+                 state.username.clone(),
+                 tg_nick(config, &state, &m.from),
+                 state.media_budget.clone())
+            };
+            let (channel, username, nick, budget) = channel;
+            outbound.reactivate_tg(&title);
 
-                            match m.msg {
-                                MessageType::Text(t) => {
-                                    let relay_msg = format!("<{nick}> {message}",
-                                                            nick = nick,
-                                                            message = t);
-                                    println!("[INFO] Relaying \"{}\" → \"{}\": {}",
-                                            title,
-                                            channel,
-                                            relay_msg);
-                                    irc.send_privmsg(channel, &relay_msg).unwrap();
-                                },
-                                MessageType::Photo(ps) => {
-                                    // Print received text message to stdout
-                                    if config.relay_media.unwrap_or(false) {
-                                        if let Some(file) = ps.last() {
-                                            let file = tg.get_file(&file.file_id).unwrap();
-                                            if let Some(path) = file.file_path {
-                                                let download_dir = PathBuf::from(config.download_dir.clone().unwrap());
-                                                let mut base_url = config.base_url.clone().unwrap();
-
-                                                // Create the final download directory by combining the base
-                                                // directory with the username, and ensure it exists.
-                                                let user_path = user_path(&m.from);
-                                                let download_dir_user = download_dir.join(&user_path);
-                                                ensure_dir(&download_dir_user);
-
-                                                // Create the final URL by combining the base URL and the
-                                                // username.
-                                                base_url.path_mut().unwrap().push(user_path);
-                                                let tg_url = Url::parse(&tg.get_file_url(&path)).unwrap();
-                                                let local_url = download_file(&tg_url, &download_dir_user, &base_url).unwrap();
-
-                                                let relay_msg = format!("<{nick}> {message}",
-                                                                        nick = nick,
-                                                                        message = local_url);
-                                                println!("[INFO] Relaying \"{}\" → \"{}\": {}",
-                                                        title,
-                                                        channel,
-                                                        relay_msg);
-                                                irc.send_privmsg(channel, &relay_msg).unwrap();
-                                            }
-                                        }
-                                    }
-                                },
-                                MessageType::Document(doc) => {
-                                    if config.relay_media.unwrap_or(false) {
-                                        let file = tg.get_file(&doc.file_id).unwrap();
-                                        if let Some(path) = file.file_path {
-                                            let download_dir = PathBuf::from(config.download_dir.clone().unwrap());
-                                            let mut base_url = config.base_url.clone().unwrap();
-
-                                            // Create the final download directory by combining the base
-                                            // directory with the username, and ensure it exists.
-                                            let user_path = user_path(&m.from);
-                                            let download_dir_user = download_dir.join(&user_path);
-                                            ensure_dir(&download_dir_user);
-
-                                            // Create the final URL by combining the base URL and the
-                                            // username.
-                                            base_url.path_mut().unwrap().push(user_path);
-                                            let tg_url = Url::parse(&tg.get_file_url(&path)).unwrap();
-                                            let local_url = download_file(&tg_url, &download_dir_user, &base_url).unwrap();
-
-                                            let relay_msg = format!("<{nick}> {message}",
-                                                                    nick = nick,
-                                                                    message = local_url);
-                                            println!("[INFO] Relaying \"{}\" → \"{}\": {}",
-                                                    title,
-                                                    channel,
-                                                    relay_msg);
-                                            irc.send_privmsg(channel, &relay_msg).unwrap();
-                                        }
-                                    }
-                                },
-                                MessageType::Sticker(sticker) => {
-                                    let message: String = if let Some(emoji) = sticker.emoji {
-                                            format!("(Sticker) {}", emoji)
+            if let Some(channel) = channel {
+                // Messages replayed long after they were sent go before any media is mirrored
+                let staleness = stale::check(config, m.date);
+                if staleness == stale::Verdict::Drop {
+                    println!("[INFO] Not relaying old message from {} in \"{}\"", nick, title);
+                    return;
+                }
+                let origin = media::Origin {
+                    user: &m.from,
+                    chat_id: id,
+                    group: &title,
+                    date: m.date,
+                    caption: m.caption.as_ref().map(|caption| &caption[..]),
+                };
+
+                // A large file to mirror after the message saying it was sent is relayed
+                let mut deferred = None;
+                let uploading = locale::text(config, &title, locale::UPLOADING_MIRROR, &[]);
+                let message = match m.msg {
+                    MessageType::Text(t) => {
+                        match prefixes::other_bot_command(&t, &username) {
+                            // Commands for other bots are only noise on IRC
+                            Some((command, bot)) => {
+                                if config.summarize_bot_commands.unwrap_or(false) {
+                                    Some(locale::text(config, &title, locale::SENT_COMMAND, &[("command", command),
+                                                                                              ("bot", bot)]))
+                                } else {
+                                    None
+                                }
+                            }
+                            None => {
+                                prefixes::apply(config, &title, prefixes::Direction::TgToIrc, &t)
+                                    .map(|t| replace_custom_emoji(config, &title, &t))
+                            }
+                        }
+                    }
+                    MessageType::Photo(ps) => {
+                        ps.last().map(|photo| {
+                            match mirror(tg, config, &budget, &origin, "photo", &photo.file_id, None) {
+                                Some(local_url) => local_url.to_string(),
+                                // Fall back to describing the photo
+                                None => media::describe_photo(photo),
+                            }
+                        })
+                    },
+                    MessageType::Document(ref doc) if defer_mirror(config, doc.file_size) => {
+                        deferred = Some(("document", doc.file_id.clone(), doc.file_name.clone()));
+                        Some(format!("{} {}", media::describe_document(doc), uploading))
+                    },
+                    MessageType::Document(doc) => {
+                        let name = doc.file_name.as_ref().map(|name| &name[..]);
+                        match mirror(tg, config, &budget, &origin, "document", &doc.file_id, name) {
+                            Some(local_url) => {
+                                match doc.file_name {
+                                    Some(ref name) => {
+                                        Some(locale::text(config, &title, locale::SENT_DOCUMENT, &[("name", &name[..]),
+                                                                                                   ("url", &local_url.to_string())]))
                                     }
-                                    else {
-                                        "(Sticker)".into()
-                                    };
-                                    let relay_msg = format!("<{nick}> {message}",
-                                                            nick = nick,
-                                                            message = message);
-                                    println!("[INFO] Relaying \"{}\" → \"{}\": {}",
-                                             title,
-                                             channel,
-                                             relay_msg);
-                                    irc.send_privmsg(channel, &relay_msg).unwrap();
+                                    None => Some(local_url.to_string()),
                                 }
-                                _ => {}
                             }
+                            // Fall back to describing the document
+                            None => Some(media::describe_document(&doc)),
+                        }
+                    },
+                    MessageType::Video(ref video) if defer_mirror(config, video.file_size) => {
+                        deferred = Some(("video", video.file_id.clone(), None));
+                        let long_label = locale::text(config, &title, locale::LONG_VIDEO, &[]);
+                        Some(format!("{} {}",
+                                     media::describe_video(video, None, config.long_video_seconds, &long_label),
+                                     uploading))
+                    },
+                    MessageType::Video(video) => {
+                        let local_url = mirror(tg, config, &budget, &origin, "video", &video.file_id, None);
+                        let long_label = locale::text(config, &title, locale::LONG_VIDEO, &[]);
+                        Some(media::describe_video(&video, local_url.as_ref(), config.long_video_seconds, &long_label))
+                    },
+                    MessageType::Audio(ref audio) if defer_mirror(config, audio.file_size) => {
+                        deferred = Some(("audio", audio.file_id.clone(), None));
+                        Some(format!("{} {}", media::describe_audio(audio, None), uploading))
+                    },
+                    MessageType::Audio(audio) => {
+                        let local_url = mirror(tg, config, &budget, &origin, "audio", &audio.file_id, None);
+                        Some(media::describe_audio(&audio, local_url.as_ref()))
+                    },
+                    MessageType::Sticker(sticker) => {
+                        let mut text = if let Some(ref emoji) = sticker.emoji {
+                            locale::text(config, &title, locale::STICKER_WITH_EMOJI, &[("emoji", &emoji[..])])
+                        }
+                        else {
+                            locale::text(config, &title, locale::STICKER, &[])
+                        };
+                        if let Some(local_url) = mirror_sticker(tg, config, &budget, &title, &sticker.file_id) {
+                            text.push_str(&format!(" {}", local_url));
                         }
+                        if let Some(ref pack) = sticker.set_name {
+                            text.push_str(&format!(" {}{}", media::STICKER_PACK_URL, pack));
+                        }
+                        Some(text)
+                    }
+                    _ => None,
+                };
+
+                let message = match staleness {
+                    stale::Verdict::Mark(mark) => message.map(|message| format!("{} {}", mark, message)),
+                    _ => message,
+                };
+                if let Some(message) = message {
+                    let verdict = {
+                        let mut state = state.lock().unwrap();
+                        state.repeats.check(config, "telegram", &title, &nick, &message)
+                    };
+                    match verdict {
+                        repeats::Verdict::Drop => return,
+                        repeats::Verdict::Relay(Some((repeated_nick, note))) => {
+                            let note = RelayMessage::new(Some(message::Sender {
+                                                             hostmask: None,
+                                                             nick: repeated_nick,
+                                                         }),
+                                                         &note);
+                            relay_from_tg(outbound, &title, Some(&channel), &note, m.date, received);
+                        }
+                        repeats::Verdict::Relay(None) => {}
+                    }
+                    {
+                        let mut state = state.lock().unwrap();
+                        state.activity.entry(title.clone()).or_insert(Default::default()).tg_message(&nick);
+                        state.relayed.record(id, m.message_id, relayed::RelayedMessage {
+                            nick: nick.clone(),
+                            user_id: m.from.id,
+                            text: message.clone(),
+                        });
+                    }
+                    println!("[INFO] Relaying \"{}\" → \"{}\": <{}> {}",
+                             title,
+                             channel,
+                             nick,
+                             message);
+                    hooks::fire(config, hooks::MESSAGE_RELAYED, &[("from", "telegram"),
+                                                                  ("group", &title[..]),
+                                                                  ("channel", &channel[..]),
+                                                                  ("nick", &nick[..]),
+                                                                  ("text", &message[..])]);
+                    chatlog::append(config, &title, &chatlog::LogEntry {
+                        date: m.date,
+                        network: "telegram".to_owned(),
+                        nick: nick.clone(),
+                        text: message.clone(),
+                        action: false,
+                        user_id: Some(m.from.id),
+                    });
+                    let hostmask = tg_hostmask(config, &nick, &m.from);
+                    let outgoing = RelayMessage::new(Some(message::Sender {
+                                                        hostmask: hostmask.clone(),
+                                                        nick: nick.clone(),
+                                                    }),
+                                                    &message);
+                    relay_from_tg(outbound, &title, Some(&channel), &outgoing, m.date, received);
+
+                    // Follow up with where the file ended up, or that it couldn't be mirrored.
+                    // Downloading a large file takes a while, so it happens on a thread of its
+                    // own, leaving the updates after it to be relayed meanwhile.
+                    if let Some((kind, file_id, name)) = deferred {
+                        let tg = tg.clone();
+                        let outbound = outbound.clone();
+                        let config = config.clone();
+                        let title = title.clone();
+                        let user = m.from.clone();
+                        let caption = m.caption.clone();
+                        let date = m.date;
+                        thread::spawn(move || {
+                            let origin = media::Origin {
+                                user: &user,
+                                chat_id: id,
+                                group: &title,
+                                date: date,
+                                caption: caption.as_ref().map(|caption| &caption[..]),
+                            };
+                            let note = match mirror(&tg, &config, &budget, &origin, kind, &file_id, name.as_ref().map(|name| &name[..])) {
+                                Some(local_url) => locale::text(&config, &title, locale::MIRRORED, &[("url", &local_url.to_string())]),
+                                None => locale::text(&config, &title, locale::MIRROR_FAILED, &[]),
+                            };
+                            println!("[INFO] Relaying \"{}\" → \"{}\": <{}> {}", title, channel, nick, note);
+                            let follow_up = RelayMessage::new(Some(message::Sender {
+                                                                 hostmask: hostmask,
+                                                                 nick: nick,
+                                                             }),
+                                                             &note);
+                            relay_from_tg(&outbound, &title, Some(&channel), &follow_up, date, Instant::now());
+                        });
                     }
-                    _ => (),
                 }
             }
+        }
+        _ => (),
+    }
+}
 
-            // If none of the "try!" statements returned an error: It's Ok!
-            Ok(ListeningAction::Continue)
+// Tell Telegram which kinds of updates to send us. The API we use doesn't support this
+// parameter, but Telegram remembers the last value given to getUpdates, so a single
+// request of our own is enough.
+fn set_allowed_updates(token: &str, offset: i64, allowed: &[String]) -> error::Result<()> {
+    let mut url = try!(urls::parse(&format!("https://api.telegram.org/bot{}/getUpdates", token), "getUpdates url"));
+    let allowed = allowed.iter().map(|kind| format!("\"{}\"", kind)).collect::<Vec<_>>().join(",");
+    url.set_query_from_pairs(vec![("offset", offset.to_string()),
+                                  ("limit", "1".to_owned()),
+                                  ("timeout", "0".to_owned()),
+                                  ("allowed_updates", format!("[{}]", allowed))]
+                                 .iter()
+                                 .map(|&(key, ref value)| (key, &value[..])));
+    let resp = try!(Request::new(Method::Get, url)
+                        .and_then(|req| req.start())
+                        .and_then(|req| req.send())
+                        .context("setting allowed updates"));
+    if !resp.status.is_success() {
+        return Err(format!("setting allowed updates: server responded with {}", resp.status).into());
+    }
+    Ok(())
+}
+
+// Post a message of the bridge's own, such as a feed item, to both sides of a mapping
+fn post_to_mapping(outbound: &Outbound, state: &RelayState, group: &str, text: String) {
+    if let Some(id) = state.chat_ids.get(group) {
+        outbound.to_tg(group, *id, text.clone());
+    }
+    if let Some(channel) = state.irc_channel.get(group) {
+        outbound.to_irc(channel, IrcLine {
+            nick: String::new(),
+            hostmask: None,
+            text: text,
+            date: time::get_time().sec,
+            received: Instant::now(),
         });
-        if let Err(e) = res {
-            println!("{}", e);
-            std::process::exit(1);
+    }
+}
+
+// Make an IRC user's correction to their last message by editing it on Telegram. Returns
+// false if there is nothing it applies to there, for it to be relayed as it is. The edit is
+// made in the background, without holding the state, and should it fail the correction is
+// relayed after all.
+fn correct_on_tg(config: &Config,
+                 state: &mut RelayState,
+                 shared: &Arc<Mutex<RelayState>>,
+                 outbound: &Arc<Outbound>,
+                 channel: &str,
+                 nick: &str,
+                 host: &str,
+                 correction: &str,
+                 substitution: &corrections::Substitution)
+                 -> bool {
+    let (group, id) = match state.tg_group.get(channel).and_then(|group| state.chat_ids.get(group).map(|id| (group, id))) {
+        Some((group, &id)) => (group.clone(), id),
+        None => return false,
+    };
+    let said = match state.corrections.last_said(channel, nick) {
+        Some(said) => said.clone(),
+        None => return false,
+    };
+    let text = match substitution.apply(&said.text) {
+        Some(text) => text,
+        None => return false,
+    };
+    let event = if said.action { templates::ACTION } else { templates::MESSAGE };
+    let line = templates::render(config, &group, event, &[("nick", nick),
+                                                          ("host", host),
+                                                          ("channel", channel),
+                                                          ("message", &text[..])]);
+    let (message_id, edited) = match state.corrections.correct(id, &said.line, &line) {
+        Some(edit) => edit,
+        // Not posted yet, or batched into a digest
+        None => return false,
+    };
+    let fallback = templates::render(config, &group, templates::MESSAGE, &[("nick", nick),
+                                                                           ("host", host),
+                                                                           ("channel", channel),
+                                                                           ("message", correction)]);
+    let token = config.token.clone();
+    let state = shared.clone();
+    let outbound = outbound.clone();
+    let channel = channel.to_owned();
+    let nick = nick.to_owned();
+    thread::spawn(move || {
+        if let Err(err) = corrections::edit_message_text(&token, id, message_id, &edited) {
+            println!("[ERROR] Could not correct message of {} in \"{}\": {}", nick, group, err);
+            outbound.to_tg(&group, id, fallback);
+            return;
+        }
+        println!("[INFO] Corrected message of {} in \"{}\": {}", nick, group, line);
+        let mut state = state.lock().unwrap();
+        state.corrections.edited(id, message_id, edited);
+        state.corrections.said(&channel, &nick, corrections::Said {
+            text: text,
+            action: said.action,
+            line: line,
+        });
+    });
+    true
+}
+
+// Delete a message in a Telegram group, which only works while we're an admin there
+fn delete_message(token: &str, chat_id: ChatID, message_id: i64) -> error::Result<()> {
+    let mut url = try!(urls::parse(&format!("https://api.telegram.org/bot{}/deleteMessage", token), "deleteMessage url"));
+    url.set_query_from_pairs(vec![("chat_id", chat_id.to_string()), ("message_id", message_id.to_string())]
+                                 .iter()
+                                 .map(|&(key, ref value)| (key, &value[..])));
+    let resp = try!(Request::new(Method::Get, url)
+                        .and_then(|req| req.start())
+                        .and_then(|req| req.send())
+                        .context(format!("deleting message {}", message_id)));
+    if !resp.status.is_success() {
+        return Err(format!("deleting message {}: server responded with {}", message_id, resp.status).into());
+    }
+    Ok(())
+}
+
+fn leave_chat(token: &str, chat_id: ChatID) -> error::Result<()> {
+    let mut url = try!(urls::parse(&format!("https://api.telegram.org/bot{}/leaveChat", token), "leaveChat url"));
+    url.set_query_from_pairs(vec![("chat_id", chat_id.to_string())].iter().map(|&(key, ref value)| (key, &value[..])));
+    let resp = try!(Request::new(Method::Get, url)
+                        .and_then(|req| req.start())
+                        .and_then(|req| req.send())
+                        .context(format!("leaving chat {}", chat_id)));
+    if !resp.status.is_success() {
+        return Err(format!("leaving chat {}: server responded with {}", chat_id, resp.status).into());
+    }
+    Ok(())
+}
+
+// Relay the edit of a relayed text message, if edits are relayed for its group, as a notice
+// to every destination of the mapping that can't edit messages in place
+fn handle_edit(outbound: &Outbound, config: &Config, state: &Mutex<RelayState>, m: Message) {
+    let (id, title) = match m.chat {
+        telegram_bot::types::Chat::Group { id, title, .. } => (id, title),
+        _ => return,
+    };
+    if !check_group(config, &title, id) {
+        return;
+    }
+    let text = match m.msg {
+        MessageType::Text(text) => text,
+        _ => return,
+    };
+    let mode = edits::mode(config, &title);
+    if mode == edits::Mode::Off {
+        return;
+    }
+    let text = match prefixes::apply(config, &title, prefixes::Direction::TgToIrc, &text) {
+        Some(text) => replace_custom_emoji(config, &title, &text),
+        None => return,
+    };
+
+    let (channel, previous, nick) = {
+        let mut state = state.lock().unwrap();
+        if !accept_group(&mut state, &title, id) {
+            return;
+        }
+        let nick = tg_nick(config, &state, &m.from);
+        let previous = state.relayed.get(id, m.message_id).map(|previous| previous.text.clone());
+        state.relayed.record(id, m.message_id, relayed::RelayedMessage {
+            nick: nick.clone(),
+            user_id: m.from.id,
+            text: text.clone(),
+        });
+        (state.irc_channel.get(&title).cloned(), previous, nick)
+    };
+    // Edits of formatting and the like change nothing we relayed
+    if previous.as_ref() == Some(&text) {
+        return;
+    }
+
+    let diff = match (mode, previous) {
+        (edits::Mode::Diff, Some(previous)) => edits::diff(&previous, &text),
+        _ => None,
+    };
+    let meant = mode == edits::Mode::Meant &&
+                time::get_time().sec - m.date <= config.edit_meant_seconds.unwrap_or(edits::DEFAULT_MEANT_SECONDS);
+    if meant {
+        // Said by the bridge, the sender is part of the text
+        let message = locale::text(config, &title, locale::EDITED_MEANT, &[("nick", &nick[..]), ("message", &text[..])]);
+        println!("[INFO] Relaying edit in \"{}\": {}", title, message);
+        let outgoing = RelayMessage {
+            edit_of: Some(message::Reference {
+                chat: title.clone(),
+                id: m.message_id,
+            }),
+            ..RelayMessage::new(None, &message)
+        };
+        relay_from_tg(outbound, &title, channel.as_ref().map(|channel| &channel[..]), &outgoing, time::get_time().sec, Instant::now());
+        return;
+    }
+    let message = match diff {
+        Some(diff) => locale::text(config, &title, locale::EDITED_DIFF, &[("diff", &diff[..])]),
+        None => locale::text(config, &title, locale::EDITED, &[("message", &text[..])]),
+    };
+    println!("[INFO] Relaying edit in \"{}\": <{}> {}", title, nick, message);
+    let outgoing = RelayMessage {
+        edit_of: Some(message::Reference {
+            chat: title.clone(),
+            id: m.message_id,
+        }),
+        ..RelayMessage::new(Some(message::Sender {
+                                hostmask: tg_hostmask(config, &nick, &m.from),
+                                nick: nick,
+                            }),
+                            &message)
+    };
+    relay_from_tg(outbound, &title, channel.as_ref().map(|channel| &channel[..]), &outgoing, time::get_time().sec, Instant::now());
+}
+
+// Hand a message from Telegram to the other destinations of its mapping, each rendered the
+// way the destination can show it. Edits only go where they can't be made in place.
+fn relay_from_tg(outbound: &Outbound,
+                 group: &str,
+                 channel: Option<&str>,
+                 outgoing: &RelayMessage,
+                 date: i64,
+                 received: Instant) {
+    for (destination, capabilities) in outbound.capabilities(group) {
+        if outgoing.edit_of.is_some() && capabilities.supports_edits {
+            continue;
+        }
+        match destination {
+            "irc" => {
+                if let Some(channel) = channel {
+                    outbound.to_irc(channel, IrcLine {
+                        nick: outgoing.nick().to_owned(),
+                        hostmask: outgoing.sender.as_ref().and_then(|sender| sender.hostmask.clone()),
+                        text: message::to_irc(outgoing),
+                        date: date,
+                        received: received,
+                    });
+                }
+            }
+            "slack" => outbound.to_slack(group, message::to_slack(outgoing)),
+            "teamchat" => outbound.to_teamchat(group, outgoing.nick(), message::to_markdown(outgoing)),
+            _ => {}
+        }
+    }
+}
+
+// Announce the post of a Telegram channel on IRC, if the channel is mirrored
+fn handle_channel_post(outbound: &Outbound, config: &Config, m: Message) {
+    let title = match m.chat {
+        telegram_bot::types::Chat::Channel { title, .. } => title,
+        _ => return,
+    };
+    let announcement = match config.announcements
+                                   .as_ref()
+                                   .and_then(|announcements| announcements.iter().find(|a| a.channel == title)) {
+        Some(announcement) => announcement,
+        None => return,
+    };
+    let text = match (m.msg, m.caption) {
+        (MessageType::Text(text), _) => text,
+        // Posts of photos and the like are announced by their caption
+        (_, Some(caption)) => caption,
+        _ => return,
+    };
+    let template = announcement.template.as_ref().map_or(DEFAULT_ANNOUNCEMENT_TEMPLATE, |template| &template[..]);
+    let message = templates::fill(template, &[("channel", &title[..]), ("message", &text[..])]);
+    println!("[INFO] Announcing post of \"{}\" in {}", title, announcement.irc_channels.join(", "));
+    if let Some(ref mastodon) = config.mastodon {
+        if mastodon.post_announcements.unwrap_or(false) {
+            let mastodon = mastodon.clone();
+            let text = text.clone();
+            thread::spawn(move || {
+                if let Err(err) = mastodon::post(&mastodon, &text) {
+                    println!("[ERROR] {}", err);
+                }
+            });
+        }
+    }
+    outbound.announce(&title, message);
+}
+
+fn handle_update(tg: &Arc<Api>,
+                 outbound: &Arc<Outbound>,
+                 config: &Config,
+                 state: &Mutex<RelayState>,
+                 seen: &mut Seen,
+                 u: Update) {
+    let key = u.message.as_ref().map(|m| (m.chat.id(), m.message_id));
+    if !seen.record(u.update_id, key) {
+        println!("[INFO] Skipping already relayed update {}", u.update_id);
+        return;
+    }
+
+    // Check for message in received update
+    if let Some(m) = u.message {
+        handle_message(tg, outbound, config, state, m);
+    }
+    if let Some(m) = u.edited_message {
+        handle_edit(outbound, config, state, m);
+    }
+    if let Some(m) = u.channel_post {
+        handle_channel_post(outbound, config, m);
+    }
+}
+
+fn handle_tg(tg: Arc<Api>,
+             outbound: Arc<Outbound>,
+             config: Config,
+             state: Arc<Mutex<RelayState>>,
+             watchdog: Arc<Watchdog>) {
+    let mut seen = Seen::load(state.lock().unwrap().store.clone());
+    let mut backoff = Backoff::new(config.reconnect.as_ref());
+
+    let allowed = config.allowed_updates
+                        .clone()
+                        .unwrap_or(DEFAULT_ALLOWED_UPDATES.iter().map(|kind| kind.to_string()).collect());
+    if let Some(webhook) = config.webhook.clone() {
+        webhook::serve(tg, outbound, config, state, seen, webhook, &allowed);
+        return;
+    }
+    // getUpdates is refused while a webhook of an earlier run is still set
+    if let Err(err) = webhook::unregister(&config.token) {
+        println!("[WARN] {}", err);
+    }
+    match set_allowed_updates(&config.token, seen.offset(), &allowed) {
+        Ok(()) => println!("[INFO] Receiving Telegram updates: {}", allowed.join(", ")),
+        Err(err) => println!("[WARN] {}", err),
+    }
+
+    loop {
+        watchdog.beat(TG_POLL);
+        // Fetch new updates via long poll method, starting after the last update we handled
+        let updates = match tg.get_updates(Some(seen.offset()), None, Some(LONG_POLL_TIMEOUT)) {
+            Ok(updates) => {
+                backoff.reset();
+                if outbound.tg_link.set_up() {
+                    println!("[INFO] Connection to Telegram restored");
+                    hooks::fire(&config, hooks::BRIDGE_UP, &[("network", "telegram")]);
+                }
+                updates
+            }
+            Err(e) => {
+                println!("[ERROR] Telegram error: {}", e);
+                if outbound.tg_link.set_down() {
+                    println!("[WARN] Lost connection to Telegram, holding messages for it");
+                    hooks::fire(&config, hooks::BRIDGE_DOWN, &[("network", "telegram"), ("error", &e.to_string()[..])]);
+                }
+                backoff.wait("polling Telegram");
+                continue;
+            }
+        };
+
+        for u in updates {
+            watchdog.beat(TG_POLL);
+            handle_update(&tg, &outbound, &config, &state, &mut seen, u);
         }
     }
 }
 
 fn main() {
+    let options = daemon::parse_args();
+
+    if let Some(ref path) = options.encrypt_secrets {
+        match secrets::encrypt(path, options.key_file.as_ref().map(|path| &path[..])) {
+            Ok(()) => println!("[INFO] Wrote secrets to \"{}\"", path),
+            Err(err) => {
+                println!("[ERROR] {}", err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
     // Parse config file and chat IDs
-    let config = load_config(CONFIG_FILE);
-    let chat_ids = load_chat_ids(CHAT_IDS_FILE);
+    let mut config = load_config(CONFIG_FILE);
+    secrets::resolve(&mut config).unwrap_or_else(|err| panic!("error loading secrets: {}", err));
+    if let Some(ref inbound) = config.inbound {
+        inbound::check(inbound).unwrap_or_else(|err| panic!("error in inbound config: {}", err));
+    }
+    prefixes::check(&config).unwrap_or_else(|err| panic!("error in prefix_rules config: {}", err));
+    if let Some(ref webhook) = config.webhook {
+        webhook::check(webhook).unwrap_or_else(|err| panic!("error in webhook config: {}", err));
+    }
+
+    // Detach before any threads are started, they wouldn't survive the fork
+    if options.daemon {
+        daemon::daemonize(config.log_file.as_ref().map(|path| &path[..])).expect("Could not daemonize.");
+    }
+    if let Some(ref pidfile) = options.pidfile {
+        daemon::write_pidfile(pidfile).expect("Could not write pidfile.");
+    }
+
+    // Ask for SIGUSR1, SIGTERM and SIGINT before any threads are started, so that they all
+    // leave them to us
+    let dump_signal = chan_signal::notify(&[Signal::USR1]);
+    let shutdown_signal = chan_signal::notify(&[Signal::TERM, Signal::INT]);
+
+    let store = store::open(&config);
+    let mut chat_ids = load_chat_ids(&config, &*store);
+    let aliases = store.load_aliases().unwrap_or_else(|err| panic!("error loading aliases: {}", err));
+    let factoids = store.load_factoids().unwrap_or_else(|err| panic!("error loading factoids: {}", err));
+    let reminders = store.load_reminders().unwrap_or_else(|err| panic!("error loading reminders: {}", err));
+    let pinned = load_pins(&config, &*store, &mut chat_ids);
+    let ambiguous = load_ambiguous(&*store, &pinned, &mut chat_ids);
+    let mut relayed = relayed::Relayed::new();
+    relayed.restore(store.load_relayed().unwrap_or_else(|err| panic!("error loading relayed messages: {}", err)));
     // Ensure that download dir exists
     if let Some(ref download_dir) = config.download_dir {
         ensure_dir(&PathBuf::from(download_dir));
+
+        if let Some(hours) = config.media_expiry_hours {
+            let download_dir = PathBuf::from(download_dir);
+            let max_age = Duration::from_secs(hours * 60 * 60);
+            thread::spawn(move || expire_media(download_dir, max_age));
+        }
+    }
+    if config.base_url.is_some() {
+        let base_url = urls::base_url(&config).unwrap_or_else(|err| panic!("invalid base_url: {}", err));
+        if config.media_url_tokens.unwrap_or(false) && base_url.scheme != "https" {
+            println!("[WARN] base_url is not https, mirrored media links can be observed in transit");
+        }
     }
 
     // Initialize IRC connection and identify with server
-    let irc_cfg = config.irc.clone();
-    let client = IrcServer::from_config(irc_cfg).expect("Could not connect to server, check configuration.");
-    if config.irc.password.is_some() {
-        client.send_sasl_plain().expect("Could not authenticate with SASL.");
-    }
-    client.identify().expect("Could not identify to server.");
+    let mut servers = Servers::new(&config);
+    let client = servers.connect().expect("Could not connect to any server, check configuration.");
+    servers::start(&config, &client).unwrap_or_else(|err| panic!("{}", err));
 
     // Initialize Telegram API and package into Arc
     let token = config.token.clone();
     let api = Api::from_token(&token).unwrap();
     let me = api.get_me().unwrap();
+    let username = me.username.unwrap_or(String::new());
     let arc_tg = Arc::new(api);
 
     // Setup Telegram <-> IRC bridges
@@ -380,28 +2258,248 @@ fn main() {
         tg_group: tg_group,
         irc_channel: irc_channel,
         chat_ids: chat_ids,
+        username: username.clone(),
+        activity: activity::Activity::new(),
+        relayed: relayed,
+        aliases: aliases,
+        factoids: factoids,
+        reminders: reminders,
+        pinned: pinned,
+        ambiguous: ambiguous,
+        media_budget: Arc::new(Budget::new(&config, store.clone())),
+        repeats: Default::default(),
+        irc_users: HashMap::new(),
+        group_admins: HashMap::new(),
+        tg_members: HashMap::new(),
+        corrections: Default::default(),
+        store: store,
     }));
 
-    println!("[INFO] Telegram username: @{}", me.username.unwrap());
+    println!("[INFO] Telegram username: @{}", username);
     println!("[INFO] IRC nick: {}", client.current_nickname());
 
     // Wait for a little bit because IRC sucks?
     thread::sleep(Duration::new(3, 0));
 
+    // Start the watchdog keeping an eye on the handler threads
+    let watchdog = Arc::new(Watchdog::new());
+    let stall_seconds = config.watchdog_seconds.unwrap_or(watchdog::DEFAULT_STALL_SECONDS);
+    if stall_seconds > 0 {
+        let watchdog = watchdog.clone();
+        let tg = arc_tg.clone();
+        let admins = config.admins.clone().unwrap_or(vec![]);
+        thread::spawn(move || watchdog::watch(watchdog, tg, admins, Duration::from_secs(stall_seconds)));
+    }
+
+    // Start threads delivering queued messages to irc and telegram
+    let current = CurrentServer::new(client.clone());
+    let outbound = Arc::new(spawn_outbound(current.clone(),
+                                           arc_tg.clone(),
+                                           &config,
+                                           state.clone(),
+                                           watchdog.clone()));
+
+    // Pick up where we left off, and keep the queues saved from now on
+    let persist_queues = config.persist_queues.unwrap_or(true);
+    if persist_queues {
+        let store = state.lock().unwrap().store.clone();
+        match store.load_queued() {
+            Ok(messages) => {
+                outbound.restore(messages);
+                // What was restored is being delivered now, and mustn't be again after a crash
+                save_queued(&*store, &outbound);
+            }
+            Err(err) => println!("[ERROR] {}", err.context("loading queued messages")),
+        }
+        let outbound = outbound.clone();
+        let interval = Duration::from_secs(config.queue_save_seconds.unwrap_or(DEFAULT_QUEUE_SAVE_SECONDS));
+        thread::spawn(move || {
+            loop {
+                thread::sleep(interval);
+                save_queued(&*store, &outbound);
+            }
+        });
+    }
+    {
+        let state = state.clone();
+        thread::spawn(move || {
+            loop {
+                thread::sleep(Duration::from_secs(RELAYED_SAVE_SECONDS));
+                save_relayed(&state);
+            }
+        });
+    }
+    {
+        let outbound = outbound.clone();
+        let state = state.clone();
+        // Taken now, a handler may hold the state for a while when we're asked to stop
+        let store = state.lock().unwrap().store.clone();
+        thread::spawn(move || {
+            if let Some(signal) = shutdown_signal.recv() {
+                println!("[INFO] Received {:?}, shutting down", signal);
+                if persist_queues {
+                    // Let the workers finish the messages they took off the queues, for a while
+                    outbound.close();
+                    let deadline = Instant::now() + Duration::from_secs(SHUTDOWN_GRACE_SECONDS);
+                    while !outbound.settled() && Instant::now() < deadline {
+                        thread::sleep(Duration::from_millis(100));
+                    }
+                    if !outbound.settled() {
+                        println!("[WARN] Some messages were still being delivered and may be lost");
+                    }
+                    save_queued(&*store, &outbound);
+                }
+                // Not worth waiting for a handler holding the state, the last periodic save
+                // has most of the relayed messages
+                if let Ok(state) = state.try_lock() {
+                    if let Err(err) = store.save_relayed(&state.relayed.entries()) {
+                        println!("[ERROR] Could not save relayed messages: {}", err);
+                    }
+                }
+                std::process::exit(0);
+            }
+        });
+    }
+
+    // Keep track of who administrates the mapped groups
+    if config.group_admins.unwrap_or(false) {
+        let token = config.token.clone();
+        let state = state.clone();
+        let minutes = config.group_admin_refresh_minutes.unwrap_or(admins::DEFAULT_REFRESH_MINUTES);
+        thread::spawn(move || admins::refresh(token, state, if minutes == 0 { 1 } else { minutes }));
+    }
+
+    // Deliver reminders as they come due, including the ones missed while we were down
+    {
+        let outbound = outbound.clone();
+        let state = state.clone();
+        thread::spawn(move || reminders::deliver(outbound, state));
+    }
+
+    // Show the health of the bridge in the group descriptions
+    if config.status_in_description.unwrap_or(false) {
+        let token = config.token.clone();
+        let outbound = outbound.clone();
+        let state = state.clone();
+        let minutes = config.status_refresh_minutes.unwrap_or(presence::DEFAULT_REFRESH_MINUTES);
+        thread::spawn(move || presence::run(token, outbound, state, if minutes == 0 { 1 } else { minutes }));
+    }
+
+    // Count the members of the Telegram groups for the members command, and post the
+    // member summaries
+    {
+        let token = config.token.clone();
+        let state = state.clone();
+        thread::spawn(move || members::count_telegram(token, state, members::COUNT_MINUTES));
+    }
+    if let Some(hours) = config.member_summary_hours {
+        let outbound = outbound.clone();
+        let state = state.clone();
+        thread::spawn(move || members::post_summaries(outbound, state, if hours == 0 { 1 } else { hours }));
+    }
+
+    // Post the daily digests
+    if let Some(hour) = config.daily_digest_hour {
+        let outbound = outbound.clone();
+        let state = state.clone();
+        thread::spawn(move || activity::post_digests(outbound, state, hour % 24));
+    }
+
+    // Poll the feeds
+    for feed in config.feeds.iter().flat_map(|feeds| feeds) {
+        let feed = feed.clone();
+        let config = config.clone();
+        let outbound = outbound.clone();
+        let state = state.clone();
+        thread::spawn(move || feeds::poll(feed, config, outbound, state));
+    }
+
+    // Accept notifications from other services
+    if let Some(inbound) = config.inbound.clone() {
+        let outbound = outbound.clone();
+        let state = state.clone();
+        thread::spawn(move || inbound::serve(inbound, outbound, state));
+    }
+
+    // Accept the events of Slack channels
+    if let Some(slack) = config.slack.clone() {
+        let config = config.clone();
+        let outbound = outbound.clone();
+        let state = state.clone();
+        thread::spawn(move || slack::serve(slack, config, outbound, state));
+    }
+
+    // Accept the outgoing webhooks of Mattermost or Rocket.Chat channels
+    if let Some(teamchat) = config.teamchat.clone() {
+        let config = config.clone();
+        let outbound = outbound.clone();
+        let state = state.clone();
+        thread::spawn(move || teamchat::serve(teamchat, config, outbound, state));
+    }
+
+    // Follow the Nostr channel
+    if let Some(nostr) = config.nostr.clone() {
+        let config = config.clone();
+        let outbound = outbound.clone();
+        let state = state.clone();
+        thread::spawn(move || nostr::run(nostr, config, outbound, state));
+    }
+
+    // Mirror the Mastodon account
+    if let Some(mastodon) = config.mastodon.clone() {
+        let config = config.clone();
+        let outbound = outbound.clone();
+        let state = state.clone();
+        thread::spawn(move || mastodon::poll(mastodon, config, outbound, state));
+    }
+
+    // Accept mail for the mappings
+    if let Some(email) = config.email.clone() {
+        let config = Arc::new(config.clone());
+        let outbound = outbound.clone();
+        let state = state.clone();
+        thread::spawn(move || email::listen(email, config, outbound, state));
+    }
+
+    // Dump the internal state to the log on SIGUSR1
+    {
+        let outbound = outbound.clone();
+        let state = state.clone();
+        thread::spawn(move || {
+            while dump_signal.recv().is_some() {
+                let dump = commands::dump(&state.lock().unwrap(), &outbound);
+                for line in dump.lines() {
+                    println!("[INFO] {}", line);
+                }
+            }
+        });
+    }
+
+    // Accept admin commands on the control socket
+    if let Some(ref path) = config.control_socket {
+        let path = path.clone();
+        let config = Arc::new(config.clone());
+        let state = state.clone();
+        let outbound = outbound.clone();
+        thread::spawn(move || control::listen(path, config, state, outbound));
+    }
+
     // Start threads handling irc and telegram
     let irc_handle = {
-        let client = client.clone();
-        let api = arc_tg.clone();
+        let current = current.clone();
+        let outbound = outbound.clone();
         let config = config.clone();
         let state = state.clone();
-        thread::spawn(move || handle_irc(client, api, config, state))
+        let watchdog = watchdog.clone();
+        thread::spawn(move || handle_irc(current, servers, outbound, config, state, watchdog))
     };
     let tg_handle = {
-        let client = client.clone();
         let api = arc_tg.clone();
+        let outbound = outbound.clone();
         let config = config.clone();
         let state = state.clone();
-        thread::spawn(move || handle_tg(client, api, config, state))
+        let watchdog = watchdog.clone();
+        thread::spawn(move || handle_tg(api, outbound, config, state, watchdog))
     };
 
     // Clean up threads. This should probably never need to be run, as this would imply
@@ -0,0 +1,163 @@
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use hyper::Url;
+use hyper::method::Method;
+use hyper::client::Request;
+use rustc_serialize::json::Json;
+use error::{self, ResultExt};
+use urls;
+use media;
+use budget::Budget;
+use outbound::Outbound;
+use templates;
+use super::{Config, MastodonConfig, RelayState, post_to_mapping};
+
+// Seconds between polls of the account, unless configured
+const DEFAULT_MASTODON_INTERVAL: u64 = 300;
+// Directory of the download directory attachments are mirrored to
+const MASTODON_MEDIA_DIR: &'static str = "_mastodon";
+
+struct Status {
+    id: String,
+    url: String,
+    text: String,
+    attachments: Vec<String>,
+}
+
+// Reduce the HTML of a post to its text
+fn strip_html(html: &str) -> String {
+    let html = html.replace("<br>", "\n").replace("<br />", "\n").replace("</p>", "\n");
+    let mut text = String::new();
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            c if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+        .trim()
+        .to_owned()
+}
+
+fn api_url(mastodon: &MastodonConfig, path: &str) -> error::Result<Url> {
+    urls::parse(&format!("{}/api/v1/{}", mastodon.instance.trim_right_matches('/'), path),
+                "Mastodon API url")
+}
+
+// The posts of the account newer than `since_id`, newest first. Boosts are left out.
+fn fetch(mastodon: &MastodonConfig, since_id: Option<&str>) -> error::Result<Vec<Status>> {
+    let mut url = try!(api_url(mastodon, &format!("accounts/{}/statuses", mastodon.account_id)));
+    if let Some(since_id) = since_id {
+        url.set_query_from_pairs(vec![("since_id", since_id)].into_iter());
+    }
+    let mut resp = try!(Request::new(Method::Get, url)
+                            .and_then(|req| req.start())
+                            .and_then(|req| req.send())
+                            .context("fetching posts"));
+    if !resp.status.is_success() {
+        return Err(format!("fetching posts: server responded with {}", resp.status).into());
+    }
+    let mut body = String::new();
+    try!(resp.read_to_string(&mut body).context("reading posts"));
+    let statuses = try!(Json::from_str(&body).map_err(|err| err.to_string()));
+    let statuses = try!(statuses.as_array().ok_or("expected a list of posts"));
+    Ok(statuses.iter()
+               .filter(|status| status.find("reblog").map_or(true, |reblog| reblog.is_null()))
+               .map(|status| {
+                   let string = |key: &str| status.find(key).and_then(|value| value.as_string()).unwrap_or("").to_owned();
+                   Status {
+                       id: string("id"),
+                       url: string("url"),
+                       text: strip_html(&string("content")),
+                       attachments: status.find("media_attachments")
+                                          .and_then(|attachments| attachments.as_array())
+                                          .map_or(vec![], |attachments| {
+                                              attachments.iter()
+                                                         .filter_map(|attachment| attachment.find("url").and_then(|url| url.as_string()))
+                                                         .map(|url| url.to_owned())
+                                                         .collect()
+                                          }),
+                   }
+               })
+               .collect())
+}
+
+// Link to an attachment, mirrored to our media directory if asked for
+fn attachment_url(config: &Config, budget: &Budget, mastodon: &MastodonConfig, url: &str) -> String {
+    if !mastodon.mirror_media.unwrap_or(false) {
+        return url.to_owned();
+    }
+    let mirrored = urls::parse(url, "attachment url")
+                       .and_then(|url| media::download_external(config, budget, &url, MASTODON_MEDIA_DIR));
+    match mirrored {
+        Ok(mirrored) => mirrored.to_string(),
+        Err(err) => {
+            println!("[WARN] {}", err.context("mirroring a Mastodon attachment"));
+            url.to_owned()
+        }
+    }
+}
+
+// Poll the account forever, posting its new posts to both sides of the mappings it is
+// configured for. Whatever was posted before we start has been seen already.
+pub fn poll(mastodon: MastodonConfig, config: Config, outbound: Arc<Outbound>, state: Arc<Mutex<RelayState>>) {
+    let interval = Duration::from_secs(mastodon.interval.unwrap_or(DEFAULT_MASTODON_INTERVAL));
+    let mut since_id: Option<String> = None;
+    let mut started = false;
+    let budget = state.lock().unwrap().media_budget.clone();
+    loop {
+        match fetch(&mastodon, since_id.as_ref().map(|id| &id[..])) {
+            Ok(statuses) => {
+                if let Some(newest) = statuses.first() {
+                    since_id = Some(newest.id.clone());
+                }
+                if started {
+                    for status in statuses.iter().rev() {
+                        let mut text = status.text.clone();
+                        for attachment in &status.attachments {
+                            text.push_str(&format!(" {}", attachment_url(&config, &budget, &mastodon, attachment)));
+                        }
+                        println!("[INFO] New post of Mastodon account {}: {}", mastodon.account_id, status.url);
+                        let state = state.lock().unwrap();
+                        for group in &mastodon.groups {
+                            let text = templates::render(&config, group, templates::FEED, &[("feed", &mastodon.name[..]),
+                                                                                            ("title", &text[..]),
+                                                                                            ("link", &status.url[..])]);
+                            post_to_mapping(&outbound, &state, group, text);
+                        }
+                    }
+                }
+                started = true;
+            }
+            Err(err) => println!("[WARN] {}", err.context(format!("polling Mastodon account {}", mastodon.account_id))),
+        }
+        thread::sleep(interval);
+    }
+}
+
+// Post an announcement to the account
+pub fn post(mastodon: &MastodonConfig, text: &str) -> error::Result<()> {
+    let token = try!(mastodon.access_token.as_ref().ok_or("no Mastodon access_token configured"));
+    let mut url = try!(api_url(mastodon, "statuses"));
+    url.set_query_from_pairs(vec![("status", text)].into_iter());
+    let resp = try!(Request::new(Method::Post, url)
+                        .and_then(|mut req| {
+                            req.headers_mut().set_raw("Authorization", vec![format!("Bearer {}", token).into_bytes()]);
+                            req.start()
+                        })
+                        .and_then(|req| req.send())
+                        .context("posting to Mastodon"));
+    if !resp.status.is_success() {
+        return Err(format!("posting to Mastodon: server responded with {}", resp.status).into());
+    }
+    Ok(())
+}
@@ -0,0 +1,739 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::{Read, Write, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+use hyper::Url;
+use hyper::method::Method;
+use hyper::client::Request;
+use hyper::header::{Range, ByteRangeSpec};
+use hyper::status::StatusCode;
+use rand::{Rng, OsRng};
+use telegram_bot::Api;
+use telegram_bot::types::{Integer, User, PhotoSize, Document, Audio, Video};
+use toml;
+use error::{self, ResultExt};
+use urls;
+use gallery::{self, MediaEntry};
+use mediastore;
+use budget::Budget;
+use super::{Config, ChatID, format_tg_nick, load_toml};
+
+const DEFAULT_CONNECT_TIMEOUT: u64 = 10;
+const DEFAULT_READ_TIMEOUT: u64 = 30;
+const DEFAULT_DOWNLOAD_TIMEOUT: u64 = 300;
+const DEFAULT_DOWNLOAD_RETRIES: u32 = 2;
+// Index of user ids to names, kept in the download directory
+const USER_INDEX_FILE: &'static str = "users.toml";
+// Longest original filename kept when mirroring documents
+const MAX_FILENAME_LEN: usize = 100;
+// Length of the random token prefixed to mirrored filenames
+const TOKEN_LENGTH: usize = 24;
+// Directory of the download directory stickers are cached in. Usernames can't start with
+// an underscore, so it can't clash with a user's directory.
+const STICKER_DIR: &'static str = "_stickers";
+// Link to add a sticker pack, followed by the pack's name
+pub const STICKER_PACK_URL: &'static str = "https://t.me/addstickers/";
+// Seconds between sweeps for expired media
+const EXPIRY_INTERVAL: u64 = 600;
+// Maximum number of downloaded chunks buffered between the download thread and the writer
+const CHUNK_BACKLOG: usize = 16;
+
+// Limits on how long a download may take before it is abandoned
+#[derive(Clone, Copy, Debug)]
+pub struct Timeouts {
+    // Time allowed to connect and receive the response headers
+    pub connect: Duration,
+    // Time allowed between consecutive reads of the response body
+    pub read: Duration,
+    // Time allowed for the whole download
+    pub total: Duration,
+}
+
+impl Timeouts {
+    pub fn from_config(config: &Config) -> Timeouts {
+        Timeouts {
+            connect: Duration::from_secs(config.download_connect_timeout.unwrap_or(DEFAULT_CONNECT_TIMEOUT)),
+            read: Duration::from_secs(config.download_read_timeout.unwrap_or(DEFAULT_READ_TIMEOUT)),
+            total: Duration::from_secs(config.download_timeout.unwrap_or(DEFAULT_DOWNLOAD_TIMEOUT)),
+        }
+    }
+}
+
+pub fn ensure_dir(path: &Path) {
+    let _ = fs::create_dir(&path);
+}
+
+// The message a mirrored file was sent in
+pub struct Origin<'a> {
+    pub user: &'a User,
+    pub chat_id: ChatID,
+    pub group: &'a str,
+    // Time the message was sent, as a unix timestamp
+    pub date: i64,
+    pub caption: Option<&'a str>,
+}
+
+// Files kept alongside the mirrored media, which must survive expiry
+fn is_metadata(path: &Path) -> bool {
+    match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) => name == USER_INDEX_FILE || name == gallery::MANIFEST_FILE || name == gallery::INDEX_FILE,
+        None => false,
+    }
+}
+
+// Human readable file size, e.g. "245 KB" or "1.2 MB"
+pub fn format_size(bytes: i64) -> String {
+    const KB: f64 = 1024.0;
+    const MB: f64 = 1024.0 * KB;
+    const GB: f64 = 1024.0 * MB;
+    let bytes = bytes as f64;
+    if bytes >= GB {
+        format!("{:.1} GB", bytes / GB)
+    } else if bytes >= MB {
+        format!("{:.1} MB", bytes / MB)
+    } else if bytes >= KB {
+        format!("{:.0} KB", bytes / KB)
+    } else {
+        format!("{} B", bytes)
+    }
+}
+
+// Format a duration in seconds as "3:45", or "1:02:03" when over an hour
+pub fn format_duration(seconds: i64) -> String {
+    if seconds >= 60 * 60 {
+        format!("{}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+    } else {
+        format!("{}:{:02}", seconds / 60, seconds % 60)
+    }
+}
+
+// Text relayed for an audio file, e.g. "sent audio: Artist – Title (3:45) <url>"
+pub fn describe_audio(audio: &Audio, url: Option<&Url>) -> String {
+    let mut description = match (audio.performer.as_ref(), audio.title.as_ref()) {
+        (Some(performer), Some(title)) => format!("sent audio: {} – {}", performer, title),
+        (None, Some(title)) => format!("sent audio: {}", title),
+        (Some(performer), None) => format!("sent audio: {}", performer),
+        (None, None) => "sent audio".into(),
+    };
+    description.push_str(&format!(" ({})", format_duration(audio.duration)));
+    if let Some(url) = url {
+        description.push_str(&format!(" {}", url));
+    }
+    description
+}
+
+// Text relayed for a video, e.g. "sent a video (1280×720, 2:13) <url>". Videos longer
+// than `long_after` seconds are flagged with `long_label` so people can decide whether
+// to click.
+pub fn describe_video(video: &Video, url: Option<&Url>, long_after: Option<i64>, long_label: &str) -> String {
+    let mut description = format!("sent a video ({}×{}, {})",
+                                  video.width,
+                                  video.height,
+                                  format_duration(video.duration));
+    if long_after.map_or(false, |limit| video.duration > limit) {
+        description.push_str(&format!(" {}", long_label));
+    }
+    if let Some(url) = url {
+        description.push_str(&format!(" {}", url));
+    }
+    description
+}
+
+// Reduce a user supplied filename to characters that are safe in paths. Letters of any
+// script are kept, URLs percent-encode them.
+pub fn sanitize_filename(name: &str) -> String {
+    let sanitized: String = name.chars()
+                                .map(|c| if c.is_alphanumeric() || c == '.' || c == '-' || c == '_' { c } else { '_' })
+                                .take(MAX_FILENAME_LEN)
+                                .collect();
+    // Don't produce hidden files or names like "..", nor names Windows drops the trailing
+    // dots of
+    let sanitized = sanitized.trim_matches('.');
+    if sanitized.is_empty() {
+        return "file".to_owned();
+    }
+    // Names of devices on Windows, with or without an extension
+    let stem = sanitized.split('.').next().unwrap_or("").to_uppercase();
+    let reserved = ["CON", "PRN", "AUX", "NUL"].contains(&&stem[..]) ||
+                   ((stem.starts_with("COM") || stem.starts_with("LPT")) && stem.len() == 4 &&
+                    stem[3..].chars().all(|c| c.is_digit(10)));
+    if reserved { format!("_{}", sanitized) } else { sanitized.to_owned() }
+}
+
+// Whether `name` can be used as a single directory or file name without ending up outside
+// the directory it is joined to, on unix as well as on Windows, where a colon names a drive
+// or a stream of the file
+pub fn is_safe_component(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." &&
+    !name.contains(|c: char| c == '/' || c == '\\' || c == ':' || c == '\0')
+}
+
+// Text relayed in place of a photo that isn't mirrored
+pub fn describe_photo(photo: &PhotoSize) -> String {
+    match photo.file_size {
+        Some(size) => format!("sent a photo ({}×{}, {})", photo.width, photo.height, format_size(size)),
+        None => format!("sent a photo ({}×{})", photo.width, photo.height),
+    }
+}
+
+// Text relayed in place of a document that isn't mirrored
+pub fn describe_document(doc: &Document) -> String {
+    let name = match doc.file_name {
+        Some(ref name) => format!("document {}", name),
+        None => "a document".into(),
+    };
+    match doc.file_size {
+        Some(size) => format!("sent {} ({})", name, format_size(size)),
+        None => format!("sent {}", name),
+    }
+}
+
+// Media is kept in a directory per user, named by their numeric id since usernames are
+// optional and can change
+pub fn user_path(user: &User) -> String {
+    user.id.to_string()
+}
+
+// Entry in the index of user directories, letting operators find a user's media by name
+#[derive(Clone, Default, PartialEq, RustcEncodable, RustcDecodable, Debug)]
+struct IndexedUser {
+    name: String,
+    // Empty if the user has no username
+    username: String,
+}
+
+fn load_user_index(download_dir: &Path) -> HashMap<String, IndexedUser> {
+    let path = download_dir.join(USER_INDEX_FILE);
+    if path.exists() {
+        load_toml(&path.to_string_lossy())
+    } else {
+        HashMap::new()
+    }
+}
+
+// Record the user's current names in the index kept next to the media directories
+fn update_user_index(download_dir: &Path, user: &User) -> error::Result<()> {
+    let mut index = load_user_index(download_dir);
+    let entry = IndexedUser {
+        name: format_tg_nick(user),
+        username: user.username.clone().unwrap_or(String::new()),
+    };
+    if index.get(&user_path(user)) == Some(&entry) {
+        return Ok(());
+    }
+    index.insert(user_path(user), entry);
+    let path = download_dir.join(USER_INDEX_FILE);
+    let mut file = try!(File::create(&path).context(format!("creating {}", path.display())));
+    try!(file.write_all(toml::encode_str(&index).as_bytes()));
+    Ok(())
+}
+
+enum Chunk {
+    // The response has started. `resumed` is false if the server ignored the requested
+    // range and is sending the file from the beginning.
+    Start { resumed: bool },
+    Data(Vec<u8>),
+    End,
+}
+
+// Perform the request on a separate thread, streaming the body back in chunks. The thread
+// exits once its receiver goes away, or once the connection stalls for longer than the
+// socket timeouts, so a stalled download doesn't keep it around.
+fn fetch(url: &Url, offset: u64, timeouts: &Timeouts) -> mpsc::Receiver<error::Result<Chunk>> {
+    let (tx, rx) = mpsc::sync_channel(CHUNK_BACKLOG);
+    let url = url.clone();
+    // The first read waits for the response headers
+    let read_timeout = cmp::max(timeouts.connect, timeouts.read);
+    let write_timeout = timeouts.connect;
+    thread::spawn(move || {
+        let resp = Request::new(Method::Get, url)
+                       .and_then(|mut req| {
+                           try!(req.set_read_timeout(Some(read_timeout)));
+                           try!(req.set_write_timeout(Some(write_timeout)));
+                           // Pick up where a previous attempt left off
+                           if offset > 0 {
+                               req.headers_mut().set(Range::Bytes(vec![ByteRangeSpec::AllFrom(offset)]));
+                           }
+                           req.start()
+                       })
+                       .and_then(|req| req.send());
+        let mut resp = match resp {
+            Ok(resp) => resp,
+            Err(err) => {
+                let _ = tx.send(Err(err.into()));
+                return;
+            }
+        };
+        if !resp.status.is_success() {
+            let _ = tx.send(Err(format!("server responded with {}", resp.status).into()));
+            return;
+        }
+        let resumed = offset > 0 && resp.status == StatusCode::PartialContent;
+        if tx.send(Ok(Chunk::Start { resumed: resumed })).is_err() {
+            return;
+        }
+        loop {
+            let mut buf = vec![0; 8192];
+            match resp.read(&mut buf) {
+                Ok(0) => {
+                    let _ = tx.send(Ok(Chunk::End));
+                    return;
+                }
+                Ok(n) => {
+                    buf.truncate(n);
+                    if tx.send(Ok(Chunk::Data(buf))).is_err() {
+                        // The download was abandoned
+                        return;
+                    }
+                }
+                Err(err) => {
+                    let _ = tx.send(Err(err.into()));
+                    return;
+                }
+            }
+        }
+    });
+    rx
+}
+
+// Copy the body of `url` into `file`, starting at `offset` if the server supports it, and
+// giving up once any of the timeouts is exceeded.
+fn fetch_to_file(url: &Url, file: &mut File, offset: u64, timeouts: &Timeouts) -> error::Result<()> {
+    let chunks = fetch(url, offset, timeouts);
+    let deadline = Instant::now() + timeouts.total;
+    let mut timeout = timeouts.connect;
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(format!("download did not finish within {}s", timeouts.total.as_secs()).into());
+        }
+        if deadline - now < timeout {
+            timeout = deadline - now;
+        }
+        match chunks.recv_timeout(timeout) {
+            Ok(Ok(Chunk::Start { resumed })) => {
+                if resumed {
+                    try!(file.seek(SeekFrom::Start(offset)));
+                } else {
+                    try!(file.set_len(0));
+                    try!(file.seek(SeekFrom::Start(0)));
+                }
+            }
+            Ok(Ok(Chunk::Data(chunk))) => try!(file.write_all(&chunk)),
+            Ok(Ok(Chunk::End)) => return Ok(()),
+            Ok(Err(err)) => return Err(err),
+            Err(RecvTimeoutError::Timeout) => {
+                return Err(format!("timed out after {}s", timeout.as_secs()).into())
+            }
+            Err(RecvTimeoutError::Disconnected) => return Err("download thread exited".into()),
+        }
+        timeout = timeouts.read;
+    }
+}
+
+// Download `url` to `path`, retrying interrupted transfers up to `retries` times and
+// checking the result against `expected_size` when it is known.
+pub fn download_file(url: &Url,
+                     path: &Path,
+                     expected_size: Option<u64>,
+                     retries: u32,
+                     timeouts: &Timeouts)
+                     -> error::Result<()> {
+    // Open file and copy downloaded data, making sure not to leave a partial file behind
+    let mut file = try!(File::create(path).context(format!("creating {}", path.display())));
+    let mut attempt = 0;
+    loop {
+        let result = download_attempt(url, &mut file, expected_size, timeouts);
+        match result {
+            Ok(()) => return Ok(()),
+            Err(err) => {
+                if attempt >= retries {
+                    let _ = fs::remove_file(path);
+                    return Err(err.context(format!("GET {}", urls::redacted(url))));
+                }
+                attempt += 1;
+                println!("[WARN] Download of {} failed, retrying ({}/{}): {}",
+                         urls::redacted(url),
+                         attempt,
+                         retries,
+                         err);
+            }
+        }
+    }
+}
+
+// Fetch whatever part of the file is still missing and verify the final size
+fn download_attempt(url: &Url, file: &mut File, expected_size: Option<u64>, timeouts: &Timeouts) -> error::Result<()> {
+    let mut offset = try!(file.metadata()).len();
+    if expected_size.map_or(false, |size| offset >= size) {
+        // Whatever we have is not what we expected, start over
+        try!(file.set_len(0));
+        try!(file.seek(SeekFrom::Start(0)));
+        offset = 0;
+    }
+    try!(fetch_to_file(url, file, offset, timeouts));
+
+    let size = try!(file.metadata()).len();
+    match expected_size {
+        Some(expected) if size != expected => {
+            Err(format!("expected {} bytes, but received {}", expected, size).into())
+        }
+        _ => Ok(()),
+    }
+}
+
+// A file on its way to being hosted, wherever it came from. Every attachment goes through
+// the same pipeline: fetch, check against the size limit, put in the media store and hand
+// out the URL of the stored copy.
+pub struct Attachment {
+    // Where the file is fetched from
+    pub source: Url,
+    pub expected_size: Option<u64>,
+    // Directory it is stored in, such as the id of the user that sent it
+    pub dir: String,
+    pub filename: String,
+    // Files that are sent over and over again keep a fixed name and are only fetched the
+    // first time. Others may get a random token prefixed to their name.
+    pub cached: bool,
+}
+
+// Where a Telegram file can be fetched from, and its size if Telegram knows it
+fn telegram_source(tg: &Api, file_id: &str) -> error::Result<(Url, Option<u64>)> {
+    let file = try!(tg.get_file(file_id).context(format!("looking up file {}", file_id)));
+    let path = try!(file.file_path.ok_or(format!("no file path for file {}", file_id)));
+    let url = try!(urls::parse(&tg.get_file_url(&path), "Telegram file url"));
+    Ok((url, file.file_size.map(|size| size as u64)))
+}
+
+// The last portion of the path of `url`
+fn url_filename(url: &Url) -> error::Result<String> {
+    url.path()
+       .and_then(|path| path.last())
+       .cloned()
+       .ok_or(format!("no filename in {}", urls::redacted(url)).into())
+}
+
+fn check_size(size: u64, max_size: Option<u64>) -> error::Result<()> {
+    match max_size {
+        Some(max_size) if size > max_size => {
+            Err(format!("{} is larger than the limit of {}", format_size(size as i64), format_size(max_size as i64)).into())
+        }
+        _ => Ok(()),
+    }
+}
+
+// Store `attachment` in the configured media store, returning the URL of the stored copy
+// and its path within the store. The traffic is counted against `budget`.
+pub fn host(config: &Config, budget: &Budget, attachment: &Attachment) -> error::Result<(Url, PathBuf)> {
+    let store = try!(mediastore::open(config));
+    let max_size = config.max_attachment_size;
+
+    let mut filename = attachment.filename.clone();
+    if !attachment.cached && config.media_url_tokens.unwrap_or(false) {
+        filename = format!("{}-{}", try!(random_token()), filename);
+    }
+    let meta = mediastore::Meta {
+        dir: &attachment.dir,
+        filename: &filename,
+    };
+    let path = Path::new(&attachment.dir).join(&filename);
+    if attachment.cached {
+        if let Some(url) = store.stored(&meta) {
+            return Ok((url, path));
+        }
+    }
+
+    try!(budget.check(attachment.expected_size));
+    if let Some(size) = attachment.expected_size {
+        try!(check_size(size, max_size));
+    }
+    // Fetch into a staging file first, so interrupted downloads can be resumed
+    let staging = env::temp_dir().join(format!("tiercel-{}", try!(random_token())));
+    try!(download_file(&attachment.source,
+                       &staging,
+                       attachment.expected_size,
+                       config.download_retries.unwrap_or(DEFAULT_DOWNLOAD_RETRIES),
+                       &Timeouts::from_config(config)));
+    // The size isn't always known up front
+    let size = try!(fs::metadata(&staging)).len();
+    budget.spend(size);
+    if let Err(err) = check_size(size, max_size) {
+        let _ = fs::remove_file(&staging);
+        return Err(err);
+    }
+    let bytes = try!(mediastore::take(&staging));
+    let url = try!(store.put(&bytes, &meta));
+    // Uploads elsewhere are traffic too
+    if !mediastore::is_local(config) {
+        budget.spend(size);
+    }
+    Ok((url, path))
+}
+
+// Download a Telegram file into the media directory of the user that sent it, returning
+// the URL where the mirrored copy can be found.
+// `name` is the original filename of the file, if it had one.
+pub fn download_file_user(tg: &Api,
+                          config: &Config,
+                          budget: &Budget,
+                          origin: &Origin,
+                          file_id: &str,
+                          name: Option<&str>)
+                          -> error::Result<Url> {
+    let user = origin.user;
+    let (source, expected_size) = try!(telegram_source(tg, file_id));
+
+    // Keep Telegram's name for the file, and the original name as well if asked for, so
+    // files of the same name can't collide
+    let mut filename = sanitize_filename(&try!(url_filename(&source)));
+    if let Some(name) = name {
+        if config.media_keep_filenames.unwrap_or(false) {
+            let stem = filename.split('.').next().unwrap_or("").to_owned();
+            filename = format!("{}-{}", stem, sanitize_filename(name));
+        }
+    }
+
+    let (url, path) = try!(host(config,
+                                budget,
+                                &Attachment {
+                                    source: source,
+                                    expected_size: expected_size,
+                                    dir: user_path(user),
+                                    filename: filename,
+                                    cached: false,
+                                }));
+
+    // Both are kept in the download directory, next to the media they describe
+    if !mediastore::is_local(config) {
+        return Ok(url);
+    }
+    let download_dir = PathBuf::from(config.download_dir.clone().unwrap_or(String::new()));
+    if let Err(err) = update_user_index(&download_dir, user) {
+        println!("[WARN] {}", err.context("updating the media user index"));
+    }
+    if config.media_index.unwrap_or(false) {
+        let entry = MediaEntry {
+            url: url.to_string(),
+            path: path.components()
+                      .map(|component| component.as_os_str().to_string_lossy().into_owned())
+                      .collect::<Vec<_>>()
+                      .join("/"),
+            user_id: user.id,
+            user: format_tg_nick(user),
+            chat_id: origin.chat_id,
+            group: origin.group.to_owned(),
+            date: origin.date,
+            caption: origin.caption.unwrap_or("").to_owned(),
+        };
+        if let Err(err) = gallery::record(&download_dir, entry) {
+            println!("[WARN] {}", err.context("updating the media index"));
+        }
+    }
+    Ok(url)
+}
+
+// Mirror a sticker, returning the URL of the mirrored copy. Stickers are sent over and
+// over again, so they are cached by file id and only downloaded the first time.
+pub fn download_sticker(tg: &Api, config: &Config, budget: &Budget, file_id: &str) -> error::Result<Url> {
+    let filename = format!("{}.webp", sanitize_filename(file_id));
+    // Only look the file up with Telegram when it isn't cached yet
+    let stored = try!(mediastore::open(config)).stored(&mediastore::Meta {
+        dir: STICKER_DIR,
+        filename: &filename,
+    });
+    if let Some(url) = stored {
+        return Ok(url);
+    }
+    let (source, expected_size) = try!(telegram_source(tg, file_id));
+    host(config,
+         budget,
+         &Attachment {
+             source: source,
+             expected_size: expected_size,
+             dir: STICKER_DIR.to_owned(),
+             filename: filename,
+             cached: true,
+         })
+        .map(|(url, _)| url)
+}
+
+// Mirror a file from elsewhere on the web into `dir` of the download directory, returning
+// the URL of the mirrored copy
+pub fn download_external(config: &Config, budget: &Budget, url: &Url, dir: &str) -> error::Result<Url> {
+    let filename = sanitize_filename(&try!(url_filename(url)));
+    host(config,
+         budget,
+         &Attachment {
+             source: url.clone(),
+             expected_size: None,
+             dir: dir.to_owned(),
+             filename: filename,
+             cached: false,
+         })
+        .map(|(url, _)| url)
+}
+
+// Count the files in `dir` and everything below it
+fn count_files(dir: &Path) -> error::Result<usize> {
+    let mut count = 0;
+    for entry in try!(fs::read_dir(dir)) {
+        let path = try!(entry).path();
+        if path.is_dir() {
+            count += try!(count_files(&path));
+        } else if !is_metadata(&path) {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+// The ids of the Telegram user `user` names: their id, or the ids the media index knows
+// their username for
+pub fn user_ids(config: &Config, user: &str) -> Vec<Integer> {
+    if let Ok(id) = user.parse() {
+        return vec![id];
+    }
+    let download_dir = match config.download_dir {
+        Some(ref download_dir) if mediastore::is_local(config) => PathBuf::from(download_dir),
+        _ => return vec![],
+    };
+    let username = user.trim_left_matches('@');
+    load_user_index(&download_dir)
+        .iter()
+        .filter(|&(_, entry)| !entry.username.is_empty() && entry.username == username)
+        .filter_map(|(id, _)| id.parse().ok())
+        .collect()
+}
+
+// Delete all media mirrored for the users `ids`, returning the number of files removed.
+// Only media in the download directory can be deleted: S3 and imgur don't let us find the
+// files of a user again.
+pub fn purge_user(config: &Config, ids: &[Integer]) -> error::Result<usize> {
+    if !mediastore::is_local(config) {
+        return Err(format!("purge only deletes media kept in download_dir, media stored with {} has to be deleted \
+                            there",
+                           mediastore::kind(config))
+                       .into());
+    }
+    let download_dir = PathBuf::from(try!(config.download_dir.clone()
+                                              .ok_or("download_dir is not configured")));
+
+    // Media is stored by user id, see `user_path`
+    let mut count = 0;
+    let mut forgotten = false;
+    let mut index = load_user_index(&download_dir);
+    for id in ids {
+        let name = id.to_string();
+        let dir = download_dir.join(&name);
+        if dir.is_dir() {
+            count += try!(count_files(&dir));
+            try!(fs::remove_dir_all(&dir).context(format!("removing {}", dir.display())));
+        }
+        forgotten |= index.remove(&name).is_some();
+    }
+
+    // Forget the names of the purged users as well
+    if forgotten {
+        let path = download_dir.join(USER_INDEX_FILE);
+        let mut file = try!(File::create(&path).context(format!("creating {}", path.display())));
+        try!(file.write_all(toml::encode_str(&index).as_bytes()));
+    }
+    try!(gallery::rebuild(&download_dir).context("updating the media index"));
+    Ok(count)
+}
+
+pub fn random_token() -> error::Result<String> {
+    let mut rng = try!(OsRng::new().context("opening the OS random number generator"));
+    Ok(rng.gen_ascii_chars().take(TOKEN_LENGTH).collect())
+}
+
+// Delete every mirrored file under `dir` that is older than `max_age`
+fn expire_files(dir: &Path, max_age: Duration) -> error::Result<()> {
+    for entry in try!(fs::read_dir(dir)) {
+        let path = try!(entry).path();
+        let metadata = try!(fs::metadata(&path));
+        if metadata.is_dir() {
+            try!(expire_files(&path, max_age));
+        } else if !is_metadata(&path) {
+            let age = try!(metadata.modified()).elapsed().unwrap_or(Duration::from_secs(0));
+            if age > max_age {
+                println!("[INFO] Removing expired media file \"{}\"", path.display());
+                try!(fs::remove_file(&path).context(format!("removing {}", path.display())));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Periodically remove mirrored media once it is older than `max_age`, so that links to
+// it stop working
+pub fn expire_media(dir: PathBuf, max_age: Duration) {
+    loop {
+        if let Err(err) = expire_files(&dir, max_age).and_then(|()| gallery::rebuild(&dir)) {
+            println!("[ERROR] {}", err.context("expiring mirrored media"));
+        }
+        thread::sleep(Duration::from_secs(EXPIRY_INTERVAL));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_safe_component, sanitize_filename};
+
+    #[test]
+    fn sanitize_filename_keeps_plain_names() {
+        assert_eq!(sanitize_filename("photo-1_a.jpg"), "photo-1_a.jpg");
+    }
+
+    #[test]
+    fn sanitize_filename_defuses_parent_directories() {
+        assert_eq!(sanitize_filename(".."), "file");
+        assert_eq!(sanitize_filename("."), "file");
+        assert_eq!(sanitize_filename("../../etc/passwd"), "_.._etc_passwd");
+        assert!(is_safe_component(&sanitize_filename("../../etc/passwd")));
+    }
+
+    #[test]
+    fn sanitize_filename_defuses_absolute_paths() {
+        assert_eq!(sanitize_filename("/etc/passwd"), "_etc_passwd");
+        assert_eq!(sanitize_filename("C:\\Windows\\win.ini"), "C__Windows_win.ini");
+    }
+
+    #[test]
+    fn sanitize_filename_replaces_separators_and_nul() {
+        assert_eq!(sanitize_filename("a/b\\c"), "a_b_c");
+        assert_eq!(sanitize_filename("a\0b"), "a_b");
+        assert_eq!(sanitize_filename(""), "file");
+    }
+
+    #[test]
+    fn sanitize_filename_avoids_windows_device_names() {
+        assert_eq!(sanitize_filename("con.txt"), "_con.txt");
+        assert_eq!(sanitize_filename("COM1"), "_COM1");
+        assert_eq!(sanitize_filename("COMPUTER"), "COMPUTER");
+    }
+
+    #[test]
+    fn is_safe_component_accepts_names() {
+        assert!(is_safe_component("123"));
+        assert!(is_safe_component("photo.jpg"));
+        assert!(is_safe_component("..photo"));
+    }
+
+    #[test]
+    fn is_safe_component_rejects_traversal() {
+        assert!(!is_safe_component(""));
+        assert!(!is_safe_component("."));
+        assert!(!is_safe_component(".."));
+        assert!(!is_safe_component("/etc"));
+        assert!(!is_safe_component("a/b"));
+        assert!(!is_safe_component("..\\secret"));
+        assert!(!is_safe_component("C:"));
+        assert!(!is_safe_component("a\0b"));
+    }
+}
@@ -0,0 +1,282 @@
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use crypto::digest::Digest;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use hyper::Url;
+use hyper::header::ContentLength;
+use hyper::method::Method;
+use hyper::client::Request;
+use rustc_serialize::base64::{self, ToBase64};
+use rustc_serialize::hex::ToHex;
+use rustc_serialize::json::Json;
+use time;
+use error::{self, ResultExt};
+use urls;
+use media::{ensure_dir, is_safe_component};
+use super::{Config, ImgurConfig, S3Config};
+
+const IMGUR_UPLOAD_URL: &'static str = "https://api.imgur.com/3/image";
+
+// What is known about a file being stored
+pub struct Meta<'a> {
+    // Directory the file belongs in, such as the id of the user that sent it
+    pub dir: &'a str,
+    pub filename: &'a str,
+}
+
+// Where mirrored media is hosted
+pub trait MediaStore {
+    // URL of the file if it is stored already, so files sent over and over again are only
+    // fetched once. Stores that can't tell return None.
+    fn stored(&self, meta: &Meta) -> Option<Url>;
+    // Store the file, returning the URL it can be found at
+    fn put(&self, bytes: &[u8], meta: &Meta) -> error::Result<Url>;
+}
+
+// The download directory, served by a web server at the base URL
+pub struct LocalStore {
+    download_dir: PathBuf,
+    base_url: Url,
+}
+
+impl LocalStore {
+    fn url(&self, meta: &Meta) -> error::Result<Url> {
+        urls::join(&self.base_url, &[meta.dir, meta.filename])
+    }
+
+    // Where the file goes in the download directory, refusing names that would put it
+    // anywhere else
+    fn path(&self, meta: &Meta) -> error::Result<PathBuf> {
+        for name in &[meta.dir, meta.filename] {
+            if !is_safe_component(name) {
+                return Err(format!("refusing to store media under \"{}\"", name).into());
+            }
+        }
+        Ok(self.download_dir.join(meta.dir).join(meta.filename))
+    }
+}
+
+impl MediaStore for LocalStore {
+    fn stored(&self, meta: &Meta) -> Option<Url> {
+        match self.path(meta) {
+            Ok(ref path) if path.is_file() => self.url(meta).ok(),
+            _ => None,
+        }
+    }
+
+    fn put(&self, bytes: &[u8], meta: &Meta) -> error::Result<Url> {
+        let path = try!(self.path(meta));
+        ensure_dir(&self.download_dir.join(meta.dir));
+        try!(File::create(&path)
+                 .and_then(|mut file| file.write_all(bytes))
+                 .context(format!("writing {}", path.display())));
+        self.url(meta)
+    }
+}
+
+// An S3 bucket, or a bucket of a service speaking the same API
+pub struct S3Store {
+    config: S3Config,
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input(data);
+    hasher.result_str()
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::new(Sha256::new(), key);
+    mac.input(data.as_bytes());
+    mac.result().code().to_vec()
+}
+
+// Content type to store a file with, by its extension
+fn content_type(filename: &str) -> &'static str {
+    match &filename.rsplit('.').next().unwrap_or("").to_lowercase()[..] {
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "mp4" => "video/mp4",
+        "mp3" => "audio/mpeg",
+        "ogg" | "oga" => "audio/ogg",
+        "pdf" => "application/pdf",
+        "txt" => "text/plain; charset=utf-8",
+        "json" => "application/json",
+        _ => "application/octet-stream",
+    }
+}
+
+impl S3Store {
+    fn host(&self) -> String {
+        match self.config.endpoint {
+            Some(ref endpoint) => format!("{}.{}", self.config.bucket, endpoint),
+            None => format!("{}.s3.{}.amazonaws.com", self.config.bucket, self.config.region),
+        }
+    }
+
+    fn key(meta: &Meta) -> String {
+        // Encoded the way AWS expects in signed requests
+        format!("/{}/{}", urls::encode_segment(meta.dir), urls::encode_segment(meta.filename))
+    }
+
+    // Authorization header for a PUT of a body with hash `payload_hash`, signed with AWS
+    // signature version 4
+    fn authorization(&self, host: &str, key: &str, payload_hash: &str, amz_date: &str, date_stamp: &str) -> String {
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical_request = format!("PUT\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+                                        key,
+                                        host,
+                                        payload_hash,
+                                        amz_date,
+                                        signed_headers,
+                                        payload_hash);
+        let scope = format!("{}/{}/s3/aws4_request", date_stamp, self.config.region);
+        let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}",
+                                     amz_date,
+                                     scope,
+                                     sha256_hex(canonical_request.as_bytes()));
+        let signing_key = [&self.config.region[..], "s3", "aws4_request"]
+                              .iter()
+                              .fold(hmac_sha256(format!("AWS4{}", self.config.secret_key).as_bytes(), date_stamp),
+                                    |key, part| hmac_sha256(&key, part));
+        format!("AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                self.config.access_key,
+                scope,
+                signed_headers,
+                hmac_sha256(&signing_key, &string_to_sign).to_hex())
+    }
+}
+
+impl MediaStore for S3Store {
+    fn stored(&self, _meta: &Meta) -> Option<Url> {
+        None
+    }
+
+    fn put(&self, bytes: &[u8], meta: &Meta) -> error::Result<Url> {
+        let host = self.host();
+        let key = S3Store::key(meta);
+        let url = try!(urls::parse(&format!("https://{}{}", host, key), "S3 url"));
+        let now = time::now_utc();
+        let amz_date = try!(time::strftime("%Y%m%dT%H%M%SZ", &now).map_err(|err| err.to_string()));
+        let date_stamp = try!(time::strftime("%Y%m%d", &now).map_err(|err| err.to_string()));
+        let payload_hash = sha256_hex(bytes);
+        let authorization = self.authorization(&host, &key, &payload_hash, &amz_date, &date_stamp);
+
+        let resp = try!(Request::new(Method::Put, url.clone())
+                            .and_then(|mut req| {
+                                {
+                                    let headers = req.headers_mut();
+                                    headers.set(ContentLength(bytes.len() as u64));
+                                    headers.set_raw("Content-Type", vec![content_type(meta.filename).as_bytes().to_vec()]);
+                                    headers.set_raw("x-amz-content-sha256", vec![payload_hash.clone().into_bytes()]);
+                                    headers.set_raw("x-amz-date", vec![amz_date.clone().into_bytes()]);
+                                    headers.set_raw("Authorization", vec![authorization.clone().into_bytes()]);
+                                }
+                                req.start()
+                            })
+                            .and_then(|mut req| {
+                                try!(req.write_all(bytes));
+                                req.send()
+                            })
+                            .context("uploading to S3"));
+        if !resp.status.is_success() {
+            return Err(format!("uploading to S3: server responded with {}", resp.status).into());
+        }
+        match self.config.public_url {
+            Some(ref public_url) => {
+                urls::parse(&format!("{}{}", public_url.trim_right_matches('/'), key), "public S3 url")
+            }
+            None => Ok(url),
+        }
+    }
+}
+
+// Anonymous uploads to imgur, which only takes images and short videos
+pub struct ImgurStore {
+    config: ImgurConfig,
+}
+
+impl MediaStore for ImgurStore {
+    fn stored(&self, _meta: &Meta) -> Option<Url> {
+        None
+    }
+
+    fn put(&self, bytes: &[u8], _meta: &Meta) -> error::Result<Url> {
+        let url = try!(urls::parse(IMGUR_UPLOAD_URL, "imgur upload url"));
+        let image = bytes.to_base64(base64::STANDARD).replace("+", "%2B").replace("/", "%2F").replace("=", "%3D");
+        let body = format!("type=base64&image={}", image);
+        let mut resp = try!(Request::new(Method::Post, url)
+                                .and_then(|mut req| {
+                                    {
+                                        let headers = req.headers_mut();
+                                        headers.set(ContentLength(body.len() as u64));
+                                        headers.set_raw("Content-Type", vec![b"application/x-www-form-urlencoded".to_vec()]);
+                                        headers.set_raw("Authorization",
+                                                        vec![format!("Client-ID {}", self.config.client_id).into_bytes()]);
+                                    }
+                                    req.start()
+                                })
+                                .and_then(|mut req| {
+                                    try!(req.write_all(body.as_bytes()));
+                                    req.send()
+                                })
+                                .context("uploading to imgur"));
+        let mut response = String::new();
+        try!(resp.read_to_string(&mut response).context("reading the imgur response"));
+        if !resp.status.is_success() {
+            return Err(format!("uploading to imgur: server responded with {}: {}", resp.status, response).into());
+        }
+        let response = try!(Json::from_str(&response).map_err(|err| err.to_string()));
+        let link = try!(response.find_path(&["data", "link"])
+                                .and_then(|link| link.as_string())
+                                .ok_or("no link in the imgur response"));
+        urls::parse(link, "imgur link")
+    }
+}
+
+pub fn kind(config: &Config) -> &str {
+    config.media_store.as_ref().map_or("local", |kind| &kind[..])
+}
+
+// Whether media is kept in the download directory, where indexes of it can be kept too
+pub fn is_local(config: &Config) -> bool {
+    kind(config) == "local"
+}
+
+// Open the media store selected in the config
+pub fn open(config: &Config) -> error::Result<Box<MediaStore>> {
+    let kind = kind(config);
+    match kind {
+        "local" => {
+            let download_dir = try!(config.download_dir.clone().ok_or("download_dir is not configured"));
+            let base_url = try!(urls::base_url(config));
+            Ok(Box::new(LocalStore {
+                download_dir: PathBuf::from(download_dir),
+                base_url: base_url,
+            }))
+        }
+        "s3" => {
+            let s3 = try!(config.s3.clone().ok_or("media_store is \"s3\", but there is no s3 section"));
+            Ok(Box::new(S3Store { config: s3 }))
+        }
+        "imgur" => {
+            let imgur = try!(config.imgur.clone().ok_or("media_store is \"imgur\", but there is no imgur section"));
+            Ok(Box::new(ImgurStore { config: imgur }))
+        }
+        _ => Err(format!("unknown media_store \"{}\"", kind).into()),
+    }
+}
+
+// Read a downloaded file to hand it to a store, removing it afterwards
+pub fn take(path: &Path) -> error::Result<Vec<u8>> {
+    let mut bytes = vec![];
+    let read = File::open(path).and_then(|mut file| file.read_to_end(&mut bytes));
+    let _ = fs::remove_file(path);
+    try!(read.context(format!("reading {}", path.display())));
+    Ok(bytes)
+}
@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use hyper::method::Method;
+use hyper::client::Request;
+use irc::client::prelude::ServerExt;
+use rustc_serialize::json::Json;
+use time;
+use error::{self, ResultExt};
+use outbound::{IrcLine, Outbound};
+use urls;
+use super::{ChatID, RelayState};
+
+// How often the members of the Telegram groups are counted
+pub const COUNT_MINUTES: u64 = 10;
+
+// Number of members of a Telegram chat, the bot included
+fn member_count(token: &str, chat_id: ChatID) -> error::Result<u64> {
+    let mut url = try!(urls::parse(&format!("https://api.telegram.org/bot{}/getChatMemberCount", token),
+                                   "getChatMemberCount url"));
+    url.set_query_from_pairs(vec![("chat_id", chat_id.to_string())].iter().map(|&(key, ref value)| (key, &value[..])));
+    let mut resp = try!(Request::new(Method::Get, url)
+                            .and_then(|req| req.start())
+                            .and_then(|req| req.send())
+                            .context(format!("counting the members of chat {}", chat_id)));
+    if !resp.status.is_success() {
+        return Err(format!("counting the members of chat {}: server responded with {}", chat_id, resp.status).into());
+    }
+    let mut body = String::new();
+    try!(resp.read_to_string(&mut body).context("reading the member count"));
+    let reply = try!(Json::from_str(&body).map_err(|err| err.to_string()));
+    reply.find("result").and_then(|count| count.as_u64()).ok_or("expected a member count".into())
+}
+
+// Refresh the number of users in each mapped IRC channel, as the IRC client tracks them.
+// Called whenever someone joins or leaves.
+pub fn refresh<T: ServerExt>(irc: &T, state: &mut RelayState) {
+    let counts = state.tg_group
+                      .keys()
+                      .filter_map(|channel| irc.list_users(channel).map(|users| (channel.clone(), users.len())))
+                      .collect();
+    state.irc_users = counts;
+}
+
+// Count the members of every mapped Telegram group every `minutes` minutes. Telegram is
+// asked without holding the state, a slow answer mustn't hold up relaying. A group that
+// can't be counted keeps its count from before.
+pub fn count_telegram(token: String, state: Arc<Mutex<RelayState>>, minutes: u64) {
+    loop {
+        let chat_ids: HashMap<_, _> = state.lock().unwrap().chat_ids.clone();
+        for (group, id) in chat_ids {
+            match member_count(&token, id) {
+                Ok(count) => {
+                    state.lock().unwrap().tg_members.insert(group, count);
+                }
+                Err(err) => println!("[WARN] {}", err),
+            }
+        }
+        thread::sleep(Duration::from_secs(minutes * 60));
+    }
+}
+
+// One line on how many people each side of the mapping of `group` has, like
+// "IRC: 24 users; Telegram: 87 members", as last counted. A side that hasn't been counted
+// is left out.
+pub fn summary(state: &RelayState, group: &str) -> Option<String> {
+    let mut sides = vec![];
+    let irc_users = state.irc_channel.get(group).and_then(|channel| state.irc_users.get(channel));
+    if let Some(&count) = irc_users {
+        sides.push(format!("IRC: {} user{}", count, if count == 1 { "" } else { "s" }));
+    }
+    if let Some(&count) = state.tg_members.get(group) {
+        sides.push(format!("Telegram: {} member{}", count, if count == 1 { "" } else { "s" }));
+    }
+    if sides.is_empty() { None } else { Some(sides.join("; ")) }
+}
+
+// Post the summary of every mapping to both of its sides every `hours` hours
+pub fn post_summaries(outbound: Arc<Outbound>, state: Arc<Mutex<RelayState>>, hours: u64) {
+    loop {
+        thread::sleep(Duration::from_secs(hours * 60 * 60));
+        let summaries: Vec<_> = {
+            let state = state.lock().unwrap();
+            state.irc_channel
+                 .iter()
+                 .filter_map(|(group, channel)| {
+                     summary(&state, group).map(|summary| {
+                         (group.clone(), channel.clone(), summary, state.chat_ids.get(group).cloned())
+                     })
+                 })
+                 .collect()
+        };
+        for (group, channel, summary, id) in summaries {
+            println!("[INFO] Posting member summary of \"{}\": {}", group, summary);
+            if let Some(id) = id {
+                outbound.to_tg(&group, id, summary.clone());
+            }
+            outbound.to_irc(&channel, IrcLine {
+                nick: String::new(),
+                hostmask: None,
+                text: summary,
+                date: time::get_time().sec,
+                received: Instant::now(),
+            });
+        }
+    }
+}
@@ -0,0 +1,291 @@
+// Formatting of a stretch of text
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Style {
+    pub bold: bool,
+    pub italic: bool,
+    pub code: bool,
+}
+
+// A stretch of text sharing the same formatting
+#[derive(Clone, Debug, PartialEq)]
+pub struct Segment {
+    pub text: String,
+    pub style: Style,
+}
+
+// A file that came with a message, mirrored or described since not every destination takes
+// files
+#[derive(Clone, Debug, PartialEq)]
+pub struct Attachment {
+    // "photo", "document", "video", ...
+    pub kind: String,
+    pub url: Option<String>,
+    // Shown when there is no url, or next to it
+    pub description: Option<String>,
+}
+
+// Another message, by the chat it was sent in (Telegram group or IRC channel) and its id
+// there
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reference {
+    pub chat: String,
+    pub id: i64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sender {
+    pub nick: String,
+    // Made up hostmask of a Telegram sender, for IRC servers that show it
+    pub hostmask: Option<String>,
+}
+
+// A message on its way through the bridge, independent of the protocol it came from or
+// goes to. Each destination renders it the way it can.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RelayMessage {
+    // None for messages of the bridge itself
+    pub sender: Option<Sender>,
+    // A /me action rather than something said
+    pub action: bool,
+    pub segments: Vec<Segment>,
+    pub attachments: Vec<Attachment>,
+    pub reply_to: Option<Reference>,
+    // The message this one is an edit of
+    pub edit_of: Option<Reference>,
+}
+
+impl RelayMessage {
+    // A message of unformatted text
+    pub fn new(sender: Option<Sender>, text: &str) -> RelayMessage {
+        RelayMessage {
+            sender: sender,
+            action: false,
+            segments: vec![Segment {
+                               text: text.to_owned(),
+                               style: Style::default(),
+                           }],
+            attachments: vec![],
+            reply_to: None,
+            edit_of: None,
+        }
+    }
+
+    pub fn nick(&self) -> &str {
+        self.sender.as_ref().map_or("", |sender| &sender.nick[..])
+    }
+
+    // The text of the message without any formatting, followed by its attachments
+    pub fn plain(&self) -> String {
+        render(self, |text, _| text.to_owned())
+    }
+}
+
+// IRC formatting codes
+const IRC_BOLD: char = '\x02';
+const IRC_ITALIC: char = '\x1D';
+const IRC_MONOSPACE: char = '\x11';
+const IRC_COLOR: char = '\x03';
+const IRC_RESET: char = '\x0F';
+
+// Split IRC text into its formatted segments. Colors are dropped, nothing else can show them.
+pub fn parse_irc(text: &str) -> Vec<Segment> {
+    let mut segments = vec![];
+    let mut style = Style::default();
+    let mut current = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        let next_style = match c {
+            IRC_BOLD => Style { bold: !style.bold, ..style },
+            IRC_ITALIC => Style { italic: !style.italic, ..style },
+            IRC_MONOSPACE => Style { code: !style.code, ..style },
+            IRC_RESET => Style::default(),
+            IRC_COLOR => {
+                // Up to two digits of foreground, optionally a comma and two of background
+                for _ in 0..2 {
+                    if chars.peek().map_or(false, |c| c.is_digit(10)) {
+                        chars.next();
+                    }
+                }
+                if chars.peek() == Some(&',') {
+                    chars.next();
+                    for _ in 0..2 {
+                        if chars.peek().map_or(false, |c| c.is_digit(10)) {
+                            chars.next();
+                        }
+                    }
+                }
+                continue;
+            }
+            c => {
+                current.push(c);
+                continue;
+            }
+        };
+        if next_style != style && !current.is_empty() {
+            segments.push(Segment {
+                text: current,
+                style: style,
+            });
+            current = String::new();
+        }
+        style = next_style;
+    }
+    if !current.is_empty() || segments.is_empty() {
+        segments.push(Segment {
+            text: current,
+            style: style,
+        });
+    }
+    segments
+}
+
+// The text of `message` with each segment formatted by `format`, followed by its attachments
+fn render<F: Fn(&str, Style) -> String>(message: &RelayMessage, format: F) -> String {
+    let mut text = message.segments.iter().map(|segment| format(&segment.text, segment.style)).collect::<String>();
+    for attachment in &message.attachments {
+        let described = match (attachment.url.as_ref(), attachment.description.as_ref()) {
+            (Some(url), Some(description)) => format!("{} {}", description, url),
+            (Some(url), None) => url.clone(),
+            (None, Some(description)) => description.clone(),
+            (None, None) => format!("({})", attachment.kind),
+        };
+        if !text.is_empty() {
+            text.push(' ');
+        }
+        text.push_str(&described);
+    }
+    text
+}
+
+// The body for IRC, formatted with IRC codes. The sender is added by the IRC worker.
+pub fn to_irc(message: &RelayMessage) -> String {
+    render(message, |text, style| {
+        let mut codes = String::new();
+        if style.bold {
+            codes.push(IRC_BOLD);
+        }
+        if style.italic {
+            codes.push(IRC_ITALIC);
+        }
+        if style.code {
+            codes.push(IRC_MONOSPACE);
+        }
+        if codes.is_empty() {
+            text.to_owned()
+        } else {
+            format!("{}{}{}", codes, text, codes)
+        }
+    })
+}
+
+// Wrap `text` in `marker` on both sides, keeping surrounding spaces outside so the markup
+// is recognized
+fn wrap(text: &str, marker: &str) -> String {
+    let trimmed = text.trim();
+    if trimmed.is_empty() {
+        return text.to_owned();
+    }
+    let start = text.len() - text.trim_left().len();
+    let end = start + trimmed.len();
+    format!("{}{}{}{}{}", &text[..start], marker, trimmed, marker, &text[end..])
+}
+
+// Formatted with the markers of a Markdown dialect
+fn to_markup(message: &RelayMessage, bold: &str, italic: &str) -> String {
+    render(message, |text, style| {
+        if style.code {
+            wrap(text, "`")
+        } else {
+            let text = if style.bold { wrap(text, bold) } else { text.to_owned() };
+            if style.italic { wrap(&text, italic) } else { text }
+        }
+    })
+}
+
+// `message` with `escape` applied to everything in it that came from its sender. Attachment
+// URLs are left alone, so they keep working as links.
+fn escaped(message: &RelayMessage, escape: fn(&str) -> String) -> RelayMessage {
+    let mut message = message.clone();
+    if let Some(ref mut sender) = message.sender {
+        sender.nick = escape(&sender.nick);
+    }
+    for segment in &mut message.segments {
+        segment.text = escape(&segment.text);
+    }
+    for attachment in &mut message.attachments {
+        attachment.description = attachment.description.as_ref().map(|description| escape(description));
+    }
+    message
+}
+
+// Text Slack takes literally: its markup for mentions like <!channel> and <@U123> and links
+// starts with "<", so "&", "<" and ">" have to be written as entities
+fn escape_slack(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+// The whole message for Slack in mrkdwn, sender included
+pub fn to_slack(message: &RelayMessage) -> String {
+    let message = escaped(message, escape_slack);
+    let body = to_markup(&message, "*", "_");
+    match (message.sender.as_ref(), message.action) {
+        (Some(sender), true) => format!("_{} {}_", sender.nick, body),
+        (Some(sender), false) => format!("*{}*: {}", sender.nick, body),
+        (None, _) => body,
+    }
+}
+
+// Mentions that notify everyone in a Mattermost or Rocket.Chat channel
+const MASS_MENTIONS: &'static [&'static str] = &["all", "channel", "here"];
+
+// Text Mattermost and Rocket.Chat show as it is: Markdown characters are escaped, and a
+// zero width space after the "@" of mentions like @channel keeps them from notifying
+// everyone
+pub fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    let mut previous = ' ';
+    for (i, c) in text.char_indices() {
+        if "\\`*_~[]<>#|".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+        // Within a word, like in an email address, it's no mention
+        if c == '@' && !previous.is_alphanumeric() {
+            let word: String = text[i + 1..].chars().take_while(|c| c.is_alphanumeric()).collect();
+            if MASS_MENTIONS.contains(&&word.to_lowercase()[..]) {
+                escaped.push('\u{200B}');
+            }
+        }
+        previous = c;
+    }
+    escaped
+}
+
+// The body for Mattermost and Rocket.Chat in Markdown. Their worker adds the sender.
+pub fn to_markdown(message: &RelayMessage) -> String {
+    let message = escaped(message, escape_markdown);
+    let body = to_markup(&message, "**", "_");
+    if message.action { format!("_{}_", body) } else { body }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RelayMessage, Sender, escape_markdown, to_markdown, to_slack};
+
+    #[test]
+    fn slack_mentions_are_escaped() {
+        let sender = Sender {
+            nick: "<!here>".to_owned(),
+            hostmask: None,
+        };
+        let message = RelayMessage::new(Some(sender), "<!channel> <@U123> & co");
+        assert_eq!(to_slack(&message), "*&lt;!here&gt;*: &lt;!channel&gt; &lt;@U123&gt; &amp; co");
+    }
+
+    #[test]
+    fn markdown_and_mass_mentions_are_escaped() {
+        assert_eq!(escape_markdown("**not bold** @Channel @all @alice a@here.org"),
+                   "\\*\\*not bold\\*\\* @\u{200B}Channel @\u{200B}all @alice a@here.org");
+        assert_eq!(to_markdown(&RelayMessage::new(None, "_hi_")), "\\_hi\\_");
+    }
+}
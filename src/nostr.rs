@@ -0,0 +1,343 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use rand;
+use rustc_serialize::base64::{self, ToBase64};
+use rustc_serialize::hex::{FromHex, ToHex};
+use rustc_serialize::json::Json;
+use secp256k1::{Message, Secp256k1, XOnlyPublicKey};
+use secp256k1::schnorr::Signature;
+use error::{self, ResultExt};
+use urls;
+use outbound::Outbound;
+use templates;
+use super::{Config, NostrConfig, RelayState, post_to_mapping};
+
+// Seconds to wait before reconnecting to the relay, unless configured
+const DEFAULT_NOSTR_RECONNECT: u64 = 60;
+// Kind of the events holding NIP-28 public channel messages
+const CHANNEL_MESSAGE_KIND: u64 = 42;
+// Name of our subscription on the relay
+const SUBSCRIPTION: &'static str = "tiercel";
+// Largest message we take from the relay, in bytes. Anything larger ends the connection
+// rather than being read into memory.
+const MAX_MESSAGE_BYTES: u64 = 1024 * 1024;
+
+// Frame opcodes we deal with, see RFC 6455 section 5.2
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+// A websocket connection to a relay. Only unencrypted ws:// relays are supported; a
+// wss:// relay can be reached through a local TLS tunnel.
+struct Socket {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Socket {
+    fn connect(url: &str) -> error::Result<Socket> {
+        let url = try!(urls::parse(url, "relay url"));
+        if url.scheme != "ws" {
+            return Err(format!("unsupported relay scheme {}, only ws:// relays are supported", url.scheme).into());
+        }
+        let host = try!(url.serialize_host().ok_or("relay url has no host"));
+        let port = url.port_or_default().unwrap_or(80);
+        let path = url.serialize_path().unwrap_or("/".to_owned());
+        let stream = try!(TcpStream::connect((&host[..], port)).context("connecting to the relay"));
+        let mut socket = Socket {
+            reader: BufReader::new(try!(stream.try_clone().context("connecting to the relay"))),
+            writer: stream,
+        };
+        let key = rand::random::<[u8; 16]>()[..].to_base64(base64::STANDARD);
+        try!(write!(socket.writer,
+                    "GET {} HTTP/1.1\r\nHost: {}:{}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: \
+                     {}\r\nSec-WebSocket-Version: 13\r\n\r\n",
+                    path,
+                    host,
+                    port,
+                    key)
+                 .context("starting the websocket handshake"));
+        let mut status = String::new();
+        try!(socket.reader.read_line(&mut status).context("reading the handshake response"));
+        if status.split_whitespace().nth(1) != Some("101") {
+            return Err(format!("relay refused the websocket upgrade: {}", status.trim()).into());
+        }
+        // Skip the rest of the response headers
+        loop {
+            let mut line = String::new();
+            try!(socket.reader.read_line(&mut line).context("reading the handshake response"));
+            if line.trim().is_empty() {
+                break;
+            }
+        }
+        Ok(socket)
+    }
+
+    // Send one frame. Frames from a client are always masked.
+    fn send_frame(&mut self, opcode: u8, payload: &[u8]) -> error::Result<()> {
+        let mut frame = vec![0x80 | opcode];
+        let len = payload.len();
+        if len < 126 {
+            frame.push(0x80 | len as u8);
+        } else if len < 65536 {
+            frame.push(0x80 | 126);
+            frame.push((len >> 8) as u8);
+            frame.push(len as u8);
+        } else {
+            frame.push(0x80 | 127);
+            for shift in (0..8).rev() {
+                frame.push((len as u64 >> (shift * 8)) as u8);
+            }
+        }
+        let mask = rand::random::<[u8; 4]>();
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+        self.writer.write_all(&frame).context("writing to the relay")
+    }
+
+    fn send_text(&mut self, text: &str) -> error::Result<()> {
+        self.send_frame(OP_TEXT, text.as_bytes())
+    }
+
+    // The next text message from the relay, answering pings on the way. None once the
+    // relay closes the connection.
+    fn recv_text(&mut self) -> error::Result<Option<String>> {
+        let mut message = vec![];
+        loop {
+            let mut header = [0u8; 2];
+            try!(self.reader.read_exact(&mut header).context("reading from the relay"));
+            let fin = header[0] & 0x80 != 0;
+            let opcode = header[0] & 0x0F;
+            let masked = header[1] & 0x80 != 0;
+            let mut len = (header[1] & 0x7F) as u64;
+            if len == 126 || len == 127 {
+                let mut extended = vec![0u8; if len == 126 { 2 } else { 8 }];
+                try!(self.reader.read_exact(&mut extended).context("reading from the relay"));
+                len = extended.iter().fold(0, |len, &byte| len << 8 | byte as u64);
+            }
+            if len > MAX_MESSAGE_BYTES - message.len() as u64 {
+                return Err(format!("relay sent a message over {} bytes", MAX_MESSAGE_BYTES).into());
+            }
+            let mut mask = [0u8; 4];
+            if masked {
+                try!(self.reader.read_exact(&mut mask).context("reading from the relay"));
+            }
+            let mut payload = vec![0u8; len as usize];
+            try!(self.reader.read_exact(&mut payload).context("reading from the relay"));
+            if masked {
+                for (i, byte) in payload.iter_mut().enumerate() {
+                    *byte ^= mask[i % 4];
+                }
+            }
+            match opcode {
+                OP_CLOSE => return Ok(None),
+                OP_PING => try!(self.send_frame(OP_PONG, &payload)),
+                OP_TEXT | OP_CONTINUATION => {
+                    message.extend_from_slice(&payload);
+                    if fin {
+                        return Ok(Some(String::from_utf8_lossy(&message).into_owned()));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+// A string as NIP-01 has it written in the serialization of an event
+fn serialize_string(text: &str) -> String {
+    let mut serialized = String::with_capacity(text.len() + 2);
+    serialized.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => serialized.push_str("\\\""),
+            '\\' => serialized.push_str("\\\\"),
+            '\n' => serialized.push_str("\\n"),
+            '\r' => serialized.push_str("\\r"),
+            '\t' => serialized.push_str("\\t"),
+            '\u{8}' => serialized.push_str("\\b"),
+            '\u{c}' => serialized.push_str("\\f"),
+            c => serialized.push(c),
+        }
+    }
+    serialized.push('"');
+    serialized
+}
+
+// The serialization of `event` its id is the hash of, if it has everything that goes in it
+fn serialize_event(event: &Json) -> Option<String> {
+    let string = |name: &str| event.find(name).and_then(|value| value.as_string()).map(serialize_string);
+    let (pubkey, content) = match (string("pubkey"), string("content")) {
+        (Some(pubkey), Some(content)) => (pubkey, content),
+        _ => return None,
+    };
+    let created_at = event.find("created_at").and_then(|created_at| created_at.as_i64());
+    let kind = event.find("kind").and_then(|kind| kind.as_u64());
+    let (created_at, kind) = match (created_at, kind) {
+        (Some(created_at), Some(kind)) => (created_at, kind),
+        _ => return None,
+    };
+    let tags = match event.find("tags").and_then(|tags| tags.as_array()) {
+        Some(tags) => tags,
+        None => return None,
+    };
+    let mut serialized_tags = vec![];
+    for tag in tags {
+        let values = match tag.as_array() {
+            Some(values) => values,
+            None => return None,
+        };
+        let mut serialized_values = vec![];
+        for value in values {
+            match value.as_string() {
+                Some(value) => serialized_values.push(serialize_string(value)),
+                None => return None,
+            }
+        }
+        serialized_tags.push(format!("[{}]", serialized_values.join(",")));
+    }
+    Some(format!("[0,{},{},{},[{}],{}]", pubkey, created_at, kind, serialized_tags.join(","), content))
+}
+
+// Whether `signature` is the BIP-340 signature of `id` by the key `pubkey`
+fn signed(pubkey: &[u8], id: &[u8], signature: &[u8]) -> bool {
+    let pubkey = match XOnlyPublicKey::from_slice(pubkey) {
+        Ok(pubkey) => pubkey,
+        Err(_) => return false,
+    };
+    let signature = match Signature::from_slice(signature) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+    let id = match Message::from_slice(id) {
+        Ok(id) => id,
+        Err(_) => return false,
+    };
+    Secp256k1::verification_only().verify_schnorr(&signature, &id, &pubkey).is_ok()
+}
+
+// Whether `event` is the one its id names, signed by the key of its author. Relays pass on
+// whatever they are sent, so anyone could otherwise speak as anyone.
+fn authentic(event: &Json) -> bool {
+    let serialized = match serialize_event(event) {
+        Some(serialized) => serialized,
+        None => return false,
+    };
+    let mut hasher = Sha256::new();
+    hasher.input(serialized.as_bytes());
+    let mut id = [0u8; 32];
+    hasher.result(&mut id);
+    let claimed = event.find("id").and_then(|id| id.as_string()).unwrap_or("");
+    if claimed.to_lowercase() != id.to_hex() {
+        return false;
+    }
+    let bytes = |name: &str| event.find(name).and_then(|value| value.as_string()).and_then(|value| value.from_hex().ok());
+    match (bytes("pubkey"), bytes("sig")) {
+        (Some(pubkey), Some(sig)) => signed(&pubkey, &id, &sig),
+        _ => false,
+    }
+}
+
+// The author and content of a channel message, if `message` is one of the events of our
+// subscription
+fn channel_message(message: &str) -> Option<(String, String)> {
+    let message = match Json::from_str(message) {
+        Ok(message) => message,
+        Err(_) => return None,
+    };
+    let message = match message.as_array() {
+        Some(message) => message,
+        None => return None,
+    };
+    if message.len() < 3 || message[0].as_string() != Some("EVENT") || message[1].as_string() != Some(SUBSCRIPTION) {
+        return None;
+    }
+    let event = &message[2];
+    if event.find("kind").and_then(|kind| kind.as_u64()) != Some(CHANNEL_MESSAGE_KIND) {
+        return None;
+    }
+    if !authentic(event) {
+        println!("[WARN] Ignoring Nostr event {} with a wrong id or signature",
+                 event.find("id").and_then(|id| id.as_string()).unwrap_or("without id"));
+        return None;
+    }
+    let pubkey = event.find("pubkey").and_then(|pubkey| pubkey.as_string()).unwrap_or("");
+    let content = event.find("content").and_then(|content| content.as_string()).unwrap_or("");
+    // Public keys are long, their beginning tells people apart well enough
+    Some((pubkey.chars().take(8).collect(), content.to_owned()))
+}
+
+// Relay the messages of the channel until the connection drops
+fn follow(nostr: &NostrConfig, config: &Config, outbound: &Outbound, state: &Mutex<RelayState>) -> error::Result<()> {
+    let mut socket = try!(Socket::connect(&nostr.relay));
+    // Only messages from now on, the channel's history has been seen already
+    let since = ::time::get_time().sec;
+    try!(socket.send_text(&format!("[\"REQ\",\"{}\",{{\"kinds\":[{}],\"#e\":[\"{}\"],\"since\":{}}}]",
+                                   SUBSCRIPTION,
+                                   CHANNEL_MESSAGE_KIND,
+                                   nostr.channel_id,
+                                   since)));
+    println!("[INFO] Following Nostr channel {} on {}", nostr.channel_id, nostr.relay);
+    while let Some(message) = try!(socket.recv_text()) {
+        if let Some((author, content)) = channel_message(&message) {
+            let state = state.lock().unwrap();
+            for group in &nostr.groups {
+                let text = templates::render(config, group, templates::MESSAGE, &[("nick", &author[..]),
+                                                                                ("message", &content[..])]);
+                post_to_mapping(outbound, &state, group, text);
+            }
+        }
+    }
+    Err("relay closed the connection".into())
+}
+
+// Follow a Nostr public channel (NIP-28) forever, posting its messages to both sides of
+// the mappings it is configured for. Experimental: messages only flow from the channel,
+// publishing to it would need events signed with a secp256k1 key.
+pub fn run(nostr: NostrConfig, config: Config, outbound: Arc<Outbound>, state: Arc<Mutex<RelayState>>) {
+    let reconnect = Duration::from_secs(nostr.reconnect.unwrap_or(DEFAULT_NOSTR_RECONNECT));
+    loop {
+        if let Err(err) = follow(&nostr, &config, &outbound, &state) {
+            println!("[WARN] {}", err.context(format!("following Nostr channel {}", nostr.channel_id)));
+        }
+        thread::sleep(reconnect);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::channel_message;
+
+    // A channel message signed with the secret key of the second BIP-340 test vector
+    const EVENT: &'static str = r#"["EVENT","tiercel",{"id":"fe78fdf07733c0b95f1b78a0ad7a994c2f4e113e1ea47360f04c99044334fb04","pubkey":"dff1d77f2a671c5f36183726db2341be58feae1da2deced843240f7b502ba659","created_at":1700000000,"kind":42,"tags":[["e","aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa","","root"]],"content":"hi \"there\"\nbye","sig":"b230dc1cd6ec5cb496ca67eea193aa22485a97042a36b25c0f578844008b8626e5cd813c6732e9ff737d052dec355d0fe5f8cc545779a3adaf3c4e64246b816b"}]"#;
+
+    #[test]
+    fn relays_signed_messages() {
+        assert_eq!(channel_message(EVENT),
+                   Some(("dff1d77f".to_owned(), "hi \"there\"\nbye".to_owned())));
+    }
+
+    #[test]
+    fn ignores_altered_messages() {
+        assert_eq!(channel_message(&EVENT.replace("bye", "bye!")), None);
+        assert_eq!(channel_message(&EVENT.replace("1700000000", "1700000001")), None);
+        assert_eq!(channel_message(&EVENT.replace("\"root\"", "\"reply\"")), None);
+    }
+
+    #[test]
+    fn ignores_messages_signed_by_someone_else() {
+        // The right id, but claimed by another author
+        let other = "f9308a019258c31049344f85f89d5229b531c845836f99b08601f113bce036f9";
+        let event = EVENT.replace("dff1d77f2a671c5f36183726db2341be58feae1da2deced843240f7b502ba659", other);
+        assert_eq!(channel_message(&event), None);
+        let event = EVENT.replace("b230dc1c", "b230dc1d");
+        assert_eq!(channel_message(&event), None);
+    }
+}
@@ -0,0 +1,1119 @@
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, Condvar};
+use std::thread;
+use std::usize;
+use std::time::{Duration, Instant};
+use irc::client::prelude::ServerExt;
+use irc::client::data::{Command, Message};
+use irc::client::data::message::Tag;
+use telegram_bot::Api;
+use capabilities::{self, Capabilities};
+use digest::{self, Digest};
+use queue::{self, BoundedQueue, Overflow};
+use quiet;
+use sanitize;
+use servers::CurrentServer;
+use stale;
+use store::QueuedMessage;
+use watchdog::Watchdog;
+use slack;
+use teamchat;
+use super::{Config, ChatID, IrcChannel, TelegramGroup, RelayState, save_chat_ids};
+
+const DEFAULT_QUEUE_CAPACITY: usize = 100;
+// How long the IRC workers wait for more messages to batch with the one at hand
+const BATCH_LINGER_MS: u64 = 250;
+// Seconds between announcements relayed from a Telegram channel, unless configured
+const DEFAULT_ANNOUNCEMENT_INTERVAL: u64 = 10;
+// Message tag carrying the made up hostmask of a Telegram sender
+const HOSTMASK_TAG: &'static str = "+tiercel/hostmask";
+// Seconds without being rate limited after which a Telegram worker leaves slow mode
+const SLOW_MODE_RESET: u64 = 600;
+// Number of recent deliveries the latency percentiles are computed over
+const LATENCY_SAMPLES: usize = 1000;
+// Deliveries taking longer than this many seconds from receipt are logged
+const DEFAULT_LATENCY_WARNING: u64 = 30;
+
+// Longest line IRC servers pass on, in bytes, counting the source they put in front of it
+// and the line break
+const IRC_LINE_BYTES: usize = 512;
+// Room left for the ":nick!user@host " servers put in front of our lines
+const IRC_SOURCE_BYTES: usize = 100;
+// Longest sender nick put in front of relayed lines, in bytes. Telegram names can be far
+// longer, and would leave no room for the text.
+const MAX_IRC_NICK_BYTES: usize = 64;
+// Text every line has room for, however long the channel name
+const MIN_IRC_TEXT_BYTES: usize = 16;
+
+// Stand-ins for angle brackets in what others say on IRC, so that it can't be mistaken
+// for the "<nick>" a line starts with
+const LEFT_BRACKET: char = '‹';
+const RIGHT_BRACKET: char = '›';
+
+// Messages waiting to be delivered to an IRC channel
+type IrcQueue = BoundedQueue<IrcLine>;
+// Messages waiting to be delivered to Telegram, as (chat_id, message, time received)
+type TgQueue = BoundedQueue<(ChatID, String, Instant)>;
+
+// A Telegram message waiting to be relayed to IRC
+pub struct IrcLine {
+    // Empty for messages of the bridge itself, which are sent as they are
+    pub nick: String,
+    // Hostmask of the sender to tag the message with, if any
+    pub hostmask: Option<String>,
+    pub text: String,
+    // Time the message was sent, as a unix timestamp
+    pub date: i64,
+    // When we received the message
+    pub received: Instant,
+}
+
+// Time from receiving messages to delivering them, for the most recent deliveries
+#[derive(Clone, Default, Debug)]
+pub struct Latency {
+    // In milliseconds
+    samples: VecDeque<u64>,
+}
+
+impl Latency {
+    fn record(&mut self, latency: Duration) {
+        if self.samples.len() >= LATENCY_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(latency.as_secs() * 1000 + latency.subsec_nanos() as u64 / 1000000);
+    }
+
+    // The given percentile of the recorded latencies, in milliseconds
+    pub fn percentile(&self, percentile: usize) -> Option<u64> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        let mut sorted: Vec<u64> = self.samples.iter().cloned().collect();
+        sorted.sort();
+        let index = cmp::min(sorted.len() * percentile / 100, sorted.len() - 1);
+        Some(sorted[index])
+    }
+}
+
+// Health of the delivery to one destination, updated by its worker
+#[derive(Clone, Default, Debug)]
+pub struct DeliveryStatus {
+    // Interval in seconds Telegram requires between our messages, while rate limited
+    pub slow_mode: Option<u64>,
+    // When we were last told to slow down
+    pub slow_mode_since: Option<Instant>,
+    // Set when the bot can no longer post to the destination, e.g. after being kicked
+    pub deactivated: bool,
+    // The most recent delivery error
+    pub last_error: Option<String>,
+    pub latency: Latency,
+    // Relaying is paused by an admin until then
+    pub muted_until: Option<Instant>,
+    // Relaying is turned off by an admin until they turn it on again
+    pub off: bool,
+}
+
+impl DeliveryStatus {
+    pub fn muted(&mut self) -> bool {
+        if self.off {
+            return true;
+        }
+        match self.muted_until {
+            Some(until) if Instant::now() < until => true,
+            Some(_) => {
+                self.muted_until = None;
+                false
+            }
+            None => false,
+        }
+    }
+}
+
+// Note how long a delivery took from receiving the message, warning if it was slow
+fn delivered(status: &Mutex<DeliveryStatus>, destination: &str, received: Instant, warning: u64) {
+    let latency = received.elapsed();
+    if latency.as_secs() >= warning {
+        println!("[WARN] Delivery to \"{}\" took {}s from receiving the message", destination, latency.as_secs());
+    }
+    status.lock().unwrap().latency.record(latency);
+}
+
+pub struct Delivery<T> {
+    pub queue: Arc<BoundedQueue<T>>,
+    pub status: Arc<Mutex<DeliveryStatus>>,
+}
+
+// Something deliveries depend on, like a working connection to a network. While it is
+// down, the workers depending on it hold on to their messages.
+pub struct Link {
+    // Why messages were held up, for the notice before the backlog
+    reason: &'static str,
+    down: Mutex<bool>,
+    restored: Condvar,
+}
+
+impl Link {
+    fn new(reason: &'static str) -> Link {
+        Link {
+            reason: reason,
+            down: Mutex::new(false),
+            restored: Condvar::new(),
+        }
+    }
+
+    // Returns true if the link was up until now
+    pub fn set_down(&self) -> bool {
+        let mut down = self.down.lock().unwrap();
+        let changed = !*down;
+        *down = true;
+        changed
+    }
+
+    // Returns true if the link was down until now
+    pub fn set_up(&self) -> bool {
+        let mut down = self.down.lock().unwrap();
+        let changed = *down;
+        *down = false;
+        self.restored.notify_all();
+        changed
+    }
+
+    pub fn is_down(&self) -> bool {
+        *self.down.lock().unwrap()
+    }
+
+    // Block while the link is down. Returns true if we had to wait.
+    fn wait_up(&self) -> bool {
+        let mut down = self.down.lock().unwrap();
+        let waited = *down;
+        while *down {
+            down = self.restored.wait(down).unwrap();
+        }
+        waited
+    }
+}
+
+// Block until all of the links are up. Returns the reason messages were held up, if they were.
+pub fn wait_up(links: &[&Link]) -> Option<&'static str> {
+    let mut reason = None;
+    for link in links {
+        if link.wait_up() {
+            reason = Some(link.reason);
+        }
+    }
+    reason
+}
+
+fn replay_notice(count: usize, reason: &str) -> String {
+    format!("— replaying {} message{} {} —",
+            count,
+            if count == 1 { "" } else { "s" },
+            reason)
+}
+
+// Outbound message queues. Every mapping gets its own pair of queues, each drained by
+// its own worker thread, so that one slow or rate-limited destination can't hold up
+// relaying for the others.
+//
+// Messages for a destination are delivered in the order they were queued. Each queue has
+// a single worker, which only ever combines consecutive messages when batching, retries
+// a message before taking anything else off the queue, and announces dropped messages at
+// the point in the stream where they were dropped.
+pub struct Outbound {
+    pub irc: HashMap<IrcChannel, Delivery<IrcLine>>,
+    pub tg: HashMap<TelegramGroup, Delivery<(ChatID, String, Instant)>>,
+    pub irc_link: Arc<Link>,
+    pub tg_link: Arc<Link>,
+    // While in maintenance mode nothing is delivered, but messages are still queued
+    pub maintenance: Arc<Link>,
+    // Posts of Telegram channels waiting to be announced on IRC, by channel title
+    pub announcements: HashMap<String, Arc<BoundedQueue<String>>>,
+    // Messages waiting to be posted to Slack, as (Slack channel, text), and the Slack
+    // channel of each mapping that has one
+    pub slack: Option<Arc<BoundedQueue<(String, String)>>>,
+    pub slack_channels: HashMap<TelegramGroup, String>,
+    // Messages waiting to be posted to Mattermost or Rocket.Chat, as (incoming webhook url,
+    // sender, text), and the incoming webhook of each mapping that has one
+    pub teamchat: Option<Arc<BoundedQueue<(String, String, String)>>>,
+    pub teamchat_urls: HashMap<TelegramGroup, String>,
+    // Telegram groups that get the messages of their IRC channel in periodic digests
+    pub digests: HashMap<TelegramGroup, Arc<Digest>>,
+    // Escape angle brackets in the text of relayed messages, not just in the nicks
+    pub escape_brackets: bool,
+    // What is done about direction controls in relayed texts
+    pub direction_controls: sanitize::Mode,
+}
+
+// `text` with its angle brackets replaced by lookalikes
+fn escape_brackets(text: &str) -> String {
+    text.chars()
+        .map(|c| {
+            match c {
+                '<' => LEFT_BRACKET,
+                '>' => RIGHT_BRACKET,
+                c => c,
+            }
+        })
+        .collect()
+}
+
+// Bytes of text that fit in a line to `channel` sent as "<nick> text", so the line stays
+// within IRC_LINE_BYTES as the server passes it on: ":us!user@host PRIVMSG #channel :<nick>
+// text\r\n". Message tags don't count, they have a limit of their own.
+fn irc_text_len(channel: &str, nick: &str) -> usize {
+    let mut overhead = IRC_SOURCE_BYTES + "PRIVMSG  :\r\n".len() + channel.len();
+    if !nick.is_empty() {
+        overhead += nick.len() + "<> ".len();
+    }
+    cmp::max(IRC_LINE_BYTES.saturating_sub(overhead), MIN_IRC_TEXT_BYTES)
+}
+
+// `nick` cut to MAX_IRC_NICK_BYTES, at a character boundary
+fn shorten_nick(mut nick: String) -> String {
+    if nick.len() > MAX_IRC_NICK_BYTES {
+        let mut end = MAX_IRC_NICK_BYTES;
+        while !nick.is_char_boundary(end) {
+            end -= 1;
+        }
+        nick.truncate(end);
+    }
+    nick
+}
+
+impl Outbound {
+    // A relayed message can't pass for someone else's: neither a name like "> evil <admin"
+    // nor, with escape_brackets, a "<admin>" in the text gets through as it is. IRC messages
+    // can't span lines, and servers cut off long ones, so the text is sent as one message
+    // per line and piece of a long line, each with the sender's nick in front and all of it
+    // within what servers pass on.
+    pub fn to_irc(&self, channel: &str, line: IrcLine) {
+        if let Some(delivery) = self.irc.get(channel) {
+            if !delivery.status.lock().unwrap().muted() {
+                let nick = shorten_nick(escape_brackets(&sanitize::name(&line.nick)));
+                let text = sanitize::text(self.direction_controls, &line.text);
+                let text = if self.escape_brackets && !line.nick.is_empty() {
+                    escape_brackets(&text)
+                } else {
+                    text
+                };
+                let max_len = irc_text_len(channel, &nick);
+                let pieces = text.lines()
+                                 .filter(|line| !line.trim().is_empty())
+                                 .flat_map(|line| capabilities::split_within(line, max_len));
+                for text in pieces {
+                    delivery.queue.push(IrcLine {
+                        nick: nick.clone(),
+                        hostmask: line.hostmask.clone(),
+                        text: text,
+                        date: line.date,
+                        received: line.received,
+                    });
+                }
+            }
+        }
+    }
+
+    pub fn to_slack(&self, group: &str, text: String) {
+        if let (Some(queue), Some(channel)) = (self.slack.as_ref(), self.slack_channels.get(group)) {
+            let text = sanitize::text(self.direction_controls, &text);
+            for text in capabilities::split(&capabilities::SLACK, &text) {
+                queue.push((channel.clone(), text));
+            }
+        }
+    }
+
+    pub fn to_teamchat(&self, group: &str, nick: &str, text: String) {
+        if let (Some(queue), Some(url)) = (self.teamchat.as_ref(), self.teamchat_urls.get(group)) {
+            let nick = sanitize::name(nick);
+            let text = sanitize::text(self.direction_controls, &text);
+            for text in capabilities::split(&capabilities::TEAMCHAT, &text) {
+                queue.push((url.clone(), nick.clone(), text));
+            }
+        }
+    }
+
+    // What the destinations of a mapping can take
+    pub fn capabilities(&self, group: &str) -> Vec<(&'static str, Capabilities)> {
+        let mut destinations = vec![("irc", capabilities::IRC), ("telegram", capabilities::TELEGRAM)];
+        if self.slack.is_some() && self.slack_channels.contains_key(group) {
+            destinations.push(("slack", capabilities::SLACK));
+        }
+        if self.teamchat.is_some() && self.teamchat_urls.contains_key(group) {
+            destinations.push(("teamchat", capabilities::TEAMCHAT));
+        }
+        destinations
+    }
+
+    // Everything waiting in the IRC and Telegram queues, to be saved across a restart
+    pub fn queued(&self) -> Vec<QueuedMessage> {
+        let mut messages = vec![];
+        for (channel, delivery) in &self.irc {
+            messages.extend(delivery.queue.items(|line| {
+                QueuedMessage {
+                    network: "irc".to_owned(),
+                    destination: channel.clone(),
+                    nick: line.nick.clone(),
+                    hostmask: line.hostmask.clone().unwrap_or(String::new()),
+                    chat_id: 0,
+                    text: line.text.clone(),
+                    date: line.date,
+                }
+            }));
+        }
+        for (group, delivery) in &self.tg {
+            messages.extend(delivery.queue.items(|&(id, ref text, _)| {
+                QueuedMessage {
+                    network: "telegram".to_owned(),
+                    destination: group.clone(),
+                    nick: String::new(),
+                    hostmask: String::new(),
+                    chat_id: id,
+                    text: text.clone(),
+                    date: 0,
+                }
+            }));
+        }
+        messages
+    }
+
+    // Queue the messages saved before a restart again. Those for destinations that are no
+    // longer mapped are dropped.
+    pub fn restore(&self, messages: Vec<QueuedMessage>) {
+        let mut restored = 0;
+        for message in messages {
+            let received = Instant::now();
+            match &message.network[..] {
+                "irc" => {
+                    if let Some(delivery) = self.irc.get(&message.destination) {
+                        delivery.queue.push(IrcLine {
+                            nick: message.nick,
+                            hostmask: if message.hostmask.is_empty() { None } else { Some(message.hostmask) },
+                            text: message.text,
+                            date: message.date,
+                            received: received,
+                        });
+                        restored += 1;
+                    }
+                }
+                "telegram" => {
+                    if let Some(delivery) = self.tg.get(&message.destination) {
+                        delivery.queue.push((message.chat_id, message.text, received));
+                        restored += 1;
+                    }
+                }
+                _ => {}
+            }
+        }
+        if restored > 0 {
+            println!("[INFO] Restored {} queued messages from before the restart", restored);
+        }
+    }
+
+    pub fn announce(&self, tg_channel: &str, text: String) {
+        if let Some(queue) = self.announcements.get(tg_channel) {
+            queue.push(sanitize::text(self.direction_controls, &text));
+        }
+    }
+
+    pub fn to_tg(&self, group: &str, id: ChatID, msg: String) {
+        if let Some(delivery) = self.tg.get(group) {
+            let mut status = delivery.status.lock().unwrap();
+            if !status.deactivated && !status.muted() {
+                let msg = sanitize::text(self.direction_controls, &msg);
+                for msg in capabilities::split(&capabilities::TELEGRAM, &msg) {
+                    delivery.queue.push((id, msg, Instant::now()));
+                }
+            }
+        }
+    }
+
+    // Collect a message from IRC for the next digest of the group. Returns false if the
+    // group doesn't get digests, for the message to be relayed as usual.
+    pub fn to_digest(&self, group: &str, id: ChatID, entry: digest::Entry) -> bool {
+        let digest = match self.digests.get(group) {
+            Some(digest) => digest,
+            None => return false,
+        };
+        if let Some(delivery) = self.tg.get(group) {
+            let mut status = delivery.status.lock().unwrap();
+            if !status.deactivated && !status.muted() {
+                digest.push(id, digest::Entry {
+                    text: sanitize::text(self.direction_controls, &entry.text),
+                    ..entry
+                });
+            }
+        }
+        true
+    }
+
+    // Stop the IRC and Telegram workers from taking more messages off their queues, ahead
+    // of saving the queues on shutdown
+    pub fn close(&self) {
+        for delivery in self.irc.values() {
+            delivery.queue.close();
+        }
+        for delivery in self.tg.values() {
+            delivery.queue.close();
+        }
+    }
+
+    // Whether the IRC and Telegram workers are done with the messages they took
+    pub fn settled(&self) -> bool {
+        self.irc.values().all(|delivery| delivery.queue.consumer_waiting()) &&
+        self.tg.values().all(|delivery| delivery.queue.consumer_waiting())
+    }
+
+    // Resume delivery to a Telegram group, once we've heard from it again
+    pub fn reactivate_tg(&self, group: &str) {
+        if let Some(delivery) = self.tg.get(group) {
+            let mut status = delivery.status.lock().unwrap();
+            if status.deactivated {
+                println!("[INFO] Telegram group \"{}\" is reachable again, resuming relaying", group);
+                status.deactivated = false;
+            }
+        }
+    }
+}
+
+// The ways sending to Telegram can fail that we handle specifically
+#[derive(Debug, PartialEq)]
+enum SendError {
+    // The bot was kicked from (or otherwise can't post to) the chat
+    Forbidden,
+    // Too many requests, or slow mode. Retry after the given number of seconds.
+    RetryAfter(u64),
+    // The chat id we have is no longer valid, e.g. after the group became a supergroup
+    ChatNotFound,
+    Other,
+}
+
+// The Bot API only hands us the description of an error, so classify it from that
+fn classify(description: &str) -> SendError {
+    if let Some(seconds) = retry_after(description) {
+        SendError::RetryAfter(seconds)
+    } else if description.contains("Forbidden") || description.contains("kicked") {
+        SendError::Forbidden
+    } else if description.contains("chat not found") {
+        SendError::ChatNotFound
+    } else {
+        SendError::Other
+    }
+}
+
+fn new_queue<T>(name: &str, config: &Config) -> Arc<BoundedQueue<T>> {
+    let (capacity, overflow) = match config.queue {
+        Some(ref queue) => (queue.capacity, queue.overflow.clone()),
+        None => (None, None),
+    };
+    let overflow = match overflow {
+        Some(overflow) => overflow.parse().unwrap_or_else(|err| panic!("error in [queue] config: {}", err)),
+        None => Overflow::DropOldest,
+    };
+    let capacity = capacity.unwrap_or(DEFAULT_QUEUE_CAPACITY);
+    if capacity == 0 {
+        panic!("error in [queue] config: capacity must be at least 1");
+    }
+    Arc::new(BoundedQueue::new(name, capacity, overflow))
+}
+
+fn dropped_notice(dropped: usize) -> String {
+    format!("[{} message{} dropped]", dropped, if dropped == 1 { "" } else { "s" })
+}
+
+// Pull the number of seconds to wait out of a Telegram "Too Many Requests: retry after N"
+// error, which is also what slow mode in supergroups results in.
+fn retry_after(description: &str) -> Option<u64> {
+    description.find("retry after ").and_then(|start| {
+        let seconds: String = description[start + "retry after ".len()..]
+                                  .chars()
+                                  .take_while(|c| c.is_digit(10))
+                                  .collect();
+        seconds.parse().ok()
+    })
+}
+
+fn send_irc(current: CurrentServer,
+            channel: IrcChannel,
+            queue: Arc<IrcQueue>,
+            status: Arc<Mutex<DeliveryStatus>>,
+            batch_seconds: i64,
+            latency_warning: u64,
+            max_age: Option<stale::MaxAge>,
+            link: Arc<Link>,
+            maintenance: Arc<Link>,
+            watchdog: Arc<Watchdog>) {
+    // Messages dropped by the queue are announced before the next delivered message
+    let mut dropped = 0;
+    // Entry taken off the queue while batching that didn't belong to the batch
+    let mut next = None;
+    loop {
+        let entry = match next.take() {
+            Some(entry) => entry,
+            None => {
+                watchdog.idle(queue.name());
+                queue.pop()
+            }
+        };
+        watchdog.beat(queue.name());
+        match entry {
+            queue::Entry::Item(line) => {
+                // Hold on to the message until IRC is back or maintenance is over, and then
+                // let the channel know that what follows is the backlog
+                watchdog.idle(queue.name());
+                let held_up = wait_up(&[&maintenance, &link]);
+                watchdog.beat(queue.name());
+                // Taken after waiting, as a reconnect may have replaced the server
+                let irc = current.get();
+                if max_age.map_or(false, |max_age| max_age.expired(line.received)) {
+                    dropped += 1;
+                    continue;
+                }
+                if let Some(reason) = held_up {
+                    let _ = irc.send_privmsg(&channel, &replay_notice(queue.len() + 1, reason));
+                }
+                if dropped > 0 {
+                    let _ = irc.send_privmsg(&channel, &dropped_notice(dropped));
+                    dropped = 0;
+                }
+
+                // Gather up any following messages from the same sender, such as a burst of
+                // backlog delivered after a reconnect, so they don't trip flood limits
+                let mut texts = vec![line.text];
+                let mut len = texts[0].len();
+                let max_len = irc_text_len(&channel, &line.nick);
+                while batch_seconds > 0 {
+                    match queue.pop_timeout(Duration::from_millis(BATCH_LINGER_MS)) {
+                        Some(queue::Entry::Item(more)) => {
+                            if more.nick == line.nick && more.hostmask == line.hostmask &&
+                               more.date - line.date <= batch_seconds &&
+                               len + more.text.len() + 3 <= max_len {
+                                len += more.text.len() + 3;
+                                texts.push(more.text);
+                            } else {
+                                next = Some(queue::Entry::Item(more));
+                                break;
+                            }
+                        }
+                        other => {
+                            next = other;
+                            break;
+                        }
+                    }
+                }
+
+                let msg = if line.nick.is_empty() {
+                    texts.join(" | ")
+                } else {
+                    format!("<{nick}> {message}", nick = line.nick, message = texts.join(" | "))
+                };
+                let sent = match line.hostmask {
+                    Some(hostmask) => {
+                        irc.send(Message {
+                            tags: Some(vec![Tag(HOSTMASK_TAG.to_owned(), Some(hostmask))]),
+                            prefix: None,
+                            command: Command::PRIVMSG(channel.clone(), msg),
+                        })
+                    }
+                    None => irc.send_privmsg(&channel, &msg),
+                };
+                match sent {
+                    Ok(_) => delivered(&status, &channel, line.received, latency_warning),
+                    Err(err) => {
+                        println!("[ERROR] Could not send message to \"{}\": {}", channel, err);
+                        status.lock().unwrap().last_error = Some(err.to_string());
+                    }
+                }
+            }
+            queue::Entry::Dropped(n) => dropped += n,
+        }
+    }
+}
+
+// Deliver the posts of a Telegram channel to each of `channels`, waiting `interval`
+// between posts so a burst of them doesn't flood the channels
+fn send_announcements(current: CurrentServer,
+                      channels: Vec<IrcChannel>,
+                      queue: Arc<BoundedQueue<String>>,
+                      interval: Duration,
+                      link: Arc<Link>,
+                      maintenance: Arc<Link>,
+                      watchdog: Arc<Watchdog>) {
+    let mut dropped = 0;
+    loop {
+        watchdog.idle(queue.name());
+        let entry = queue.pop();
+        match entry {
+            queue::Entry::Item(text) => {
+                wait_up(&[&maintenance, &link]);
+                watchdog.beat(queue.name());
+                let irc = current.get();
+                for channel in &channels {
+                    if dropped > 0 {
+                        let _ = irc.send_privmsg(channel, &dropped_notice(dropped));
+                    }
+                    // Posts can be longer than fits in an IRC message, and have blank lines
+                    let max_len = irc_text_len(channel, "");
+                    let pieces = text.lines()
+                                     .filter(|line| !line.trim().is_empty())
+                                     .flat_map(|line| capabilities::split_within(line, max_len));
+                    for piece in pieces {
+                        if let Err(err) = irc.send_privmsg(channel, &piece) {
+                            println!("[ERROR] Could not send announcement to \"{}\": {}", channel, err);
+                        }
+                    }
+                }
+                dropped = 0;
+                watchdog.idle(queue.name());
+                thread::sleep(interval);
+            }
+            queue::Entry::Dropped(n) => dropped += n,
+        }
+    }
+}
+
+// Interval to wait between messages if the destination is in slow mode
+fn slow_mode(status: &Mutex<DeliveryStatus>) -> Option<u64> {
+    let mut status = status.lock().unwrap();
+    let expired = status.slow_mode_since.map_or(false, |since| since.elapsed().as_secs() >= SLOW_MODE_RESET);
+    if expired {
+        status.slow_mode = None;
+        status.slow_mode_since = None;
+    }
+    status.slow_mode
+}
+
+// Append the messages for chat `id` waiting in the queue to `msg`, at most `max` of them and
+// as long as they fit in one Telegram message. The first entry that doesn't belong is left in
+// `next`. Returns the number of messages appended.
+fn merge_queued(queue: &TgQueue,
+                id: ChatID,
+                msg: &mut String,
+                next: &mut Option<queue::Entry<(ChatID, String, Instant)>>,
+                max: usize)
+                -> usize {
+    let mut merged = 0;
+    while merged < max && next.is_none() {
+        match queue.pop_timeout(Duration::from_millis(0)) {
+            Some(queue::Entry::Item((more_id, more, more_received))) => {
+                if more_id == id && msg.len() + more.len() + 1 <= capabilities::TELEGRAM.max_message_len {
+                    msg.push('\n');
+                    msg.push_str(&more);
+                    merged += 1;
+                } else {
+                    *next = Some(queue::Entry::Item((more_id, more, more_received)));
+                }
+            }
+            // Announce the drop before anything queued after it
+            Some(queue::Entry::Dropped(n)) => *next = Some(queue::Entry::Dropped(n)),
+            None => break,
+        }
+    }
+    merged
+}
+
+fn send_tg(tg: Arc<Api>,
+           group: TelegramGroup,
+           queue: Arc<TgQueue>,
+           status: Arc<Mutex<DeliveryStatus>>,
+           state: Arc<Mutex<RelayState>>,
+           quiet_hours: Option<quiet::Schedule>,
+           latency_warning: u64,
+           max_age: Option<stale::MaxAge>,
+           link: Arc<Link>,
+           maintenance: Arc<Link>,
+           watchdog: Arc<Watchdog>) {
+    // Messages dropped by the queue are announced before the next delivered message
+    let mut dropped = 0;
+    // Entry taken off the queue while batching that didn't fit in the batch
+    let mut next = None;
+    // Number of messages held during quiet hours still to be merged into posts
+    let mut held = 0;
+    loop {
+        let entry = match next.take() {
+            Some(entry) => entry,
+            None => {
+                watchdog.idle(queue.name());
+                queue.pop()
+            }
+        };
+        watchdog.beat(queue.name());
+        let (id, mut msg, received) = match entry {
+            queue::Entry::Item(item) => item,
+            queue::Entry::Dropped(n) => {
+                dropped += n;
+                continue;
+            }
+        };
+        // Hold on to the message until Telegram is back or maintenance is over, and then
+        // let the group know that what follows is the backlog
+        watchdog.idle(queue.name());
+        let held_up = wait_up(&[&maintenance, &link]);
+        watchdog.beat(queue.name());
+        if max_age.map_or(false, |max_age| max_age.expired(received)) {
+            dropped += 1;
+            continue;
+        }
+        if dropped > 0 {
+            msg = format!("{}\n{}", dropped_notice(dropped), msg);
+            dropped = 0;
+        }
+        if let Some(reason) = held_up {
+            msg = format!("{}\n{}", replay_notice(queue.len() + 1, reason), msg);
+        }
+
+        // Don't disturb the group during its quiet hours, what comes in meanwhile is
+        // delivered once they are over
+        if let Some(quiet_hours) = quiet_hours {
+            if let Some(remaining) = quiet_hours.remaining() {
+                watchdog.idle(queue.name());
+                thread::sleep(remaining);
+                watchdog.beat(queue.name());
+                let count = queue.len() + 1;
+                println!("[INFO] Quiet hours of \"{}\" are over, delivering {} held messages", group, count);
+                msg = format!("{}\n{}", quiet::notice(count), msg);
+                if quiet_hours.mode == quiet::Mode::Merge {
+                    held = count - 1;
+                }
+            }
+        }
+        if held > 0 {
+            held -= merge_queued(&queue, id, &mut msg, &mut next, held);
+            if next.is_some() {
+                held -= 1;
+            }
+        }
+
+        loop {
+            // In slow mode, wait out the interval and then send everything that has queued
+            // up in the meantime as a single message
+            if let Some(interval) = slow_mode(&status) {
+                thread::sleep(Duration::from_secs(interval));
+                merge_queued(&queue, id, &mut msg, &mut next, usize::MAX);
+            }
+
+            watchdog.beat(queue.name());
+            match tg.send_message(id, msg.clone(), None, None, None, None) {
+                Ok(sent) => {
+                    // Kept for IRC users' corrections to be made to it
+                    state.lock().unwrap().corrections.posted(id, sent.message_id, msg.clone());
+                    // A batch is as late as its oldest message
+                    delivered(&status, &group, received, latency_warning);
+                    break;
+                }
+                Err(err) => {
+                    status.lock().unwrap().last_error = Some(err.to_string());
+                    match classify(&err.to_string()) {
+                        SendError::RetryAfter(seconds) => {
+                            // Keep the message and try again once the interval has passed
+                            let mut status = status.lock().unwrap();
+                            if status.slow_mode.is_none() {
+                                println!("[WARN] Telegram group \"{}\" is rate limited, batching messages every {}s",
+                                         group,
+                                         seconds);
+                            }
+                            status.slow_mode = Some(seconds);
+                            status.slow_mode_since = Some(Instant::now());
+                        }
+                        SendError::Forbidden => {
+                            // Stop relaying until the bot is back in the group
+                            println!("[WARN] Not allowed to post to Telegram group \"{}\", deactivating it: {}",
+                                     group,
+                                     err);
+                            status.lock().unwrap().deactivated = true;
+                            break;
+                        }
+                        SendError::ChatNotFound => {
+                            // Forget the id, it is picked up again with the group's next message
+                            println!("[WARN] Telegram group \"{}\" ({}) not found, waiting to rediscover it",
+                                     group,
+                                     id);
+                            let mut state = state.lock().unwrap();
+                            if state.chat_ids.get(&group) == Some(&id) {
+                                state.chat_ids.remove(&group);
+                                save_chat_ids(&state);
+                            }
+                            break;
+                        }
+                        SendError::Other => {
+                            println!("[ERROR] Could not send message to {}: {} (message: {:?})", id, err, msg);
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Post the digest of a Telegram group every so many minutes, if anything was said
+fn send_digests(digest: Arc<Digest>, queue: Arc<TgQueue>) {
+    loop {
+        thread::sleep(Duration::from_secs(digest.minutes * 60));
+        let (id, entries) = digest.take();
+        if entries.is_empty() {
+            continue;
+        }
+        let text = digest::format(&digest.channel, digest.minutes, &entries);
+        for text in capabilities::split(&capabilities::TELEGRAM, &text) {
+            queue.push((id, text, Instant::now()));
+        }
+    }
+}
+
+// Create the outbound queues for every mapping and spawn the workers delivering them
+pub fn spawn_outbound(irc: CurrentServer,
+                      tg: Arc<Api>,
+                      config: &Config,
+                      state: Arc<Mutex<RelayState>>,
+                      watchdog: Arc<Watchdog>)
+                      -> Outbound {
+    let mut outbound = Outbound {
+        irc: HashMap::new(),
+        tg: HashMap::new(),
+        irc_link: Arc::new(Link::new("missed while IRC was down")),
+        tg_link: Arc::new(Link::new("missed while Telegram was down")),
+        maintenance: Arc::new(Link::new("queued during maintenance")),
+        announcements: HashMap::new(),
+        slack: None,
+        slack_channels: HashMap::new(),
+        teamchat: None,
+        teamchat_urls: HashMap::new(),
+        digests: HashMap::new(),
+        escape_brackets: config.escape_brackets.unwrap_or(false),
+        direction_controls: sanitize::mode(config),
+    };
+    let latency_warning = config.latency_warning_seconds.unwrap_or(DEFAULT_LATENCY_WARNING);
+    let max_age = stale::max_age(config);
+    for (group, channel) in &config.maps {
+        let irc_delivery = Delivery {
+            queue: new_queue(&format!("irc:{}", channel), config),
+            status: Arc::new(Mutex::new(DeliveryStatus::default())),
+        };
+        let tg_delivery = Delivery {
+            queue: new_queue(&format!("telegram:{}", group), config),
+            status: Arc::new(Mutex::new(DeliveryStatus::default())),
+        };
+        {
+            let irc = irc.clone();
+            let channel = channel.clone();
+            let queue = irc_delivery.queue.clone();
+            let status = irc_delivery.status.clone();
+            let batch_seconds = config.irc_batch_seconds.unwrap_or(0);
+            let link = outbound.irc_link.clone();
+            let maintenance = outbound.maintenance.clone();
+            let watchdog = watchdog.clone();
+            thread::Builder::new()
+                .name(queue.name().to_owned())
+                .spawn(move || {
+                    send_irc(irc,
+                             channel,
+                             queue,
+                             status,
+                             batch_seconds,
+                             latency_warning,
+                             max_age,
+                             link,
+                             maintenance,
+                             watchdog)
+                })
+                .unwrap();
+        }
+        {
+            let tg = tg.clone();
+            let group = group.clone();
+            let queue = tg_delivery.queue.clone();
+            let status = tg_delivery.status.clone();
+            let state = state.clone();
+            let quiet_hours = quiet::schedule(config, group);
+            let link = outbound.tg_link.clone();
+            let maintenance = outbound.maintenance.clone();
+            let watchdog = watchdog.clone();
+            thread::Builder::new()
+                .name(queue.name().to_owned())
+                .spawn(move || {
+                    send_tg(tg,
+                            group,
+                            queue,
+                            status,
+                            state,
+                            quiet_hours,
+                            latency_warning,
+                            max_age,
+                            link,
+                            maintenance,
+                            watchdog)
+                })
+                .unwrap();
+        }
+        if let Some(digest) = digest::digest(config, group, channel) {
+            let digest = Arc::new(digest);
+            {
+                let digest = digest.clone();
+                let queue = tg_delivery.queue.clone();
+                thread::Builder::new()
+                    .name(format!("digest:{}", group))
+                    .spawn(move || send_digests(digest, queue))
+                    .unwrap();
+            }
+            outbound.digests.insert(group.clone(), digest);
+        }
+        outbound.irc.insert(channel.clone(), irc_delivery);
+        outbound.tg.insert(group.clone(), tg_delivery);
+    }
+    for announcement in config.announcements.iter().flat_map(|announcements| announcements) {
+        let queue = new_queue(&format!("announcements:{}", announcement.channel), config);
+        {
+            let irc = irc.clone();
+            let channels = announcement.irc_channels.clone();
+            let queue = queue.clone();
+            let interval = Duration::from_secs(announcement.min_interval.unwrap_or(DEFAULT_ANNOUNCEMENT_INTERVAL));
+            let link = outbound.irc_link.clone();
+            let maintenance = outbound.maintenance.clone();
+            let watchdog = watchdog.clone();
+            thread::Builder::new()
+                .name(queue.name().to_owned())
+                .spawn(move || send_announcements(irc, channels, queue, interval, link, maintenance, watchdog))
+                .unwrap();
+        }
+        outbound.announcements.insert(announcement.channel.clone(), queue);
+    }
+    if let Some(ref slack) = config.slack {
+        let queue = new_queue("slack", config);
+        {
+            let slack = slack.clone();
+            let queue = queue.clone();
+            let maintenance = outbound.maintenance.clone();
+            let watchdog = watchdog.clone();
+            thread::Builder::new()
+                .name(queue.name().to_owned())
+                .spawn(move || slack::send(slack, queue, maintenance, watchdog))
+                .unwrap();
+        }
+        outbound.slack = Some(queue);
+        outbound.slack_channels = slack.channels.clone();
+    }
+    if let Some(ref teamchat) = config.teamchat {
+        let queue = new_queue("teamchat", config);
+        {
+            let queue = queue.clone();
+            let maintenance = outbound.maintenance.clone();
+            let watchdog = watchdog.clone();
+            thread::Builder::new()
+                .name(queue.name().to_owned())
+                .spawn(move || teamchat::send(queue, maintenance, watchdog))
+                .unwrap();
+        }
+        outbound.teamchat = Some(queue);
+        outbound.teamchat_urls = teamchat.links.iter().map(|link| (link.group.clone(), link.incoming_url.clone())).collect();
+    }
+    outbound
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use queue::{BoundedQueue, Entry, Overflow};
+    use std::iter;
+    use capabilities;
+    use super::{SendError, TgQueue, classify, irc_text_len, merge_queued, shorten_nick};
+
+    const CHAT: i64 = -100;
+    const OTHER_CHAT: i64 = -200;
+
+    fn queue(capacity: usize, overflow: Overflow, messages: &[(i64, &str)]) -> TgQueue {
+        let queue = BoundedQueue::new("test", capacity, overflow);
+        for &(id, text) in messages {
+            queue.push((id, text.to_owned(), Instant::now()));
+        }
+        queue
+    }
+
+    #[test]
+    fn rate_limits_are_retried() {
+        assert_eq!(classify("Too Many Requests: retry after 7"), SendError::RetryAfter(7));
+        assert_eq!(classify("Forbidden: bot was kicked from the group chat"), SendError::Forbidden);
+    }
+
+    // While a message is retried in slow mode, what queued up behind it follows it in the
+    // order it was queued
+    #[test]
+    fn retried_message_comes_first() {
+        let queue = queue(10, Overflow::DropOldest, &[(CHAT, "second"), (CHAT, "third")]);
+        let mut msg = "first".to_owned();
+        let mut next = None;
+        assert_eq!(merge_queued(&queue, CHAT, &mut msg, &mut next, usize::max_value()), 2);
+        assert_eq!(msg, "first\nsecond\nthird");
+        assert!(next.is_none());
+        assert!(queue.is_empty());
+    }
+
+    // A message for another chat ends the batch and is delivered next, ahead of anything
+    // queued after it
+    #[test]
+    fn other_chat_ends_batch() {
+        let queue = queue(10,
+                          Overflow::DropOldest,
+                          &[(CHAT, "second"), (OTHER_CHAT, "elsewhere"), (CHAT, "third")]);
+        let mut msg = "first".to_owned();
+        let mut next = None;
+        assert_eq!(merge_queued(&queue, CHAT, &mut msg, &mut next, usize::max_value()), 1);
+        assert_eq!(msg, "first\nsecond");
+        match next {
+            Some(Entry::Item((id, text, _))) => {
+                assert_eq!(id, OTHER_CHAT);
+                assert_eq!(text, "elsewhere");
+            }
+            other => panic!("expected the other chat's message next, got {:?}", other),
+        }
+        assert_eq!(queue.items(|item| item.1.clone()), vec!["third".to_owned()]);
+    }
+
+    // Drops are announced where they happened, not after the messages queued behind them
+    #[test]
+    fn drop_ends_batch() {
+        let queue = queue(2, Overflow::Summarize, &[(CHAT, "first"), (CHAT, "second"), (CHAT, "lost")]);
+        let mut msg = match queue.pop_timeout(Duration::from_millis(0)) {
+            Some(Entry::Item((_, text, _))) => text,
+            other => panic!("expected the first message, got {:?}", other),
+        };
+        queue.push((CHAT, "third".to_owned(), Instant::now()));
+        let mut next = None;
+        assert_eq!(merge_queued(&queue, CHAT, &mut msg, &mut next, usize::max_value()), 1);
+        assert_eq!(msg, "first\nsecond");
+        match next {
+            Some(Entry::Dropped(n)) => assert_eq!(n, 1),
+            other => panic!("expected the drop next, got {:?}", other),
+        }
+        assert_eq!(queue.items(|item| item.1.clone()), vec!["third".to_owned()]);
+    }
+
+    // Merging held messages stops at the count asked for, leaving the rest in order
+    #[test]
+    fn merge_stops_at_max() {
+        let queue = queue(10, Overflow::DropOldest, &[(CHAT, "second"), (CHAT, "third")]);
+        let mut msg = "first".to_owned();
+        let mut next = None;
+        assert_eq!(merge_queued(&queue, CHAT, &mut msg, &mut next, 1), 1);
+        assert_eq!(msg, "first\nsecond");
+        assert!(next.is_none());
+        assert_eq!(queue.items(|item| item.1.clone()), vec!["third".to_owned()]);
+    }
+
+    #[test]
+    fn irc_lines_fit_with_long_nicks() {
+        let channel = "#a-rather-long-channel-name";
+        // A Telegram name of 128 two byte characters
+        let nick = shorten_nick(iter::repeat("ж").take(128).collect());
+        assert!(nick.len() <= 64 && nick.chars().all(|c| c == 'ж'));
+        let text: String = iter::repeat("жж ").take(300).collect();
+        let source: String = iter::repeat("x").take(99).collect();
+        let max_len = irc_text_len(channel, &nick);
+        let pieces = capabilities::split_within(&text, max_len);
+        assert!(pieces.len() > 1);
+        for piece in &pieces {
+            let line = format!(":{} PRIVMSG {} :<{}> {}\r\n", source, channel, nick, piece);
+            assert!(line.len() <= 512, "{} bytes", line.len());
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use std::cmp;
+use std::collections::HashMap;
+use telegram_bot::types::User;
+use admins;
+use commands::Command;
+use super::{Config, RelayState, is_irc_admin, is_tg_admin};
+
+// What someone may do with the bot's commands, from least to most
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    User,
+    Moderator,
+    Admin,
+    Owner,
+}
+
+const ROLES: &'static [(&'static str, Role)] = &[("user", Role::User),
+                                                  ("moderator", Role::Moderator),
+                                                  ("admin", Role::Admin),
+                                                  ("owner", Role::Owner)];
+
+impl Role {
+    pub fn name(&self) -> &'static str {
+        ROLES.iter().find(|&&(_, role)| role == *self).map(|&(name, _)| name).unwrap()
+    }
+
+    // Roles below admin only run commands for the mapping they are given in
+    pub fn scoped(&self) -> bool {
+        *self < Role::Admin
+    }
+}
+
+fn parse(name: &str) -> Option<Role> {
+    ROLES.iter().find(|&&(role, _)| role == name).map(|&(_, role)| role)
+}
+
+// Whether `role` may run `command`. A role given in role_commands may run exactly the
+// commands listed there, "*" standing for all of them. Otherwise it may run the commands
+// of its own role and the roles below it.
+pub fn allowed(config: &Config, role: Role, command: &Command) -> bool {
+    match config.role_commands.as_ref().and_then(|roles| roles.get(role.name())) {
+        Some(commands) => commands.iter().any(|allowed| allowed == command.name || allowed == "*"),
+        None => role >= command.role,
+    }
+}
+
+fn configured(roles: Option<&HashMap<String, String>>, key: &str) -> Option<Role> {
+    roles.and_then(|roles| roles.get(key)).and_then(|name| {
+        let role = parse(name);
+        if role.is_none() {
+            println!("[WARN] Unknown role \"{}\" given to {}", name, key);
+        }
+        role
+    })
+}
+
+// The role of a Telegram user in the group `here`, if any. The admins in the config are
+// owners, the admins of a group are at least moderators in it with group_admins on.
+pub fn telegram_role(config: &Config, state: &RelayState, user: &User, here: Option<&str>) -> Role {
+    if is_tg_admin(config, user) {
+        return Role::Owner;
+    }
+    let role = configured(config.telegram_roles.as_ref(), &user.id.to_string()).unwrap_or(Role::User);
+    let group_admin = config.group_admins.unwrap_or(false) &&
+                      here.map_or(false, |group| admins::is_group_admin(state, group, user.id));
+    if group_admin { cmp::max(role, Role::Moderator) } else { role }
+}
+
+// The role of an IRC user. The owners in the config are owners. When those are verified by
+// services account, so are the other roles, a nick alone doesn't get one.
+pub fn irc_role(config: &Config, nick: &str, account: Option<&str>) -> Role {
+    if is_irc_admin(config, nick, account) {
+        return Role::Owner;
+    }
+    let key = if config.irc_owner_accounts.is_some() { account } else { Some(nick) };
+    key.and_then(|key| configured(config.irc_roles.as_ref(), key)).unwrap_or(Role::User)
+}
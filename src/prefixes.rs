@@ -0,0 +1,101 @@
+use std::str::FromStr;
+use super::Config;
+use locale;
+
+// Prefix of the IRC admin commands, unless configured otherwise
+pub const DEFAULT_IRC_COMMAND_PREFIX: &'static str = "!";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    IrcToTg,
+    TgToIrc,
+}
+
+// What to do with a message starting with one of the configured prefixes, usually a
+// command meant for another bot
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    Drop,
+    // Relay the message, marked as a command for another bot
+    Annotate,
+    Relay,
+}
+
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Action, String> {
+        match s {
+            "drop" => Ok(Action::Drop),
+            "annotate" => Ok(Action::Annotate),
+            "relay" => Ok(Action::Relay),
+            _ => Err(format!("unknown prefix action \"{}\"", s)),
+        }
+    }
+}
+
+// The only direction a rule applies in, or None if it applies in both
+fn parse_direction(direction: Option<&str>) -> Result<Option<Direction>, String> {
+    match direction {
+        None | Some("both") => Ok(None),
+        Some("irc→tg") | Some("irc->tg") => Ok(Some(Direction::IrcToTg)),
+        Some("tg→irc") | Some("tg->irc") => Ok(Some(Direction::TgToIrc)),
+        Some(other) => Err(format!("unknown direction \"{}\"", other)),
+    }
+}
+
+fn applies(rule_direction: Option<&str>, direction: Direction) -> bool {
+    match parse_direction(rule_direction) {
+        Ok(only) => only.map_or(true, |only| only == direction),
+        // Ruled out by `check` at startup
+        Err(_) => false,
+    }
+}
+
+// Make sure every prefix rule has a direction and an action we know, so a typo shows up
+// at startup rather than with the first message it applies to
+pub fn check(config: &Config) -> Result<(), String> {
+    for rule in config.prefix_rules.iter().flat_map(|rules| rules) {
+        try!(parse_direction(rule.direction.as_ref().map(|d| &d[..]))
+                 .map_err(|err| format!("rule for \"{}\": {}", rule.prefix, err)));
+        try!(rule.action.parse::<Action>().map_err(|err| format!("rule for \"{}\": {}", rule.prefix, err)));
+    }
+    Ok(())
+}
+
+// If the message is a Telegram command addressed to another bot, like "/stats@otherbot",
+// return the command and the bot it is for.
+pub fn other_bot_command<'a>(text: &'a str, own_username: &str) -> Option<(&'a str, &'a str)> {
+    if !text.starts_with('/') {
+        return None;
+    }
+    let word = text.split_whitespace().next().unwrap_or("");
+    let mut parts = word.splitn(2, '@');
+    let command = parts.next().unwrap_or("");
+    match parts.next() {
+        Some(bot) if !bot.is_empty() && bot.to_lowercase() != own_username.to_lowercase() => Some((command, bot)),
+        _ => None,
+    }
+}
+
+// Apply the first matching prefix rule to a message about to be relayed to or from the
+// Telegram group `group`. Returns the text to relay, or None if the message is dropped.
+pub fn apply(config: &Config, group: &str, direction: Direction, text: &str) -> Option<String> {
+    let rules = match config.prefix_rules {
+        Some(ref rules) => rules,
+        None => return Some(text.to_owned()),
+    };
+    let rule = rules.iter().find(|rule| {
+        text.starts_with(&rule.prefix[..]) && applies(rule.direction.as_ref().map(|d| &d[..]), direction)
+    });
+    let action = match rule {
+        // Checked at startup
+        Some(rule) => rule.action.parse().unwrap_or(Action::Relay),
+        None => Action::Relay,
+    };
+    match action {
+        Action::Drop => None,
+        Action::Annotate => Some(locale::text(config, group, locale::BOT_COMMAND, &[("message", text)])),
+        Action::Relay => Some(text.to_owned()),
+    }
+}
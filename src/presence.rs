@@ -0,0 +1,125 @@
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use hyper::method::Method;
+use hyper::client::Request;
+use rustc_serialize::json::Json;
+use time;
+use error::{self, ResultExt};
+use outbound::Outbound;
+use urls;
+use super::{ChatID, RelayState, TelegramGroup};
+
+// The line of the group description that is ours, recognized by how it starts
+const MARKER: &'static str = "IRC link: ";
+// Longest description Telegram accepts, in characters
+const MAX_DESCRIPTION: usize = 255;
+// Seconds between checks of the IRC link
+const CHECK_INTERVAL: u64 = 30;
+pub const DEFAULT_REFRESH_MINUTES: u64 = 10;
+
+// Call a Telegram method with `params`, returning its result
+fn call(token: &str, method: &str, params: Vec<(&str, String)>) -> error::Result<Json> {
+    let mut url = try!(urls::parse(&format!("https://api.telegram.org/bot{}/{}", token, method),
+                                   &format!("{} url", method)));
+    url.set_query_from_pairs(params.iter().map(|&(key, ref value)| (key, &value[..])));
+    let mut resp = try!(Request::new(Method::Get, url)
+                            .and_then(|req| req.start())
+                            .and_then(|req| req.send())
+                            .context(format!("calling {}", method)));
+    if !resp.status.is_success() {
+        return Err(format!("calling {}: server responded with {}", method, resp.status).into());
+    }
+    let mut body = String::new();
+    try!(resp.read_to_string(&mut body).context(format!("reading the reply to {}", method)));
+    let reply = try!(Json::from_str(&body).map_err(|err| err.to_string()));
+    reply.find("result").cloned().ok_or(format!("{} returned no result", method).into())
+}
+
+// How long ago a unix timestamp was, roughly
+fn ago(date: i64) -> String {
+    let minutes = (time::get_time().sec - date) / 60;
+    if minutes < 1 {
+        "just now".to_owned()
+    } else if minutes < 60 {
+        format!("{}m ago", minutes)
+    } else if minutes < 24 * 60 {
+        format!("{}h ago", minutes / 60)
+    } else {
+        format!("{}d ago", minutes / (24 * 60))
+    }
+}
+
+// The status line for a mapping, like "IRC link: connected ✓, last message 2m ago"
+fn marker(connected: bool, last_message: Option<i64>) -> String {
+    let mut marker = format!("{}{}", MARKER, if connected { "connected ✓" } else { "disconnected ✗" });
+    if let Some(date) = last_message {
+        marker.push_str(&format!(", last message {}", ago(date)));
+    }
+    marker
+}
+
+// `description` with our line replaced by `marker`, or added at the end. None if there
+// isn't room for both.
+fn with_marker(description: &str, marker: &str) -> Option<String> {
+    let rest: Vec<&str> = description.lines().filter(|line| !line.starts_with(MARKER)).collect();
+    let rest = rest.join("\n");
+    let rest = rest.trim_right();
+    let updated = if rest.is_empty() { marker.to_owned() } else { format!("{}\n\n{}", rest, marker) };
+    if updated.chars().count() > MAX_DESCRIPTION { None } else { Some(updated) }
+}
+
+// Put the status line into the description of a group, if it changed
+fn update(token: &str, chat_id: ChatID, marker: &str) -> error::Result<()> {
+    let chat = try!(call(token, "getChat", vec![("chat_id", chat_id.to_string())]));
+    let description = chat.find("description").and_then(|description| description.as_string()).unwrap_or("");
+    let updated = match with_marker(description, marker) {
+        Some(updated) => updated,
+        None => {
+            // Rather leave the status out than cut off what the admins wrote
+            println!("[WARN] No room for the status line in the description of {}, leaving it as it is",
+                     chat_id);
+            return Ok(());
+        }
+    };
+    if updated == description {
+        return Ok(());
+    }
+    try!(call(token,
+              "setChatDescription",
+              vec![("chat_id", chat_id.to_string()), ("description", updated)]));
+    Ok(())
+}
+
+// Keep the status line in the descriptions of the mapped groups up to date: right away when
+// the IRC link goes down or comes back, otherwise every `refresh` minutes. Needs the bot to
+// be allowed to change the group info.
+pub fn run(token: String, outbound: Arc<Outbound>, state: Arc<Mutex<RelayState>>, refresh: u64) {
+    let refresh = Duration::from_secs(refresh * 60);
+    let mut connected = None;
+    let mut last_update = Instant::now();
+    loop {
+        let now_connected = !outbound.irc_link.is_down();
+        if connected != Some(now_connected) || last_update.elapsed() >= refresh {
+            connected = Some(now_connected);
+            last_update = Instant::now();
+            let groups: Vec<(TelegramGroup, ChatID, Option<i64>)> = {
+                let state = state.lock().unwrap();
+                state.chat_ids
+                     .iter()
+                     .filter(|&(group, _)| state.irc_channel.contains_key(group))
+                     .map(|(group, &id)| {
+                         (group.clone(), id, state.activity.get(group).and_then(|activity| activity.last_message))
+                     })
+                     .collect()
+            };
+            for (group, id, last_message) in groups {
+                if let Err(err) = update(&token, id, &marker(now_connected, last_message)) {
+                    println!("[WARN] Could not update the description of \"{}\": {}", group, err);
+                }
+            }
+        }
+        thread::sleep(Duration::from_secs(CHECK_INTERVAL));
+    }
+}
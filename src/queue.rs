@@ -0,0 +1,216 @@
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::{Mutex, Condvar};
+use std::time::{Duration, Instant};
+
+// What to do with a new message when the queue is already at capacity
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Overflow {
+    // Discard the oldest queued message to make room for the new one
+    DropOldest,
+    // Discard the new message
+    DropNewest,
+    // Discard the new message, but let the consumer know how many were dropped
+    Summarize,
+}
+
+impl FromStr for Overflow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Overflow, String> {
+        match s {
+            "drop-oldest" => Ok(Overflow::DropOldest),
+            "drop-newest" => Ok(Overflow::DropNewest),
+            "summarize" => Ok(Overflow::Summarize),
+            _ => Err(format!("unknown overflow policy \"{}\"", s)),
+        }
+    }
+}
+
+// An entry handed to the consumer of a queue
+#[derive(Debug)]
+pub enum Entry<T> {
+    Item(T),
+    // A number of messages were dropped at this point in the queue. Only produced by the
+    // `Summarize` policy.
+    Dropped(usize),
+}
+
+struct Inner<T> {
+    entries: VecDeque<Entry<T>>,
+    // Number of `Entry::Item`s currently queued
+    len: usize,
+    // Drops that have not yet been reported to the consumer
+    pending_drops: usize,
+    // Total number of messages dropped over the lifetime of the queue
+    total_drops: usize,
+    // Closed queues don't hand out anything anymore, see `close`
+    closed: bool,
+    // The consumer is waiting in `pop`, so it holds nothing taken off the queue
+    waiting: bool,
+}
+
+// A multi-producer queue with a fixed capacity. Producers never block; once the queue
+// is full, messages are discarded according to the overflow policy.
+pub struct BoundedQueue<T> {
+    name: String,
+    capacity: usize,
+    overflow: Overflow,
+    inner: Mutex<Inner<T>>,
+    available: Condvar,
+}
+
+impl<T> BoundedQueue<T> {
+    pub fn new<S: Into<String>>(name: S, capacity: usize, overflow: Overflow) -> BoundedQueue<T> {
+        BoundedQueue {
+            name: name.into(),
+            capacity: capacity,
+            overflow: overflow,
+            inner: Mutex::new(Inner {
+                entries: VecDeque::new(),
+                len: 0,
+                pending_drops: 0,
+                total_drops: 0,
+                closed: false,
+                waiting: false,
+            }),
+            available: Condvar::new(),
+        }
+    }
+
+    // Add an item to the back of the queue. Returns false if a message had to be dropped.
+    pub fn push(&self, item: T) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let mut accepted = true;
+        if inner.len >= self.capacity {
+            accepted = false;
+            inner.total_drops += 1;
+            println!("[WARN] Queue \"{}\" is full ({} messages), dropping {} message ({} dropped so far)",
+                     self.name,
+                     self.capacity,
+                     match self.overflow {
+                         Overflow::DropOldest => "oldest",
+                         _ => "newest",
+                     },
+                     inner.total_drops);
+            match self.overflow {
+                Overflow::DropOldest => {
+                    // Only items are ever queued under this policy
+                    if inner.entries.pop_front().is_some() {
+                        inner.len -= 1;
+                    }
+                }
+                Overflow::DropNewest => return false,
+                Overflow::Summarize => {
+                    inner.pending_drops += 1;
+                    return false;
+                }
+            }
+        }
+        if inner.pending_drops > 0 {
+            let dropped = inner.pending_drops;
+            inner.pending_drops = 0;
+            inner.entries.push_back(Entry::Dropped(dropped));
+        }
+        inner.entries.push_back(Entry::Item(item));
+        inner.len += 1;
+        self.available.notify_one();
+        accepted
+    }
+
+    // Remove the entry at the front of the queue, blocking until one is available.
+    pub fn pop(&self) -> Entry<T> {
+        let mut inner = self.inner.lock().unwrap();
+        loop {
+            if inner.closed {
+                inner.waiting = true;
+                inner = self.available.wait(inner).unwrap();
+                continue;
+            }
+            if let Some(entry) = inner.entries.pop_front() {
+                if let Entry::Item(_) = entry {
+                    inner.len -= 1;
+                }
+                return entry;
+            }
+            if inner.pending_drops > 0 {
+                let dropped = inner.pending_drops;
+                inner.pending_drops = 0;
+                return Entry::Dropped(dropped);
+            }
+            inner.waiting = true;
+            inner = self.available.wait(inner).unwrap();
+            inner.waiting = false;
+        }
+    }
+
+    // Like `pop`, but gives up and returns `None` if nothing arrives within `timeout`.
+    pub fn pop_timeout(&self, timeout: Duration) -> Option<Entry<T>> {
+        let deadline = Instant::now() + timeout;
+        let mut inner = self.inner.lock().unwrap();
+        loop {
+            if inner.closed {
+                return None;
+            }
+            if let Some(entry) = inner.entries.pop_front() {
+                if let Entry::Item(_) = entry {
+                    inner.len -= 1;
+                }
+                return Some(entry);
+            }
+            if inner.pending_drops > 0 {
+                let dropped = inner.pending_drops;
+                inner.pending_drops = 0;
+                return Some(Entry::Dropped(dropped));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            inner = self.available.wait_timeout(inner, deadline - now).unwrap().0;
+        }
+    }
+
+    // Stop handing out entries, for what is still queued to be saved on shutdown. The
+    // consumer finishes what it has taken and then waits for good.
+    pub fn close(&self) {
+        self.inner.lock().unwrap().closed = true;
+    }
+
+    // Whether the consumer is waiting for entries, holding none
+    pub fn consumer_waiting(&self) -> bool {
+        self.inner.lock().unwrap().waiting
+    }
+
+    // The queued messages, front first, each converted by `f`
+    pub fn items<U, F: Fn(&T) -> U>(&self, f: F) -> Vec<U> {
+        self.inner
+            .lock()
+            .unwrap()
+            .entries
+            .iter()
+            .filter_map(|entry| match *entry {
+                Entry::Item(ref item) => Some(f(item)),
+                Entry::Dropped(_) => None,
+            })
+            .collect()
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // Number of messages currently waiting in the queue
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Total number of messages this queue has dropped
+    pub fn dropped(&self) -> usize {
+        self.inner.lock().unwrap().total_drops
+    }
+}
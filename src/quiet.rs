@@ -0,0 +1,95 @@
+use std::time::Duration;
+use time;
+use super::{Config, QuietHoursConfig};
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+// What happens to the messages for a Telegram group during its quiet hours
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    // Keep them and deliver them one by one once quiet hours are over
+    Hold,
+    // Keep them and deliver them merged into as few posts as possible
+    Merge,
+}
+
+// Daily quiet hours of a Telegram group, in minutes since midnight local time
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Schedule {
+    pub start: u32,
+    pub end: u32,
+    pub mode: Mode,
+}
+
+// Minutes since midnight of a time given as "HH:MM"
+fn parse_time(text: &str) -> Result<u32, String> {
+    let mut parts = text.splitn(2, ':');
+    let hours = parts.next().and_then(|hours| hours.trim().parse::<u32>().ok());
+    let minutes = parts.next().and_then(|minutes| minutes.trim().parse::<u32>().ok());
+    match (hours, minutes) {
+        (Some(hours), Some(minutes)) if hours < 24 && minutes < 60 => Ok(hours * 60 + minutes),
+        _ => Err(format!("invalid time \"{}\", expected HH:MM", text)),
+    }
+}
+
+fn parse(quiet: &QuietHoursConfig) -> Result<Schedule, String> {
+    let mode = match quiet.mode.as_ref().map(|mode| &mode[..]) {
+        None | Some("hold") => Mode::Hold,
+        Some("merge") => Mode::Merge,
+        Some(mode) => return Err(format!("unknown quiet hours mode \"{}\"", mode)),
+    };
+    Ok(Schedule {
+        start: try!(parse_time(&quiet.start)),
+        end: try!(parse_time(&quiet.end)),
+        mode: mode,
+    })
+}
+
+// The quiet hours of the Telegram group `group`, if it has any
+pub fn schedule(config: &Config, group: &str) -> Option<Schedule> {
+    config.quiet_hours
+          .as_ref()
+          .and_then(|quiet_hours| quiet_hours.get(group))
+          .map(|quiet| parse(quiet).unwrap_or_else(|err| panic!("error in quiet hours of \"{}\": {}", group, err)))
+}
+
+fn now() -> (u32, u32) {
+    let now = time::now();
+    (now.tm_hour as u32 * 60 + now.tm_min as u32, now.tm_sec as u32)
+}
+
+impl Schedule {
+    fn contains(&self, minute: u32) -> bool {
+        if self.start <= self.end {
+            self.start <= minute && minute < self.end
+        } else {
+            // Spans midnight, like 23:00 to 07:00
+            minute >= self.start || minute < self.end
+        }
+    }
+
+    // How long the current quiet hours last, if they are on right now
+    pub fn remaining(&self) -> Option<Duration> {
+        let (minute, second) = now();
+        if !self.contains(minute) {
+            return None;
+        }
+        let minutes = (self.end + MINUTES_PER_DAY - minute) % MINUTES_PER_DAY;
+        Some(Duration::from_secs((minutes as u64 * 60).saturating_sub(second as u64)))
+    }
+
+    // The schedule as configured, for the state dump
+    pub fn describe(&self) -> String {
+        format!("{:02}:{:02}–{:02}:{:02} ({})",
+                self.start / 60,
+                self.start % 60,
+                self.end / 60,
+                self.end % 60,
+                if self.mode == Mode::Merge { "merge" } else { "hold" })
+    }
+}
+
+// Put in front of what was held back during quiet hours
+pub fn notice(count: usize) -> String {
+    format!("[{} message{} from quiet hours]", count, if count == 1 { "" } else { "s" })
+}
@@ -0,0 +1,122 @@
+use std::collections::{HashMap, VecDeque};
+use telegram_bot::types::Integer;
+use store::RelayedEntry;
+use super::ChatID;
+
+// Number of relayed Telegram messages remembered
+const CAPACITY: usize = 1000;
+
+#[derive(Clone, Debug)]
+pub struct RelayedMessage {
+    pub nick: String,
+    // The Telegram user who sent it
+    pub user_id: Integer,
+    // The text as relayed to IRC
+    pub text: String,
+}
+
+// Telegram messages recently relayed to IRC, by chat and message id, so that later
+// updates about a message can refer back to what was relayed
+#[derive(Clone, Default)]
+pub struct Relayed {
+    order: VecDeque<(ChatID, Integer)>,
+    messages: HashMap<(ChatID, Integer), RelayedMessage>,
+}
+
+impl Relayed {
+    pub fn new() -> Relayed {
+        Relayed::default()
+    }
+
+    pub fn record(&mut self, chat_id: ChatID, message_id: Integer, message: RelayedMessage) {
+        let key = (chat_id, message_id);
+        if self.messages.insert(key, message).is_some() {
+            return;
+        }
+        self.order.push_back(key);
+        if self.order.len() > CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.messages.remove(&oldest);
+            }
+        }
+    }
+
+    // Forget the messages of the users `user_ids`, returning how many there were
+    pub fn forget_users(&mut self, user_ids: &[Integer]) -> usize {
+        let forgotten: Vec<(ChatID, Integer)> = self.messages
+                                                    .iter()
+                                                    .filter(|&(_, message)| user_ids.contains(&message.user_id))
+                                                    .map(|(key, _)| *key)
+                                                    .collect();
+        for key in &forgotten {
+            self.messages.remove(key);
+        }
+        let messages = &self.messages;
+        self.order.retain(|key| messages.contains_key(key));
+        forgotten.len()
+    }
+
+    // The remembered messages, oldest first, to be saved
+    pub fn entries(&self) -> Vec<RelayedEntry> {
+        self.order
+            .iter()
+            .filter_map(|&(chat_id, message_id)| {
+                self.messages.get(&(chat_id, message_id)).map(|message| {
+                    RelayedEntry {
+                        chat_id: chat_id,
+                        message_id: message_id,
+                        nick: message.nick.clone(),
+                        user_id: message.user_id,
+                        text: message.text.clone(),
+                    }
+                })
+            })
+            .collect()
+    }
+
+    // Remember the messages saved from `entries` before a restart
+    pub fn restore(&mut self, entries: Vec<RelayedEntry>) {
+        for entry in entries {
+            self.record(entry.chat_id, entry.message_id, RelayedMessage {
+                nick: entry.nick,
+                user_id: entry.user_id,
+                text: entry.text,
+            });
+        }
+    }
+
+    pub fn get(&self, chat_id: ChatID, message_id: Integer) -> Option<&RelayedMessage> {
+        self.messages.get(&(chat_id, message_id))
+    }
+
+    // Look up the id of a message relayed from `chat_id` by reference: its message id, "^"
+    // for the latest message, "^2" for the one before it and so on, or the name of the
+    // sender for their latest message
+    pub fn find(&self, chat_id: ChatID, reference: &str) -> Option<Integer> {
+        if let Ok(message_id) = reference.parse() {
+            return self.get(chat_id, message_id).map(|_| message_id);
+        }
+        let mut recent = self.order
+                             .iter()
+                             .rev()
+                             .filter(|&&(chat, _)| chat == chat_id)
+                             .map(|&(_, message_id)| message_id);
+        if reference.starts_with('^') {
+            let back = match &reference[1..] {
+                "" => 1,
+                back => {
+                    match back.parse::<usize>() {
+                        Ok(back) if back > 0 => back,
+                        _ => return None,
+                    }
+                }
+            };
+            recent.nth(back - 1)
+        } else {
+            let reference = reference.to_lowercase();
+            recent.find(|&message_id| {
+                self.get(chat_id, message_id).map_or(false, |message| message.nick.to_lowercase() == reference)
+            })
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use time;
+use outbound::{IrcLine, Outbound};
+use store::Reminder;
+use super::RelayState;
+
+// Seconds between looks for reminders that have come due
+const CHECK_INTERVAL: u64 = 15;
+
+// Send a reminder to the side or sides of its mapping it is for
+fn send(outbound: &Outbound, state: &RelayState, reminder: &Reminder) {
+    let text = format!("Reminder for {}: {}", reminder.nick, reminder.text);
+    println!("[INFO] Reminding {} in \"{}\": {}", reminder.nick, reminder.group, reminder.text);
+    if reminder.network != "irc" {
+        if let Some(&id) = state.chat_ids.get(&reminder.group) {
+            outbound.to_tg(&reminder.group, id, text.clone());
+        }
+    }
+    if reminder.network != "telegram" {
+        if let Some(channel) = state.irc_channel.get(&reminder.group) {
+            outbound.to_irc(channel, IrcLine {
+                nick: String::new(),
+                hostmask: None,
+                text: text,
+                date: time::get_time().sec,
+                received: Instant::now(),
+            });
+        }
+    }
+}
+
+// Deliver reminders once they are due. Those that came due while we were down are
+// delivered right away.
+pub fn deliver(outbound: Arc<Outbound>, state: Arc<Mutex<RelayState>>) {
+    loop {
+        {
+            let mut state = state.lock().unwrap();
+            let now = time::get_time().sec;
+            let (due, pending): (Vec<_>, Vec<_>) = mem::replace(&mut state.reminders, vec![])
+                                                       .into_iter()
+                                                       .partition(|reminder| reminder.due <= now);
+            state.reminders = pending;
+            if !due.is_empty() {
+                for reminder in &due {
+                    send(&outbound, &state, reminder);
+                }
+                if let Err(err) = state.store.save_reminders(&state.reminders) {
+                    println!("[ERROR] Could not save reminders: {}", err);
+                }
+            }
+        }
+        thread::sleep(Duration::from_secs(CHECK_INTERVAL));
+    }
+}
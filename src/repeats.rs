@@ -0,0 +1,85 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use super::Config;
+
+// Times the same text may be relayed from one network within duplicate_window_seconds,
+// unless configured
+const DEFAULT_DUPLICATE_LIMIT: usize = 3;
+
+// What to do with a message about to be relayed
+#[derive(Clone, Debug, PartialEq)]
+pub enum Verdict {
+    // Relay it, after a note on how often the previous message was repeated, as the sender
+    // of that message and the text to relay for them
+    Relay(Option<(String, String)>),
+    // Don't relay it, it repeats what was just said or has been going around too often
+    Drop,
+}
+
+// A message that is being repeated, and how many times it was said in a row
+#[derive(Clone)]
+struct Run {
+    nick: String,
+    text: String,
+    count: usize,
+}
+
+// Keeps bots and misbehaving clients that send the same line over and over from flooding
+// the other side. Identical consecutive messages of a sender in a mapping are collapsed
+// into the first, followed by "message ×N" once the sender or someone else says something
+// else. Past that, the same text is relayed from a network at most a few times per window,
+// whoever sends it and wherever.
+#[derive(Clone, Default)]
+pub struct Repeats {
+    // By network and mapping
+    runs: HashMap<(String, String), Run>,
+    // Texts recently relayed from each network, oldest first
+    recent: HashMap<String, VecDeque<(Instant, String)>>,
+}
+
+impl Repeats {
+    // Check a message from `nick` on `network` ("irc" or "telegram") about to be relayed
+    // through the mapping of `group`
+    pub fn check(&mut self, config: &Config, network: &str, group: &str, nick: &str, text: &str) -> Verdict {
+        let mut note = None;
+        if config.collapse_repeats.unwrap_or(false) {
+            let key = (network.to_owned(), group.to_owned());
+            if let Some(run) = self.runs.get_mut(&key) {
+                if run.nick == nick && run.text == text {
+                    run.count += 1;
+                    return Verdict::Drop;
+                }
+            }
+            if let Some(run) = self.runs.remove(&key) {
+                if run.count > 1 {
+                    note = Some((run.nick, format!("{} ×{}", run.text, run.count)));
+                }
+            }
+            self.runs.insert(key,
+                             Run {
+                                 nick: nick.to_owned(),
+                                 text: text.to_owned(),
+                                 count: 1,
+                             });
+        }
+
+        if let Some(window) = config.duplicate_window_seconds {
+            let window = Duration::from_secs(window);
+            let limit = config.duplicate_limit.unwrap_or(DEFAULT_DUPLICATE_LIMIT);
+            let recent = self.recent.entry(network.to_owned()).or_insert(VecDeque::new());
+            while recent.front().map_or(false, |&(sent, _)| sent.elapsed() > window) {
+                recent.pop_front();
+            }
+            if recent.iter().filter(|&&(_, ref recent_text)| recent_text == text).count() >= limit {
+                println!("[INFO] Not relaying \"{}\" from {} again, it was relayed {} times in the last {}s",
+                         text,
+                         network,
+                         limit,
+                         window.as_secs());
+                return Verdict::Drop;
+            }
+            recent.push_back((Instant::now(), text.to_owned()));
+        }
+        Verdict::Relay(note)
+    }
+}
@@ -0,0 +1,73 @@
+use super::Config;
+
+// What is done about characters that silently change the direction of the text after
+// them, like the right-to-left override that makes a "gpj.exe" after it show as "exe.jpg"
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    // Relay them as they are
+    Keep,
+    // Leave them out
+    Strip,
+    // Show them, as "[RLO]" and the like
+    Flag,
+}
+
+pub fn mode(config: &Config) -> Mode {
+    match config.direction_controls.as_ref().map(|mode| &mode[..]) {
+        None | Some("strip") => Mode::Strip,
+        Some("flag") => Mode::Flag,
+        Some("keep") => Mode::Keep,
+        Some(mode) => panic!("unknown direction_controls \"{}\", expected \"strip\", \"flag\" or \"keep\"", mode),
+    }
+}
+
+// The name of a character that embeds, overrides or isolates a direction, if `c` is one
+fn direction_control(c: char) -> Option<&'static str> {
+    match c {
+        '\u{202A}' => Some("LRE"),
+        '\u{202B}' => Some("RLE"),
+        '\u{202C}' => Some("PDF"),
+        '\u{202D}' => Some("LRO"),
+        '\u{202E}' => Some("RLO"),
+        '\u{2066}' => Some("LRI"),
+        '\u{2067}' => Some("RLI"),
+        '\u{2068}' => Some("FSI"),
+        '\u{2069}' => Some("PDI"),
+        _ => None,
+    }
+}
+
+// Characters that take no space: zero width spaces and joiners, direction marks, the word
+// joiner and byte order marks. Text needs some of them, in emoji and in Persian for
+// example, but in a name they only serve to look like someone else.
+fn is_invisible(c: char) -> bool {
+    match c as u32 {
+        0x200B...0x200F | 0x061C | 0x2060 | 0xFEFF => true,
+        _ => false,
+    }
+}
+
+// A sender's name with direction controls and invisible characters left out, whatever
+// the mode
+pub fn name(name: &str) -> String {
+    name.chars().filter(|&c| direction_control(c).is_none() && !is_invisible(c)).collect()
+}
+
+// Relayed text with its direction controls dealt with according to `mode`
+pub fn text(mode: Mode, text: &str) -> String {
+    if mode == Mode::Keep || !text.chars().any(|c| direction_control(c).is_some()) {
+        return text.to_owned();
+    }
+    let mut sanitized = String::with_capacity(text.len());
+    for c in text.chars() {
+        match direction_control(c) {
+            Some(control) => {
+                if mode == Mode::Flag {
+                    sanitized.push_str(&format!("[{}]", control));
+                }
+            }
+            None => sanitized.push(c),
+        }
+    }
+    sanitized
+}
@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use crypto::aead::{AeadDecryptor, AeadEncryptor};
+use crypto::chacha20poly1305::ChaCha20Poly1305;
+use crypto::scrypt::{scrypt, ScryptParams};
+use libc;
+use rand::{Rng, OsRng};
+use toml;
+use error::{self, ResultExt};
+use super::Config;
+
+// Config values of the form "secret:<name>" are looked up in the secrets file
+const REFERENCE_PREFIX: &'static str = "secret:";
+// Start of every secrets file, followed by the salt, the nonce, the tag and the ciphertext
+const MAGIC: &'static [u8] = b"TCSECRT1";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 8;
+const TAG_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+
+fn derive_key(secret: &[u8], salt: &[u8]) -> [u8; KEY_LEN] {
+    let mut key = [0; KEY_LEN];
+    scrypt(secret, salt, &ScryptParams::new(14, 8, 1), &mut key);
+    key
+}
+
+// Ask for the passphrase on the terminal, without echoing it
+fn prompt_passphrase(prompt: &str) -> error::Result<Vec<u8>> {
+    let mut tty = try!(OpenOptions::new().read(true).write(true).open("/dev/tty").context("opening terminal"));
+    try!(write!(tty, "{}", prompt).context("writing to terminal"));
+    let fd = tty.as_raw_fd();
+    let mut termios: libc::termios = unsafe { mem::zeroed() };
+    let echo_off = unsafe { libc::tcgetattr(fd, &mut termios) } == 0 && {
+        let mut silent = termios;
+        silent.c_lflag &= !libc::ECHO;
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &silent) == 0 }
+    };
+    let mut passphrase = String::new();
+    let read = BufReader::new(&tty).read_line(&mut passphrase);
+    if echo_off {
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &termios) };
+    }
+    let _ = writeln!(tty, "");
+    try!(read.context("reading passphrase"));
+    Ok(passphrase.trim_right_matches(|c: char| c == '\n' || c == '\r').as_bytes().to_vec())
+}
+
+// The secret the key is derived from: the contents of the key file, or a passphrase
+fn key_secret(key_file: Option<&str>) -> error::Result<Vec<u8>> {
+    match key_file {
+        Some(path) => {
+            let mut secret = vec![];
+            try!(File::open(path)
+                     .and_then(|mut file| file.read_to_end(&mut secret))
+                     .context(format!("reading key file {}", path)));
+            Ok(secret)
+        }
+        None => prompt_passphrase("Passphrase for the secrets file: "),
+    }
+}
+
+fn decrypt(path: &str, key_file: Option<&str>) -> error::Result<HashMap<String, String>> {
+    let mut data = vec![];
+    try!(File::open(path)
+             .and_then(|mut file| file.read_to_end(&mut data))
+             .context(format!("reading secrets file {}", path)));
+    let header = MAGIC.len() + SALT_LEN + NONCE_LEN + TAG_LEN;
+    if data.len() < header || &data[..MAGIC.len()] != MAGIC {
+        return Err(format!("{} is not a secrets file", path).into());
+    }
+    let (salt, rest) = data[MAGIC.len()..].split_at(SALT_LEN);
+    let (nonce, rest) = rest.split_at(NONCE_LEN);
+    let (tag, ciphertext) = rest.split_at(TAG_LEN);
+
+    let key = derive_key(&try!(key_secret(key_file)), salt);
+    let mut plaintext = vec![0; ciphertext.len()];
+    if !ChaCha20Poly1305::new(&key, nonce, MAGIC).decrypt(ciphertext, &mut plaintext, tag) {
+        return Err(format!("could not decrypt {}, wrong key or passphrase?", path).into());
+    }
+
+    let plaintext = try!(String::from_utf8(plaintext).map_err(|_| format!("{} does not contain text", path)));
+    let table = try!(toml::Parser::new(&plaintext)
+                         .parse()
+                         .ok_or(format!("{} does not contain valid TOML", path)));
+    toml::decode(toml::Value::Table(table)).ok_or(format!("{} should only contain names and values", path).into())
+}
+
+fn is_reference(value: &Option<String>) -> bool {
+    value.as_ref().map_or(false, |value| value.starts_with(REFERENCE_PREFIX))
+}
+
+fn substitute(value: &mut String, secrets: &HashMap<String, String>) -> error::Result<()> {
+    if value.starts_with(REFERENCE_PREFIX) {
+        let name = value[REFERENCE_PREFIX.len()..].to_owned();
+        match secrets.get(&name) {
+            Some(secret) => *value = secret.clone(),
+            None => return Err(format!("secret \"{}\" not found in the secrets file", name).into()),
+        }
+    }
+    Ok(())
+}
+
+// Replace the Telegram token and IRC password with their values from the secrets file,
+// if they reference it
+pub fn resolve(config: &mut Config) -> error::Result<()> {
+    if !config.token.starts_with(REFERENCE_PREFIX) && !is_reference(&config.irc.password) {
+        return Ok(());
+    }
+    let path = match config.secrets_file {
+        Some(ref path) => path.clone(),
+        None => return Err("secrets are referenced, but no secrets_file is configured".into()),
+    };
+    let secrets = try!(decrypt(&path, config.secrets_key_file.as_ref().map(|path| &path[..])));
+    try!(substitute(&mut config.token, &secrets));
+    if let Some(ref mut password) = config.irc.password {
+        try!(substitute(password, &secrets));
+    }
+    Ok(())
+}
+
+// Encrypt the secrets (TOML, e.g. `token = "..."`) read from stdin into a secrets file
+pub fn encrypt(path: &str, key_file: Option<&str>) -> error::Result<()> {
+    let mut plaintext = vec![];
+    try!(io::stdin().read_to_end(&mut plaintext).context("reading secrets from stdin"));
+    let secret = match key_file {
+        Some(_) => try!(key_secret(key_file)),
+        None => {
+            let passphrase = try!(prompt_passphrase("New passphrase: "));
+            if try!(prompt_passphrase("Repeat passphrase: ")) != passphrase {
+                return Err("passphrases don't match".into());
+            }
+            passphrase
+        }
+    };
+
+    let mut rng = try!(OsRng::new().context("opening the OS random number generator"));
+    let mut salt = [0; SALT_LEN];
+    let mut nonce = [0; NONCE_LEN];
+    rng.fill_bytes(&mut salt);
+    rng.fill_bytes(&mut nonce);
+    let key = derive_key(&secret, &salt);
+    let mut ciphertext = vec![0; plaintext.len()];
+    let mut tag = [0; TAG_LEN];
+    ChaCha20Poly1305::new(&key, &nonce, MAGIC).encrypt(&plaintext, &mut ciphertext, &mut tag);
+
+    let mut data = MAGIC.to_vec();
+    data.extend(&salt);
+    data.extend(&nonce);
+    data.extend(&tag);
+    data.extend(ciphertext);
+    File::create(path)
+        .and_then(|mut file| file.write_all(&data))
+        .context(format!("writing secrets file {}", path))
+}
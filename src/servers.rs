@@ -0,0 +1,134 @@
+use std::io::{self, BufReader, BufWriter};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, RwLock};
+use irc::client::prelude::{IrcServer, ServerExt};
+use irc::client::conn::{Connection, NetStream};
+use irc::client::data;
+use net2::TcpBuilder;
+use accounts;
+use stale;
+use typing;
+use super::Config;
+
+// The IRC server currently connected to, shared with the threads sending to it. The IRC
+// handler swaps in a new one after reconnecting, possibly to another server.
+#[derive(Clone)]
+pub struct CurrentServer(Arc<RwLock<IrcServer>>);
+
+impl CurrentServer {
+    pub fn new(server: IrcServer) -> CurrentServer {
+        CurrentServer(Arc::new(RwLock::new(server)))
+    }
+
+    pub fn get(&self) -> IrcServer {
+        self.0.read().unwrap().clone()
+    }
+
+    pub fn set(&self, server: IrcServer) {
+        *self.0.write().unwrap() = server;
+    }
+}
+
+// The configured IRC server followed by the fallback servers, connected to in turn
+pub struct Servers {
+    candidates: Vec<data::Config>,
+    bind: Option<IpAddr>,
+    // Index of the candidate to try first on the next connection
+    next: usize,
+}
+
+impl Servers {
+    pub fn new(config: &Config) -> Servers {
+        let mut candidates = vec![config.irc.clone()];
+        for server in config.irc_fallback_servers.as_ref().unwrap_or(&vec![]) {
+            let mut irc_cfg = config.irc.clone();
+            let mut parts = server.splitn(2, ':');
+            irc_cfg.server = parts.next().map(|host| host.to_owned());
+            if let Some(port) = parts.next() {
+                match port.parse() {
+                    Ok(port) => irc_cfg.port = Some(port),
+                    Err(_) => {
+                        println!("[WARN] Ignoring IRC server \"{}\" with invalid port", server);
+                        continue;
+                    }
+                }
+            }
+            candidates.push(irc_cfg);
+        }
+
+        let bind = config.irc_bind_address.as_ref().map(|addr| {
+            addr.parse::<IpAddr>().unwrap_or_else(|err| panic!("error in irc_bind_address: {}", err))
+        });
+
+        Servers {
+            candidates: candidates,
+            bind: bind,
+            next: 0,
+        }
+    }
+
+    // Connect to the next server in the list, trying the ones after it if that fails. A
+    // reconnect after losing the connection starts with the server after the lost one.
+    pub fn connect(&mut self) -> Option<IrcServer> {
+        for _ in 0..self.candidates.len() {
+            let irc_cfg = self.candidates[self.next].clone();
+            self.next = (self.next + 1) % self.candidates.len();
+            let server = irc_cfg.server.clone().unwrap_or(String::new());
+            let connected = match self.bind {
+                Some(bind) => connect_from(irc_cfg, bind),
+                None => IrcServer::from_config(irc_cfg),
+            };
+            match connected {
+                Ok(client) => {
+                    println!("[INFO] Connected to IRC server {}", server);
+                    return Some(client);
+                }
+                Err(err) => println!("[WARN] Could not connect to IRC server {}: {}", server, err),
+            }
+        }
+        None
+    }
+}
+
+// Authenticate, ask for the capabilities we need and identify on a new connection
+pub fn start(config: &Config, client: &IrcServer) -> Result<(), String> {
+    if config.irc.password.is_some() {
+        try!(client.send_sasl_plain().map_err(|err| format!("Could not authenticate with SASL: {}", err)));
+    }
+    // Typing notifications and the hostmasks of Telegram senders are sent as client tags
+    if config.relay_typing.unwrap_or(false) || config.relay_hostmasks.unwrap_or(false) {
+        typing::request_tags(client);
+    }
+    if config.max_message_age_minutes.is_some() {
+        stale::request_server_time(client);
+    }
+    if config.irc_owner_accounts.is_some() {
+        accounts::request_accounts(client);
+    }
+    client.identify().map_err(|err| format!("Could not identify to server: {}", err))
+}
+
+// Connect to an IRC server from a specific local address
+fn connect_from(irc_cfg: data::Config, bind: IpAddr) -> io::Result<IrcServer> {
+    if irc_cfg.use_ssl() {
+        return Err(io::Error::new(io::ErrorKind::Other, "irc_bind_address is not supported with SSL"));
+    }
+    let remote = try!((irc_cfg.server(), irc_cfg.port()).to_socket_addrs())
+        .find(|addr| match (*addr, bind) {
+            (SocketAddr::V4(_), IpAddr::V4(_)) | (SocketAddr::V6(_), IpAddr::V6(_)) => true,
+            _ => false,
+        });
+    let remote = match remote {
+        Some(remote) => remote,
+        None => return Err(io::Error::new(io::ErrorKind::Other, format!("server has no address of the same family as {}", bind))),
+    };
+    let builder = try!(match bind {
+        IpAddr::V4(_) => TcpBuilder::new_v4(),
+        IpAddr::V6(_) => TcpBuilder::new_v6(),
+    });
+    try!(builder.bind((bind, 0)));
+    let stream = try!(builder.connect(remote));
+    let reader = NetStream::UnsecuredTcpStream(try!(stream.try_clone()));
+    let writer = NetStream::UnsecuredTcpStream(stream);
+    Ok(IrcServer::from_connection(irc_cfg, Connection::new(BufReader::new(reader), BufWriter::new(writer))))
+}
@@ -0,0 +1,341 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use hyper::client::Request;
+use hyper::header::ContentLength;
+use hyper::method::Method;
+use hyper::server::{self, Server, Response};
+use hyper::status::StatusCode;
+use rustc_serialize::hex::ToHex;
+use rustc_serialize::json::Json;
+use time;
+use error::{self, ResultExt};
+use urls;
+use outbound::{IrcLine, Outbound, Link, wait_up};
+use queue::{self, BoundedQueue, Overflow};
+use templates;
+use watchdog::Watchdog;
+use webhook::{reject, secret_matches};
+use super::{Config, RelayState, SlackConfig};
+
+const API_URL: &'static str = "https://slack.com/api/";
+const TIMESTAMP_HEADER: &'static str = "X-Slack-Request-Timestamp";
+const SIGNATURE_HEADER: &'static str = "X-Slack-Signature";
+// Set on events Slack sends again because we didn't answer in time
+const RETRY_HEADER: &'static str = "X-Slack-Retry-Num";
+// Requests signed longer ago than this many seconds are refused, so they can't be replayed
+const MAX_REQUEST_AGE: i64 = 5 * 60;
+// Slack allows about one message per second per channel
+const POST_INTERVAL_MS: u64 = 1000;
+// Events received but not yet relayed. Slack wants an answer within three seconds, so
+// events are answered right away and relayed from this queue.
+const EVENT_QUEUE_CAPACITY: usize = 100;
+// Number of event ids remembered to recognize events sent again
+const RECENT_EVENTS: usize = 1000;
+// Larger requests are refused
+const MAX_BODY_BYTES: u64 = 1024 * 1024;
+
+fn call(slack: &SlackConfig, method: &str, body: Option<String>, query: &[(&str, &str)]) -> error::Result<Json> {
+    let mut url = try!(urls::parse(&format!("{}{}", API_URL, method), "Slack API url"));
+    if !query.is_empty() {
+        url.set_query_from_pairs(query.iter().cloned());
+    }
+    let token = format!("Bearer {}", slack.bot_token);
+    let mut resp = try!(match body {
+                            Some(body) => {
+                                Request::new(Method::Post, url)
+                                    .and_then(|mut req| {
+                                        req.headers_mut().set_raw("Authorization", vec![token.into_bytes()]);
+                                        req.headers_mut().set_raw("Content-Type",
+                                                                  vec![b"application/json; charset=utf-8".to_vec()]);
+                                        req.headers_mut().set(ContentLength(body.len() as u64));
+                                        req.start()
+                                    })
+                                    .and_then(|mut req| {
+                                        try!(req.write_all(body.as_bytes()));
+                                        req.send()
+                                    })
+                            }
+                            None => {
+                                Request::new(Method::Get, url)
+                                    .and_then(|mut req| {
+                                        req.headers_mut().set_raw("Authorization", vec![token.into_bytes()]);
+                                        req.start()
+                                    })
+                                    .and_then(|req| req.send())
+                            }
+                        }
+                        .context(format!("calling Slack {}", method)));
+    let mut reply = String::new();
+    try!(resp.read_to_string(&mut reply));
+    let reply = try!(Json::from_str(&reply).map_err(|err| err.to_string()));
+    if reply.find("ok").and_then(|ok| ok.as_boolean()) != Some(true) {
+        let error = reply.find("error").and_then(|error| error.as_string()).unwrap_or("unknown error");
+        return Err(format!("Slack {} failed: {}", method, error).into());
+    }
+    Ok(reply)
+}
+
+// Post the messages of the mappings to their Slack channels, one at a time
+pub fn send(slack: SlackConfig,
+            queue: Arc<BoundedQueue<(String, String)>>,
+            maintenance: Arc<Link>,
+            watchdog: Arc<Watchdog>) {
+    loop {
+        watchdog.idle(queue.name());
+        let entry = queue.pop();
+        if let queue::Entry::Item((channel, text)) = entry {
+            wait_up(&[&maintenance]);
+            watchdog.beat(queue.name());
+            let mut message = BTreeMap::new();
+            message.insert("channel".to_owned(), Json::String(channel.clone()));
+            message.insert("text".to_owned(), Json::String(text));
+            if let Err(err) = call(&slack, "chat.postMessage", Some(Json::Object(message).to_string()), &[]) {
+                println!("[ERROR] Could not post to Slack channel {}: {}", channel, err);
+            }
+            watchdog.idle(queue.name());
+            thread::sleep(Duration::from_millis(POST_INTERVAL_MS));
+        }
+    }
+}
+
+// Display names of Slack users, looked up once
+struct Users {
+    names: Mutex<HashMap<String, String>>,
+}
+
+impl Users {
+    fn name(&self, slack: &SlackConfig, user: &str) -> String {
+        if let Some(name) = self.names.lock().unwrap().get(user) {
+            return name.clone();
+        }
+        let name = match call(slack, "users.info", None, &[("user", user)]) {
+            Ok(reply) => {
+                let profile = |key: &str| {
+                    reply.find_path(&["user", "profile", key]).and_then(|name| name.as_string()).unwrap_or("").to_owned()
+                };
+                let display = profile("display_name");
+                if display.is_empty() { profile("real_name") } else { display }
+            }
+            Err(err) => {
+                println!("[WARN] Could not look up Slack user {}: {}", user, err);
+                String::new()
+            }
+        };
+        // Fall back to the id, without remembering it so the lookup is tried again
+        if name.is_empty() {
+            return user.to_owned();
+        }
+        self.names.lock().unwrap().insert(user.to_owned(), name.clone());
+        name
+    }
+}
+
+// Turn Slack's markup into plain text: <@U123> mentions become @name, <#C123|general>
+// becomes #general and <https://example.org|example> becomes "example (https://example.org)"
+fn plain_text(slack: &SlackConfig, users: &Users, text: &str) -> String {
+    let mut plain = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find('<') {
+        // An unclosed "<" is left as it is, with the rest of the text
+        let end = match rest[start..].find('>') {
+            Some(end) => start + end,
+            None => break,
+        };
+        plain.push_str(&rest[..start]);
+        let entity = &rest[start + 1..end];
+        let (target, label) = match entity.find('|') {
+            Some(bar) => (&entity[..bar], Some(&entity[bar + 1..])),
+            None => (entity, None),
+        };
+        if target.starts_with('@') {
+            plain.push_str(&format!("@{}", label.map_or_else(|| users.name(slack, &target[1..]), |label| label.to_owned())));
+        } else if target.starts_with('#') {
+            plain.push_str(&format!("#{}", label.unwrap_or(&target[1..])));
+        } else if target.starts_with('!') {
+            // Special mentions like <!here>
+            plain.push_str(&format!("@{}", label.unwrap_or(&target[1..])));
+        } else {
+            match label {
+                Some(label) if label != target => plain.push_str(&format!("{} ({})", label, target)),
+                _ => plain.push_str(target),
+            }
+        }
+        rest = &rest[end + 1..];
+    }
+    plain.push_str(rest);
+    plain.replace("&lt;", "<").replace("&gt;", ">").replace("&amp;", "&")
+}
+
+fn signed(slack: &SlackConfig, req: &server::Request, body: &[u8]) -> bool {
+    let header = |name: &str| {
+        req.headers.get_raw(name).and_then(|values| values.first()).map(|value| String::from_utf8_lossy(value).into_owned())
+    };
+    let (timestamp, signature) = match (header(TIMESTAMP_HEADER), header(SIGNATURE_HEADER)) {
+        (Some(timestamp), Some(signature)) => (timestamp, signature),
+        _ => return false,
+    };
+    let fresh = timestamp.parse::<i64>().map(|sent| (time::get_time().sec - sent).abs() <= MAX_REQUEST_AGE).unwrap_or(false);
+    let mut hmac = Hmac::new(Sha256::new(), slack.signing_secret.as_bytes());
+    hmac.input(format!("v0:{}:", timestamp).as_bytes());
+    hmac.input(body);
+    let expected = format!("v0={}", hmac.result().code().to_hex());
+    fresh && secret_matches(signature.as_bytes(), expected.as_bytes())
+}
+
+// Relay a message posted in a Slack channel to the rest of its mapping
+fn relay(slack: &SlackConfig,
+         users: &Users,
+         config: &Config,
+         outbound: &Outbound,
+         state: &Mutex<RelayState>,
+         event: &Json) {
+    let field = |key: &str| event.find(key).and_then(|value| value.as_string()).unwrap_or("");
+    // Edits, joins and the like have a subtype, and our own posts come from a bot
+    if field("type") != "message" || event.find("subtype").is_some() || event.find("bot_id").is_some() {
+        return;
+    }
+    let group = match slack.channels.iter().find(|&(_, channel)| channel == field("channel")) {
+        Some((group, _)) => group,
+        None => return,
+    };
+    let nick = users.name(slack, field("user"));
+    let text = plain_text(slack, users, field("text"));
+    println!("[INFO] Relaying Slack → \"{}\": <{}> {}", group, nick, text);
+
+    let state = state.lock().unwrap();
+    if let Some(id) = state.chat_ids.get(group) {
+        let message = templates::render(config, group, templates::MESSAGE, &[("nick", &nick[..]),
+                                                                             ("host", "slack"),
+                                                                             ("channel", field("channel")),
+                                                                             ("message", &text[..])]);
+        outbound.to_tg(group, *id, message);
+    }
+    if let Some(channel) = state.irc_channel.get(group) {
+        for line in text.lines() {
+            outbound.to_irc(channel, IrcLine {
+                nick: nick.clone(),
+                hostmask: None,
+                text: line.to_owned(),
+                date: time::get_time().sec,
+                received: Instant::now(),
+            });
+        }
+    }
+}
+
+// Accept the events of the Slack channels that are part of mappings
+pub fn serve(slack: SlackConfig, config: Config, outbound: Arc<Outbound>, state: Arc<Mutex<RelayState>>) {
+    let listen = slack.listen.clone();
+    let events = Arc::new(BoundedQueue::new("slack events", EVENT_QUEUE_CAPACITY, Overflow::DropOldest));
+    {
+        let slack = slack.clone();
+        let events = events.clone();
+        thread::spawn(move || {
+            let users = Users { names: Mutex::new(HashMap::new()) };
+            loop {
+                if let queue::Entry::Item(event) = events.pop() {
+                    relay(&slack, &users, &config, &outbound, &state, &event);
+                }
+            }
+        });
+    }
+    let recent: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+    let handler = move |mut req: server::Request, res: Response| {
+        // A body announced as too large is not read at all
+        if req.headers.get::<ContentLength>().map_or(false, |length| length.0 > MAX_BODY_BYTES) {
+            println!("[WARN] Refused Slack request of over {} bytes from {}", MAX_BODY_BYTES, req.remote_addr);
+            return reject(res, StatusCode::BadRequest);
+        }
+        let mut body = vec![];
+        if let Err(err) = req.by_ref().take(MAX_BODY_BYTES + 1).read_to_end(&mut body) {
+            println!("[ERROR] Could not read Slack request: {}", err);
+            return reject(res, StatusCode::BadRequest);
+        }
+        if body.len() as u64 > MAX_BODY_BYTES {
+            println!("[WARN] Refused Slack request of over {} bytes from {}", MAX_BODY_BYTES, req.remote_addr);
+            return reject(res, StatusCode::BadRequest);
+        }
+        if !signed(&slack, &req, &body) {
+            println!("[WARN] Rejected Slack request without a valid signature from {}", req.remote_addr);
+            return reject(res, StatusCode::Forbidden);
+        }
+        let payload = match Json::from_str(&String::from_utf8_lossy(&body)) {
+            Ok(payload) => payload,
+            Err(err) => {
+                println!("[ERROR] Could not decode Slack request: {}", err);
+                return reject(res, StatusCode::BadRequest);
+            }
+        };
+        match payload.find("type").and_then(|kind| kind.as_string()) {
+            // Sent when the request URL is configured in Slack
+            Some("url_verification") => {
+                let challenge = payload.find("challenge").and_then(|challenge| challenge.as_string()).unwrap_or("");
+                let _ = res.send(challenge.as_bytes());
+                return;
+            }
+            Some("event_callback") => {
+                let id = payload.find("event_id").and_then(|id| id.as_string()).unwrap_or("").to_owned();
+                let retry = req.headers.get_raw(RETRY_HEADER).is_some();
+                let mut recent = recent.lock().unwrap();
+                if recent.contains(&id) || (id.is_empty() && retry) {
+                    println!("[INFO] Skipping Slack event {} sent again", id);
+                } else if let Some(event) = payload.find("event") {
+                    if !id.is_empty() {
+                        recent.push_back(id);
+                        if recent.len() > RECENT_EVENTS {
+                            recent.pop_front();
+                        }
+                    }
+                    events.push(event.clone());
+                }
+            }
+            _ => {}
+        }
+        let _ = res.send(b"");
+    };
+
+    let _listening = match Server::http(&listen[..]).and_then(|server| server.handle(handler)) {
+        Ok(listening) => listening,
+        Err(err) => {
+            println!("[ERROR] Could not listen for Slack events on {}: {}", listen, err);
+            return;
+        }
+    };
+    println!("[INFO] Receiving Slack events on {}", listen);
+    // The server runs on its own threads
+    loop {
+        thread::park();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use SlackConfig;
+    use super::{Users, plain_text};
+
+    fn plain(text: &str) -> String {
+        let users = Users { names: Mutex::new(HashMap::new()) };
+        plain_text(&SlackConfig::default(), &users, text)
+    }
+
+    #[test]
+    fn markup_becomes_plain_text() {
+        assert_eq!(plain("see <#C123|general> &amp; <https://example.org|this>"),
+                   "see #general & this (https://example.org)");
+        assert_eq!(plain("<@U123|ann>: <!here>"), "@ann: @here");
+    }
+
+    #[test]
+    fn unclosed_markup_is_kept_once() {
+        assert_eq!(plain("a <b"), "a <b");
+        assert_eq!(plain("<!here> a <b"), "@here a <b");
+    }
+}
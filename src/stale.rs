@@ -0,0 +1,111 @@
+use std::time::Instant;
+use irc::client::prelude::ServerExt;
+use irc::client::data::{Command, Message};
+use time;
+use super::Config;
+
+// What happens to a message that is too old by the time it would be relayed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    // Don't relay it
+    Drop,
+    // Relay it, saying how long ago it was sent
+    Mark,
+}
+
+// Oldest a message may be to be relayed as if it was just said
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MaxAge {
+    pub seconds: u64,
+    pub action: Action,
+}
+
+// What to do with a message, given when it was sent
+#[derive(Clone, Debug, PartialEq)]
+pub enum Verdict {
+    Fresh,
+    // Relay it with this text in front
+    Mark(String),
+    Drop,
+}
+
+pub fn max_age(config: &Config) -> Option<MaxAge> {
+    config.max_message_age_minutes.map(|minutes| {
+        let action = match config.stale_messages.as_ref().map(|action| &action[..]) {
+            None | Some("drop") => Action::Drop,
+            Some("mark") => Action::Mark,
+            Some(action) => panic!("unknown stale_messages \"{}\", expected \"drop\" or \"mark\"", action),
+        };
+        MaxAge {
+            seconds: minutes * 60,
+            action: action,
+        }
+    })
+}
+
+// How long ago, roughly, `seconds` were
+fn describe_age(seconds: u64) -> String {
+    let minutes = seconds / 60;
+    if minutes < 60 {
+        format!("{} min ago", minutes)
+    } else if minutes < 24 * 60 {
+        format!("{}h {}m ago", minutes / 60, minutes % 60)
+    } else {
+        format!("{}d {}h ago", minutes / (24 * 60), minutes % (24 * 60) / 60)
+    }
+}
+
+impl MaxAge {
+    // Check a message that has been around for `age` seconds
+    pub fn check_age(&self, age: u64) -> Verdict {
+        if age <= self.seconds {
+            return Verdict::Fresh;
+        }
+        match self.action {
+            Action::Drop => Verdict::Drop,
+            Action::Mark => Verdict::Mark(format!("[sent {}]", describe_age(age))),
+        }
+    }
+
+    // Whether a message queued since `received` has waited too long to still be delivered.
+    // Marked messages are delivered anyway, a backlog is introduced by the replay notice.
+    pub fn expired(&self, received: Instant) -> bool {
+        self.action == Action::Drop && received.elapsed().as_secs() > self.seconds
+    }
+}
+
+// Check a message sent at `date`, a unix timestamp, such as one replayed from old Telegram
+// updates or from a bouncer's playback
+pub fn check(config: &Config, date: i64) -> Verdict {
+    let age = time::get_time().sec - date;
+    match max_age(config) {
+        Some(max_age) if age > 0 => max_age.check_age(age as u64),
+        _ => Verdict::Fresh,
+    }
+}
+
+// When an IRC message was sent according to its server-time tag, such as
+// "2024-05-01T12:34:56.789Z". Servers and bouncers only add the tag to messages they
+// replay late.
+pub fn server_time(msg: &Message) -> Option<i64> {
+    msg.tags
+       .iter()
+       .flat_map(|tags| tags)
+       .find(|tag| tag.0 == "time")
+       .and_then(|tag| tag.1.as_ref())
+       .and_then(|value| time::strptime(value.splitn(2, '.').next().unwrap_or(""), "%Y-%m-%dT%H:%M:%S").ok())
+       .map(|sent| sent.to_timespec().sec)
+}
+
+// Ask the server for the time messages were sent, which bouncers need before they tag
+// their playback with it. Needs to happen before identifying, like requesting message tags.
+pub fn request_server_time<T: ServerExt>(irc: &T) {
+    let requested = irc.send(Message {
+        tags: None,
+        prefix: None,
+        command: Command::Raw("CAP".to_owned(), vec!["REQ".to_owned()], Some("server-time".to_owned())),
+    });
+    if let Err(err) = requested {
+        println!("[WARN] Could not request server-time, old IRC messages won't be recognized: {}", err);
+    }
+}
@@ -0,0 +1,607 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+use rusqlite::Connection;
+use rustc_serialize::Decodable;
+use telegram_bot::types::Integer;
+use toml;
+use error::{self, ResultExt};
+use super::{ChatID, Config, TelegramGroup, ALIASES_FILE, AMBIGUOUS_FILE, CHAT_IDS_FILE, FACTOIDS_FILE, MEDIA_USAGE_FILE, PINS_FILE,
+            QUEUED_FILE, RELAYED_FILE, REMINDERS_FILE, UPDATES_FILE, load_toml};
+
+// Factoids learned in each mapping, by Telegram group and name
+pub type Factoids = HashMap<TelegramGroup, HashMap<String, String>>;
+
+// Handled Telegram updates, see `dedup::Seen`
+#[derive(Clone, Default, RustcEncodable, RustcDecodable, Debug)]
+pub struct Updates {
+    // Id of the next update to ask for
+    pub offset: Integer,
+    // Recently handled messages, as "chat_id:message_id", oldest first
+    pub recent: Vec<String>,
+}
+
+// A message waiting in an outbound queue, kept so it survives a restart
+#[derive(Clone, Default, RustcEncodable, RustcDecodable, Debug)]
+pub struct QueuedMessage {
+    // "irc" or "telegram"
+    pub network: String,
+    // IRC channel or Telegram group the queue delivers to
+    pub destination: String,
+    // Sender and hostmask of a message for IRC, empty if there are none
+    pub nick: String,
+    pub hostmask: String,
+    // Chat a message for Telegram goes to
+    pub chat_id: ChatID,
+    pub text: String,
+    pub date: i64,
+}
+
+// Bytes moved for mirroring media in the current day and month, see `budget::Budget`
+#[derive(Clone, Default, RustcEncodable, RustcDecodable, Debug)]
+pub struct MediaUsage {
+    // Days since the unix epoch
+    pub day: i64,
+    pub day_bytes: i64,
+    // Months since the year 0
+    pub month: i64,
+    pub month_bytes: i64,
+}
+
+// Something to remind someone of, see the remind command
+#[derive(Clone, Default, RustcEncodable, RustcDecodable, Debug)]
+pub struct Reminder {
+    // When, as a unix timestamp
+    pub due: i64,
+    // Side of the mapping to remind them on, "irc", "telegram" or "both"
+    pub network: String,
+    pub group: TelegramGroup,
+    pub nick: String,
+    pub text: String,
+}
+
+// A Telegram message relayed to IRC, see `relayed::Relayed`
+#[derive(Clone, Default, RustcEncodable, RustcDecodable, Debug)]
+pub struct RelayedEntry {
+    pub chat_id: ChatID,
+    pub message_id: Integer,
+    pub nick: String,
+    pub user_id: Integer,
+    // The text as relayed to IRC
+    pub text: String,
+}
+
+// TOML files are tables at the top
+#[derive(Clone, Default, RustcEncodable, RustcDecodable, Debug)]
+struct QueuedFile {
+    messages: Vec<QueuedMessage>,
+}
+
+#[derive(Clone, Default, RustcEncodable, RustcDecodable, Debug)]
+struct RemindersFile {
+    reminders: Vec<Reminder>,
+}
+
+#[derive(Clone, Default, RustcEncodable, RustcDecodable, Debug)]
+struct RelayedFile {
+    messages: Vec<RelayedEntry>,
+}
+
+// Where the state that has to survive restarts is kept
+pub trait StateStore: Send + Sync {
+    fn load_chat_ids(&self) -> error::Result<HashMap<TelegramGroup, ChatID>>;
+    fn save_chat_ids(&self, chat_ids: &HashMap<TelegramGroup, ChatID>) -> error::Result<()>;
+    fn load_updates(&self) -> error::Result<Updates>;
+    fn save_updates(&self, updates: &Updates) -> error::Result<()>;
+    fn load_aliases(&self) -> error::Result<HashMap<Integer, String>>;
+    fn save_aliases(&self, aliases: &HashMap<Integer, String>) -> error::Result<()>;
+    fn load_pins(&self) -> error::Result<HashMap<TelegramGroup, ChatID>>;
+    fn save_pins(&self, pins: &HashMap<TelegramGroup, ChatID>) -> error::Result<()>;
+    fn load_ambiguous(&self) -> error::Result<HashMap<TelegramGroup, Vec<ChatID>>>;
+    fn save_ambiguous(&self, ambiguous: &HashMap<TelegramGroup, Vec<ChatID>>) -> error::Result<()>;
+    fn load_queued(&self) -> error::Result<Vec<QueuedMessage>>;
+    fn save_queued(&self, messages: &[QueuedMessage]) -> error::Result<()>;
+    fn load_media_usage(&self) -> error::Result<MediaUsage>;
+    fn save_media_usage(&self, usage: &MediaUsage) -> error::Result<()>;
+    fn load_factoids(&self) -> error::Result<Factoids>;
+    fn save_factoids(&self, factoids: &Factoids) -> error::Result<()>;
+    fn load_reminders(&self) -> error::Result<Vec<Reminder>>;
+    fn save_reminders(&self, reminders: &[Reminder]) -> error::Result<()>;
+    // Oldest first
+    fn load_relayed(&self) -> error::Result<Vec<RelayedEntry>>;
+    fn save_relayed(&self, messages: &[RelayedEntry]) -> error::Result<()>;
+}
+
+// Plain TOML files in the working directory
+pub struct FileStore;
+
+// Like load_toml, but a file that can't be read or parsed is an error rather than a panic
+fn read_toml<T: Default + Decodable>(path: &str) -> error::Result<T> {
+    let mut text = String::new();
+    match File::open(path) {
+        Ok(mut file) => try!(file.read_to_string(&mut text).context(format!("reading {}", path))),
+        Err(_) => return Ok(T::default()),
+    };
+    let table = try!(toml::Parser::new(&text).parse().ok_or_else(|| format!("{} is not valid TOML", path)));
+    toml::decode(toml::Value::Table(table)).ok_or_else(|| format!("unexpected contents in {}", path).into())
+}
+
+// Written to a temporary file first and renamed over the old one, so a crash mid-write
+// leaves the old file in place rather than a truncated one
+fn write_toml<T: ::rustc_serialize::Encodable>(path: &str, value: &T) -> error::Result<()> {
+    let temporary = format!("{}.tmp", path);
+    try!(File::create(&temporary)
+             .and_then(|mut file| {
+                 try!(file.write_all(toml::encode_str(value).as_bytes()));
+                 file.sync_all()
+             })
+             .context(format!("writing {}", temporary)));
+    fs::rename(&temporary, path).context(format!("replacing {}", path))
+}
+
+impl StateStore for FileStore {
+    fn load_chat_ids(&self) -> error::Result<HashMap<TelegramGroup, ChatID>> {
+        Ok(load_toml(CHAT_IDS_FILE))
+    }
+
+    fn save_chat_ids(&self, chat_ids: &HashMap<TelegramGroup, ChatID>) -> error::Result<()> {
+        write_toml(CHAT_IDS_FILE, chat_ids)
+    }
+
+    fn load_updates(&self) -> error::Result<Updates> {
+        Ok(load_toml(UPDATES_FILE))
+    }
+
+    fn save_updates(&self, updates: &Updates) -> error::Result<()> {
+        write_toml(UPDATES_FILE, updates)
+    }
+
+    // TOML keys are strings, so the user ids are written as such
+    fn load_aliases(&self) -> error::Result<HashMap<Integer, String>> {
+        let aliases: HashMap<String, String> = load_toml(ALIASES_FILE);
+        let mut by_id = HashMap::new();
+        for (id, name) in aliases {
+            let id = try!(id.parse().map_err(|_| format!("invalid user id \"{}\" in {}", id, ALIASES_FILE)));
+            by_id.insert(id, name);
+        }
+        Ok(by_id)
+    }
+
+    fn save_aliases(&self, aliases: &HashMap<Integer, String>) -> error::Result<()> {
+        let aliases: HashMap<String, String> = aliases.iter().map(|(id, name)| (id.to_string(), name.clone())).collect();
+        write_toml(ALIASES_FILE, &aliases)
+    }
+
+    fn load_pins(&self) -> error::Result<HashMap<TelegramGroup, ChatID>> {
+        Ok(load_toml(PINS_FILE))
+    }
+
+    fn save_pins(&self, pins: &HashMap<TelegramGroup, ChatID>) -> error::Result<()> {
+        write_toml(PINS_FILE, pins)
+    }
+
+    fn load_ambiguous(&self) -> error::Result<HashMap<TelegramGroup, Vec<ChatID>>> {
+        Ok(load_toml(AMBIGUOUS_FILE))
+    }
+
+    fn save_ambiguous(&self, ambiguous: &HashMap<TelegramGroup, Vec<ChatID>>) -> error::Result<()> {
+        write_toml(AMBIGUOUS_FILE, ambiguous)
+    }
+
+    fn load_factoids(&self) -> error::Result<Factoids> {
+        Ok(load_toml(FACTOIDS_FILE))
+    }
+
+    fn save_factoids(&self, factoids: &Factoids) -> error::Result<()> {
+        write_toml(FACTOIDS_FILE, factoids)
+    }
+
+    fn load_reminders(&self) -> error::Result<Vec<Reminder>> {
+        let reminders: RemindersFile = load_toml(REMINDERS_FILE);
+        Ok(reminders.reminders)
+    }
+
+    fn save_reminders(&self, reminders: &[Reminder]) -> error::Result<()> {
+        write_toml(REMINDERS_FILE, &RemindersFile { reminders: reminders.to_vec() })
+    }
+
+    fn load_queued(&self) -> error::Result<Vec<QueuedMessage>> {
+        let queued: QueuedFile = try!(read_toml(QUEUED_FILE));
+        Ok(queued.messages)
+    }
+
+    fn save_queued(&self, messages: &[QueuedMessage]) -> error::Result<()> {
+        write_toml(QUEUED_FILE, &QueuedFile { messages: messages.to_vec() })
+    }
+
+    fn load_media_usage(&self) -> error::Result<MediaUsage> {
+        Ok(load_toml(MEDIA_USAGE_FILE))
+    }
+
+    fn save_media_usage(&self, usage: &MediaUsage) -> error::Result<()> {
+        write_toml(MEDIA_USAGE_FILE, usage)
+    }
+
+    fn load_relayed(&self) -> error::Result<Vec<RelayedEntry>> {
+        let relayed: RelayedFile = try!(read_toml(RELAYED_FILE));
+        Ok(relayed.messages)
+    }
+
+    fn save_relayed(&self, messages: &[RelayedEntry]) -> error::Result<()> {
+        write_toml(RELAYED_FILE, &RelayedFile { messages: messages.to_vec() })
+    }
+}
+
+// An SQLite database, which survives crashes mid-write
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    pub fn open(path: &str) -> error::Result<SqliteStore> {
+        let conn = try!(Connection::open(path).context(format!("opening {}", path)));
+        try!(conn.execute_batch("CREATE TABLE IF NOT EXISTS chat_ids (
+                                     tg_group TEXT PRIMARY KEY,
+                                     chat_id INTEGER NOT NULL
+                                 );
+                                 CREATE TABLE IF NOT EXISTS update_offset (
+                                     id INTEGER PRIMARY KEY CHECK (id = 0),
+                                     next_update INTEGER NOT NULL
+                                 );
+                                 CREATE TABLE IF NOT EXISTS recent_messages (
+                                     position INTEGER PRIMARY KEY,
+                                     message TEXT NOT NULL
+                                 );
+                                 CREATE TABLE IF NOT EXISTS aliases (
+                                     user_id INTEGER PRIMARY KEY,
+                                     name TEXT NOT NULL
+                                 );
+                                 CREATE TABLE IF NOT EXISTS pins (
+                                     tg_group TEXT PRIMARY KEY,
+                                     chat_id INTEGER NOT NULL
+                                 );
+                                 CREATE TABLE IF NOT EXISTS ambiguous_titles (
+                                     tg_group TEXT NOT NULL,
+                                     chat_id INTEGER NOT NULL,
+                                     PRIMARY KEY (tg_group, chat_id)
+                                 );
+                                 CREATE TABLE IF NOT EXISTS queued_messages (
+                                     position INTEGER PRIMARY KEY,
+                                     network TEXT NOT NULL,
+                                     destination TEXT NOT NULL,
+                                     nick TEXT NOT NULL,
+                                     hostmask TEXT NOT NULL,
+                                     chat_id INTEGER NOT NULL,
+                                     text TEXT NOT NULL,
+                                     date INTEGER NOT NULL
+                                 );
+                                 CREATE TABLE IF NOT EXISTS media_usage (
+                                     id INTEGER PRIMARY KEY CHECK (id = 0),
+                                     day INTEGER NOT NULL,
+                                     day_bytes INTEGER NOT NULL,
+                                     month INTEGER NOT NULL,
+                                     month_bytes INTEGER NOT NULL
+                                 );
+                                 CREATE TABLE IF NOT EXISTS factoids (
+                                     tg_group TEXT NOT NULL,
+                                     name TEXT NOT NULL,
+                                     text TEXT NOT NULL,
+                                     PRIMARY KEY (tg_group, name)
+                                 );
+                                 CREATE TABLE IF NOT EXISTS reminders (
+                                     position INTEGER PRIMARY KEY,
+                                     due INTEGER NOT NULL,
+                                     network TEXT NOT NULL,
+                                     tg_group TEXT NOT NULL,
+                                     nick TEXT NOT NULL,
+                                     text TEXT NOT NULL
+                                 );
+                                 CREATE TABLE IF NOT EXISTS relayed_messages (
+                                     position INTEGER PRIMARY KEY,
+                                     chat_id INTEGER NOT NULL,
+                                     message_id INTEGER NOT NULL,
+                                     nick TEXT NOT NULL,
+                                     user_id INTEGER NOT NULL,
+                                     text TEXT NOT NULL
+                                 );")
+                 .context(format!("creating tables in {}", path)));
+        Ok(SqliteStore { conn: Mutex::new(conn) })
+    }
+}
+
+impl StateStore for SqliteStore {
+    fn load_chat_ids(&self) -> error::Result<HashMap<TelegramGroup, ChatID>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = try!(conn.prepare("SELECT tg_group, chat_id FROM chat_ids"));
+        let rows = try!(stmt.query_map(&[], |row| (row.get(0), row.get(1))));
+        let mut chat_ids = HashMap::new();
+        for row in rows {
+            let (group, id) = try!(row);
+            chat_ids.insert(group, id);
+        }
+        Ok(chat_ids)
+    }
+
+    fn save_chat_ids(&self, chat_ids: &HashMap<TelegramGroup, ChatID>) -> error::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = try!(conn.transaction());
+        try!(tx.execute("DELETE FROM chat_ids", &[]));
+        for (group, id) in chat_ids {
+            try!(tx.execute("INSERT INTO chat_ids (tg_group, chat_id) VALUES (?, ?)", &[group, id]));
+        }
+        tx.commit().context("saving chat ids")
+    }
+
+    fn load_updates(&self) -> error::Result<Updates> {
+        let conn = self.conn.lock().unwrap();
+        let mut updates = Updates::default();
+        let mut stmt = try!(conn.prepare("SELECT next_update FROM update_offset"));
+        for offset in try!(stmt.query_map(&[], |row| row.get(0))) {
+            updates.offset = try!(offset);
+        }
+        let mut stmt = try!(conn.prepare("SELECT message FROM recent_messages ORDER BY position"));
+        for message in try!(stmt.query_map(&[], |row| row.get(0))) {
+            updates.recent.push(try!(message));
+        }
+        Ok(updates)
+    }
+
+    fn save_updates(&self, updates: &Updates) -> error::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = try!(conn.transaction());
+        try!(tx.execute("INSERT OR REPLACE INTO update_offset (id, next_update) VALUES (0, ?)",
+                        &[&updates.offset]));
+        try!(tx.execute("DELETE FROM recent_messages", &[]));
+        for (position, message) in updates.recent.iter().enumerate() {
+            try!(tx.execute("INSERT INTO recent_messages (position, message) VALUES (?, ?)",
+                            &[&(position as i64), message]));
+        }
+        tx.commit().context("saving handled updates")
+    }
+
+    fn load_aliases(&self) -> error::Result<HashMap<Integer, String>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = try!(conn.prepare("SELECT user_id, name FROM aliases"));
+        let rows = try!(stmt.query_map(&[], |row| (row.get(0), row.get(1))));
+        let mut aliases = HashMap::new();
+        for row in rows {
+            let (id, name) = try!(row);
+            aliases.insert(id, name);
+        }
+        Ok(aliases)
+    }
+
+    fn save_aliases(&self, aliases: &HashMap<Integer, String>) -> error::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = try!(conn.transaction());
+        try!(tx.execute("DELETE FROM aliases", &[]));
+        for (id, name) in aliases {
+            try!(tx.execute("INSERT INTO aliases (user_id, name) VALUES (?, ?)", &[id, name]));
+        }
+        tx.commit().context("saving aliases")
+    }
+
+    fn load_pins(&self) -> error::Result<HashMap<TelegramGroup, ChatID>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = try!(conn.prepare("SELECT tg_group, chat_id FROM pins"));
+        let rows = try!(stmt.query_map(&[], |row| (row.get(0), row.get(1))));
+        let mut pins = HashMap::new();
+        for row in rows {
+            let (group, id) = try!(row);
+            pins.insert(group, id);
+        }
+        Ok(pins)
+    }
+
+    fn save_pins(&self, pins: &HashMap<TelegramGroup, ChatID>) -> error::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = try!(conn.transaction());
+        try!(tx.execute("DELETE FROM pins", &[]));
+        for (group, id) in pins {
+            try!(tx.execute("INSERT INTO pins (tg_group, chat_id) VALUES (?, ?)", &[group, id]));
+        }
+        tx.commit().context("saving pins")
+    }
+
+    fn load_ambiguous(&self) -> error::Result<HashMap<TelegramGroup, Vec<ChatID>>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = try!(conn.prepare("SELECT tg_group, chat_id FROM ambiguous_titles ORDER BY rowid"));
+        let rows = try!(stmt.query_map(&[], |row| (row.get(0), row.get(1))));
+        let mut ambiguous = HashMap::new();
+        for row in rows {
+            let (group, id): (TelegramGroup, ChatID) = try!(row);
+            ambiguous.entry(group).or_insert(vec![]).push(id);
+        }
+        Ok(ambiguous)
+    }
+
+    fn save_ambiguous(&self, ambiguous: &HashMap<TelegramGroup, Vec<ChatID>>) -> error::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = try!(conn.transaction());
+        try!(tx.execute("DELETE FROM ambiguous_titles", &[]));
+        for (group, ids) in ambiguous {
+            for id in ids {
+                try!(tx.execute("INSERT INTO ambiguous_titles (tg_group, chat_id) VALUES (?, ?)", &[group, id]));
+            }
+        }
+        tx.commit().context("saving ambiguous titles")
+    }
+
+    fn load_queued(&self) -> error::Result<Vec<QueuedMessage>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = try!(conn.prepare("SELECT network, destination, nick, hostmask, chat_id, text, date
+                                          FROM queued_messages ORDER BY position"));
+        let rows = try!(stmt.query_map(&[], |row| {
+            QueuedMessage {
+                network: row.get(0),
+                destination: row.get(1),
+                nick: row.get(2),
+                hostmask: row.get(3),
+                chat_id: row.get(4),
+                text: row.get(5),
+                date: row.get(6),
+            }
+        }));
+        let mut messages = vec![];
+        for row in rows {
+            messages.push(try!(row));
+        }
+        Ok(messages)
+    }
+
+    fn save_queued(&self, messages: &[QueuedMessage]) -> error::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = try!(conn.transaction());
+        try!(tx.execute("DELETE FROM queued_messages", &[]));
+        for (position, message) in messages.iter().enumerate() {
+            try!(tx.execute("INSERT INTO queued_messages (position, network, destination, nick, hostmask, chat_id, \
+                             text, date) VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+                            &[&(position as i64),
+                              &message.network,
+                              &message.destination,
+                              &message.nick,
+                              &message.hostmask,
+                              &message.chat_id,
+                              &message.text,
+                              &message.date]));
+        }
+        tx.commit().context("saving queued messages")
+    }
+
+    fn load_media_usage(&self) -> error::Result<MediaUsage> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = try!(conn.prepare("SELECT day, day_bytes, month, month_bytes FROM media_usage"));
+        let rows = try!(stmt.query_map(&[], |row| {
+            MediaUsage {
+                day: row.get(0),
+                day_bytes: row.get(1),
+                month: row.get(2),
+                month_bytes: row.get(3),
+            }
+        }));
+        let mut usage = MediaUsage::default();
+        for row in rows {
+            usage = try!(row);
+        }
+        Ok(usage)
+    }
+
+    fn save_media_usage(&self, usage: &MediaUsage) -> error::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        try!(conn.execute("INSERT OR REPLACE INTO media_usage (id, day, day_bytes, month, month_bytes) \
+                           VALUES (0, ?, ?, ?, ?)",
+                          &[&usage.day, &usage.day_bytes, &usage.month, &usage.month_bytes])
+                 .context("saving media usage"));
+        Ok(())
+    }
+
+    fn load_factoids(&self) -> error::Result<Factoids> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = try!(conn.prepare("SELECT tg_group, name, text FROM factoids"));
+        let rows = try!(stmt.query_map(&[], |row| (row.get(0), row.get(1), row.get(2))));
+        let mut factoids = Factoids::new();
+        for row in rows {
+            let (group, name, text): (String, String, String) = try!(row);
+            factoids.entry(group).or_insert(HashMap::new()).insert(name, text);
+        }
+        Ok(factoids)
+    }
+
+    fn save_factoids(&self, factoids: &Factoids) -> error::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = try!(conn.transaction());
+        try!(tx.execute("DELETE FROM factoids", &[]));
+        for (group, factoids) in factoids {
+            for (name, text) in factoids {
+                try!(tx.execute("INSERT INTO factoids (tg_group, name, text) VALUES (?, ?, ?)",
+                                &[group, name, text]));
+            }
+        }
+        tx.commit().context("saving factoids")
+    }
+
+    fn load_reminders(&self) -> error::Result<Vec<Reminder>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = try!(conn.prepare("SELECT due, network, tg_group, nick, text FROM reminders ORDER BY position"));
+        let rows = try!(stmt.query_map(&[], |row| {
+            Reminder {
+                due: row.get(0),
+                network: row.get(1),
+                group: row.get(2),
+                nick: row.get(3),
+                text: row.get(4),
+            }
+        }));
+        let mut reminders = vec![];
+        for row in rows {
+            reminders.push(try!(row));
+        }
+        Ok(reminders)
+    }
+
+    fn save_reminders(&self, reminders: &[Reminder]) -> error::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = try!(conn.transaction());
+        try!(tx.execute("DELETE FROM reminders", &[]));
+        for (position, reminder) in reminders.iter().enumerate() {
+            try!(tx.execute("INSERT INTO reminders (position, due, network, tg_group, nick, text) \
+                             VALUES (?, ?, ?, ?, ?, ?)",
+                            &[&(position as i64),
+                              &reminder.due,
+                              &reminder.network,
+                              &reminder.group,
+                              &reminder.nick,
+                              &reminder.text]));
+        }
+        tx.commit().context("saving reminders")
+    }
+
+    fn load_relayed(&self) -> error::Result<Vec<RelayedEntry>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = try!(conn.prepare("SELECT chat_id, message_id, nick, user_id, text FROM relayed_messages \
+                                          ORDER BY position"));
+        let rows = try!(stmt.query_map(&[], |row| {
+            RelayedEntry {
+                chat_id: row.get(0),
+                message_id: row.get(1),
+                nick: row.get(2),
+                user_id: row.get(3),
+                text: row.get(4),
+            }
+        }));
+        let mut messages = vec![];
+        for row in rows {
+            messages.push(try!(row));
+        }
+        Ok(messages)
+    }
+
+    fn save_relayed(&self, messages: &[RelayedEntry]) -> error::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = try!(conn.transaction());
+        try!(tx.execute("DELETE FROM relayed_messages", &[]));
+        for (position, message) in messages.iter().enumerate() {
+            try!(tx.execute("INSERT INTO relayed_messages (position, chat_id, message_id, nick, user_id, text) \
+                             VALUES (?, ?, ?, ?, ?, ?)",
+                            &[&(position as i64),
+                              &message.chat_id,
+                              &message.message_id,
+                              &message.nick,
+                              &message.user_id,
+                              &message.text]));
+        }
+        tx.commit().context("saving relayed messages")
+    }
+}
+
+// Open the store selected in the config
+pub fn open(config: &Config) -> Arc<StateStore> {
+    match config.state_db {
+        Some(ref path) => {
+            println!("[INFO] Keeping state in \"{}\"", path);
+            Arc::new(SqliteStore::open(path).unwrap_or_else(|err| panic!("error opening state_db: {}", err)))
+        }
+        None => Arc::new(FileStore),
+    }
+}
@@ -0,0 +1,196 @@
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::str;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use hyper::client::Request;
+use hyper::header::ContentLength;
+use hyper::method::Method;
+use hyper::server::{self, Server, Response};
+use hyper::status::StatusCode;
+use rustc_serialize::json::Json;
+use time;
+use error::{self, ResultExt};
+use message::escape_markdown;
+use urls;
+use outbound::{IrcLine, Outbound, Link, wait_up};
+use queue::{self, BoundedQueue};
+use templates;
+use watchdog::Watchdog;
+use webhook::{reject, secret_matches};
+use super::{Config, RelayState, TeamChatConfig, TeamChatLink};
+
+// Name our posts are made under, unless configured
+pub const DEFAULT_USERNAME: &'static str = "tiercel";
+// Pause between posts, to stay clear of rate limits
+const POST_INTERVAL_MS: u64 = 500;
+// Larger requests are refused
+const MAX_BODY_BYTES: u64 = 1024 * 1024;
+
+// Decode an application/x-www-form-urlencoded value
+fn decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                match str::from_utf8(&bytes[i + 1..i + 3]).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+// The fields of an outgoing webhook request, which Mattermost sends as a form by default
+// and Rocket.Chat as JSON
+fn fields(body: &str) -> BTreeMap<String, String> {
+    let mut fields = BTreeMap::new();
+    if body.trim_left().starts_with('{') {
+        if let Ok(Json::Object(object)) = Json::from_str(body) {
+            for (key, value) in object {
+                match value {
+                    Json::String(value) => {
+                        fields.insert(key, value);
+                    }
+                    Json::Boolean(true) => {
+                        fields.insert(key, "true".to_owned());
+                    }
+                    _ => {}
+                }
+            }
+        }
+    } else {
+        for pair in body.split('&') {
+            let mut parts = pair.splitn(2, '=');
+            let key = decode(parts.next().unwrap_or(""));
+            fields.insert(key, decode(parts.next().unwrap_or("")));
+        }
+    }
+    fields
+}
+
+fn post(url: &str, body: &str) -> error::Result<()> {
+    let url = try!(urls::parse(url, "incoming webhook url"));
+    let resp = try!(Request::new(Method::Post, url)
+                        .and_then(|mut req| {
+                            req.headers_mut().set_raw("Content-Type", vec![b"application/json".to_vec()]);
+                            req.headers_mut().set(ContentLength(body.len() as u64));
+                            req.start()
+                        })
+                        .and_then(|mut req| {
+                            try!(req.write_all(body.as_bytes()));
+                            req.send()
+                        })
+                        .context("posting to the incoming webhook"));
+    if !resp.status.is_success() {
+        return Err(format!("posting to the incoming webhook: server responded with {}", resp.status).into());
+    }
+    Ok(())
+}
+
+// Post the messages of the mappings to their incoming webhooks, one at a time. Entries
+// are (webhook url, sender, Markdown text). Messages of the bridge itself have no sender.
+pub fn send(queue: Arc<BoundedQueue<(String, String, String)>>, maintenance: Arc<Link>, watchdog: Arc<Watchdog>) {
+    loop {
+        watchdog.idle(queue.name());
+        let entry = queue.pop();
+        if let queue::Entry::Item((url, nick, text)) = entry {
+            wait_up(&[&maintenance]);
+            watchdog.beat(queue.name());
+            let mut message = BTreeMap::new();
+            let text = if nick.is_empty() { text } else { format!("**{}**: {}", escape_markdown(&nick), text) };
+            message.insert("text".to_owned(), Json::String(text));
+            if let Err(err) = post(&url, &Json::Object(message).to_string()) {
+                println!("[ERROR] {}", err);
+            }
+            watchdog.idle(queue.name());
+            thread::sleep(Duration::from_millis(POST_INTERVAL_MS));
+        }
+    }
+}
+
+// Relay a message posted in a team chat channel to the rest of its mapping
+fn relay(link: &TeamChatLink, config: &Config, outbound: &Outbound, state: &Mutex<RelayState>, nick: &str, text: &str) {
+    println!("[INFO] Relaying team chat → \"{}\": <{}> {}", link.group, nick, text);
+    let state = state.lock().unwrap();
+    if let Some(id) = state.chat_ids.get(&link.group) {
+        let message = templates::render(config, &link.group, templates::MESSAGE, &[("nick", nick),
+                                                                                   ("host", "teamchat"),
+                                                                                   ("channel", &link.group[..]),
+                                                                                   ("message", text)]);
+        outbound.to_tg(&link.group, *id, message);
+    }
+    if let Some(channel) = state.irc_channel.get(&link.group) {
+        for line in text.lines() {
+            outbound.to_irc(channel, IrcLine {
+                nick: nick.to_owned(),
+                hostmask: None,
+                text: line.to_owned(),
+                date: time::get_time().sec,
+                received: Instant::now(),
+            });
+        }
+    }
+}
+
+// Accept the outgoing webhook requests of Mattermost or Rocket.Chat channels taking part
+// in mappings. Requests are told apart by the token of their webhook.
+pub fn serve(teamchat: TeamChatConfig, config: Config, outbound: Arc<Outbound>, state: Arc<Mutex<RelayState>>) {
+    let listen = teamchat.listen.clone();
+    let username = teamchat.username.clone().unwrap_or(DEFAULT_USERNAME.to_owned());
+    let handler = move |mut req: server::Request, res: Response| {
+        // A body announced as too large is not read at all
+        if req.headers.get::<ContentLength>().map_or(false, |length| length.0 > MAX_BODY_BYTES) {
+            println!("[WARN] Refused team chat request of over {} bytes from {}", MAX_BODY_BYTES, req.remote_addr);
+            return reject(res, StatusCode::BadRequest);
+        }
+        let mut body = String::new();
+        if let Err(err) = req.by_ref().take(MAX_BODY_BYTES + 1).read_to_string(&mut body) {
+            println!("[ERROR] Could not read team chat request: {}", err);
+            return reject(res, StatusCode::BadRequest);
+        }
+        if body.len() as u64 > MAX_BODY_BYTES {
+            println!("[WARN] Refused team chat request of over {} bytes from {}", MAX_BODY_BYTES, req.remote_addr);
+            return reject(res, StatusCode::BadRequest);
+        }
+        let fields = fields(&body);
+        let token = fields.get("token").map_or("", |token| &token[..]);
+        let link = match teamchat.links.iter().find(|link| secret_matches(token.as_bytes(), link.token.as_bytes())) {
+            Some(link) => link,
+            None => {
+                println!("[WARN] Rejected team chat request without a valid token from {}", req.remote_addr);
+                return reject(res, StatusCode::Forbidden);
+            }
+        };
+        let field = |key: &str| fields.get(key).map_or("", |value| &value[..]);
+        // Leave out our own posts and those of other bots
+        if field("user_name") != username && field("bot") != "true" && !field("text").is_empty() {
+            relay(link, &config, &outbound, &state, field("user_name"), field("text"));
+        }
+        let _ = res.send(b"");
+    };
+
+    let _listening = match Server::http(&listen[..]).and_then(|server| server.handle(handler)) {
+        Ok(listening) => listening,
+        Err(err) => {
+            println!("[ERROR] Could not listen for team chat webhooks on {}: {}", listen, err);
+            return;
+        }
+    };
+    println!("[INFO] Receiving team chat webhooks on {}", listen);
+    // The server runs on its own threads
+    loop {
+        thread::park();
+    }
+}
@@ -0,0 +1,65 @@
+use super::{Config, TemplateConfig};
+
+// Events relayed from IRC to Telegram, each phrased by its own template
+pub const MESSAGE: &'static str = "message";
+pub const ACTION: &'static str = "action";
+pub const JOIN: &'static str = "join";
+pub const PART: &'static str = "part";
+// Bridge posts to both sides of a mapping
+pub const FEED: &'static str = "feed";
+pub const EMAIL: &'static str = "email";
+
+// Language used when neither the mapping nor the config names one
+pub const DEFAULT_LANGUAGE: &'static str = "en";
+
+// The language of a mapping: its own, else the configured one, else English
+pub fn language<'a>(config: &'a Config, group: &str) -> &'a str {
+    config.languages
+          .as_ref()
+          .and_then(|languages| languages.get(group))
+          .or(config.language.as_ref())
+          .map_or(DEFAULT_LANGUAGE, |language| &language[..])
+}
+
+fn default(event: &str) -> &'static str {
+    match event {
+        ACTION => "* {nick} {message}",
+        JOIN => "{nick} ({host}) joined {channel}",
+        PART => "{nick} left {channel}",
+        FEED => "[{feed}] {title} {link}",
+        EMAIL => "[mail] {from}: {subject}\n{summary}",
+        _ => "<{nick}> {message}",
+    }
+}
+
+fn configured<'a>(templates: &'a TemplateConfig, event: &str) -> Option<&'a String> {
+    match event {
+        MESSAGE => templates.message.as_ref(),
+        ACTION => templates.action.as_ref(),
+        JOIN => templates.join.as_ref(),
+        PART => templates.part.as_ref(),
+        FEED => templates.feed.as_ref(),
+        EMAIL => templates.email.as_ref(),
+        _ => None,
+    }
+}
+
+// Phrase `event` for the Telegram group `group` in the group's language, filling in the
+// {placeholders} from `fields`. Unknown placeholders are left as they are.
+pub fn render(config: &Config, group: &str, event: &str, fields: &[(&str, &str)]) -> String {
+    let template = config.templates
+                         .as_ref()
+                         .and_then(|templates| templates.get(language(config, group)))
+                         .and_then(|templates| configured(templates, event))
+                         .map_or(default(event), |template| &template[..]);
+    fill(template, fields)
+}
+
+// Fill the {placeholders} of `template` from `fields`
+pub fn fill(template: &str, fields: &[(&str, &str)]) -> String {
+    let mut text = template.to_owned();
+    for &(name, value) in fields {
+        text = text.replace(&format!("{{{}}}", name), value);
+    }
+    text
+}
@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+use std::thread;
+use std::time::{Duration, Instant};
+use hyper::method::Method;
+use hyper::client::Request;
+use irc::client::prelude::ServerExt;
+use irc::client::data::{Command, Message};
+use error::{self, ResultExt};
+use urls;
+use super::{ChatID, TelegramGroup};
+
+// Telegram shows a chat action for about five seconds, so it is renewed no more often
+const ACTION_INTERVAL: u64 = 4;
+
+// Client tags of the IRCv3 typing specification, and the draft it grew out of
+const TYPING_TAGS: &'static [&'static str] = &["+typing", "+draft/typing"];
+
+// Ask the server to pass on client tags, which typing notifications and the hostmasks of
+// Telegram senders are sent as. Needs to happen before identifying, which ends capability
+// negotiation.
+pub fn request_tags<T: ServerExt>(irc: &T) {
+    let requested = irc.send(Message {
+        tags: None,
+        prefix: None,
+        command: Command::Raw("CAP".to_owned(), vec!["REQ".to_owned()], Some("message-tags".to_owned())),
+    });
+    if let Err(err) = requested {
+        println!("[WARN] Could not request message tags, typing and hostmasks won't be relayed: {}", err);
+    }
+}
+
+// The channel `msg` says someone started typing in, if it is a typing notification
+pub fn typing_channel(msg: &Message) -> Option<&str> {
+    let target = match msg.command {
+        Command::Raw(ref command, ref args, _) if command == "TAGMSG" => args.first(),
+        _ => None,
+    };
+    let active = msg.tags.iter().flat_map(|tags| tags).any(|tag| {
+        TYPING_TAGS.contains(&&tag.0[..]) && tag.1.as_ref().map(|value| &value[..]) == Some("active")
+    });
+    if active { target.map(|target| &target[..]) } else { None }
+}
+
+// Show the bot as typing in a Telegram chat
+fn send_typing(token: &str, chat_id: ChatID) -> error::Result<()> {
+    let mut url = try!(urls::parse(&format!("https://api.telegram.org/bot{}/sendChatAction", token),
+                                   "sendChatAction url"));
+    url.set_query_from_pairs(vec![("chat_id", chat_id.to_string()), ("action", "typing".to_owned())]
+                                 .iter()
+                                 .map(|&(key, ref value)| (key, &value[..])));
+    let resp = try!(Request::new(Method::Get, url)
+                        .and_then(|req| req.start())
+                        .and_then(|req| req.send())
+                        .context(format!("sending typing to chat {}", chat_id)));
+    if !resp.status.is_success() {
+        return Err(format!("sending typing to chat {}: server responded with {}", chat_id, resp.status).into());
+    }
+    Ok(())
+}
+
+// Mirrors IRC users typing to their Telegram groups, at most as often as Telegram needs
+#[derive(Default)]
+pub struct Typing {
+    last_sent: HashMap<TelegramGroup, Instant>,
+}
+
+impl Typing {
+    // Someone is typing in the channel of `group`. The request is made in the background,
+    // the IRC reader can't wait for Telegram.
+    pub fn typing(&mut self, token: &str, group: &str, chat_id: ChatID) {
+        let due = self.last_sent
+                      .get(group)
+                      .map_or(true, |sent| sent.elapsed() >= Duration::from_secs(ACTION_INTERVAL));
+        if !due {
+            return;
+        }
+        self.last_sent.insert(group.to_owned(), Instant::now());
+        let token = token.to_owned();
+        thread::spawn(move || {
+            if let Err(err) = send_typing(&token, chat_id) {
+                println!("[WARN] {}", err);
+            }
+        });
+    }
+}
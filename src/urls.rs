@@ -0,0 +1,84 @@
+use hyper;
+use hyper::Url;
+use error::{self, ResultExt};
+use super::Config;
+
+// Parse `url`, naming it `what` in the error. The URL itself is left out of the error, as
+// some carry the bot token.
+pub fn parse(url: &str, what: &str) -> error::Result<Url> {
+    Url::parse(url).map_err(hyper::Error::Uri).context(format!("parsing the {}", what))
+}
+
+// `url` as it can be shown in logs and errors, with the bot token in the path of Telegram
+// file URLs ("/file/bot<token>/...") left out
+pub fn redacted(url: &Url) -> String {
+    let mut url = url.clone();
+    if let Some(path) = url.path_mut() {
+        for segment in path.iter_mut() {
+            if segment.starts_with("bot") && (segment.contains(':') || segment.contains("%3A")) {
+                *segment = "bot<token>".to_owned();
+            }
+        }
+    }
+    url.to_string()
+}
+
+// Percent-encode everything but the unreserved characters of a path segment
+pub fn encode_segment(segment: &str) -> String {
+    segment.bytes()
+           .map(|byte| match byte {
+               b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+               _ => format!("%{:02X}", byte),
+           })
+           .collect()
+}
+
+// `base` with `segments` appended to its path, each percent-encoded. Segments that would
+// leave the directory of the one before, such as "..", are refused.
+pub fn join(base: &Url, segments: &[&str]) -> error::Result<Url> {
+    let mut url = base.clone();
+    {
+        let path = try!(url.path_mut().ok_or(format!("{} can't have a path", base)));
+        // A trailing slash leaves an empty last segment
+        if path.last().map_or(false, |last| last.is_empty()) {
+            path.pop();
+        }
+        for segment in segments {
+            if segment.is_empty() || *segment == "." || *segment == ".." {
+                return Err(format!("invalid path segment \"{}\"", segment).into());
+            }
+            path.push(encode_segment(segment));
+        }
+    }
+    Ok(url)
+}
+
+// The configured base URL of mirrored media
+pub fn base_url(config: &Config) -> error::Result<Url> {
+    let base_url = try!(config.base_url.as_ref().ok_or("base_url is not configured"));
+    let url = try!(parse(base_url, "base_url"));
+    if url.scheme != "http" && url.scheme != "https" {
+        return Err(format!("base_url must be an http or https URL, not {}", url.scheme).into());
+    }
+    Ok(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::Url;
+    use super::redacted;
+
+    #[test]
+    fn redacts_the_token_of_file_urls() {
+        let url = Url::parse("https://api.telegram.org/file/bot123456:AAbbCC-dd/photos/file_1.jpg").unwrap();
+        let shown = redacted(&url);
+        assert!(!shown.contains("123456:AAbbCC-dd"));
+        assert!(shown.ends_with("/file/bot<token>/photos/file_1.jpg"));
+    }
+
+    #[test]
+    fn leaves_other_urls_alone() {
+        let url = Url::parse("https://example.org/media/bottle.jpg").unwrap();
+        assert_eq!(redacted(&url), url.to_string());
+    }
+}
@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use telegram_bot::Api;
+
+// Seconds a handler may go without any activity before it is considered stalled. This
+// needs to leave room for the Telegram handler mirroring media, with retries.
+pub const DEFAULT_STALL_SECONDS: u64 = 1200;
+// How often the watchdog looks at the handlers
+const CHECK_INTERVAL: u64 = 30;
+// How long we give the admins' alerts to go out before exiting
+const ALERT_GRACE: u64 = 10;
+
+struct Activity {
+    last: Instant,
+    // Only busy handlers can stall. Outbound workers are idle while their queue is empty.
+    busy: bool,
+}
+
+// Tracks the last activity of the IRC reader, the Telegram long poll and the outbound
+// workers, so that a handler that silently wedges can be noticed.
+pub struct Watchdog {
+    handlers: Mutex<HashMap<String, Activity>>,
+}
+
+impl Watchdog {
+    pub fn new() -> Watchdog {
+        Watchdog { handlers: Mutex::new(HashMap::new()) }
+    }
+
+    // Note that a handler is making progress
+    pub fn beat(&self, name: &str) {
+        self.handlers.lock().unwrap().insert(name.to_owned(),
+                                             Activity {
+                                                 last: Instant::now(),
+                                                 busy: true,
+                                             });
+    }
+
+    // Note that a handler is waiting for work, and can't stall until it gets some
+    pub fn idle(&self, name: &str) {
+        self.handlers.lock().unwrap().insert(name.to_owned(),
+                                             Activity {
+                                                 last: Instant::now(),
+                                                 busy: false,
+                                             });
+    }
+
+    fn stalled(&self, threshold: Duration) -> Vec<(String, u64)> {
+        self.handlers
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|&(_, activity)| activity.busy && activity.last.elapsed() >= threshold)
+            .map(|(name, activity)| (name.clone(), activity.last.elapsed().as_secs()))
+            .collect()
+    }
+}
+
+// Check on the handlers until one of them stalls. Threads can't be torn down from the
+// outside, so a stalled handler is restarted by alerting the admins and exiting, leaving
+// the restart to whatever supervises the bot. Along with the persisted update offset this
+// picks up right where we left off.
+pub fn watch(watchdog: Arc<Watchdog>, tg: Arc<Api>, admins: Vec<i64>, threshold: Duration) {
+    loop {
+        thread::sleep(Duration::from_secs(CHECK_INTERVAL));
+        let stalled = watchdog.stalled(threshold);
+        if stalled.is_empty() {
+            continue;
+        }
+
+        let report = stalled.iter()
+                            .map(|&(ref name, seconds)| format!("\"{}\" (no activity for {}s)", name, seconds))
+                            .collect::<Vec<_>>()
+                            .join(", ");
+        println!("[ERROR] Watchdog: stalled handlers: {}, restarting", report);
+
+        // The alert goes out on its own thread, as Telegram itself may be what is stuck
+        let (done_tx, done_rx) = mpsc::channel();
+        thread::spawn(move || {
+            let alert = format!("Relay restarting, stalled handlers: {}", report);
+            for admin in admins {
+                if let Err(err) = tg.send_message(admin, alert.clone(), None, None, None, None) {
+                    println!("[ERROR] Could not alert admin {}: {}", admin, err);
+                }
+            }
+            let _ = done_tx.send(());
+        });
+        let _ = done_rx.recv_timeout(Duration::from_secs(ALERT_GRACE));
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,225 @@
+use std::fs::File;
+use std::io::{Read, Write};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use hyper::client::Request;
+use hyper::header::ContentLength;
+use hyper::method::Method;
+use hyper::server::{self, Server, Response};
+use hyper::status::StatusCode;
+use rustc_serialize::json;
+use telegram_bot::Api;
+use telegram_bot::types::Update;
+use dedup::Seen;
+use error::{self, ResultExt};
+use urls;
+use outbound::Outbound;
+use super::{Config, RelayState, WebhookConfig, handle_update};
+
+const SECRET_HEADER: &'static str = "X-Telegram-Bot-Api-Secret-Token";
+// Set by reverse proxies to the address of whoever they got the request from
+const FORWARDED_FOR_HEADER: &'static str = "X-Forwarded-For";
+// Separates the parts of the setWebhook request when uploading a certificate
+const BOUNDARY: &'static str = "tiercel-webhook-certificate";
+// Address ranges Telegram delivers webhook requests from, as (network, prefix length)
+const TELEGRAM_RANGES: &'static [([u8; 4], u32)] = &[([149, 154, 160, 0], 20), ([91, 108, 4, 0], 22)];
+
+// Point Telegram at our webhook
+fn register(token: &str, webhook: &WebhookConfig, allowed: &[String]) -> error::Result<()> {
+    let mut url = try!(urls::parse(&format!("https://api.telegram.org/bot{}/setWebhook", token), "setWebhook url"));
+    let allowed = allowed.iter().map(|kind| format!("\"{}\"", kind)).collect::<Vec<_>>().join(",");
+    let mut params = vec![("url", webhook.url.clone()), ("allowed_updates", format!("[{}]", allowed))];
+    if let Some(ref secret) = webhook.secret_token {
+        params.push(("secret_token", secret.clone()));
+    }
+
+    let resp = match webhook.certificate {
+        // A certificate has to be uploaded, so everything goes in a multipart form
+        Some(ref path) => {
+            let mut certificate = vec![];
+            try!(File::open(path)
+                     .and_then(|mut file| file.read_to_end(&mut certificate))
+                     .context(format!("reading webhook certificate {}", path)));
+            let mut body = vec![];
+            for &(key, ref value) in &params {
+                body.extend(format!("--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                                    BOUNDARY,
+                                    key,
+                                    value)
+                                .as_bytes());
+            }
+            body.extend(format!("--{}\r\nContent-Disposition: form-data; name=\"certificate\"; \
+                                 filename=\"certificate.pem\"\r\nContent-Type: application/octet-stream\r\n\r\n",
+                                BOUNDARY)
+                            .as_bytes());
+            body.extend(certificate);
+            body.extend(format!("\r\n--{}--\r\n", BOUNDARY).as_bytes());
+
+            try!(Request::new(Method::Post, url)
+                     .and_then(|mut req| {
+                         req.headers_mut().set_raw("Content-Type",
+                                                   vec![format!("multipart/form-data; boundary={}", BOUNDARY)
+                                                            .into_bytes()]);
+                         req.headers_mut().set(ContentLength(body.len() as u64));
+                         req.start()
+                     })
+                     .and_then(|mut req| {
+                         try!(req.write_all(&body));
+                         req.send()
+                     })
+                     .context("registering webhook"))
+        }
+        None => {
+            url.set_query_from_pairs(params.iter().map(|&(key, ref value)| (key, &value[..])));
+            try!(Request::new(Method::Get, url)
+                     .and_then(|req| req.start())
+                     .and_then(|req| req.send())
+                     .context("registering webhook"))
+        }
+    };
+    if !resp.status.is_success() {
+        return Err(format!("registering webhook: server responded with {}", resp.status).into());
+    }
+    Ok(())
+}
+
+// Check the webhook settings before registering it
+pub fn check(webhook: &WebhookConfig) -> Result<(), String> {
+    if let Some(ref proxy) = webhook.trusted_proxy {
+        try!(proxy.parse::<IpAddr>().map_err(|_| format!("trusted_proxy \"{}\" is not an IP address", proxy)));
+    } else if webhook.telegram_ips_only.unwrap_or(false) {
+        // Every request would come from the TLS terminating proxy
+        return Err("telegram_ips_only needs the address of the proxy in front of the webhook as trusted_proxy".to_owned());
+    }
+    if webhook.secret_token.is_none() && !webhook.insecure.unwrap_or(false) {
+        return Err("no secret_token set, set insecure = true to accept updates from anyone".to_owned());
+    }
+    Ok(())
+}
+
+// Stop Telegram from pushing updates to the webhook, for polling them instead
+pub fn unregister(token: &str) -> error::Result<()> {
+    let url = try!(urls::parse(&format!("https://api.telegram.org/bot{}/deleteWebhook", token), "deleteWebhook url"));
+    let resp = try!(Request::new(Method::Get, url)
+                        .and_then(|req| req.start())
+                        .and_then(|req| req.send())
+                        .context("removing webhook"));
+    if !resp.status.is_success() {
+        return Err(format!("removing webhook: server responded with {}", resp.status).into());
+    }
+    Ok(())
+}
+
+// The address a request was sent from: the peer, or the address the trusted proxy says it
+// forwarded the request for. None if a proxy is configured but the request didn't come
+// through it.
+fn sender(webhook: &WebhookConfig, req: &server::Request) -> Option<IpAddr> {
+    let proxy = match webhook.trusted_proxy {
+        Some(ref proxy) => proxy.parse::<IpAddr>().ok(),
+        None => return Some(req.remote_addr.ip()),
+    };
+    if proxy != Some(req.remote_addr.ip()) {
+        return None;
+    }
+    // Earlier entries are whatever the client claimed, only the last one is the proxy's
+    req.headers
+       .get_raw(FORWARDED_FOR_HEADER)
+       .and_then(|values| values.last())
+       .and_then(|value| String::from_utf8_lossy(value).split(',').last().map(|addr| addr.trim().to_owned()))
+       .and_then(|addr| addr.parse().ok())
+}
+
+fn from_telegram(ip: IpAddr) -> bool {
+    let ip = match ip {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(_) => return false,
+    };
+    let ip = ip.octets().iter().fold(0u32, |acc, &octet| acc << 8 | octet as u32);
+    TELEGRAM_RANGES.iter().any(|&(network, prefix)| {
+        let network = network.iter().fold(0u32, |acc, &octet| acc << 8 | octet as u32);
+        let mask = !0u32 << (32 - prefix);
+        ip & mask == network & mask
+    })
+}
+
+// Compare secrets without giving away how much of them matched through timing
+pub fn secret_matches(given: &[u8], secret: &[u8]) -> bool {
+    given.len() == secret.len() && given.iter().zip(secret).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+pub fn reject(mut res: Response, status: StatusCode) {
+    *res.status_mut() = status;
+    let _ = res.send(b"");
+}
+
+// Accept updates pushed to us by Telegram instead of polling for them
+pub fn serve(tg: Arc<Api>,
+             outbound: Arc<Outbound>,
+             config: Config,
+             state: Arc<Mutex<RelayState>>,
+             seen: Seen,
+             webhook: WebhookConfig,
+             allowed: &[String]) {
+    if let Err(err) = register(&config.token, &webhook, allowed) {
+        println!("[ERROR] {}", err);
+        ::std::process::exit(1);
+    }
+    if webhook.secret_token.is_none() {
+        println!("[WARN] Webhook is insecure, anyone who finds it can inject messages");
+    }
+
+    let seen = Mutex::new(seen);
+    let listen = webhook.listen.clone();
+    let handler = move |mut req: server::Request, res: Response| {
+        let sender = match sender(&webhook, &req) {
+            Some(sender) => sender,
+            None => {
+                println!("[WARN] Rejected webhook request from {} not forwarded by the trusted proxy", req.remote_addr);
+                return reject(res, StatusCode::Forbidden);
+            }
+        };
+        if webhook.telegram_ips_only.unwrap_or(false) && !from_telegram(sender) {
+            println!("[WARN] Rejected webhook request from {}", sender);
+            return reject(res, StatusCode::Forbidden);
+        }
+        if let Some(ref secret) = webhook.secret_token {
+            let valid = match req.headers.get_raw(SECRET_HEADER) {
+                Some(values) => values.len() == 1 && secret_matches(&values[0], secret.as_bytes()),
+                None => false,
+            };
+            if !valid {
+                println!("[WARN] Rejected webhook request without a valid secret from {}", req.remote_addr);
+                return reject(res, StatusCode::Forbidden);
+            }
+        }
+
+        let mut body = String::new();
+        if let Err(err) = req.read_to_string(&mut body) {
+            println!("[ERROR] Could not read webhook request: {}", err);
+            return reject(res, StatusCode::BadRequest);
+        }
+        let update: Update = match json::decode(&body) {
+            Ok(update) => update,
+            Err(err) => {
+                println!("[ERROR] Could not decode webhook update: {}", err);
+                return reject(res, StatusCode::BadRequest);
+            }
+        };
+        handle_update(&tg, &outbound, &config, &state, &mut seen.lock().unwrap(), update);
+        let _ = res.send(b"");
+    };
+
+    let _listening = match Server::http(&listen[..]).and_then(|server| server.handle(handler)) {
+        Ok(listening) => listening,
+        Err(err) => {
+            println!("[ERROR] Could not listen for webhook requests on {}: {}", listen, err);
+            ::std::process::exit(1);
+        }
+    };
+    println!("[INFO] Receiving Telegram updates on {}", listen);
+    // The server runs on its own threads
+    loop {
+        thread::park();
+    }
+}